@@ -0,0 +1,250 @@
+/// Compose flags that take a value, which may be given as the next argument.
+const VALUE_FLAGS: [&str; 14] = [
+    "--attach",
+    "--env-file",
+    "--exit-code-from",
+    "--file",
+    "--no-attach",
+    "--profile",
+    "--project-name",
+    "--pull",
+    "--scale",
+    "--timeout",
+    "--wait-timeout",
+    "-f",
+    "-p",
+    "-t",
+];
+
+/// Flags of `exec` and `run` that take a value, which may be given as the
+/// next argument.
+const CONTAINER_VALUE_FLAGS: [&str; 18] = [
+    "--cap-add",
+    "--cap-drop",
+    "--entrypoint",
+    "--env",
+    "--env-from-file",
+    "--index",
+    "--label",
+    "--name",
+    "--publish",
+    "--user",
+    "--volume",
+    "--workdir",
+    "-e",
+    "-l",
+    "-p",
+    "-u",
+    "-v",
+    "-w",
+];
+
+/// The flags that stop `exec` and `run` from allocating a TTY.
+pub const NO_TTY_FLAGS: [&str; 2] = ["-T", "--no-TTY"];
+
+/// Splits arguments into groups of a flag along with its value if it takes one
+/// as a separate argument. Anything that isn't a flag is a group of its own.
+fn groups(args: &[String]) -> Vec<&[String]> {
+    let mut groups = Vec::new();
+    let mut index = 0;
+
+    while index < args.len() {
+        let arg = &args[index];
+        let length = if VALUE_FLAGS.contains(&arg.as_str()) && index + 1 < args.len() {
+            2
+        } else {
+            1
+        };
+
+        groups.push(&args[index..index + length]);
+        index += length;
+    }
+
+    groups
+}
+
+/// The name of the flag an argument sets, if it is a flag.
+fn flag_name(arg: &str) -> Option<&str> {
+    if !arg.starts_with('-') || arg == "-" || arg == "--" {
+        return None;
+    }
+
+    Some(arg.split_once('=').map(|(name, _)| name).unwrap_or(arg))
+}
+
+/// Whether any of the named flags is given in the arguments.
+pub fn has_flag(args: &[String], names: &[&str]) -> bool {
+    groups(args)
+        .into_iter()
+        .filter_map(|group| flag_name(&group[0]))
+        .any(|name| names.contains(&name))
+}
+
+/// The options of `exec` or `run` given before the service. Everything from
+/// the service on is the service and the command to run in its container.
+pub fn service_options(args: &[String]) -> &[String] {
+    let mut index = 0;
+
+    while index < args.len() {
+        let arg = args[index].as_str();
+        if flag_name(arg).is_none() {
+            break;
+        }
+
+        index += if CONTAINER_VALUE_FLAGS.contains(&arg) {
+            2
+        } else {
+            1
+        };
+    }
+
+    &args[..index.min(args.len())]
+}
+
+/// The boolean flag that a `--no-` flag turns off, if it is one. Flags that
+/// take a value, like `--no-attach`, are flags of their own.
+fn negated(name: &str) -> Option<String> {
+    let flag = name.strip_prefix("--no-")?;
+    (!VALUE_FLAGS.contains(&name)).then(|| format!("--{}", flag))
+}
+
+/// Combines the flags that stacks adds to a command with the user's arguments.
+/// Injected flags the user already gave, or turned off with `--no-`, are
+/// dropped so the user's choice wins.
+pub fn merge(injected: &[String], user: &[String]) -> Vec<String> {
+    let given: Vec<String> = groups(user)
+        .into_iter()
+        .filter_map(|group| flag_name(&group[0]))
+        .flat_map(|name| [Some(name.to_owned()), negated(name)])
+        .flatten()
+        .collect();
+
+    groups(injected)
+        .into_iter()
+        .filter(|group| match flag_name(&group[0]) {
+            Some(name) => !given.iter().any(|given| given == name),
+            None => true,
+        })
+        .flatten()
+        .chain(user)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_flag, merge, service_options, NO_TTY_FLAGS};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn boolean_flags() {
+        assert_eq!(merge(&args(&["--wait"]), &args(&[])), args(&["--wait"]));
+        assert_eq!(
+            merge(
+                &args(&["--wait"]),
+                &args(&["--wait", "--wait-timeout", "60"])
+            ),
+            args(&["--wait", "--wait-timeout", "60"])
+        );
+        assert_eq!(
+            merge(&args(&["--wait", "--build"]), &args(&["web", "--build"])),
+            args(&["--wait", "web", "--build"])
+        );
+
+        // Turning a flag off replaces it.
+        assert_eq!(
+            merge(&args(&["--wait"]), &args(&["--no-wait"])),
+            args(&["--no-wait"])
+        );
+        assert_eq!(
+            merge(&args(&["--wait", "--build"]), &args(&["--no-build", "web"])),
+            args(&["--wait", "--no-build", "web"])
+        );
+
+        // `--no-attach` takes a value and doesn't turn `--attach` off.
+        assert_eq!(
+            merge(&args(&["--attach", "web"]), &args(&["--no-attach", "db"])),
+            args(&["--attach", "web", "--no-attach", "db"])
+        );
+    }
+
+    #[test]
+    fn value_flags() {
+        assert_eq!(
+            merge(
+                &args(&["--wait-timeout", "30", "--wait"]),
+                &args(&["--wait-timeout", "60"])
+            ),
+            args(&["--wait", "--wait-timeout", "60"])
+        );
+        assert_eq!(
+            merge(&args(&["--profile", "dev"]), &args(&["-t", "5", "web"])),
+            args(&["--profile", "dev", "-t", "5", "web"])
+        );
+
+        // A value that looks like a flag is not mistaken for one.
+        assert_eq!(
+            merge(&args(&["--wait"]), &args(&["--attach", "--wait"])),
+            args(&["--wait", "--attach", "--wait"])
+        );
+    }
+
+    #[test]
+    fn equals_values() {
+        assert_eq!(
+            merge(
+                &args(&["--wait-timeout", "30"]),
+                &args(&["--wait-timeout=60"])
+            ),
+            args(&["--wait-timeout=60"])
+        );
+        assert_eq!(
+            merge(
+                &args(&["--wait-timeout=30"]),
+                &args(&["--wait-timeout", "60"])
+            ),
+            args(&["--wait-timeout", "60"])
+        );
+        assert_eq!(
+            merge(&args(&["--pull=always"]), &args(&["web"])),
+            args(&["--pull=always", "web"])
+        );
+    }
+
+    #[test]
+    fn given_flags() {
+        let timeout = ["-t", "--timeout"];
+        assert!(has_flag(&args(&["-t", "5"]), &timeout));
+        assert!(has_flag(&args(&["web", "--timeout=5"]), &timeout));
+        assert!(!has_flag(&args(&["web"]), &timeout));
+        assert!(!has_flag(&args(&["--attach", "-t"]), &timeout));
+    }
+
+    #[test]
+    fn service() {
+        assert_eq!(
+            service_options(&args(&["-T", "--user", "root", "-e", "A=1", "web", "sh"])),
+            args(&["-T", "--user", "root", "-e", "A=1"])
+        );
+        assert_eq!(
+            service_options(&args(&["--env=A=1", "web", "ls", "-T"])),
+            args(&["--env=A=1"])
+        );
+        assert!(service_options(&args(&["web", "psql", "-T"])).is_empty());
+        assert!(service_options(&args(&["--", "web"])).is_empty());
+        assert_eq!(service_options(&args(&["-u"])), args(&["-u"]));
+
+        // A flag in the container's command isn't the user's choice.
+        assert!(!has_flag(
+            service_options(&args(&["web", "ls", "-T"])),
+            &NO_TTY_FLAGS
+        ));
+        assert!(has_flag(
+            service_options(&args(&["--no-TTY", "web"])),
+            &NO_TTY_FLAGS
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::preflight::default_compose_file;
+
+/// What stacks are deployed with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// Projects run by docker compose.
+    #[default]
+    Compose,
+    /// Stacks deployed to a Docker Swarm with `docker stack`.
+    Swarm,
+}
+
+impl BackendKind {
+    pub fn backend(&self) -> &'static dyn Backend {
+        match self {
+            BackendKind::Compose => &ComposeBackend,
+            BackendKind::Swarm => &SwarmBackend,
+        }
+    }
+}
+
+/// The stack that a command is being run against.
+pub struct Target<'a> {
+    /// The key of the stack in the config.
+    pub key: &'a str,
+    /// The project name.
+    pub name: &'a str,
+    pub project_directory: &'a Path,
+    /// The compose files given in the config.
+    pub files: &'a [PathBuf],
+}
+
+/// Translates the compose subcommands that stacks runs into the command line
+/// for a stack.
+pub trait Backend: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The full command line, starting with the program, to run the compose
+    /// subcommand with the arguments against the target. `binary` is the
+    /// configured compose command.
+    fn command_line(
+        &self,
+        binary: &[OsString],
+        command: &str,
+        target: &Target,
+        args: &[OsString],
+    ) -> Result<Vec<OsString>>;
+}
+
+/// Runs commands with docker compose.
+pub struct ComposeBackend;
+
+impl Backend for ComposeBackend {
+    fn name(&self) -> &'static str {
+        "compose"
+    }
+
+    fn command_line(
+        &self,
+        binary: &[OsString],
+        command: &str,
+        target: &Target,
+        args: &[OsString],
+    ) -> Result<Vec<OsString>> {
+        let mut line = binary.to_vec();
+        line.extend([
+            "-p".into(),
+            target.name.into(),
+            "--project-directory".into(),
+            target.project_directory.into(),
+        ]);
+        for file in target.files {
+            line.extend(["-f".into(), file.into()]);
+        }
+        line.push(command.into());
+        line.extend(args.iter().cloned());
+        Ok(line)
+    }
+}
+
+/// Lists the logs of every service in the swarm stack given as the first
+/// argument, the docker program is `$0`.
+const SWARM_LOGS: &str = "stack=\"$1\"; shift; \
+    for service in $(\"$0\" stack services --format '{{.Name}}' \"$stack\"); do \
+    \"$0\" service logs \"$@\" \"$service\" & done; wait";
+
+/// Deploys stacks to a Docker Swarm.
+pub struct SwarmBackend;
+
+impl SwarmBackend {
+    /// The docker program, the configured command is used if it is docker
+    /// itself.
+    fn docker(binary: &[OsString]) -> OsString {
+        match binary.first() {
+            Some(program) if Path::new(program).file_stem() == Some("docker".as_ref()) => {
+                program.clone()
+            }
+            _ => "docker".into(),
+        }
+    }
+
+    /// The compose files to deploy, the same default as docker compose is
+    /// used when none are configured.
+    fn files(target: &Target) -> Vec<PathBuf> {
+        if !target.files.is_empty() {
+            return target.files.to_vec();
+        }
+
+        vec![default_compose_file(target.project_directory)]
+    }
+}
+
+impl Backend for SwarmBackend {
+    fn name(&self) -> &'static str {
+        "swarm"
+    }
+
+    fn command_line(
+        &self,
+        binary: &[OsString],
+        command: &str,
+        target: &Target,
+        args: &[OsString],
+    ) -> Result<Vec<OsString>> {
+        let docker = SwarmBackend::docker(binary);
+        let compose_files = || {
+            SwarmBackend::files(target)
+                .into_iter()
+                .flat_map(|file| ["-c".into(), file.into_os_string()])
+        };
+
+        let mut line: Vec<OsString> = vec![docker.clone(), "stack".into()];
+        match command {
+            "up" => {
+                // Deploying waits for services with `--detach=false`.
+                line.push("deploy".into());
+                line.extend(compose_files());
+                line.extend(args.iter().map(|arg| {
+                    if arg == "--wait" {
+                        "--detach=false".into()
+                    } else {
+                        arg.clone()
+                    }
+                }));
+                line.push(target.name.into());
+            }
+            "down" | "ps" => {
+                line.push(if command == "down" { "rm" } else { "ps" }.into());
+                line.extend(args.iter().cloned());
+                line.push(target.name.into());
+            }
+            "config" => {
+                line.push("config".into());
+                line.extend(compose_files());
+                line.extend(args.iter().cloned());
+            }
+            "logs" => {
+                line = vec!["sh".into(), "-c".into(), SWARM_LOGS.into(), docker];
+                line.push(target.name.into());
+                line.extend(args.iter().cloned());
+            }
+            _ => {
+                return Err(Error::UnsupportedCommand {
+                    stack: target.key.to_owned(),
+                    command: command.to_owned(),
+                    backend: self.name(),
+                })
+            }
+        }
+
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BackendKind, Target};
+    use crate::error::Error;
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+
+    fn command_line(kind: BackendKind, command: &str, args: &[&str]) -> Result<String, Error> {
+        let files = [
+            PathBuf::from("/stacks/web/a.yml"),
+            PathBuf::from("/stacks/web/b.yml"),
+        ];
+        let target = Target {
+            key: "web",
+            name: "website",
+            project_directory: Path::new("/stacks/web"),
+            files: &files,
+        };
+        let binary: Vec<OsString> = vec!["/usr/bin/docker".into(), "compose".into()];
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+
+        kind.backend()
+            .command_line(&binary, command, &target, &args)
+            .map(|line| {
+                line.iter()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+    }
+
+    #[test]
+    fn compose() {
+        assert_eq!(
+            command_line(BackendKind::Compose, "up", &["--wait"]).unwrap(),
+            "/usr/bin/docker compose -p website --project-directory /stacks/web \
+            -f /stacks/web/a.yml -f /stacks/web/b.yml up --wait"
+        );
+        assert_eq!(
+            command_line(BackendKind::Compose, "pause", &[]).unwrap(),
+            "/usr/bin/docker compose -p website --project-directory /stacks/web \
+            -f /stacks/web/a.yml -f /stacks/web/b.yml pause"
+        );
+    }
+
+    #[test]
+    fn swarm() {
+        assert_eq!(
+            command_line(BackendKind::Swarm, "up", &["--wait", "--prune"]).unwrap(),
+            "/usr/bin/docker stack deploy -c /stacks/web/a.yml -c /stacks/web/b.yml \
+            --detach=false --prune website"
+        );
+        assert_eq!(
+            command_line(BackendKind::Swarm, "down", &[]).unwrap(),
+            "/usr/bin/docker stack rm website"
+        );
+        assert_eq!(
+            command_line(BackendKind::Swarm, "ps", &["-q"]).unwrap(),
+            "/usr/bin/docker stack ps -q website"
+        );
+        assert_eq!(
+            command_line(BackendKind::Swarm, "config", &[]).unwrap(),
+            "/usr/bin/docker stack config -c /stacks/web/a.yml -c /stacks/web/b.yml"
+        );
+
+        let logs = command_line(BackendKind::Swarm, "logs", &["-f"]).unwrap();
+        assert!(logs.starts_with("sh -c "));
+        assert!(logs.ends_with(" /usr/bin/docker website -f"));
+
+        for command in ["pause", "cp"] {
+            assert_eq!(
+                command_line(BackendKind::Swarm, command, &[])
+                    .unwrap_err()
+                    .to_string(),
+                format!(
+                    "`{}` is not supported for stack `web` which uses the swarm backend.",
+                    command
+                )
+            );
+        }
+    }
+}
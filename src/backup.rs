@@ -0,0 +1,374 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::state;
+
+/// The image used to archive and extract volumes.
+pub const ARCHIVER_IMAGE: &str = "alpine";
+
+/// The file in a backup directory describing the backup.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// A named volume of a stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    /// The volume's key in the compose file.
+    pub volume: String,
+    /// The name of the volume in docker.
+    pub name: String,
+}
+
+/// A volume's archive in a backup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeBackup {
+    pub volume: String,
+    pub name: String,
+    /// The archive's file name in the backup directory.
+    pub archive: String,
+    /// The size of the archive in bytes, if it could be read.
+    pub size: Option<u64>,
+}
+
+/// Describes a backup of a stack's volumes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub stack: String,
+    /// The compose project name of the stack.
+    pub project: String,
+    /// When the backup was made, in seconds since the epoch.
+    pub created: u64,
+    /// The hash of the stack's configuration when it was backed up.
+    pub hash: Option<String>,
+    pub version: String,
+    pub volumes: Vec<VolumeBackup>,
+}
+
+impl Manifest {
+    /// Parses a manifest. Each archive must be a plain file name in the
+    /// backup directory.
+    pub fn parse(content: &str) -> Result<Self> {
+        let manifest: Self = serde_json::from_str(content)
+            .map_err(|e| Error::Invalid(format!("Invalid backup manifest: {}", e)))?;
+
+        if let Some(backup) = manifest
+            .volumes
+            .iter()
+            .find(|backup| !is_file_name(&backup.archive))
+        {
+            return Err(Error::Invalid(format!(
+                "Invalid backup manifest: the archive `{}` of volume `{}` is not a file name.",
+                backup.archive, backup.volume
+            )));
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn render(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Whether the name is just a file name, without any directories.
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains(['/', '\\'])
+        && Path::new(name).file_name() == Some(OsStr::new(name))
+}
+
+/// The name of the directory for a backup of the stack made at the time.
+pub fn backup_dir_name(stack: &str, timestamp: u64) -> String {
+    let [year, month, day, hour, minute, second] = state::utc(timestamp);
+    format!(
+        "{}-{:04}{:02}{:02}-{:02}{:02}{:02}",
+        stack, year, month, day, hour, minute, second
+    )
+}
+
+/// The docker names of a project's volumes, compose prefixes them with the
+/// project name.
+pub fn project_volumes(project: &str, volumes_output: &str) -> Vec<Volume> {
+    volumes_output
+        .lines()
+        .map(str::trim)
+        .filter(|volume| !volume.is_empty())
+        .map(|volume| Volume {
+            volume: volume.to_owned(),
+            name: format!("{}_{}", project, volume),
+        })
+        .collect()
+}
+
+/// Parses the output of `docker volume inspect` into the names of the volumes
+/// that exist. Docker prints the volumes it found even when some are missing.
+pub fn existing_volumes(inspect_output: &str) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Inspected {
+        #[serde(rename = "Name")]
+        name: String,
+    }
+
+    if inspect_output.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let volumes: Vec<Inspected> = serde_json::from_str(inspect_output)
+        .map_err(|e| Error::Invalid(format!("Invalid volume inspect output: {}", e)))?;
+    Ok(volumes.into_iter().map(|volume| volume.name).collect())
+}
+
+/// The command that archives a volume into the directory.
+pub fn archive_command(program: &str, volume: &str, dir: &Path, archive: &str) -> Vec<String> {
+    vec![
+        program.to_owned(),
+        "run".to_owned(),
+        "--rm".to_owned(),
+        "-v".to_owned(),
+        format!("{}:/data:ro", volume),
+        "-v".to_owned(),
+        format!("{}:/backup", dir.display()),
+        ARCHIVER_IMAGE.to_owned(),
+        "tar".to_owned(),
+        "czf".to_owned(),
+        format!("/backup/{}", archive),
+        "-C".to_owned(),
+        "/data".to_owned(),
+        ".".to_owned(),
+    ]
+}
+
+/// The volumes in the backup to restore, all of them unless only some are
+/// asked for.
+pub fn select_volumes<'a>(
+    manifest: &'a Manifest,
+    only: &[String],
+) -> Result<Vec<&'a VolumeBackup>> {
+    if let Some(unknown) = only.iter().find(|volume| {
+        !manifest
+            .volumes
+            .iter()
+            .any(|backup| &backup.volume == *volume)
+    }) {
+        return Err(Error::Invalid(format!(
+            "The backup has no volume `{}`, it has {}.",
+            unknown,
+            manifest
+                .volumes
+                .iter()
+                .map(|backup| backup.volume.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
+
+    Ok(manifest
+        .volumes
+        .iter()
+        .filter(|backup| only.is_empty() || only.contains(&backup.volume))
+        .collect())
+}
+
+/// The command that creates a missing volume, labelled like compose would so
+/// that compose adopts it.
+pub fn create_volume_command(program: &str, project: &str, volume: &Volume) -> Vec<String> {
+    vec![
+        program.to_owned(),
+        "volume".to_owned(),
+        "create".to_owned(),
+        "--label".to_owned(),
+        format!("com.docker.compose.project={}", project),
+        "--label".to_owned(),
+        format!("com.docker.compose.volume={}", volume.volume),
+        volume.name.clone(),
+    ]
+}
+
+/// The command that replaces the contents of a volume with an archive from
+/// the directory. The archive is passed to the shell as an argument so that
+/// it is never interpreted by it.
+pub fn restore_command(program: &str, volume: &str, dir: &Path, archive: &str) -> Vec<String> {
+    vec![
+        program.to_owned(),
+        "run".to_owned(),
+        "--rm".to_owned(),
+        "-v".to_owned(),
+        format!("{}:/data", volume),
+        "-v".to_owned(),
+        format!("{}:/backup:ro", dir.display()),
+        ARCHIVER_IMAGE.to_owned(),
+        "sh".to_owned(),
+        "-c".to_owned(),
+        "find /data -mindepth 1 -delete && tar xzf \"/backup/$1\" -C /data".to_owned(),
+        "sh".to_owned(),
+        archive.to_owned(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        archive_command, backup_dir_name, create_volume_command, existing_volumes, project_volumes,
+        restore_command, select_volumes, Manifest, Volume, VolumeBackup,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn volumes() {
+        assert_eq!(
+            project_volumes("shop", "data\n\ncache\n"),
+            vec![
+                Volume {
+                    volume: "data".to_string(),
+                    name: "shop_data".to_string(),
+                },
+                Volume {
+                    volume: "cache".to_string(),
+                    name: "shop_cache".to_string(),
+                },
+            ]
+        );
+        assert!(project_volumes("shop", "").is_empty());
+
+        assert_eq!(
+            existing_volumes(
+                r#"[{"Name":"shop_data","Driver":"local","Mountpoint":"/var/lib/docker/volumes/shop_data/_data"}]"#
+            )
+            .unwrap(),
+            vec!["shop_data".to_string()]
+        );
+        assert!(existing_volumes("[]\n").unwrap().is_empty());
+        assert!(existing_volumes("").unwrap().is_empty());
+        assert!(existing_volumes("Error: no such volume").is_err());
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            archive_command(
+                "docker",
+                "shop_data",
+                Path::new("/backups/shop-20240102-030405"),
+                "data.tar.gz"
+            )
+            .join(" "),
+            "docker run --rm -v shop_data:/data:ro -v /backups/shop-20240102-030405:/backup \
+            alpine tar czf /backup/data.tar.gz -C /data ."
+        );
+        assert_eq!(backup_dir_name("shop", 1704164645), "shop-20240102-030405");
+
+        assert_eq!(
+            restore_command(
+                "podman",
+                "shop_data",
+                Path::new("/backups/shop-20240102-030405"),
+                "data.tar.gz"
+            ),
+            vec![
+                "podman",
+                "run",
+                "--rm",
+                "-v",
+                "shop_data:/data",
+                "-v",
+                "/backups/shop-20240102-030405:/backup:ro",
+                "alpine",
+                "sh",
+                "-c",
+                "find /data -mindepth 1 -delete && tar xzf \"/backup/$1\" -C /data",
+                "sh",
+                "data.tar.gz"
+            ]
+        );
+        assert_eq!(
+            create_volume_command(
+                "docker",
+                "shop",
+                &Volume {
+                    volume: "data".to_string(),
+                    name: "shop_data".to_string(),
+                }
+            )
+            .join(" "),
+            "docker volume create --label com.docker.compose.project=shop \
+            --label com.docker.compose.volume=data shop_data"
+        );
+    }
+
+    #[test]
+    fn manifest() {
+        let manifest = Manifest {
+            stack: "shop".to_string(),
+            project: "shop".to_string(),
+            created: 1704164645,
+            hash: Some("0123456789abcdef".to_string()),
+            version: "1.0.0".to_string(),
+            volumes: vec![VolumeBackup {
+                volume: "data".to_string(),
+                name: "shop_data".to_string(),
+                archive: "data.tar.gz".to_string(),
+                size: Some(2048),
+            }],
+        };
+
+        assert_eq!(Manifest::parse(&manifest.render()).unwrap(), manifest);
+        assert!(Manifest::parse("{}").is_err());
+
+        // Archives can't escape the backup directory.
+        for archive in [
+            "../data.tar.gz",
+            "/etc/passwd",
+            "sub/data.tar.gz",
+            "..",
+            ".",
+            "",
+        ] {
+            let mut escaping = manifest.clone();
+            escaping.volumes[0].archive = archive.to_string();
+            assert!(Manifest::parse(&escaping.render()).is_err(), "{}", archive);
+        }
+        // Anything else is only ever an argument to the shell.
+        let mut quoted = manifest.clone();
+        quoted.volumes[0].archive = "data $(reboot).tar.gz".to_string();
+        assert_eq!(Manifest::parse(&quoted.render()).unwrap(), quoted);
+    }
+
+    #[test]
+    fn selection() {
+        let backup = |volume: &str| VolumeBackup {
+            volume: volume.to_string(),
+            name: format!("shop_{}", volume),
+            archive: format!("{}.tar.gz", volume),
+            size: None,
+        };
+        let manifest = Manifest {
+            stack: "shop".to_string(),
+            project: "shop".to_string(),
+            created: 0,
+            hash: None,
+            version: "1.0.0".to_string(),
+            volumes: vec![backup("data"), backup("cache"), backup("uploads")],
+        };
+        let names = |only: &[&str]| {
+            let only: Vec<String> = only.iter().map(|s| s.to_string()).collect();
+            select_volumes(&manifest, &only).map(|volumes| {
+                volumes
+                    .iter()
+                    .map(|backup| backup.volume.clone())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(names(&[]).unwrap(), vec!["data", "cache", "uploads"]);
+        assert_eq!(
+            names(&["uploads", "data"]).unwrap(),
+            vec!["data", "uploads"]
+        );
+        assert_eq!(
+            names(&["logs"]).unwrap_err().to_string(),
+            "The backup has no volume `logs`, it has data, cache, uploads."
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::io;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+
+/// How often to check on a running child.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The default time a child has to exit after being asked to terminate before
+/// it is killed.
+pub const DEFAULT_KILL_GRACE: Duration = Duration::from_secs(10);
+
+/// Asks a child and, on Unix, everything in its process group to terminate.
+#[cfg(unix)]
+fn terminate(_child: &mut Child, group: Option<u32>) -> io::Result<()> {
+    match group {
+        Some(group) => signal_group(group, libc::SIGTERM),
+        // The child shares our process group so will have already seen the
+        // interrupt from the terminal.
+        None => Ok(()),
+    }
+}
+
+/// Forcibly kills a child and, on Unix, everything in its process group.
+#[cfg(unix)]
+fn kill(child: &mut Child, group: Option<u32>) -> io::Result<()> {
+    match group {
+        Some(group) => signal_group(group, libc::SIGKILL),
+        None => child.kill(),
+    }
+}
+
+/// Checks whether any process in the group is still running.
+#[cfg(unix)]
+fn group_exists(group: u32) -> bool {
+    // SAFETY: killpg has no memory safety requirements.
+    unsafe { libc::killpg(group as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(unix)]
+fn signal_group(group: u32, signal: libc::c_int) -> io::Result<()> {
+    // SAFETY: killpg has no memory safety requirements.
+    if unsafe { libc::killpg(group as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        let error = io::Error::last_os_error();
+        // The group may have already exited.
+        if error.raw_os_error() == Some(libc::ESRCH) {
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+}
+
+/// Windows has no process groups so only the direct child can be stopped, any
+/// processes that it started will be left running.
+#[cfg(not(unix))]
+fn terminate(_child: &mut Child, _group: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child, _group: Option<u32>) -> io::Result<()> {
+    child.kill()
+}
+
+#[cfg(not(unix))]
+fn group_exists(_group: u32) -> bool {
+    false
+}
+
+/// Tracks whether the user has asked for the run to stop. The first request
+/// lets any running commands finish but starts no more, a second request stops
+/// the running commands, first asking them to terminate and then killing them
+/// if they have not exited within the grace period.
+#[derive(Debug, Clone)]
+pub struct Cancellation {
+    requests: Arc<AtomicUsize>,
+    grace: Duration,
+}
+
+impl Default for Cancellation {
+    fn default() -> Self {
+        Self::with_grace(DEFAULT_KILL_GRACE)
+    }
+}
+
+impl Cancellation {
+    pub fn with_grace(grace: Duration) -> Self {
+        Self {
+            requests: Default::default(),
+            grace,
+        }
+    }
+
+    /// Registers a request to cancel, returning how many requests there have
+    /// been.
+    pub fn cancel(&self) -> usize {
+        self.requests.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.requests.load(Ordering::SeqCst) > 0
+    }
+
+    fn should_kill(&self) -> bool {
+        self.requests.load(Ordering::SeqCst) > 1
+    }
+
+    /// Sleeps for the given duration, returning early with false if the run is
+    /// cancelled.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+
+            sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Cancels the run when the user presses Ctrl-C.
+    pub fn install_handler(&self) -> Result<()> {
+        let cancellation = self.clone();
+
+        ctrlc::set_handler(move || {
+            if cancellation.cancel() == 1 {
+                eprintln!(
+                    "Interrupted, waiting for running commands to finish. Press Ctrl-C again to stop them."
+                );
+            }
+        })
+        .map_err(|e| Error::Invalid(format!("Failed to install the Ctrl-C handler: {}", e)))
+    }
+
+    /// Waits for a child to exit, stopping it after a second cancellation
+    /// request. `group` indicates that the child was started in its own process
+    /// group, in which case the whole group is stopped.
+    pub fn wait(&self, child: &mut Child, group: bool) -> io::Result<ExitStatus> {
+        // Children in their own group are group leaders so the group ID is the
+        // child's process ID.
+        let group = group.then(|| child.id());
+        let mut kill_at: Option<Instant> = None;
+
+        loop {
+            if let Some(status) = child.try_wait()? {
+                // Other processes in the group may outlive the child, they
+                // still need to be stopped.
+                if let (Some(group), Some(deadline)) = (group, kill_at) {
+                    while group_exists(group) && Instant::now() < deadline {
+                        sleep(POLL_INTERVAL);
+                    }
+                    kill(child, Some(group))?;
+                }
+
+                return Ok(status);
+            }
+
+            if self.should_kill() {
+                match kill_at {
+                    None => {
+                        terminate(child, group)?;
+                        kill_at = Some(Instant::now() + self.grace);
+                    }
+                    Some(deadline) if Instant::now() >= deadline => {
+                        kill(child, group)?;
+                        return child.wait();
+                    }
+                    _ => {}
+                }
+            }
+
+            sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cancellation;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+    #[cfg(unix)]
+    use std::{fs, os::unix::process::CommandExt, thread::sleep};
+
+    #[test]
+    fn requests() {
+        let cancellation = Cancellation::default();
+        assert!(!cancellation.is_cancelled());
+
+        let clone = cancellation.clone();
+        assert_eq!(clone.cancel(), 1);
+        assert!(cancellation.is_cancelled());
+        assert!(!cancellation.should_kill());
+
+        assert_eq!(cancellation.cancel(), 2);
+        assert!(clone.should_kill());
+    }
+
+    #[test]
+    fn wait() {
+        let cancellation = Cancellation::default();
+        let mut child = Command::new("sh").args(["-c", "exit 4"]).spawn().unwrap();
+        assert_eq!(
+            cancellation.wait(&mut child, false).unwrap().code(),
+            Some(4)
+        );
+
+        // A single request lets the child finish.
+        cancellation.cancel();
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 0.2; exit 3"])
+            .spawn()
+            .unwrap();
+        assert_eq!(
+            cancellation.wait(&mut child, false).unwrap().code(),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn interrupted_sleep() {
+        let cancellation = Cancellation::default();
+        assert!(cancellation.sleep(Duration::from_millis(10)));
+
+        cancellation.cancel();
+        let start = Instant::now();
+        assert!(!cancellation.sleep(Duration::from_secs(5)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    fn is_running(pid: i32) -> bool {
+        // SAFETY: kill has no memory safety requirements.
+        unsafe { libc::kill(pid, 0) == 0 }
+    }
+
+    #[cfg(unix)]
+    fn wait_for_exit(pid: i32) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            if !is_running(pid) {
+                return true;
+            }
+            sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[cfg(unix)]
+    fn grandchild(script: &str, cancellation: &Cancellation) -> (i32, Duration) {
+        let temp = tempfile::tempdir().unwrap();
+        let pid_file = temp.path().join("pid");
+        let script = format!(
+            "sh -c '{}' & echo $! > {}; wait",
+            script,
+            pid_file.display()
+        );
+
+        let mut child = Command::new("sh")
+            .args(["-c", &script])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+
+        let pid = loop {
+            if let Ok(pid) = fs::read_to_string(&pid_file) {
+                if let Ok(pid) = pid.trim().parse::<i32>() {
+                    break pid;
+                }
+            }
+            sleep(Duration::from_millis(20));
+        };
+        assert!(is_running(pid));
+
+        cancellation.cancel();
+        cancellation.cancel();
+        let start = Instant::now();
+        cancellation.wait(&mut child, true).unwrap();
+        (pid, start.elapsed())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn terminates_group() {
+        // The grandchild exits when asked to terminate.
+        let cancellation = Cancellation::with_grace(Duration::from_secs(5));
+        let (pid, elapsed) = grandchild("trap \"exit 0\" TERM; sleep 30 & wait", &cancellation);
+        assert!(wait_for_exit(pid));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kills_group() {
+        // The grandchild ignores the request to terminate so must be killed.
+        let cancellation = Cancellation::with_grace(Duration::from_millis(300));
+        let (pid, elapsed) = grandchild("trap \"\" TERM; sleep 30", &cancellation);
+        assert!(wait_for_exit(pid));
+        assert!(elapsed >= Duration::from_millis(300));
+    }
+}
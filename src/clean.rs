@@ -0,0 +1,345 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use serde::Deserialize;
+
+use crate::error::Result;
+use crate::freeze::normalize;
+use crate::status::parse_ps_as;
+
+/// An image as listed by `docker image ls --format json`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Image {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Repository", default)]
+    pub repository: String,
+    #[serde(rename = "Tag", default)]
+    pub tag: String,
+    #[serde(rename = "Size", default)]
+    pub size: String,
+}
+
+impl Image {
+    /// Whether the image has lost its tag, usually to a newer build or pull.
+    pub fn is_dangling(&self) -> bool {
+        self.repository == "<none>" || self.tag == "<none>"
+    }
+
+    /// How to refer to the image, by its ID once it has no tag.
+    pub fn reference(&self) -> String {
+        if self.is_dangling() {
+            self.id.clone()
+        } else {
+            format!("{}:{}", self.repository, self.tag)
+        }
+    }
+}
+
+/// A container as listed by `docker ps --format json`, only the image it
+/// was created from matters.
+#[derive(Debug, Clone, Deserialize)]
+struct Container {
+    #[serde(rename = "Image")]
+    image: String,
+}
+
+/// Parses the output of `docker image ls --format json`.
+pub fn parse_images(output: &str) -> Result<Vec<Image>> {
+    parse_ps_as(output)
+}
+
+/// The images that must not be removed.
+#[derive(Debug, Clone, Default)]
+pub struct InUse {
+    /// Normalised references to images from the compose configs and
+    /// containers.
+    references: HashSet<String>,
+    /// Image IDs that containers were created from.
+    ids: Vec<String>,
+}
+
+/// An ID without its algorithm.
+fn bare_id(id: &str) -> &str {
+    id.strip_prefix("sha256:").unwrap_or(id)
+}
+
+impl InUse {
+    /// Adds the images of a compose config as listed by `config --images`.
+    pub fn add_config_images(&mut self, output: &str) {
+        for image in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            self.references.insert(normalize(image));
+        }
+    }
+
+    /// Adds the images of containers from the output of
+    /// `docker ps --all --format json`. Containers whose image has since lost
+    /// its tag show the image's ID instead.
+    pub fn add_containers(&mut self, output: &str) -> Result<()> {
+        let containers: Vec<Container> = parse_ps_as(output)?;
+        for container in containers {
+            self.references.insert(normalize(&container.image));
+            self.ids.push(bare_id(&container.image).to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, image: &Image) -> bool {
+        if !image.is_dangling() && self.references.contains(&normalize(&image.reference())) {
+            return true;
+        }
+
+        // IDs may be shortened on either side.
+        let id = bare_id(&image.id);
+        self.ids.iter().any(|used| {
+            used.len() >= 12 && id.len() >= 12 && (used.starts_with(id) || id.starts_with(used))
+        })
+    }
+}
+
+/// Why an image can be removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The image has no tag left.
+    Dangling,
+    /// The image is tagged but no compose config or container uses it.
+    Unused,
+}
+
+impl Reason {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Reason::Dangling => "dangling",
+            Reason::Unused => "unused",
+        }
+    }
+}
+
+/// An image that can be removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub stack: String,
+    pub image: Image,
+    pub reason: Reason,
+}
+
+/// Picks the images labelled with a stack's project that can be removed.
+/// Images that are in use are never picked, dangling images always are and
+/// other images only when all unused images should be.
+pub fn candidates(
+    stack: &str,
+    images: &[Image],
+    in_use: &InUse,
+    all_unused: bool,
+) -> Vec<Candidate> {
+    images
+        .iter()
+        .filter(|image| !in_use.contains(image))
+        .filter_map(|image| {
+            let reason = if image.is_dangling() {
+                Reason::Dangling
+            } else if all_unused {
+                Reason::Unused
+            } else {
+                return None;
+            };
+            Some(Candidate {
+                stack: stack.to_owned(),
+                image: image.clone(),
+                reason,
+            })
+        })
+        .collect()
+}
+
+/// The command that removes the images. Dangling images are removed by ID
+/// and others by their tag so that the image survives if it has other tags.
+pub fn remove_command(program: &str, candidates: &[Candidate]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    [program, "image", "rm"]
+        .into_iter()
+        .map(str::to_owned)
+        .chain(
+            candidates
+                .iter()
+                .map(|candidate| candidate.image.reference())
+                .filter(|reference| seen.insert(reference.clone())),
+        )
+        .collect()
+}
+
+/// The command that removes the build cache of a project. Podman keeps its
+/// build cache as images so only docker has one to prune.
+pub fn prune_command(program: &str, project: &str) -> Option<Vec<String>> {
+    if program != "docker" {
+        return None;
+    }
+
+    Some(
+        [program, "builder", "prune", "--force", "--filter"]
+            .into_iter()
+            .map(str::to_owned)
+            .chain([format!("label=com.docker.compose.project={}", project)])
+            .collect(),
+    )
+}
+
+/// Lists the images that would be removed.
+pub fn render(candidates: &[Candidate]) -> String {
+    if candidates.is_empty() {
+        return "Nothing to clean.\n".to_string();
+    }
+
+    let header = [
+        "Stack".to_string(),
+        "Image".to_string(),
+        "Size".to_string(),
+        "Reason".to_string(),
+    ];
+    let rows: Vec<[String; 4]> = candidates
+        .iter()
+        .map(|candidate| {
+            [
+                candidate.stack.clone(),
+                candidate.image.reference(),
+                candidate.image.size.clone(),
+                candidate.reason.label().to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = [0; 4];
+    for row in [&header].into_iter().chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(rows.iter()) {
+        let _ = writeln!(
+            table,
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{candidates, parse_images, prune_command, remove_command, render, InUse, Reason};
+
+    /// `docker image ls --format json` for images labelled with the shop
+    /// project.
+    const IMAGES: &str = r#"{"ID":"1111aaaa2222","Repository":"shop-app","Tag":"latest","Size":"120MB"}
+{"ID":"3333bbbb4444","Repository":"<none>","Tag":"<none>","Size":"118MB"}
+{"ID":"5555cccc6666","Repository":"<none>","Tag":"<none>","Size":"117MB"}
+{"ID":"7777dddd8888","Repository":"shop-worker","Tag":"old","Size":"80MB"}
+{"ID":"9999eeeeffff","Repository":"registry.example.com/shop/api","Tag":"2","Size":"95MB"}"#;
+
+    /// The images in the current compose configs.
+    const CONFIG_IMAGES: &str = "shop-app\nregistry.example.com/shop/api:2\npostgres:16\n";
+
+    /// `docker ps --all --format json`, one stopped container still uses a
+    /// dangling image.
+    const CONTAINERS: &str = r#"{"Names":"shop-app-1","Image":"shop-app","State":"running"}
+{"Names":"shop-job-1","Image":"5555cccc6666","State":"exited"}"#;
+
+    fn in_use() -> InUse {
+        let mut in_use = InUse::default();
+        in_use.add_config_images(CONFIG_IMAGES);
+        in_use.add_containers(CONTAINERS).unwrap();
+        in_use
+    }
+
+    fn picked(all_unused: bool) -> Vec<(String, Reason)> {
+        candidates(
+            "shop",
+            &parse_images(IMAGES).unwrap(),
+            &in_use(),
+            all_unused,
+        )
+        .into_iter()
+        .map(|candidate| (candidate.image.reference(), candidate.reason))
+        .collect()
+    }
+
+    #[test]
+    fn dangling() {
+        // The dangling image a container still uses is kept.
+        assert_eq!(
+            picked(false),
+            vec![("3333bbbb4444".to_string(), Reason::Dangling)]
+        );
+    }
+
+    #[test]
+    fn unused() {
+        // Images in the compose configs are kept however they are written.
+        assert_eq!(
+            picked(true),
+            vec![
+                ("3333bbbb4444".to_string(), Reason::Dangling),
+                ("shop-worker:old".to_string(), Reason::Unused),
+            ]
+        );
+    }
+
+    #[test]
+    fn in_use_images() {
+        let images = parse_images(IMAGES).unwrap();
+        let in_use = in_use();
+        assert!(in_use.contains(&images[0]));
+        assert!(!in_use.contains(&images[1]));
+        assert!(in_use.contains(&images[2]));
+        assert!(!in_use.contains(&images[3]));
+        assert!(in_use.contains(&images[4]));
+
+        // Full IDs match shortened ones.
+        let mut in_use = InUse::default();
+        in_use
+            .add_containers(r#"[{"Image":"sha256:3333bbbb4444abcdef"}]"#)
+            .unwrap();
+        assert!(in_use.contains(&images[1]));
+
+        // Nothing is removed when nothing is known to be unused.
+        assert!(candidates("shop", &[], &InUse::default(), true).is_empty());
+    }
+
+    #[test]
+    fn removal() {
+        let candidates = candidates("shop", &parse_images(IMAGES).unwrap(), &in_use(), true);
+        assert_eq!(
+            remove_command("docker", &candidates).join(" "),
+            "docker image rm 3333bbbb4444 shop-worker:old"
+        );
+        assert_eq!(
+            prune_command("docker", "shop").unwrap(),
+            vec![
+                "docker",
+                "builder",
+                "prune",
+                "--force",
+                "--filter",
+                "label=com.docker.compose.project=shop"
+            ]
+        );
+        assert_eq!(prune_command("podman", "shop"), None);
+
+        assert_eq!(
+            render(&candidates),
+            "Stack  Image            Size   Reason\n\
+            shop   3333bbbb4444     118MB  dangling\n\
+            shop   shop-worker:old  80MB   unused\n"
+        );
+        assert_eq!(render(&[]), "Nothing to clean.\n");
+    }
+}
@@ -1,66 +1,5487 @@
 use crate::{
-    config::{Config, Stack},
-    exec::ExecOptions,
+    args::{has_flag, merge, service_options, NO_TTY_FLAGS},
+    backend::BackendKind,
+    backup,
+    cancel::Cancellation,
+    clean,
+    config::{Config, Stack, StackKind},
+    diff,
+    engine::Engine,
+    environment,
+    error::{Error, Result},
+    events::{EventFile, Recorded},
+    exec::{
+        ExecOptions, OutputMode, CREATE_DIR_COMMANDS, FILELESS_COMMANDS, INTERACTIVE_COMMANDS,
+        OFFLINE_COMMANDS, TTY_COMMANDS,
+    },
+    executor::{Executor, ProcessExecutor},
+    freeze,
+    graph::{Graph, GraphFormat},
+    history::{self, HistoryLog},
+    list,
+    open::{self, Opener, SystemOpener},
+    output::{prefix_line, LogFiles, OutputHandler, Prefixed, Tee},
+    paths, ports,
+    preflight::{
+        check_compose_files, check_daemons, check_programs, daemon_program, default_compose_file,
+        probe_daemon, Endpoint,
+    },
+    progress::{LogReporter, Progress, Reporter},
+    provides, pull,
+    retry::RetryPolicy,
+    state::{self as state_file, config_hash, StateFile},
+    status::{self, Presence},
+    summary::{format_duration, Summary},
+    timings::{Timings, TOTAL},
+    verify::{self, Verdict},
+    version::{self, Version},
+    watch,
 };
 
-use std::process::Command;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn exec(exec_options: &ExecOptions, stack: &Stack) -> Result<(), String> {
-    let exec_options = exec_options.with_stack(stack);
+/// How often to check on services while verifying that they came up.
+pub const VERIFY_INTERVAL: Duration = Duration::from_secs(1);
 
-    log::debug!(
-        "Executing `{} {}`",
-        exec_options.program(),
-        exec_options.args().join(" ")
-    );
+fn exec(
+    executor: &dyn Executor,
+    exec_options: &ExecOptions,
+    stack: &Stack,
+    output: &dyn OutputHandler,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let exec_options = exec_options.with_stack(stack)?;
+    let result = executor.run(&exec_options, stack, output, cancel);
 
-    let mut command = Command::new(exec_options.program());
-    command.args(exec_options.args());
-    for (k, v) in exec_options.environment.iter() {
-        command.env(k, v);
-    }
-
-    command.current_dir(&exec_options.working_dir);
-    let mut child = command
-        .spawn()
-        .map_err(|e| format!("Error running docker compose: {}", e))?;
-    let status = child
-        .wait()
-        .map_err(|e| format!("Error running docker compose: {}", e))?;
+    output.finished(stack, result.as_ref().map(|s| s.success()).unwrap_or(false));
+    let status = result?;
 
     if status.success() {
         Ok(())
     } else {
-        Err(format!(
-            "Error running command `{} {}`: {}",
-            exec_options.program(),
-            exec_options.args().join(" "),
-            status
-        ))
-    }
-}
-
-pub fn run_against_stacks(
-    command: &str,
-    config: &Config,
-    stacks: &Vec<&Stack>,
-    args: &Vec<String>,
-) -> Result<(), String> {
-    log::trace!(
-        "Executing command `{}` against {} stacks with arguments {:?}",
-        command,
+        Err(Error::CommandFailed {
+            stack: stack.key.clone(),
+            command: exec_options.command_line(),
+            status,
+        })
+    }
+}
+
+/// How to undo a failed `up`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rollback {
+    /// Remove the stacks that were started.
+    Down,
+    /// Stop the stacks that were started, leaving their containers.
+    Stop,
+}
+
+impl Rollback {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Rollback::Down => "down",
+            Rollback::Stop => "stop",
+        }
+    }
+}
+
+/// Which stacks must be complete before a stack's command can be run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Stacks wait for their dependencies, used for commands like `up`.
+    Dependencies,
+    /// Stacks wait for their dependants, used for commands like `down`.
+    Dependants,
+    /// Stacks don't wait for each other.
+    Independent,
+}
+
+impl Direction {
+    fn prerequisites<'a>(&self, stack: &'a Stack) -> impl Iterator<Item = &'a String> {
+        match self {
+            Direction::Dependencies => Some(&stack.dependencies),
+            Direction::Dependants => Some(&stack.dependants),
+            Direction::Independent => None,
+        }
+        .into_iter()
+        .flatten()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Running,
+    Complete,
+    Failed,
+    Skipped,
+}
+
+/// Builds the error for a run that was cancelled.
+fn interrupted(stacks: &[&Stack], states: &[State]) -> Error {
+    let keys = |wanted: &[State]| {
         stacks
             .iter()
-            .map(|s| s.name.clone())
-            .collect::<Vec<String>>()
-            .join(","),
-        args
-    );
+            .zip(states)
+            .filter(|(_, state)| wanted.contains(state))
+            .map(|(stack, _)| stack.key.clone())
+            .collect()
+    };
 
-    let exec_options = ExecOptions::new(config, command, args);
-    for stack in stacks {
-        exec(&exec_options, stack)?;
+    Error::Interrupted {
+        completed: keys(&[State::Complete]),
+        interrupted: keys(&[State::Failed]),
+        not_started: keys(&[State::Pending, State::Skipped]),
+    }
+}
+
+/// Runs a function against each stack in turn, stopping at the first failure
+/// or when cancelled.
+pub fn run_sequential<F>(stacks: &[&Stack], cancel: &Cancellation, run: F) -> Result<()>
+where
+    F: Fn(&Stack) -> Result<()>,
+{
+    let mut states = vec![State::Pending; stacks.len()];
+
+    for (index, stack) in stacks.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return Err(interrupted(stacks, &states));
+        }
+
+        match run(stack) {
+            Ok(()) => states[index] = State::Complete,
+            Err(e) => {
+                if cancel.is_cancelled() {
+                    log::error!("{}", e);
+                    states[index] = State::Failed;
+                    return Err(interrupted(stacks, &states));
+                }
+
+                return Err(e);
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Whether another stack in the same concurrency group as the stack at `index`
+/// is running.
+fn group_running(stacks: &[&Stack], states: &[State], index: usize) -> bool {
+    match stacks[index].concurrency_group {
+        Some(ref group) => stacks.iter().zip(states).any(|(stack, state)| {
+            *state == State::Running && stack.concurrency_group.as_ref() == Some(group)
+        }),
+        None => false,
+    }
+}
+
+/// Runs a function against every stack, starting each stack as soon as all of
+/// its prerequisites (within the given stacks) have completed and running at
+/// most `max_parallel` at once. Stacks in the same concurrency group wait for
+/// each other. When a stack fails anything that waits on it is skipped but
+/// unrelated stacks continue. Stacks that are ready at the same time are
+/// started in the order given. Once cancelled no more stacks are started.
+pub fn schedule<F>(
+    stacks: &[&Stack],
+    direction: Direction,
+    max_parallel: usize,
+    cancel: &Cancellation,
+    run: F,
+) -> Result<()>
+where
+    F: Fn(&Stack) -> Result<()> + Sync,
+{
+    let max_parallel = max_parallel.max(1);
+    let indexes: HashMap<&str, usize> = stacks
+        .iter()
+        .enumerate()
+        .map(|(index, stack)| (stack.key.as_str(), index))
+        .collect();
+    let prerequisites: Vec<Vec<usize>> = stacks
+        .iter()
+        .map(|stack| {
+            direction
+                .prerequisites(stack)
+                .filter_map(|key| indexes.get(key.as_str()).copied())
+                .collect()
+        })
+        .collect();
+
+    let mut states = vec![State::Pending; stacks.len()];
+    let mut errors: Vec<Error> = Vec::new();
+    let (sender, receiver) = mpsc::channel::<(usize, Result<()>)>();
+    let run = &run;
+
+    thread::scope(|scope| {
+        let mut running = 0;
+
+        loop {
+            // Anything waiting on a stack that failed can never be run.
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for index in 0..stacks.len() {
+                    if states[index] == State::Pending
+                        && prerequisites[index]
+                            .iter()
+                            .any(|p| matches!(states[*p], State::Failed | State::Skipped))
+                    {
+                        log::warn!("Skipping stack {}", stacks[index].key);
+                        states[index] = State::Skipped;
+                        changed = true;
+                    }
+                }
+            }
+
+            for index in 0..stacks.len() {
+                if running >= max_parallel || cancel.is_cancelled() {
+                    break;
+                }
+
+                if states[index] == State::Pending
+                    && prerequisites[index]
+                        .iter()
+                        .all(|p| states[*p] == State::Complete)
+                    && !group_running(stacks, &states, index)
+                {
+                    states[index] = State::Running;
+                    running += 1;
+
+                    let sender = sender.clone();
+                    let stack = stacks[index];
+                    scope.spawn(move || {
+                        // The receiver outlives all of the threads.
+                        let _ = sender.send((index, run(stack)));
+                    });
+                }
+            }
+
+            if running == 0 {
+                break;
+            }
+
+            let (index, result) = receiver.recv().unwrap();
+            running -= 1;
+
+            match result {
+                Ok(()) => states[index] = State::Complete,
+                Err(e) => {
+                    states[index] = State::Failed;
+                    errors.push(e);
+                }
+            }
+        }
+    });
+
+    if cancel.is_cancelled() && states.iter().any(|s| *s != State::Complete) {
+        for e in errors {
+            log::error!("{}", e);
+        }
+        return Err(interrupted(stacks, &states));
+    }
+
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::StacksFailed { failures: errors }),
+    }
+}
+
+/// Keeps the stacks that have no containers, in order. Stacks without a known
+/// presence are kept. Also returns the kept stacks that depend on a stack that
+/// was left out but isn't running, paired with that dependency.
+pub fn only_missing<'a>(
+    stacks: &[&'a Stack],
+    presence: &HashMap<String, Presence>,
+) -> (Vec<&'a Stack>, Vec<(&'a str, &'a str)>) {
+    let missing: Vec<&Stack> = stacks
+        .iter()
+        .filter(|stack| {
+            presence
+                .get(&stack.key)
+                .is_none_or(|presence| *presence == Presence::Missing)
+        })
+        .copied()
+        .collect();
+
+    let stopped = missing
+        .iter()
+        .flat_map(|stack| {
+            stack
+                .dependencies
+                .iter()
+                .filter(|dep| presence.get(*dep) == Some(&Presence::Stopped))
+                .map(|dep| (stack.key.as_str(), dep.as_str()))
+        })
+        .collect();
+
+    (missing, stopped)
+}
+
+/// Trims stacks in the order they would run to those from `from` until
+/// `until`, inclusive. Returns the stacks to run followed by those to skip.
+/// The named stacks must be among the stacks.
+pub fn stack_range<'s>(
+    stacks: &[&'s Stack],
+    from: Option<&str>,
+    until: Option<&str>,
+) -> Result<(Vec<&'s Stack>, Vec<&'s Stack>)> {
+    let position = |flag: &str, key: Option<&str>| -> Result<Option<usize>> {
+        let Some(key) = key else {
+            return Ok(None);
+        };
+
+        match stacks.iter().position(|stack| stack.key == key) {
+            Some(index) => Ok(Some(index)),
+            None => Err(Error::Invalid(format!(
+                "`{} {}` is not one of the stacks to run, which are {}.",
+                flag,
+                key,
+                stacks
+                    .iter()
+                    .map(|stack| stack.key.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ))),
+        }
+    };
+
+    let start = position("--from", from)?.unwrap_or(0);
+    let end = position("--until", until)?.unwrap_or(stacks.len().saturating_sub(1));
+    if start > end {
+        return Err(Error::Invalid(format!(
+            "`--from {}` runs after `--until {}` so there is nothing to run.",
+            from.unwrap_or_default(),
+            until.unwrap_or_default()
+        )));
+    }
+
+    let mut kept = Vec::new();
+    let mut skipped = Vec::new();
+    for (index, stack) in stacks.iter().enumerate() {
+        if (start..=end).contains(&index) {
+            kept.push(*stack);
+        } else {
+            skipped.push(*stack);
+        }
+    }
+    Ok((kept, skipped))
+}
+
+/// When running against several stacks a failed command is reported as such
+/// so that its exit code is not mistaken for that of a single command.
+fn aggregate(stacks: &[&Stack], result: Result<()>) -> Result<()> {
+    match result {
+        Err(e @ Error::CommandFailed { .. }) if stacks.len() > 1 => {
+            Err(Error::StacksFailed { failures: vec![e] })
+        }
+        result => result,
+    }
+}
+
+/// Runs commands against stacks.
+pub struct Runner<'a> {
+    pub config: &'a Config,
+    pub output: &'a dyn OutputHandler,
+    /// Runs the commands.
+    pub executor: &'a dyn Executor,
+    /// Whether to prefix output with the stack name when running against
+    /// multiple stacks.
+    pub prefix: bool,
+    /// Whether output may be coloured.
+    pub color: bool,
+    /// Whether stdin and stdout are a terminal that interactive commands can
+    /// use.
+    pub terminal: bool,
+    pub cancel: Cancellation,
+    pub retry: RetryPolicy,
+    /// Records the outcome of every command that was run.
+    pub summary: Summary,
+    /// Records how long each phase of the run took.
+    pub timings: Timings,
+    /// Whether to show a live progress display when running against multiple
+    /// stacks.
+    pub progress: bool,
+    /// Receives progress updates when the progress display is not shown.
+    pub reporter: &'a dyn Reporter,
+    /// Opens stacks' URLs.
+    pub opener: &'a dyn Opener,
+    /// Whether to check that commands can be run before running them.
+    pub preflight: bool,
+    /// The docker daemons that are known to be reachable.
+    reachable: Mutex<Vec<Endpoint>>,
+    /// Whether an old compose version is an error rather than a warning.
+    pub strict_version: bool,
+    compose_version: OnceLock<Option<Version>>,
+    version_checked: AtomicBool,
+    /// Whether to skip bringing up stacks that are already running.
+    pub skip_running: bool,
+    /// Skip the stacks before this one in the order they would run.
+    pub from: Option<String>,
+    /// Skip the stacks after this one in the order they would run.
+    pub until: Option<String>,
+    /// The stacks named in the selection, rather than matched by a wildcard,
+    /// pattern or default. Only these are started when autostart is disabled.
+    pub named: Vec<String>,
+    /// The stacks that were found to be running already.
+    pub already_running: Mutex<Vec<String>>,
+    /// The stacks that were brought up during this run, in the order they
+    /// were started.
+    pub started: Mutex<Vec<String>>,
+    /// Whether a failed `up` will be rolled back, so stacks that were already
+    /// up must be told apart from those this run starts.
+    rolling_back: AtomicBool,
+    /// How often to check on services while verifying that they came up.
+    pub verify_interval: Duration,
+    /// The config file, none when the config was read from stdin.
+    pub config_file: Option<PathBuf>,
+    /// Where to record what was deployed.
+    pub state: Option<StateFile>,
+    /// Where commands that change stacks are logged.
+    pub history: Option<HistoryLog>,
+    /// A directory to copy each stack's output into.
+    pub log_dir: Option<PathBuf>,
+    /// Where to write progress events.
+    pub events: Option<EventFile>,
+    /// The stacks that have been checked for missing host variables.
+    warned_env: Mutex<Vec<String>>,
+}
+
+impl<'a> Runner<'a> {
+    pub fn new(config: &'a Config, output: &'a dyn OutputHandler) -> Self {
+        Self {
+            config,
+            output,
+            executor: &ProcessExecutor,
+            prefix: false,
+            color: false,
+            terminal: io::stdin().is_terminal() && io::stdout().is_terminal(),
+            cancel: Cancellation::default(),
+            retry: RetryPolicy::default(),
+            summary: Summary::default(),
+            timings: Timings::default(),
+            progress: false,
+            reporter: &LogReporter,
+            opener: &SystemOpener,
+            preflight: true,
+            reachable: Mutex::default(),
+            strict_version: false,
+            compose_version: OnceLock::new(),
+            version_checked: AtomicBool::new(false),
+            skip_running: false,
+            from: None,
+            until: None,
+            named: Vec::new(),
+            already_running: Mutex::default(),
+            started: Mutex::default(),
+            rolling_back: AtomicBool::new(false),
+            verify_interval: VERIFY_INTERVAL,
+            config_file: None,
+            state: None,
+            history: None,
+            log_dir: None,
+            events: None,
+            warned_env: Mutex::default(),
+        }
+    }
+
+    /// Runs a compose command against a stack and returns what it printed.
+    fn query(&self, stack: &Stack, command: &str, args: &[&str]) -> Result<String> {
+        self.query_with(stack, ExecOptions::new(self.config, command, args))
+    }
+
+    /// Runs the command against the stack and returns its output.
+    fn query_with(&self, stack: &Stack, options: ExecOptions) -> Result<String> {
+        if stack.kind == StackKind::Script {
+            return Err(Error::Invalid(format!(
+                "{} is not a compose project",
+                stack.key
+            )));
+        }
+
+        let exec_options = options.with_stack(stack)?;
+        let output = self.executor.output(&exec_options)?;
+
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(Error::CommandFailed {
+                stack: stack.key.clone(),
+                command: exec_options.command_line(),
+                status: output.status,
+            })
+        }
+    }
+
+    /// Lists the stack's containers, including those that are stopped.
+    fn containers(&self, stack: &Stack) -> Result<Vec<status::Container>> {
+        status::parse_ps(&self.query(stack, "ps", &["--all", "--format", "json"])?)
+    }
+
+    /// Checks whether every service in the stack is already running and healthy.
+    fn is_up(&self, stack: &Stack) -> Result<bool> {
+        if let Some(script) = stack.scripts.status.as_deref() {
+            let exec_options = ExecOptions::new(self.config, "ps", &[] as &[&str])
+                .script(script)
+                .with_stack(stack)?;
+            return Ok(self.executor.output(&exec_options)?.status.success());
+        }
+
+        let services = status::parse_services(&self.query(stack, "config", &["--services"])?);
+        Ok(status::all_up(&services, &self.containers(stack)?))
+    }
+
+    /// Whether the stack's configuration hash matches the one recorded when it
+    /// was last brought up. Without a recorded hash it can't be known so is
+    /// assumed to have changed. Script stacks have no configuration to
+    /// compare.
+    fn unchanged_since_up(&self, stack: &Stack) -> Result<bool> {
+        if stack.kind == StackKind::Script {
+            return Ok(true);
+        }
+
+        let recorded = match self.state {
+            Some(ref state) => state.load()?.stacks.remove(&stack.key),
+            None => None,
+        };
+        match recorded.and_then(|recorded| recorded.hash) {
+            Some(hash) => Ok(hash == self.stack_hash(stack)?),
+            None => Ok(false),
+        }
+    }
+
+    /// Polls the stack's containers until every service is running, and
+    /// healthy if it has a health check, or the timeout passes.
+    pub fn wait_until_up(&self, stack: &Stack, timeout: Duration) -> Result<()> {
+        let services = status::parse_services(&self.query(stack, "config", &["--services"])?);
+        let start = Instant::now();
+
+        loop {
+            let containers = self.containers(stack)?;
+            let pending = status::not_up(&services, &containers);
+            if pending.is_empty() {
+                return Ok(());
+            }
+
+            let elapsed = start.elapsed();
+            log::debug!(
+                "Waiting for {} in {} after {}",
+                pending.join(", "),
+                stack.key,
+                format_duration(elapsed)
+            );
+            if elapsed >= timeout {
+                return Err(Error::NotReady {
+                    stack: stack.key.clone(),
+                    services: pending.iter().map(|s| s.to_string()).collect(),
+                    timeout: timeout.as_secs(),
+                });
+            }
+            if !self
+                .cancel
+                .sleep(self.verify_interval.min(timeout - elapsed))
+            {
+                return Err(Error::Interrupted {
+                    completed: Vec::new(),
+                    interrupted: vec![stack.key.clone()],
+                    not_started: Vec::new(),
+                });
+            }
+        }
+    }
+
+    /// Whether to check that the stack's services came up after running the
+    /// command. Only compose projects brought up without `--no-wait` are
+    /// checked, and not when attached as the services stop when it exits.
+    fn should_verify(&self, exec_options: &ExecOptions, stack: &Stack) -> bool {
+        exec_options.command == "up"
+            && !exec_options.host
+            && exec_options.output_mode == OutputMode::Piped
+            && stack.kind == StackKind::Compose
+            && stack.backend.unwrap_or(self.config.backend) == BackendKind::Compose
+            && self.config.verify_up(stack)
+            && !exec_options.args.iter().any(|arg| arg == "--no-wait")
+    }
+
+    /// The hash of the stack's current configuration.
+    pub fn stack_hash(&self, stack: &Stack) -> Result<String> {
+        self.rendering(stack).map(|(_, hash)| hash)
+    }
+
+    /// The stack's rendered compose config along with the hash of its
+    /// configuration.
+    fn rendering(&self, stack: &Stack) -> Result<(String, String)> {
+        let rendered = self.query(stack, "config", &[])?;
+        let exec_options =
+            ExecOptions::new(self.config, "config", &[] as &[&str]).with_stack(stack)?;
+        let hash = config_hash(&rendered, &exec_options.environment)?;
+        Ok((rendered, hash))
+    }
+
+    /// Finds the stacks that have no containers at all and marks them as
+    /// skipped for the command. Stacks that cannot be checked are assumed to
+    /// exist.
+    pub fn absent_stacks(&self, command: &str, stacks: &[&Stack]) -> Vec<String> {
+        let absent: Vec<&Stack> = stacks
+            .iter()
+            .filter(|stack| match self.containers(stack) {
+                Ok(containers) => !status::exists(&containers),
+                Err(e) => {
+                    log::debug!("Unable to list containers for {}: {}", stack.key, e);
+                    false
+                }
+            })
+            .copied()
+            .collect();
+
+        for stack in absent.iter() {
+            log::info!("Skipping {} which has no containers", stack.key);
+        }
+        self.summary.skip(&absent, command);
+        absent.iter().map(|stack| stack.key.clone()).collect()
+    }
+
+    /// Filters the stacks down to those without any containers, marking the
+    /// rest as skipped for the command. Warns about stacks that depend on a
+    /// stack that exists but is stopped since that won't be started.
+    pub fn missing_stacks<'s>(&self, command: &str, stacks: &[&'s Stack]) -> Vec<&'s Stack> {
+        let presence: HashMap<String, Presence> = stacks
+            .iter()
+            .filter_map(|stack| match self.containers(stack) {
+                Ok(containers) => Some((stack.key.clone(), Presence::of(&containers))),
+                Err(e) => {
+                    log::debug!("Unable to list containers for {}: {}", stack.key, e);
+                    None
+                }
+            })
+            .collect();
+
+        let (missing, stopped) = only_missing(stacks, &presence);
+        for (stack, dependency) in stopped {
+            log::warn!(
+                "{} depends on {} which exists but is stopped, start it with `stack {} start`",
+                stack,
+                dependency,
+                dependency
+            );
+        }
+
+        let existing: Vec<&Stack> = stacks
+            .iter()
+            .filter(|stack| !missing.iter().any(|s| s.key == stack.key))
+            .copied()
+            .collect();
+        for stack in existing.iter() {
+            log::info!("Skipping {} which already has containers", stack.key);
+        }
+        self.summary.skip(&existing, command);
+
+        missing
+    }
+
+    /// Prints the last entries from the history log.
+    pub fn history(&self, count: usize) -> Result<()> {
+        match self.history {
+            Some(ref log) => print!("{}", history::render(&log.last(count)?)),
+            None => log::warn!("There is no history for a config read from stdin."),
+        }
+        Ok(())
+    }
+
+    /// Prints the dependency graph of the stacks without running anything.
+    pub fn graph(&self, stacks: &[&Stack], format: GraphFormat) -> Result<()> {
+        let graph = Graph::new(
+            self.config,
+            stacks,
+            self.config_file.as_deref(),
+            state_file::now(),
+        );
+        print!("{}", graph.render(format));
+        Ok(())
+    }
+
+    /// Describes whether each stack is running and when it was last brought
+    /// up along with whether its configuration has changed since.
+    pub fn status(&self, stacks: &[&Stack]) -> Result<String> {
+        self.preflight("ps", stacks)?;
+
+        let state = match self.state {
+            Some(ref state) => state.load()?,
+            None => state_file::State::default(),
+        };
+
+        let statuses: Vec<status::StackStatus> = stacks
+            .iter()
+            .map(|stack| status::StackStatus {
+                stack: stack.key.clone(),
+                running: self
+                    .is_up(stack)
+                    .map_err(|e| log::debug!("Unable to check {}: {}", stack.key, e))
+                    .ok(),
+                recorded: state.stacks.get(&stack.key).cloned(),
+                hash: self
+                    .stack_hash(stack)
+                    .map_err(|e| log::debug!("Unable to hash {}: {}", stack.key, e))
+                    .ok(),
+                urls: self.listed_urls(stack),
+            })
+            .collect();
+
+        Ok(status::render(&statuses, state_file::now()))
+    }
+
+    /// Lists whether each stack's configuration has changed since it was last
+    /// brought up. With details the changes to each changed stack's rendered
+    /// compose config are shown as a unified diff.
+    pub fn diff(&self, stacks: &[&Stack], details: bool, show_secrets: bool) -> Result<String> {
+        self.preflight("config", stacks)?;
+
+        let state_file = self.state.as_ref().ok_or_else(|| {
+            Error::Invalid(
+                "There is no state to compare with when the stacks config is read from stdin."
+                    .to_owned(),
+            )
+        })?;
+        let state = state_file.load()?;
+
+        let stacks: Vec<&Stack> = stacks
+            .iter()
+            .copied()
+            .filter(|s| s.kind == StackKind::Compose)
+            .collect();
+        let width = stacks.iter().map(|s| s.key.len()).max().unwrap_or(0);
+        let mut summary = String::new();
+        let mut details_output = String::new();
+        for stack in stacks {
+            let rendering = self
+                .rendering(stack)
+                .map_err(|e| log::warn!("Unable to render {}: {}", stack.key, e))
+                .ok();
+            let drift = status::Drift::compare(
+                state
+                    .stacks
+                    .get(&stack.key)
+                    .and_then(|recorded| recorded.hash.as_deref()),
+                rendering.as_ref().map(|(_, hash)| hash.as_str()),
+            );
+            summary.push_str(&format!(
+                "{:<width$}  {}\n",
+                stack.key,
+                drift.label(),
+                width = width
+            ));
+
+            let current = match rendering {
+                Some((current, _)) if details && drift == status::Drift::Changed => current,
+                _ => continue,
+            };
+
+            let options = diff::DiffOptions {
+                color: self.color,
+                show_secrets,
+                ..Default::default()
+            };
+            details_output.push_str(&format!("\n{}:\n", stack.key));
+            match state_file.rendered(&stack.key)? {
+                Some(recorded) => match diff::unified(&recorded, &current, &options) {
+                    Some(changes) => details_output.push_str(&changes),
+                    None => details_output.push_str("Only the environment has changed.\n"),
+                },
+                None => details_output.push_str(
+                    "No rendered config was recorded when the stack was last brought up.\n",
+                ),
+            }
+        }
+
+        summary.push_str(&details_output);
+        Ok(summary)
+    }
+
+    /// Compares the running containers of each compose stack with what would
+    /// be deployed now. Stacks that can't be checked are reported as unknown.
+    pub fn verify(&self, stacks: &[&Stack]) -> Result<Vec<(String, Verdict)>> {
+        self.preflight("config", stacks)?;
+
+        let program = daemon_program(&self.config.command).ok_or_else(|| {
+            Error::Invalid("`verify` needs docker or podman to inspect containers.".to_owned())
+        })?;
+
+        Ok(stacks
+            .iter()
+            .filter(|stack| stack.kind == StackKind::Compose)
+            .map(|stack| {
+                let verdict = self
+                    .verify_stack(program, stack)
+                    .unwrap_or_else(|e| Verdict::Unknown(e.to_string()));
+                (stack.key.clone(), verdict)
+            })
+            .collect())
+    }
+
+    fn verify_stack(&self, program: &str, stack: &Stack) -> Result<Verdict> {
+        let ids: Vec<String> = self
+            .query(stack, "ps", &["--quiet"])?
+            .lines()
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if ids.is_empty() {
+            return Ok(Verdict::NotRunning);
+        }
+
+        let command: Vec<String> = [program, "container", "inspect"]
+            .into_iter()
+            .map(str::to_owned)
+            .chain(ids)
+            .collect();
+        let containers = verify::parse_inspect(&self.daemon_command(stack, "inspect", &command)?)?;
+
+        let working_dir = stack.directory(&self.config.base_dir);
+        let mut config_files: Vec<PathBuf> = stack
+            .file
+            .iter()
+            .flatten()
+            .map(|file| paths::join(&self.config.base_dir, file))
+            .collect();
+        if config_files.is_empty() {
+            config_files.push(default_compose_file(&working_dir));
+        }
+        let pinned = working_dir.join(freeze::PINNED_FILE);
+        if self.config.frozen && pinned.is_file() {
+            config_files.push(pinned);
+        }
+
+        // Compare with what `up` would create, which includes the pinned
+        // images when frozen.
+        let config = |args: &[&str]| {
+            self.query_with(stack, ExecOptions::new(self.config, "config", args).as_up())
+        };
+        let expected = verify::Expected {
+            working_dir,
+            config_files,
+            hashes: config(&["--hash", "*"])
+                .map(|output| verify::parse_hashes(&output))
+                .unwrap_or_else(|e| {
+                    log::debug!("Unable to hash the services of {}: {}", stack.key, e);
+                    BTreeMap::new()
+                }),
+            environment: verify::service_environments(&config(&[])?)?,
+        };
+
+        Ok(verify::compare(&expected, &containers))
+    }
+
+    /// Lists the stacks with their descriptions and, if asked for, how much
+    /// of each is running. The stacks are checked concurrently.
+    pub fn list(&self, stacks: &[&Stack], state: bool) -> Result<String> {
+        let states = if state {
+            self.run_states(stacks)?.into_iter().map(Some).collect()
+        } else {
+            vec![None; stacks.len()]
+        };
+
+        let entries: Vec<list::Entry> = stacks
+            .iter()
+            .zip(states)
+            .map(|(stack, state)| list::Entry::new(stack, state, self.listed_urls(stack)))
+            .collect();
+        Ok(list::render(&entries))
+    }
+
+    /// Lists the stacks as a tree of their dependencies.
+    pub fn list_tree(&self, stacks: &[&Stack], state: bool) -> Result<String> {
+        let states: HashMap<String, status::RunState> = if state {
+            stacks
+                .iter()
+                .map(|stack| stack.key.clone())
+                .zip(self.run_states(stacks)?)
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        Ok(list::render_tree(stacks, &states))
+    }
+
+    /// Checks how much of each stack is running, all at the same time.
+    fn run_states(&self, stacks: &[&Stack]) -> Result<Vec<status::RunState>> {
+        self.preflight("ps", stacks)?;
+
+        Ok(thread::scope(|scope| {
+            let handles: Vec<_> = stacks
+                .iter()
+                .map(|stack| scope.spawn(move || self.run_state(stack)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or(status::RunState::Unknown))
+                .collect()
+        }))
+    }
+
+    /// Shows the logs of every stack at once, so following them interleaves
+    /// their output.
+    pub fn logs(&self, stacks: &[&Stack], args: &[String]) -> Result<()> {
+        self.run_scheduled("logs", stacks, args, Direction::Independent, stacks.len())
+    }
+
+    /// Shows the logs of every stack at once, each since it was last brought
+    /// up.
+    pub fn logs_since_last_up(&self, stacks: &[&Stack], args: &[String]) -> Result<()> {
+        if has_flag(args, &["--since"]) {
+            return Err(Error::Invalid(
+                "`--since-last-up` can't be used with `--since`.".to_owned(),
+            ));
+        }
+
+        let state = match self.state {
+            Some(ref state) => state.load()?,
+            None => state_file::State::default(),
+        };
+
+        self.create_directories("logs", false, stacks)?;
+        self.preflight("logs", stacks)?;
+        // Each stack was brought up at a different time.
+        let mut exec_options = ExecOptions::new(self.config, "logs", args);
+        for stack in stacks {
+            exec_options.stack_args(&stack.key, &since_last_up_args(stack, &state, args));
+        }
+        self.run_options(
+            &exec_options,
+            stacks,
+            Some((Direction::Independent, stacks.len())),
+        )
+    }
+
+    /// Opens one of the stack's URLs, or just prints it.
+    pub fn open(&self, stack: &Stack, index: Option<usize>, print: bool) -> Result<()> {
+        let urls = self.stack_urls(stack)?;
+        let url = open::pick_url(stack, &urls, index)?;
+
+        if print {
+            println!("{}", url);
+            return Ok(());
+        }
+
+        log::info!("Opening {}", url);
+        self.opener
+            .open(url)
+            .map_err(|e| Error::Invalid(format!("Unable to open {}: {}", url, e)))
+    }
+
+    /// The stack's URLs for listing, as they are configured if they can't be
+    /// expanded.
+    fn listed_urls(&self, stack: &Stack) -> Vec<String> {
+        self.stack_urls(stack).unwrap_or_else(|e| {
+            log::debug!("Unable to expand the urls of {}: {}", stack.key, e);
+            stack.url.clone().unwrap_or_default()
+        })
+    }
+
+    /// How much of the stack is running. Script stacks are only up or down
+    /// according to their status script.
+    fn run_state(&self, stack: &Stack) -> status::RunState {
+        let state = match stack.kind {
+            StackKind::Script if stack.scripts.status.is_none() => {
+                return status::RunState::Unknown
+            }
+            StackKind::Script => self.is_up(stack).map(|up| {
+                if up {
+                    status::RunState::Up
+                } else {
+                    status::RunState::Down
+                }
+            }),
+            StackKind::Compose => self
+                .containers(stack)
+                .map(|containers| status::RunState::of(&containers)),
+        };
+
+        state.unwrap_or_else(|e| {
+            log::debug!("Unable to check {}: {}", stack.key, e);
+            status::RunState::Unknown
+        })
+    }
+
+    /// Lists the compose projects on the host that aren't any of the
+    /// configured stacks.
+    pub fn orphans(&self) -> Result<String> {
+        let ignore = self.config.orphan_patterns()?;
+        let exec_options = ExecOptions::new(self.config, "ls", &["--all", "--format", "json"]);
+        let output = self.executor.output(&exec_options)?;
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                stack: String::new(),
+                command: exec_options.command_line(),
+                status: output.status,
+            });
+        }
+
+        let projects = status::parse_ls(&String::from_utf8_lossy(&output.stdout))?;
+        let known: Vec<&str> = self
+            .config
+            .stacks
+            .values()
+            .map(|stack| stack.name.as_str())
+            .collect();
+        Ok(status::render_orphans(&status::orphans(
+            &projects, &known, &ignore,
+        )))
+    }
+
+    /// Collects the output of `ps` for each stack with every line prefixed by
+    /// the stack it is for. A stack that can't be listed shows the error
+    /// rather than failing the whole listing.
+    pub fn ps(&self, stacks: &[&Stack], args: &[String]) -> Result<String> {
+        self.preflight("ps", stacks)?;
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let width = stacks.iter().map(|s| s.key.len() + 2).max().unwrap_or(0);
+        let mut listing = String::new();
+        for stack in stacks {
+            let prefix = format!("{:<width$}", format!("[{}]", stack.key), width = width);
+            let output = self
+                .query(stack, "ps", &args)
+                .unwrap_or_else(|e| format!("{}\n", e));
+            for line in output.lines().filter_map(|line| prefix_line(&prefix, line)) {
+                listing.push_str(&line);
+                listing.push('\n');
+            }
+        }
+
+        Ok(listing)
+    }
+
+    /// Writes the config that compose resolves for each stack, or only the
+    /// names of its services, as a YAML document per stack. A stack that fails
+    /// doesn't stop the others being written.
+    pub fn rendered_config<W: Write>(
+        &self,
+        stacks: &[&Stack],
+        services: bool,
+        out: &mut W,
+    ) -> Result<()> {
+        self.preflight("config", stacks)?;
+
+        let args: &[&str] = if services { &["--services"] } else { &[] };
+        let mut errors = Vec::new();
+        for stack in stacks.iter().filter(|s| s.kind == StackKind::Compose) {
+            match self.query(stack, "config", args) {
+                Ok(output) => {
+                    let mut document = format!("--- # stack: {}\n{}", stack.key, output);
+                    if !document.ends_with('\n') {
+                        document.push('\n');
+                    }
+                    out.write_all(document.as_bytes())
+                        .map_err(|e| Error::io("Failed to write output", e))?;
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        match errors.len() {
+            0 => Ok(()),
+            1 => aggregate(stacks, Err(errors.remove(0))),
+            _ => Err(Error::StacksFailed { failures: errors }),
+        }
+    }
+
+    /// The environment that commands for the stack run with, leaving out
+    /// variables that are the same in the environment stacks is running in.
+    pub fn stack_environment(&self, stack: &Stack) -> Result<BTreeMap<String, String>> {
+        let exec_options =
+            ExecOptions::host(self.config, "env", &[] as &[&str]).with_stack(stack)?;
+        Ok(exec_options
+            .environment
+            .iter()
+            .filter(|(key, value)| env::var_os(key).as_ref() != Some(*value))
+            .map(|(key, value)| (key.clone(), value.to_string_lossy().into_owned()))
+            .collect())
+    }
+
+    /// The stack's URLs with any variables expanded against the environment
+    /// its commands run with.
+    pub fn stack_urls(&self, stack: &Stack) -> Result<Vec<String>> {
+        let urls = match stack.url {
+            Some(ref urls) => urls,
+            None => return Ok(Vec::new()),
+        };
+
+        let exec_options =
+            ExecOptions::host(self.config, "url", &[] as &[&str]).with_stack(stack)?;
+        let lookup = |name: &str| exec_options.var(name);
+
+        urls.iter()
+            .map(|url| {
+                environment::interpolate(url, lookup).map_err(|variable| {
+                    Error::Invalid(format!(
+                        "The url `{}` of stack `{}` references `{}` which is not set.",
+                        url, stack.key, variable
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    /// Runs a host command that talks to the daemon for the stack, failing if
+    /// it exits unsuccessfully.
+    fn daemon_command(&self, stack: &Stack, name: &str, command: &[String]) -> Result<String> {
+        let exec_options = ExecOptions::host(self.config, name, command).with_stack(stack)?;
+        let output = self.executor.output(&exec_options)?;
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                stack: stack.key.clone(),
+                command: exec_options.command_line(),
+                status: output.status,
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The names of the volumes that exist.
+    fn existing_volumes(
+        &self,
+        program: &str,
+        stack: &Stack,
+        volumes: &[backup::Volume],
+    ) -> Result<Vec<String>> {
+        if volumes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Inspecting fails when any volume is missing but still lists the
+        // others.
+        let command: Vec<String> = [program, "volume", "inspect"]
+            .into_iter()
+            .map(str::to_owned)
+            .chain(volumes.iter().map(|volume| volume.name.clone()))
+            .collect();
+        let exec_options = ExecOptions::host(self.config, "volume", &command).with_stack(stack)?;
+        let output = self.executor.output(&exec_options)?;
+        backup::existing_volumes(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Finds the stack's named volumes that have been created.
+    fn stack_volumes(&self, program: &str, stack: &Stack) -> Result<Vec<backup::Volume>> {
+        let volumes =
+            backup::project_volumes(&stack.name, &self.query(stack, "config", &["--volumes"])?);
+        let existing = self.existing_volumes(program, stack, &volumes)?;
+
+        Ok(volumes
+            .into_iter()
+            .filter(|volume| {
+                let exists = existing.contains(&volume.name);
+                if !exists {
+                    log::warn!("Skipping volume {} which has not been created", volume.name);
+                }
+                exists
+            })
+            .collect())
+    }
+
+    /// Whether any of the stack's containers are running.
+    fn has_running_containers(&self, stack: &Stack) -> Result<bool> {
+        Ok(self
+            .containers(stack)?
+            .iter()
+            .any(|container| container.state == "running"))
+    }
+
+    /// Archives each of the stack's named volumes into a new timestamped
+    /// directory in `dest` along with a manifest describing the backup. With
+    /// `stop` a running stack is stopped for the backup and started again
+    /// afterwards. Returns the directory of the backup.
+    pub fn backup(&self, stack: &Stack, dest: &Path, stop: bool) -> Result<PathBuf> {
+        if stack.kind == StackKind::Script {
+            return Err(Error::Invalid(format!(
+                "{} is not a compose project",
+                stack.key
+            )));
+        }
+        self.preflight("config", &[stack])?;
+
+        let program = daemon_program(&self.config.command).ok_or_else(|| {
+            Error::Invalid("`backup` needs docker or podman to run containers.".to_owned())
+        })?;
+
+        let volumes = self.stack_volumes(program, stack)?;
+        if volumes.is_empty() {
+            return Err(Error::Invalid(format!(
+                "Stack `{}` has no volumes to back up.",
+                stack.key
+            )));
+        }
+
+        let stopped = match self.has_running_containers(stack)? {
+            true if stop => {
+                self.run_against_stacks("stop", &[stack], &[])?;
+                true
+            }
+            true => {
+                log::warn!(
+                    "{} has running containers so the backup may be inconsistent, pass `--stop` \
+                    to stop it during the backup",
+                    stack.key
+                );
+                false
+            }
+            false => false,
+        };
+
+        let result = self.archive_volumes(program, stack, &volumes, dest);
+
+        if stopped {
+            self.run_against_stacks("start", &[stack], &[])?;
+        }
+        result
+    }
+
+    fn archive_volumes(
+        &self,
+        program: &str,
+        stack: &Stack,
+        volumes: &[backup::Volume],
+        dest: &Path,
+    ) -> Result<PathBuf> {
+        let created = state_file::now();
+        let dir = dest.join(backup::backup_dir_name(&stack.key, created));
+        fs::create_dir_all(&dir)
+            .map_err(|e| Error::io(format!("Failed to create {}", dir.display()), e))?;
+        // Docker needs an absolute path to mount.
+        let dir = dir
+            .canonicalize()
+            .map_err(|e| Error::io(format!("Failed to find {}", dir.display()), e))?;
+
+        let mut backups = Vec::new();
+        for volume in volumes {
+            let archive = format!("{}.tar.gz", volume.volume);
+            log::info!("Backing up volume {} to {}", volume.name, archive);
+            let command = backup::archive_command(program, &volume.name, &dir, &archive);
+            self.daemon_command(stack, "backup", &command)?;
+
+            backups.push(backup::VolumeBackup {
+                volume: volume.volume.clone(),
+                name: volume.name.clone(),
+                size: fs::metadata(dir.join(&archive)).ok().map(|m| m.len()),
+                archive,
+            });
+        }
+
+        let manifest = backup::Manifest {
+            stack: stack.key.clone(),
+            project: stack.name.clone(),
+            created,
+            hash: self
+                .stack_hash(stack)
+                .map_err(|e| log::debug!("Unable to hash {}: {}", stack.key, e))
+                .ok(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            volumes: backups,
+        };
+        let path = dir.join(backup::MANIFEST_FILE);
+        fs::write(&path, manifest.render())
+            .map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))?;
+
+        Ok(dir)
+    }
+
+    /// Restores the volumes in the backup at `dir` into the stack's volumes,
+    /// creating any that are missing. A stack with running containers is
+    /// only stopped, and started again afterwards, with `force_stop`.
+    pub fn restore(
+        &self,
+        stack: &Stack,
+        dir: &Path,
+        only: &[String],
+        force_stop: bool,
+        allow_mismatch: bool,
+    ) -> Result<()> {
+        if stack.kind == StackKind::Script {
+            return Err(Error::Invalid(format!(
+                "{} is not a compose project",
+                stack.key
+            )));
+        }
+        self.preflight("config", &[stack])?;
+
+        let program = daemon_program(&self.config.command).ok_or_else(|| {
+            Error::Invalid("`restore` needs docker or podman to run containers.".to_owned())
+        })?;
+
+        let path = dir.join(backup::MANIFEST_FILE);
+        let manifest = backup::Manifest::parse(
+            &fs::read_to_string(&path)
+                .map_err(|e| Error::io(format!("Failed to read {}", path.display()), e))?,
+        )?;
+        if manifest.stack != stack.key || manifest.project != stack.name {
+            if !allow_mismatch {
+                return Err(Error::Invalid(format!(
+                    "The backup in {} is of stack `{}` but is being restored to `{}`. Pass \
+                    `--allow-mismatch` to restore it anyway.",
+                    dir.display(),
+                    manifest.stack,
+                    stack.key
+                )));
+            }
+            log::warn!(
+                "Restoring the backup of stack `{}` to `{}`",
+                manifest.stack,
+                stack.key
+            );
+        }
+
+        let backups = backup::select_volumes(&manifest, only)?;
+        if let Some(missing) = backups
+            .iter()
+            .find(|backup| !dir.join(&backup.archive).is_file())
+        {
+            return Err(Error::Invalid(format!(
+                "The archive {} of volume `{}` is missing from {}.",
+                missing.archive,
+                missing.volume,
+                dir.display()
+            )));
+        }
+        // Docker needs an absolute path to mount.
+        let dir = dir
+            .canonicalize()
+            .map_err(|e| Error::io(format!("Failed to find {}", dir.display()), e))?;
+
+        let stopped = self.has_running_containers(stack)?;
+        if stopped {
+            if !force_stop {
+                return Err(Error::Invalid(format!(
+                    "Stack `{}` has running containers. Pass `--force-stop` to stop it while \
+                    its volumes are restored.",
+                    stack.key
+                )));
+            }
+            self.run_against_stacks("stop", &[stack], &[])?;
+        }
+
+        let result = self.extract_volumes(program, stack, &backups, &dir);
+
+        if stopped {
+            self.run_against_stacks("start", &[stack], &[])?;
+        }
+        result
+    }
+
+    fn extract_volumes(
+        &self,
+        program: &str,
+        stack: &Stack,
+        backups: &[&backup::VolumeBackup],
+        dir: &Path,
+    ) -> Result<()> {
+        let volumes: Vec<backup::Volume> = backups
+            .iter()
+            .map(|backup| backup::Volume {
+                volume: backup.volume.clone(),
+                name: format!("{}_{}", stack.name, backup.volume),
+            })
+            .collect();
+        let existing = self.existing_volumes(program, stack, &volumes)?;
+
+        for (volume, backup) in volumes.iter().zip(backups) {
+            if !existing.contains(&volume.name) {
+                log::info!("Creating volume {}", volume.name);
+                let command = backup::create_volume_command(program, &stack.name, volume);
+                self.daemon_command(stack, "volume", &command)?;
+            }
+
+            log::info!("Restoring volume {} from {}", volume.name, backup.archive);
+            let command = backup::restore_command(program, &volume.name, dir, &backup.archive);
+            self.daemon_command(stack, "restore", &command)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pins the images of each stack's services to their current digests in
+    /// the stack's pinned file. When checking the pinned files are compared
+    /// with the current digests instead of being written.
+    pub fn freeze(&self, stacks: &[&Stack], check: bool) -> Result<()> {
+        self.preflight("config", stacks)?;
+
+        let program = daemon_program(&self.config.command).ok_or_else(|| {
+            Error::Invalid("`freeze` needs docker or podman to inspect images.".to_owned())
+        })?;
+
+        let mut problems = Vec::new();
+        for stack in stacks.iter().filter(|s| s.kind == StackKind::Compose) {
+            let images = freeze::service_images(&self.query(stack, "config", &[])?)?;
+            let mut unpinned: Vec<&str> = images
+                .values()
+                .filter(|image| !image.contains('@'))
+                .map(String::as_str)
+                .collect();
+            unpinned.sort();
+            unpinned.dedup();
+
+            let inspect_output = if unpinned.is_empty() {
+                "[]".to_owned()
+            } else {
+                let command: Vec<String> = [program, "image", "inspect"]
+                    .into_iter()
+                    .chain(unpinned)
+                    .map(str::to_owned)
+                    .collect();
+                self.daemon_command(stack, "inspect", &command)?
+            };
+            let pinned = freeze::resolve(&images, &inspect_output)?;
+
+            let path = stack
+                .directory(&self.config.base_dir)
+                .join(freeze::PINNED_FILE);
+            if check {
+                let locked = match fs::read_to_string(&path) {
+                    Ok(content) => freeze::Pinned::parse(&content)?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        problems.push((stack.key.clone(), "no images are pinned".to_owned()));
+                        continue;
+                    }
+                    Err(e) => {
+                        return Err(Error::io(format!("Failed to read {}", path.display()), e))
+                    }
+                };
+                problems.extend(
+                    locked
+                        .differences(&pinned)
+                        .into_iter()
+                        .map(|problem| (stack.key.clone(), problem)),
+                );
+            } else {
+                fs::write(&path, pinned.render())
+                    .map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))?;
+                log::info!(
+                    "Pinned {} images for {} in {}",
+                    pinned.services.len(),
+                    stack.key,
+                    path.display()
+                );
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::StaleImages { problems })
+        }
+    }
+
+    /// Finds the images built or pulled for the stacks' projects that can be
+    /// removed. Images that any configured stack's compose config or any
+    /// container still uses are never included, so every compose stack must
+    /// be readable.
+    pub fn clean_candidates(
+        &self,
+        stacks: &[&Stack],
+        all_unused: bool,
+    ) -> Result<Vec<clean::Candidate>> {
+        let stacks: Vec<&Stack> = stacks
+            .iter()
+            .copied()
+            .filter(|stack| stack.kind == StackKind::Compose)
+            .collect();
+        let Some(first) = stacks.first() else {
+            return Ok(Vec::new());
+        };
+        self.preflight("config", &stacks)?;
+
+        let program = daemon_program(&self.config.command).ok_or_else(|| {
+            Error::Invalid("`clean` needs docker or podman to remove images.".to_owned())
+        })?;
+
+        let mut in_use = clean::InUse::default();
+        for stack in self
+            .config
+            .stacks
+            .values()
+            .filter(|stack| stack.kind == StackKind::Compose)
+        {
+            let images = self.query(stack, "config", &["--images"]).map_err(|e| {
+                Error::Invalid(format!(
+                    "Unable to find the images that {} uses so nothing was removed: {}",
+                    stack.key, e
+                ))
+            })?;
+            in_use.add_config_images(&images);
+        }
+        let command: Vec<String> = [program, "ps", "--all", "--format", "json"]
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        in_use.add_containers(&self.daemon_command(first, "containers", &command)?)?;
+
+        let mut candidates = Vec::new();
+        for stack in stacks {
+            let label = format!("label=com.docker.compose.project={}", stack.name);
+            let command: Vec<String> = [program, "image", "ls", "--filter", &label]
+                .into_iter()
+                .chain(["--format", "json"])
+                .map(str::to_owned)
+                .collect();
+            let images = clean::parse_images(&self.daemon_command(stack, "images", &command)?)?;
+            candidates.extend(clean::candidates(&stack.key, &images, &in_use, all_unused));
+        }
+
+        Ok(candidates)
+    }
+
+    /// Removes the images found by `clean_candidates` and then the build cache
+    /// of each of the stacks' projects.
+    pub fn clean(&self, stacks: &[&Stack], candidates: &[clean::Candidate]) -> Result<()> {
+        let (Some(stack), false) = (stacks.first(), candidates.is_empty()) else {
+            return Ok(());
+        };
+        let program = daemon_program(&self.config.command).ok_or_else(|| {
+            Error::Invalid("`clean` needs docker or podman to remove images.".to_owned())
+        })?;
+
+        self.daemon_command(stack, "clean", &clean::remove_command(program, candidates))?;
+
+        let mut projects = HashSet::new();
+        for stack in stacks
+            .iter()
+            .filter(|stack| stack.kind == StackKind::Compose)
+            .filter(|stack| projects.insert(stack.name.as_str()))
+        {
+            if let Some(command) = clean::prune_command(program, &stack.name) {
+                self.daemon_command(stack, "prune", &command)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the ports that the stacks' containers publish on the host,
+    /// optionally only those for a service or a container or host port.
+    pub fn ports(
+        &self,
+        stacks: &[&Stack],
+        service: Option<&str>,
+        port: Option<u16>,
+    ) -> Result<String> {
+        self.preflight("ps", stacks)?;
+
+        let mut bindings = Vec::new();
+        for stack in stacks.iter().filter(|s| s.kind == StackKind::Compose) {
+            let output = self.query(stack, "ps", &["--format", "json"])?;
+            bindings.extend(ports::parse_bindings(&stack.key, &output)?);
+        }
+
+        Ok(ports::render(&ports::filter(bindings, service, port)))
+    }
+
+    /// Pulls the images that the stacks use with each image pulled only once
+    /// for every daemon and platform that needs it, then lists which stack's
+    /// pull provided each stack's images. The arguments are passed to the
+    /// engine's `pull`.
+    pub fn pull_deduplicated(&self, stacks: &[&Stack], args: &[String]) -> Result<()> {
+        self.preflight("pull", stacks)?;
+
+        let mut needs = Vec::new();
+        for stack in stacks.iter().filter(|s| s.kind == StackKind::Compose) {
+            let images = status::parse_services(&self.query(stack, "config", &["--images"])?);
+            let options =
+                ExecOptions::new(self.config, "pull", &[] as &[&str]).with_stack(stack)?;
+            needs.push(pull::Need {
+                stack: stack.key.clone(),
+                daemon: pull::Daemon::from_environment(&options.environment),
+                images,
+            });
+        }
+
+        let pulls = pull::plan(&needs);
+        let mut errors = Vec::new();
+        for (index, image_pull) in pulls.iter().enumerate() {
+            if self.cancel.is_cancelled() {
+                let mut not_started: Vec<String> = pulls[index..]
+                    .iter()
+                    .flat_map(|pull| pull.stacks.iter().cloned())
+                    .collect();
+                not_started.sort();
+                not_started.dedup();
+                return Err(Error::Interrupted {
+                    completed: Vec::new(),
+                    interrupted: Vec::new(),
+                    not_started,
+                });
+            }
+
+            let mut command = vec![self.config.engine().name().to_string(), "pull".to_string()];
+            if let Some(ref platform) = image_pull.daemon.platform {
+                command.extend(["--platform".to_string(), platform.clone()]);
+            }
+            command.extend(args.iter().cloned());
+            command.push(image_pull.image.clone());
+
+            // The pull is run as the first stack that needs it so it gets
+            // that stack's environment.
+            let stack = stacks
+                .iter()
+                .find(|stack| stack.key == image_pull.stacks[0])
+                .unwrap();
+            let exec_options = ExecOptions::host(self.config, "pull", &command);
+            if let Err(e) = exec(
+                self.executor,
+                &exec_options,
+                stack,
+                self.output,
+                &self.cancel,
+            ) {
+                errors.push(e);
+            }
+        }
+
+        match errors.len() {
+            0 => {
+                print!("{}", pull::render(&needs, &pulls));
+                Ok(())
+            }
+            1 => aggregate(stacks, Err(errors.remove(0))),
+            _ => Err(Error::StacksFailed { failures: errors }),
+        }
+    }
+
+    /// Shows a fresh sample every interval until interrupted.
+    pub fn watch<F>(&self, interval: Duration, sample: F) -> Result<()>
+    where
+        F: FnMut() -> Result<String>,
+    {
+        watch::watch(interval, &self.cancel, sample)
+    }
+
+    /// Records a successful `up` or `down` in the state file.
+    fn record_state(&self, command: &str, stack: &Stack) {
+        let state = match self.state {
+            Some(ref state) => state,
+            None => return,
+        };
+        let _timer = self.timings.scope(Some(stack), "state");
+
+        let result = match command {
+            "up" => {
+                let rendering = self
+                    .rendering(stack)
+                    .map_err(|e| log::debug!("Unable to hash {}: {}", stack.key, e))
+                    .ok();
+                if let Some((ref rendered, _)) = rendering {
+                    if let Err(e) = state.record_rendered(&stack.key, rendered) {
+                        log::warn!("{}", e);
+                    }
+                }
+                state.record_up(&stack.key, rendering.map(|(_, hash)| hash))
+            }
+            "down" => state.record_down(&stack.key),
+            _ => return,
+        };
+
+        if let Err(e) = result {
+            log::warn!("{}", e);
+        }
+    }
+
+    /// The version of docker compose, queried the first time it is needed.
+    fn compose_version(&self) -> Option<Version> {
+        *self.compose_version.get_or_init(|| {
+            let exec_options = ExecOptions::new(self.config, "version", &["--short"]);
+            match self.executor.output(&exec_options) {
+                Ok(output) if output.status.success() => {
+                    version::parse_output(&String::from_utf8_lossy(&output.stdout))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Filters flags that stacks would add to a command down to those that the
+    /// installed compose supports.
+    pub fn supported_flags(&self, flags: &[&str]) -> Vec<String> {
+        let engine = self.config.engine();
+        let version = match engine {
+            Engine::Docker => self.compose_version(),
+            Engine::Podman => None,
+        };
+
+        flags
+            .iter()
+            .filter(|flag| {
+                let supported = engine.supports(flag) && version::supports(flag, version);
+                if !supported {
+                    log::debug!(
+                        "Not passing {} which {} compose does not support",
+                        flag,
+                        engine.name()
+                    );
+                }
+                supported
+            })
+            .map(|flag| flag.to_string())
+            .collect()
+    }
+
+    /// Checks that the installed docker compose has the command. Commands are
+    /// assumed to exist when the version is unknown.
+    fn check_command_version(&self, command: &str) -> Result<()> {
+        let introduced = match version::command_version(command) {
+            Some(introduced) if self.config.engine() == Engine::Docker => introduced,
+            _ => return Ok(()),
+        };
+
+        match self.compose_version() {
+            Some(version) if version < introduced => Err(Error::Invalid(format!(
+                "`{}` needs docker compose {} or later but {} is installed.",
+                command, introduced, version
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the installed docker compose against the configured minimum
+    /// version.
+    fn check_version(&self) -> Result<()> {
+        let minimum = match self.config.min_compose_version {
+            Some(ref minimum) => minimum.parse::<Version>()?,
+            None => return Ok(()),
+        };
+
+        if self.version_checked.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        match self.compose_version() {
+            Some(version) if version < minimum => {
+                let error = Error::ComposeTooOld {
+                    version: version.to_string(),
+                    minimum: minimum.to_string(),
+                };
+                if self.strict_version {
+                    return Err(error);
+                }
+                log::warn!("{}", error);
+            }
+            Some(_) => {}
+            None => log::warn!("Unable to determine the version of docker compose."),
+        }
+
+        Ok(())
+    }
+
+    /// Runs the command against a single stack, retrying if allowed.
+    fn exec(
+        &self,
+        exec_options: &ExecOptions,
+        stack: &Stack,
+        output: &dyn OutputHandler,
+        reporter: &dyn Reporter,
+    ) -> Result<()> {
+        // Script stacks run their script for the command instead of compose.
+        let script_options;
+        let exec_options = match stack.kind {
+            StackKind::Script if !exec_options.host => {
+                match stack.scripts.get(&exec_options.command) {
+                    Some(script) => {
+                        script_options = exec_options.script(script);
+                        &script_options
+                    }
+                    None => {
+                        log::info!(
+                            "Skipping {} which has no script for {}",
+                            stack.key,
+                            exec_options.command
+                        );
+                        self.summary.skip(&[stack], &exec_options.command);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => exec_options,
+        };
+
+        let _total = self.timings.scope(Some(stack), TOTAL);
+        // Rollback must never take down a stack that was up before this run
+        // so it is checked even when running stacks aren't skipped.
+        let rolling_back = self.rolling_back.load(Ordering::SeqCst);
+        if (self.skip_running || rolling_back) && exec_options.command == "up" {
+            let running = {
+                let _timer = self.timings.scope(Some(stack), "running check");
+                self.is_up(stack)
+            };
+            let already_up = match running {
+                Ok(true) if self.skip_running => match self.unchanged_since_up(stack) {
+                    Ok(true) => {
+                        log::info!("{} is already up", stack.key);
+                        self.already_running.lock().unwrap().push(stack.key.clone());
+                        return Ok(());
+                    }
+                    Ok(false) => {
+                        log::info!(
+                            "{} is running but its configuration has changed since it was \
+                            brought up",
+                            stack.key
+                        );
+                        true
+                    }
+                    Err(e) => {
+                        log::debug!("Unable to check whether {} changed: {}", stack.key, e);
+                        true
+                    }
+                },
+                Ok(running) => running,
+                Err(e) => {
+                    log::debug!("Unable to check whether {} is up: {}", stack.key, e);
+                    // It may be running so must not be rolled back.
+                    true
+                }
+            };
+
+            if already_up {
+                log::debug!("{} was already up so won't be rolled back", stack.key);
+            } else {
+                self.started.lock().unwrap().push(stack.key.clone());
+            }
+        }
+
+        let provided_options;
+        let exec_options = {
+            let variables = self.published_provides(stack);
+            if variables.is_empty() {
+                exec_options
+            } else {
+                let mut options = exec_options.clone();
+                options.provide(&stack.key, variables);
+                provided_options = options;
+                &provided_options
+            }
+        };
+
+        reporter.started(stack);
+        let start = Instant::now();
+        let result = self
+            .retry
+            .run(
+                &exec_options.command,
+                stack,
+                |delay| {
+                    let _timer = self.timings.scope(Some(stack), "retry wait");
+                    self.cancel.sleep(delay)
+                },
+                || {
+                    let _timer = self.timings.scope(Some(stack), "command");
+                    exec(self.executor, exec_options, stack, output, &self.cancel)
+                },
+            )
+            .and_then(|_| {
+                if !self.should_verify(exec_options, stack) {
+                    return Ok(());
+                }
+                let _timer = self.timings.scope(Some(stack), "verify");
+                self.wait_until_up(stack, self.config.verify_timeout())
+            });
+        self.summary
+            .record(stack, &exec_options.command, start, &result);
+        reporter.finished(stack, result.is_ok(), start.elapsed());
+        log::debug!(
+            "{} {} for {} after {}",
+            exec_options.command,
+            if result.is_ok() { "finished" } else { "failed" },
+            stack.key,
+            format_duration(start.elapsed())
+        );
+        if result.is_ok() {
+            self.record_state(&exec_options.command, stack);
+        }
+        result
+    }
+
+    /// Looks up the published ports that the stack receives from its
+    /// dependencies. Ports that can't be found are left out with a warning.
+    fn published_provides(&self, stack: &Stack) -> HashMap<String, String> {
+        let mut variables = HashMap::new();
+
+        for (name, dependency, port) in provides::dynamic_variables(stack, &self.config.stacks) {
+            let _timer = self.timings.scope(Some(stack), "provides");
+            let args = port.args();
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            match self.query(dependency, "port", &args) {
+                Ok(output) => match provides::parse_port(&output) {
+                    Some(value) => {
+                        variables.insert(name, value);
+                    }
+                    None => log::warn!(
+                        "Port {} of {} in {} is not published so {} has no {}",
+                        port.port,
+                        port.service,
+                        dependency.key,
+                        stack.key,
+                        name
+                    ),
+                },
+                Err(e) => log::warn!(
+                    "Unable to find the port for {} provided by {}: {}",
+                    name,
+                    dependency.key,
+                    e
+                ),
+            }
+        }
+
+        variables
+    }
+
+    /// Undoes a failed `up` by taking down the stacks that were started during
+    /// this run, in reverse order. Stacks that were already running are left
+    /// alone.
+    pub fn rollback(&self, rollback: Rollback) -> Result<()> {
+        let keys: Vec<String> = self.started.lock().unwrap().drain(..).rev().collect();
+        let stacks: Vec<&Stack> = keys
+            .iter()
+            .filter_map(|key| self.config.stacks.get(key))
+            .collect();
+        if stacks.is_empty() {
+            return Ok(());
+        }
+
+        log::warn!("Rolling back {}", keys.join(", "));
+        // Only stacks that were started are rolled back so `--from` and
+        // `--until` don't apply.
+        let exec_options = ExecOptions::new(self.config, rollback.command(), &[] as &[&str]);
+        self.run_all(&exec_options, &stacks, None)
+    }
+
+    /// Runs `up` and rolls back the stacks that were started if it fails.
+    pub fn up_with_rollback<F>(&self, rollback: Option<Rollback>, up: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        self.started.lock().unwrap().clear();
+        self.rolling_back
+            .store(rollback.is_some(), Ordering::SeqCst);
+        let result = up();
+        self.rolling_back.store(false, Ordering::SeqCst);
+
+        if let (Err(_), Some(rollback)) = (&result, rollback) {
+            if self.cancel.is_cancelled() {
+                log::warn!("Not rolling back an interrupted run.");
+            } else if let Err(e) = self.rollback(rollback) {
+                log::error!("Rollback failed: {}", e);
+            }
+        }
+
+        result
+    }
+
+    /// Creates any missing stack directories when `create_dirs` is set and the
+    /// command doesn't need a compose file. Host commands only need the
+    /// directory to run in.
+    fn create_directories(&self, command: &str, host: bool, stacks: &[&Stack]) -> Result<()> {
+        if !self.config.create_dirs || !(host || CREATE_DIR_COMMANDS.contains(&command)) {
+            return Ok(());
+        }
+
+        for stack in stacks {
+            let directory = stack.directory(&self.config.base_dir);
+            if !directory.exists() {
+                log::info!("Creating {} for {}", directory.display(), stack.key);
+                fs::create_dir_all(&directory).map_err(|e| {
+                    Error::io(format!("Failed to create {}", directory.display()), e)
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that everything needed to run the command against the stacks is
+    /// present.
+    fn preflight(&self, command: &str, stacks: &[&Stack]) -> Result<()> {
+        if !self.preflight {
+            return Ok(());
+        }
+        let _timer = self.timings.scope(None, "preflight");
+
+        // Script stacks don't need compose or the docker daemon.
+        let compose: Vec<&Stack> = stacks
+            .iter()
+            .filter(|stack| stack.kind == StackKind::Compose)
+            .copied()
+            .collect();
+
+        if !compose.is_empty() {
+            check_programs(
+                self.config.command.first().map(String::as_str),
+                &self.config.base_dir,
+                env::var_os("PATH").as_deref(),
+            )?;
+        }
+
+        if !FILELESS_COMMANDS.contains(&command) {
+            // `COMPOSE_FILE` in a stack's environment chooses its compose files.
+            let options = ExecOptions::host(self.config, command, &[] as &[&str]);
+            let environments = compose
+                .iter()
+                .map(|stack| Ok((stack.key.as_str(), options.with_stack(stack)?)))
+                .collect::<Result<HashMap<&str, ExecOptions>>>()?;
+            check_compose_files(&compose, &self.config.base_dir, |stack, name| {
+                environments[stack.key.as_str()].var(name)
+            })?;
+        }
+
+        if self.config.warn_missing_env && !self.config.inherit_env {
+            let mut warned = self.warned_env.lock().unwrap();
+            let exec_options = ExecOptions::new(self.config, command, &[] as &[&str]);
+            for stack in stacks {
+                if warned.contains(&stack.key) {
+                    continue;
+                }
+                for name in exec_options.missing_host_variables(stack) {
+                    log::warn!("{} should be passed to {} but is not set.", name, stack.key);
+                }
+                warned.push(stack.key.clone());
+            }
+        }
+
+        if compose.is_empty() {
+            return Ok(());
+        }
+
+        self.check_version()?;
+
+        if let Some(program) = daemon_program(&self.config.command) {
+            if !OFFLINE_COMMANDS.contains(&command) {
+                let mut reachable = self.reachable.lock().unwrap();
+                let endpoints: Vec<Endpoint> = compose
+                    .iter()
+                    .map(|stack| {
+                        Endpoint::for_stack(self.config, stack, |name| env::var(name).ok())
+                    })
+                    .filter(|endpoint| !reachable.contains(endpoint))
+                    .collect();
+
+                let engine = self.config.engine();
+                check_daemons(&endpoints, |endpoint| {
+                    probe_daemon(program, engine, endpoint)
+                })?;
+                reachable.extend(endpoints);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a function with the output handler and progress reporter to use
+    /// for running the command against the given stacks.
+    fn with_output<F>(&self, mode: OutputMode, stacks: &[&Stack], f: F) -> Result<()>
+    where
+        F: FnOnce(&dyn OutputHandler, &dyn Reporter) -> Result<()>,
+    {
+        let prefixed;
+        let output: &dyn OutputHandler = if self.prefix && stacks.len() > 1 {
+            prefixed = Prefixed::new(stacks, self.color, self.output);
+            &prefixed
+        } else {
+            self.output
+        };
+
+        // Commands that own the terminal cannot be logged or drawn over.
+        let interactive = mode == OutputMode::Inherit;
+
+        let log_files = match self.log_dir {
+            Some(ref dir) if !interactive => Some(LogFiles::create(dir, stacks).map_err(|e| {
+                Error::io(format!("Failed to create logs in {}", dir.display()), e)
+            })?),
+            _ => None,
+        };
+        let run = |output: &dyn OutputHandler, reporter: &dyn Reporter| {
+            let tee;
+            let output: &dyn OutputHandler = match log_files {
+                Some(ref files) => {
+                    tee = Tee::new(files, output);
+                    &tee
+                }
+                None => output,
+            };
+
+            match self.events {
+                Some(ref events) => {
+                    let recorded = Recorded::new(events, output, reporter);
+                    f(&recorded, &recorded)
+                }
+                None => f(output, reporter),
+            }
+        };
+
+        if self.progress && stacks.len() > 1 && !interactive {
+            Progress::run(output, |progress| {
+                progress.begin(stacks.len());
+                let result = run(progress, progress);
+                progress.end();
+                result
+            })
+        } else {
+            self.reporter.begin(stacks.len());
+            let result = run(output, self.reporter);
+            self.reporter.end();
+            result
+        }
+    }
+
+    pub fn run_against_stacks(
+        &self,
+        command: &str,
+        stacks: &[&Stack],
+        args: &[String],
+    ) -> Result<()> {
+        self.run_in_mode(OutputMode::Piped, command, stacks, args)
+    }
+
+    /// Runs `attach`, `exec` or `run` against the stacks attached to the
+    /// terminal.
+    /// Without a terminal for both stdin and stdout they are given `-T` so
+    /// they don't try to allocate a TTY and wait for input, unless the user
+    /// already chose or `tty` asks for a TTY regardless.
+    pub fn run_attached(
+        &self,
+        command: &str,
+        stacks: &[&Stack],
+        args: &[String],
+        tty: bool,
+    ) -> Result<()> {
+        let mut args = args.to_vec();
+        if !tty
+            && !self.terminal
+            && TTY_COMMANDS.contains(&command)
+            && !has_flag(service_options(&args), &NO_TTY_FLAGS)
+        {
+            log::debug!("Passing -T to {} as there is no terminal", command);
+            // Anything after the service is the container's command.
+            args.insert(0, "-T".to_owned());
+        }
+
+        self.run_in_mode(OutputMode::Inherit, command, stacks, &args)
+    }
+
+    /// Runs a command that stays in the foreground until interrupted, like
+    /// `watch`, with the terminal handed over to compose.
+    pub fn run_foreground(&self, command: &str, stacks: &[&Stack], args: &[String]) -> Result<()> {
+        self.check_command_version(command)?;
+        self.run_in_mode(OutputMode::Inherit, command, stacks, args)
+    }
+
+    /// Runs the command against the stacks in order with its streams
+    /// connected as given. Interactive commands always have the terminal.
+    pub fn run_in_mode(
+        &self,
+        mode: OutputMode,
+        command: &str,
+        stacks: &[&Stack],
+        args: &[String],
+    ) -> Result<()> {
+        log::trace!(
+            "Executing command `{}` against {} stacks with arguments {:?}",
+            command,
+            stacks
+                .iter()
+                .map(|s| s.name.clone())
+                .collect::<Vec<String>>()
+                .join(","),
+            args
+        );
+
+        self.create_directories(command, false, stacks)?;
+        self.preflight(command, stacks)?;
+        let mut exec_options = ExecOptions::new(self.config, command, args);
+        exec_options.output_mode = if INTERACTIVE_COMMANDS.contains(&command) {
+            OutputMode::Inherit
+        } else {
+            mode
+        };
+        self.run_options(&exec_options, stacks, None)
+    }
+
+    /// Runs the command against the stacks in parallel where the dependency
+    /// graph allows.
+    pub fn run_scheduled(
+        &self,
+        command: &str,
+        stacks: &[&Stack],
+        args: &[String],
+        direction: Direction,
+        max_parallel: usize,
+    ) -> Result<()> {
+        log::trace!(
+            "Scheduling command `{}` against {} stacks with arguments {:?}",
+            command,
+            stacks
+                .iter()
+                .map(|s| s.name.clone())
+                .collect::<Vec<String>>()
+                .join(","),
+            args
+        );
+
+        self.create_directories(command, false, stacks)?;
+        self.preflight(command, stacks)?;
+        let exec_options = ExecOptions::new(self.config, command, args);
+        self.run_options(&exec_options, stacks, Some((direction, max_parallel)))
+    }
+
+    /// Runs a host command in the directory of each stack. With `parallel` the
+    /// stacks are scheduled in the given direction, otherwise they run in
+    /// order and stop at the first failure.
+    pub fn foreach(
+        &self,
+        stacks: &[&Stack],
+        command: &[String],
+        parallel: Option<(Direction, usize)>,
+    ) -> Result<()> {
+        log::trace!("Running `{}` in {} stacks", command.join(" "), stacks.len());
+
+        self.create_directories("foreach", true, stacks)?;
+        let exec_options = ExecOptions::host(self.config, "foreach", command);
+        self.run_options(&exec_options, stacks, parallel)
+    }
+
+    /// Runs the command against the stacks within `--from` and `--until`,
+    /// marking the rest as skipped.
+    fn run_options(
+        &self,
+        exec_options: &ExecOptions,
+        stacks: &[&Stack],
+        parallel: Option<(Direction, usize)>,
+    ) -> Result<()> {
+        if self.from.is_none() && self.until.is_none() {
+            return self.run_all(exec_options, stacks, parallel);
+        }
+
+        let (stacks, skipped) = stack_range(stacks, self.from.as_deref(), self.until.as_deref())?;
+        for stack in skipped.iter() {
+            log::info!(
+                "Skipping {} which is outside of the range to run",
+                stack.key
+            );
+        }
+        self.summary.skip(&skipped, &exec_options.command);
+        self.run_all(exec_options, &stacks, parallel)
+    }
+
+    /// Runs the command against each stack, either in order or scheduled in
+    /// parallel.
+    fn run_all(
+        &self,
+        exec_options: &ExecOptions,
+        stacks: &[&Stack],
+        parallel: Option<(Direction, usize)>,
+    ) -> Result<()> {
+        let command = exec_options.command.as_str();
+        let recorded = self.summary.len();
+        let result = self.with_output(exec_options.output_mode, stacks, |output, reporter| {
+            let run = |stack: &Stack| self.exec(exec_options, stack, output, reporter);
+            match parallel {
+                Some((direction, max_parallel)) => {
+                    schedule(stacks, direction, max_parallel, &self.cancel, run)
+                }
+                None => run_sequential(stacks, &self.cancel, run),
+            }
+        });
+        self.summary.skip_missing(recorded, stacks, command);
+        aggregate(stacks, result)
+    }
+}
+
+/// Checks whether the arguments passed through to `docker compose down` will
+/// cause volumes to be removed.
+pub fn removes_volumes(args: &[String]) -> bool {
+    let mut removes = false;
+
+    for arg in args {
+        if arg == "--" {
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            match long.split_once('=') {
+                Some(("volumes", value)) => removes = value != "false",
+                None if long == "volumes" => removes = true,
+                _ => {}
+            }
+        } else if let Some(short) = arg.strip_prefix('-') {
+            for c in short.chars() {
+                match c {
+                    'v' => removes = true,
+                    // The timeout flag takes a value so anything after it is
+                    // not a flag.
+                    't' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    removes
+}
+
+/// The arguments for `docker compose logs` that only show what the stack has
+/// logged since it was last brought up. Without a recorded time the arguments
+/// are left alone.
+pub fn since_last_up_args(
+    stack: &Stack,
+    state: &state_file::State,
+    args: &[String],
+) -> Vec<String> {
+    match state
+        .stacks
+        .get(&stack.key)
+        .and_then(|recorded| recorded.last_up)
+    {
+        Some(last_up) => merge(&["--since".to_owned(), state_file::rfc3339(last_up)], args),
+        None => {
+            log::warn!(
+                "{} has no recorded time it was brought up, showing all logs.",
+                stack.key
+            );
+            args.to_vec()
+        }
+    }
+}
+
+/// Returns the stacks that were not part of the explicit selection and so must
+/// have been added through dependency expansion.
+pub fn implicit_stacks<'a>(selection: &[String], stacks: &[&'a Stack]) -> Vec<&'a Stack> {
+    // An empty selection means all stacks were requested.
+    if selection.is_empty() {
+        return Vec::new();
+    }
+
+    stacks
+        .iter()
+        .filter(|s| !selection.contains(&s.key))
+        .copied()
+        .collect()
+}
+
+fn confirm<R: BufRead, W: Write>(input: &mut R, output: &mut W, prompt: &str) -> io::Result<bool> {
+    write!(output, "{} [y/N] ", prompt)?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Asks the user to confirm that volumes should be removed from stacks that
+/// were only included because they depend on the selected stacks. Without
+/// `interactive`, or a terminal, this fails instead.
+pub fn confirm_volume_removal(
+    stacks: &[&Stack],
+    implicit: &[&Stack],
+    interactive: bool,
+) -> Result<()> {
+    let mut message =
+        "Volumes will be removed for the following stacks (* were added as dependants):\n"
+            .to_string();
+    for stack in stacks {
+        let marker = if implicit.contains(stack) { "*" } else { " " };
+        message.push_str(&format!("  {} {}\n", marker, stack.key));
+    }
+
+    if !interactive || !io::stdin().is_terminal() {
+        return Err(Error::Invalid(format!(
+            "{}Refusing to remove volumes without confirmation, pass --yes to continue.",
+            message
+        )));
+    }
+
+    let confirmed = confirm(
+        &mut io::stdin().lock(),
+        &mut io::stderr(),
+        &format!("{}Continue?", message),
+    )
+    .map_err(|e| Error::io("Failed to read confirmation", e))?;
+
+    if confirmed {
+        Ok(())
+    } else {
+        Err(Error::Aborted)
+    }
+}
+
+/// Asks the user to confirm that the listed images should be removed.
+/// Without `interactive`, or a terminal, nothing is removed and this returns
+/// false after explaining how to remove them.
+pub fn confirm_image_removal(interactive: bool) -> Result<bool> {
+    if !interactive || !io::stdin().is_terminal() {
+        eprintln!("Nothing was removed, pass --yes to remove these images.");
+        return Ok(false);
+    }
+
+    let confirmed = confirm(
+        &mut io::stdin().lock(),
+        &mut io::stderr(),
+        "Remove these images?",
+    )
+    .map_err(|e| Error::io("Failed to read confirmation", e))?;
+
+    if confirmed {
+        Ok(true)
+    } else {
+        Err(Error::Aborted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        confirm, implicit_stacks, only_missing, removes_volumes, run_sequential, schedule,
+        since_last_up_args, stack_range, Direction, Rollback, Runner,
+    };
+    use crate::args::merge;
+    use crate::backup;
+    use crate::cancel::Cancellation;
+    use crate::config::{Config, Stack};
+    use crate::error::Error;
+    use crate::exec::{ExecOptions, OutputMode};
+    use crate::output::{Captured, Filtered, LineFilter, Passthrough, Recorder, Stream};
+    use crate::progress::{Event, Events};
+    use crate::state::{StackState, State, StateFile};
+    use crate::status::Presence;
+    use crate::summary::Outcome;
+    use crate::testing::{Concurrency, RecordingExecutor};
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use std::{fs, path::PathBuf};
+
+    /// Records when each stack started and finished, as ticks of a logical
+    /// clock so the order doesn't depend on how long anything takes.
+    #[derive(Default)]
+    struct Timings {
+        runs: Mutex<Vec<(String, usize, usize)>>,
+        clock: AtomicUsize,
+        concurrency: Concurrency,
+    }
+
+    impl Timings {
+        /// Holds the first stacks until `overlap` of them are running
+        /// together.
+        fn overlapping(overlap: usize) -> Self {
+            Self {
+                concurrency: Concurrency::overlapping(overlap),
+                ..Default::default()
+            }
+        }
+
+        fn run(&self, stack: &Stack, fail: &[&str]) -> Result<(), Error> {
+            self.concurrency.start();
+            let start = self.clock.fetch_add(1, Ordering::SeqCst);
+            let end = self.clock.fetch_add(1, Ordering::SeqCst);
+            self.concurrency.finish();
+            self.runs
+                .lock()
+                .unwrap()
+                .push((stack.key.clone(), start, end));
+
+            if fail.contains(&stack.key.as_str()) {
+                Err(Error::Invalid(format!("{} failed", stack.key)))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn runs(&self) -> HashMap<String, (usize, usize)> {
+            self.runs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(key, start, end)| (key.clone(), (*start, *end)))
+                .collect()
+        }
+
+        fn max_concurrency(&self) -> usize {
+            self.concurrency.max()
+        }
+    }
+
+    fn graph() -> Config {
+        // A diamond with an unrelated stack and an unrelated chain.
+        Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                base: {}
+                left:
+                    depends_on:
+                        - base
+                right:
+                    depends_on:
+                        - base
+                top:
+                    depends_on:
+                        - left
+                        - right
+                alone: {}
+                first: {}
+                second:
+                    depends_on:
+                        - first
+            "
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn assert_ordered(config: &Config, timings: &Timings, direction: Direction) {
+        let runs = timings.runs();
+        for (key, (start, _)) in runs.iter() {
+            let stack = config.stacks.get(key).unwrap();
+            for prerequisite in direction.prerequisites(stack) {
+                if let Some((_, end)) = runs.get(prerequisite) {
+                    assert!(
+                        end <= start,
+                        "{} started before {} finished",
+                        key,
+                        prerequisite
+                    );
+                }
+            }
+        }
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn selection(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn keys(stacks: Vec<&Stack>) -> Vec<String> {
+        stacks.iter().map(|s| s.key.clone()).collect()
+    }
+
+    #[test]
+    fn volumes() {
+        assert!(!removes_volumes(&args(&[])));
+        assert!(!removes_volumes(&args(&["--remove-orphans", "-t", "10"])));
+        assert!(removes_volumes(&args(&["-v"])));
+        assert!(removes_volumes(&args(&["--volumes"])));
+        assert!(removes_volumes(&args(&["--remove-orphans", "--volumes"])));
+        assert!(removes_volumes(&args(&["--volumes=true"])));
+        assert!(!removes_volumes(&args(&["--volumes=false"])));
+        assert!(removes_volumes(&args(&["--volumes=false", "-v"])));
+        assert!(removes_volumes(&args(&["-vt", "5"])));
+        assert!(!removes_volumes(&args(&["-tv"])));
+        assert!(!removes_volumes(&args(&["-t5v"])));
+        assert!(!removes_volumes(&args(&["--", "-v"])));
+        assert!(!removes_volumes(&args(&["--rmi", "local"])));
+    }
+
+    #[test]
+    fn since_last_up() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                api: {}
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut state = State::default();
+        state.stacks.insert(
+            "api".to_string(),
+            StackState {
+                last_up: Some(1_700_000_000),
+                ..Default::default()
+            },
+        );
+        state
+            .stacks
+            .insert("web".to_string(), StackState::default());
+
+        assert_eq!(
+            since_last_up_args(&config.stacks["api"], &state, &args(&["-f", "app"])),
+            args(&["--since", "2023-11-14T22:13:20Z", "-f", "app"])
+        );
+        assert_eq!(
+            since_last_up_args(&config.stacks["web"], &state, &args(&["app"])),
+            args(&["app"])
+        );
+        assert_eq!(
+            since_last_up_args(&config.stacks["api"], &State::default(), &[]),
+            args(&[])
+        );
+    }
+
+    #[test]
+    fn implicit() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                foo:
+                    depends_on:
+                        - bar
+                bar:
+                    depends_on:
+                        - baz
+                baz: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let stacks = config.stacks_with_dependants(["baz"]).unwrap();
+        assert_eq!(
+            keys(implicit_stacks(&selection(&["baz"]), &stacks)),
+            vec!["bar".to_string(), "foo".to_string()]
+        );
+
+        let stacks = config.stacks_with_dependants(["foo"]).unwrap();
+        assert_eq!(
+            keys(implicit_stacks(&selection(&["foo"]), &stacks)),
+            Vec::<String>::new()
+        );
+
+        let stacks = config.stacks_with_dependants(["bar", "foo"]).unwrap();
+        assert_eq!(
+            keys(implicit_stacks(&selection(&["bar", "foo"]), &stacks)),
+            Vec::<String>::new()
+        );
+
+        let list: [&str; 0] = [];
+        let stacks = config.stacks_with_dependants(list).unwrap();
+        assert_eq!(
+            keys(implicit_stacks(&selection(&[]), &stacks)),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn confirmation() {
+        let mut output = Vec::new();
+        assert!(confirm(&mut "y\n".as_bytes(), &mut output, "Continue?").unwrap());
+        assert_eq!(String::from_utf8(output).unwrap(), "Continue? [y/N] ");
+
+        assert!(confirm(&mut " Yes \n".as_bytes(), &mut Vec::new(), "Continue?").unwrap());
+        assert!(!confirm(&mut "n\n".as_bytes(), &mut Vec::new(), "Continue?").unwrap());
+        assert!(!confirm(&mut "\n".as_bytes(), &mut Vec::new(), "Continue?").unwrap());
+        assert!(!confirm(&mut "".as_bytes(), &mut Vec::new(), "Continue?").unwrap());
+    }
+
+    #[test]
+    fn command_failed() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web"]);
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            command: \"false\"
+            stacks:
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let stacks = config.stacks(["web"]).unwrap();
+        let runner = Runner::new(&config, &Passthrough);
+        match runner.run_against_stacks("ps", &stacks, &[]) {
+            Err(Error::CommandFailed { stack, status, .. }) => {
+                assert_eq!(stack, "web");
+                assert_eq!(status.code(), Some(1));
+            }
+            _ => panic!("Expected the command to fail"),
+        }
+    }
+
+    #[test]
+    fn scheduled_dependencies() {
+        let config = graph();
+        let list: [&str; 0] = [];
+        let stacks = config.stacks_with_dependencies(list).unwrap();
+
+        let timings = Timings::overlapping(3);
+        schedule(
+            &stacks,
+            Direction::Dependencies,
+            8,
+            &Cancellation::default(),
+            |s| timings.run(s, &[]),
+        )
+        .unwrap();
+        assert_eq!(timings.runs().len(), 7);
+        assert_ordered(&config, &timings, Direction::Dependencies);
+        // base, alone and first can all run at once.
+        assert!(timings.max_concurrency() >= 3);
+
+        let timings = Timings::default();
+        schedule(
+            &stacks,
+            Direction::Dependencies,
+            2,
+            &Cancellation::default(),
+            |s| timings.run(s, &[]),
+        )
+        .unwrap();
+        assert_eq!(timings.runs().len(), 7);
+        assert_ordered(&config, &timings, Direction::Dependencies);
+        assert!(timings.max_concurrency() <= 2);
+
+        let timings = Timings::default();
+        schedule(
+            &stacks,
+            Direction::Dependencies,
+            1,
+            &Cancellation::default(),
+            |s| timings.run(s, &[]),
+        )
+        .unwrap();
+        assert_eq!(timings.max_concurrency(), 1);
+        let mut order: Vec<(String, usize)> = timings
+            .runs()
+            .into_iter()
+            .map(|(key, (start, _))| (key, start))
+            .collect();
+        order.sort_by_key(|(_, start)| *start);
+        let order: Vec<String> = order.into_iter().map(|(key, _)| key).collect();
+        let expected: Vec<String> = stacks.iter().map(|s| s.key.clone()).collect();
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn scheduled_dependants() {
+        let config = graph();
+        let list: [&str; 0] = [];
+        let mut stacks = config.stacks_with_dependants(list).unwrap();
+        stacks.reverse();
+
+        let timings = Timings::overlapping(3);
+        schedule(
+            &stacks,
+            Direction::Dependants,
+            8,
+            &Cancellation::default(),
+            |s| timings.run(s, &[]),
+        )
+        .unwrap();
+        assert_eq!(timings.runs().len(), 7);
+        assert_ordered(&config, &timings, Direction::Dependants);
+        assert!(timings.max_concurrency() >= 3);
+    }
+
+    #[test]
+    fn scheduled_groups() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                base: {}
+                one:
+                    concurrency_group: cache
+                two:
+                    concurrency_group: cache
+                three:
+                    concurrency_group: cache
+                    depends_on:
+                        - base
+                other:
+                    concurrency_group: builds
+                more: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let list: [&str; 0] = [];
+        let stacks = config.stacks_with_dependencies(list).unwrap();
+
+        for max_parallel in [2, 8] {
+            let timings = Timings::default();
+            schedule(
+                &stacks,
+                Direction::Dependencies,
+                max_parallel,
+                &Cancellation::default(),
+                |s| timings.run(s, &[]),
+            )
+            .unwrap();
+
+            let runs = timings.runs();
+            assert_eq!(runs.len(), 6);
+            let grouped = ["one", "two", "three"];
+            for (index, first) in grouped.iter().enumerate() {
+                for second in grouped[index + 1..].iter() {
+                    let (first_start, first_end) = runs[*first];
+                    let (second_start, second_end) = runs[*second];
+                    assert!(
+                        first_end <= second_start || second_end <= first_start,
+                        "{} and {} overlapped",
+                        first,
+                        second
+                    );
+                }
+            }
+            assert!(timings.max_concurrency() <= max_parallel);
+        }
+
+        // Stacks outside the group still run alongside it.
+        let timings = Timings::overlapping(4);
+        schedule(
+            &stacks,
+            Direction::Dependencies,
+            8,
+            &Cancellation::default(),
+            |s| timings.run(s, &[]),
+        )
+        .unwrap();
+        assert!(timings.max_concurrency() >= 4);
+    }
+
+    #[test]
+    fn scheduled_failure() {
+        let config = graph();
+        let list: [&str; 0] = [];
+        let stacks = config.stacks_with_dependencies(list).unwrap();
+
+        let timings = Timings::default();
+        let error = schedule(
+            &stacks,
+            Direction::Dependencies,
+            8,
+            &Cancellation::default(),
+            |s| timings.run(s, &["left"]),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "left failed");
+
+        let runs = timings.runs();
+        // top depends on left so never starts.
+        assert!(!runs.contains_key("top"));
+        // Everything else still completes.
+        for key in ["base", "left", "right", "alone", "first", "second"] {
+            assert!(runs.contains_key(key), "{} did not run", key);
+        }
+        assert_ordered(&config, &timings, Direction::Dependencies);
+
+        let timings = Timings::default();
+        let error = schedule(
+            &stacks,
+            Direction::Dependencies,
+            8,
+            &Cancellation::default(),
+            |s| timings.run(s, &["base"]),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "base failed");
+        let runs = timings.runs();
+        for key in ["left", "right", "top"] {
+            assert!(!runs.contains_key(key), "{} should not have run", key);
+        }
+        assert!(runs.contains_key("second"));
+
+        // Every failure is reported.
+        let error = schedule(
+            &stacks,
+            Direction::Dependencies,
+            8,
+            &Cancellation::default(),
+            |s| timings.run(s, &["left", "alone"]),
+        )
+        .unwrap_err();
+        match error {
+            Error::StacksFailed { failures } => {
+                let mut messages: Vec<String> = failures.iter().map(|e| e.to_string()).collect();
+                messages.sort();
+                assert_eq!(messages, vec!["alone failed", "left failed"]);
+            }
+            _ => panic!("Unexpected error {}", error),
+        }
+    }
+
+    /// Creates the compose projects for the given stacks.
+    fn create_stacks(dir: &std::path::Path, stacks: &[&str]) {
+        for stack in stacks {
+            fs::create_dir_all(dir.join(stack)).unwrap();
+            fs::write(dir.join(stack).join("compose.yaml"), "").unwrap();
+        }
+    }
+
+    /// Creates a fake compose binary that writes some output and fails for
+    /// stacks with `FAIL` set in their environment.
+    fn fake_binary(dir: &std::path::Path) -> String {
+        let path = dir.join("fake-compose");
+        fs::write(
+            &path,
+            "#!/bin/sh\necho \"out $STACK\"\necho \"err $STACK\" >&2\n[ -z \"$FAIL\" ]\n",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn quiet_success() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web", "db"]);
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                stacks:
+                    web:
+                        environment:
+                            STACK: web
+                    db:
+                        environment:
+                            STACK: db
+                            FAIL: \"1\"
+                ",
+                fake_binary(temp.path())
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let recorder = Recorder::default();
+        let captured = Captured::new(&recorder);
+        let runner = Runner::new(&config, &captured);
+
+        let stacks = config.stacks(["web", "db"]).unwrap();
+        let stacks: Vec<&Stack> = stacks.into_iter().rev().collect();
+        let error = runner.run_against_stacks("ps", &stacks, &[]).unwrap_err();
+        match error {
+            Error::StacksFailed { failures } => {
+                assert_eq!(failures.len(), 1);
+                assert!(
+                    matches!(&failures[0], Error::CommandFailed { stack, .. } if stack == "db")
+                );
+            }
+            _ => panic!("Unexpected error {}", error),
+        }
+
+        assert_eq!(captured.suppressed(), 2);
+        assert_eq!(recorder.stream(Stream::Stdout), vec!["out db".to_string()]);
+        assert_eq!(
+            recorder.stream(Stream::Stderr),
+            vec![
+                "==== Output from failed stack db ====".to_string(),
+                "err db".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn logs_grep() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web", "db"]);
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                stacks:
+                    web:
+                        environment:
+                            STACK: web
+                    db:
+                        environment:
+                            STACK: db
+                ",
+                fake_binary(temp.path())
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        // The filter sees the stack's prefix so can pick out a stack's lines.
+        let recorder = Recorder::default();
+        let filtered = Filtered::new(LineFilter::new(&[r"^\[db\]"], false).unwrap(), &recorder);
+        let mut runner = Runner::new(&config, &filtered);
+        runner.prefix = true;
+
+        runner
+            .logs(&config.stacks(["web", "db"]).unwrap(), &[])
+            .unwrap();
+        assert_eq!(
+            recorder.stream(Stream::Stdout),
+            vec!["[db]  out db".to_string()]
+        );
+        let mut errors = recorder.stream(Stream::Stderr);
+        errors.sort();
+        assert_eq!(errors, vec!["[db]  err db", "[web] err web"]);
+        assert_eq!(filtered.suppressed(), 1);
+    }
+
+    #[test]
+    fn retries() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web"]);
+
+        // Fails until it has been run three times.
+        let path = temp.path().join("flaky-compose");
+        fs::write(
+            &path,
+            "#!/bin/sh
+echo run >> \"$COUNT\"
+[ $(wc -l < \"$COUNT\") -ge 3 ]
+",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let count = temp.path().join("count");
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                environment:
+                    COUNT: {}
+                stacks:
+                    web: {{}}
+                ",
+                path.display(),
+                count.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["web"]).unwrap();
+        let attempts = || fs::read_to_string(&count).unwrap().lines().count();
+
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.retry.initial_backoff = Duration::from_millis(1);
+        runner.retry.retries = Some(2);
+        runner.run_against_stacks("pull", &stacks, &[]).unwrap();
+        assert_eq!(attempts(), 3);
+
+        // Not enough retries.
+        fs::remove_file(&count).unwrap();
+        runner.retry.retries = Some(1);
+        assert!(runner.run_against_stacks("pull", &stacks, &[]).is_err());
+        assert_eq!(attempts(), 2);
+
+        // Commands that are not idempotent are not retried by default.
+        fs::remove_file(&count).unwrap();
+        runner.retry.retries = Some(2);
+        assert!(runner.run_against_stacks("up", &stacks, &[]).is_err());
+        assert_eq!(attempts(), 1);
+
+        fs::remove_file(&count).unwrap();
+        runner.retry.all = true;
+        runner.run_against_stacks("up", &stacks, &[]).unwrap();
+        assert_eq!(attempts(), 3);
+    }
+
+    #[test]
+    fn summary() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web", "db", "cache"]);
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                stacks:
+                    web:
+                        depends_on:
+                            - db
+                    db:
+                        environment:
+                            FAIL: \"1\"
+                    cache: {{}}
+                ",
+                fake_binary(temp.path())
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let recorder = Recorder::default();
+        let events = Events::default();
+        let mut runner = Runner::new(&config, &recorder);
+        runner.reporter = &events;
+        let stacks = config.stacks(["cache", "db", "web"]).unwrap();
+        runner
+            .run_scheduled("up", &stacks, &[], Direction::Dependencies, 1)
+            .unwrap_err();
+
+        let entries = runner.summary.entries();
+        let outcomes: Vec<(&str, Outcome)> = entries
+            .iter()
+            .map(|e| (e.stack.as_str(), e.outcome))
+            .collect();
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes.contains(&("cache", Outcome::Ok)));
+        assert!(outcomes.contains(&("db", Outcome::Failed(Some(1)))));
+        assert_eq!(outcomes[2], ("web", Outcome::Skipped));
+
+        // Entries are in execution order and none overlap when run one at a
+        // time.
+        let timings: Vec<(Instant, Instant)> = entries.iter().filter_map(|e| e.timing).collect();
+        for (start, end) in timings.iter() {
+            assert!(start <= end);
+        }
+        for pair in timings.windows(2) {
+            assert!(pair[0].1 <= pair[1].0);
+        }
+
+        assert_eq!(
+            *events.events.lock().unwrap(),
+            vec![
+                Event::Begin(3),
+                Event::Started("cache".to_string()),
+                Event::Finished("cache".to_string(), true),
+                Event::Started("db".to_string()),
+                Event::Finished("db".to_string(), false),
+                Event::End
+            ]
+        );
+
+        let table = runner.summary.render();
+        assert!(table.contains("failed (1)"));
+        assert!(table
+            .lines()
+            .last()
+            .unwrap()
+            .contains("1 ok, 1 failed, 1 skipped"));
+    }
+
+    #[test]
+    fn missing_compose_files() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web", "cache"]);
+        fs::create_dir_all(temp.path().join("db")).unwrap();
+
+        // The binary records every time it is run.
+        let path = temp.path().join("recording-compose");
+        let runs = temp.path().join("runs");
+        fs::write(
+            &path,
+            format!("#!/bin/sh\necho run >> {}\n", runs.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                stacks:
+                    web: {{}}
+                    db: {{}}
+                    cache: {{}}
+                ",
+                path.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["web", "db", "cache"]).unwrap();
+
+        let runner = Runner::new(&config, &Passthrough);
+        let error = runner.run_against_stacks("up", &stacks, &[]).unwrap_err();
+        match error {
+            Error::MissingComposeFiles { ref problems } => {
+                assert_eq!(problems.len(), 1);
+                assert_eq!(problems[0].0, "db");
+            }
+            _ => panic!("Unexpected error {}", error),
+        }
+        assert!(error
+            .to_string()
+            .starts_with("Some stacks cannot be run:\n  db: no compose file found in"));
+        assert!(!runs.exists());
+
+        let error = runner
+            .run_scheduled("up", &stacks, &[], Direction::Dependencies, 4)
+            .unwrap_err();
+        assert!(matches!(error, Error::MissingComposeFiles { .. }));
+        assert!(!runs.exists());
+
+        // Commands that do not use the compose file still run.
+        runner.run_against_stacks("version", &stacks, &[]).unwrap();
+        assert_eq!(fs::read_to_string(&runs).unwrap().lines().count(), 3);
+
+        // The stack's environment can name its compose file.
+        fs::write(temp.path().join("db/app.yml"), "").unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                stacks:
+                    db:
+                        environment:
+                            COMPOSE_FILE: app.yml
+                ",
+                path.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["db"]).unwrap();
+        let runner = Runner::new(&config, &Passthrough);
+        runner.run_against_stacks("up", &stacks, &[]).unwrap();
+        assert_eq!(fs::read_to_string(&runs).unwrap().lines().count(), 4);
+    }
+
+    #[test]
+    fn compose_version() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web"]);
+        let path = temp.path().join("versioned-compose");
+        let config = |minimum: &str| {
+            Config::from_reader(
+                temp.path(),
+                format!(
+                    "
+                    command: {}
+                    min_compose_version: {}
+                    stacks:
+                        web: {{}}
+                    ",
+                    path.display(),
+                    minimum
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+        };
+        let set_version = |version: &str| {
+            fs::write(&path, format!("#!/bin/sh\necho {}\n", version)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+
+        set_version("v2.0.1");
+        let old = config("2.1.0");
+        let runner = Runner::new(&old, &Passthrough);
+        assert_eq!(
+            runner.supported_flags(&["--wait", "--build"]),
+            vec!["--build"]
+        );
+        // Only a warning by default.
+        runner
+            .run_against_stacks("ps", &old.stacks(["web"]).unwrap(), &[])
+            .unwrap();
+
+        let mut runner = Runner::new(&old, &Passthrough);
+        runner.strict_version = true;
+        let error = runner
+            .run_against_stacks("ps", &old.stacks(["web"]).unwrap(), &[])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "docker compose 2.0.1 is older than the minimum version 2.1.0 set by `min_compose_version`."
+        );
+
+        set_version("2.20.2-desktop.1");
+        let new = config("2.1.0");
+        let mut runner = Runner::new(&new, &Passthrough);
+        runner.strict_version = true;
+        assert_eq!(runner.supported_flags(&["--wait"]), vec!["--wait"]);
+        runner
+            .run_against_stacks("ps", &new.stacks(["web"]).unwrap(), &[])
+            .unwrap();
+
+        // Flags are kept when the version is unknown.
+        set_version("unknown");
+        let runner = Runner::new(&new, &Passthrough);
+        assert_eq!(runner.supported_flags(&["--wait"]), vec!["--wait"]);
+    }
+
+    #[test]
+    fn clean_env() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web"]);
+        let binary = temp.path().join("env-compose");
+        fs::write(&binary, "#!/bin/sh\nenv\n").unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+
+        std::env::set_var("STACKS_TEST_POLLUTION", "leaked");
+        std::env::set_var("STACKS_TEST_ALLOWED", "allowed");
+
+        let config = |extra: &str| {
+            Config::from_reader(
+                temp.path(),
+                format!(
+                    "
+                    command: {}
+                    {}
+                    stacks:
+                        web:
+                            environment:
+                                STACK: web
+                    ",
+                    binary.display(),
+                    extra
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+        };
+        let child_env = |config: &Config| {
+            let stack = config.stacks.get("web").unwrap();
+            let output = Runner::new(config, &Passthrough)
+                .query(stack, "ps", &[])
+                .unwrap();
+            let options = ExecOptions::new(config, "ps", &[] as &[&str])
+                .with_stack(stack)
+                .unwrap();
+            (output, options.environment)
+        };
+
+        // By default the host environment is inherited.
+        let (output, environment) = child_env(&config(""));
+        assert!(output.contains("STACKS_TEST_POLLUTION=leaked"));
+        assert!(!environment.contains_key("STACKS_TEST_POLLUTION"));
+
+        let (output, environment) = child_env(&config(
+            "
+                    inherit_env: false
+                    pass_env:
+                        - STACKS_TEST_ALLOWED
+            ",
+        ));
+        assert!(!output.contains("STACKS_TEST_POLLUTION"));
+        assert!(!environment.contains_key("STACKS_TEST_POLLUTION"));
+        assert!(output.contains("STACKS_TEST_ALLOWED=allowed"));
+        assert!(output.contains("STACK=web"));
+        assert_eq!(environment.get("PATH"), std::env::var_os("PATH").as_ref());
+    }
+
+    #[test]
+    fn log_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web", "db"]);
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                stacks:
+                    web:
+                        environment:
+                            STACK: web
+                    db:
+                        environment:
+                            STACK: db
+                ",
+                fake_binary(temp.path())
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let logs = temp.path().join("logs");
+        fs::create_dir(&logs).unwrap();
+
+        let recorder = Recorder::default();
+        let mut runner = Runner::new(&config, &recorder);
+        runner.prefix = false;
+        runner.log_dir = Some(logs.clone());
+
+        let stacks = config.stacks(["web", "db"]).unwrap();
+        runner.run_against_stacks("ps", &stacks, &[]).unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(&logs)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["db.log", "web.log"]);
+
+        for stack in ["web", "db"] {
+            let mut logged: Vec<String> = fs::read_to_string(logs.join(format!("{}.log", stack)))
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+            logged.sort();
+            let mut forwarded: Vec<String> = recorder
+                .lines
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(key, _, _)| key == stack)
+                .map(|(_, _, line)| line.clone())
+                .collect();
+            forwarded.sort();
+            assert_eq!(logged, forwarded);
+            assert_eq!(
+                logged,
+                vec![format!("err {}", stack), format!("out {}", stack)]
+            );
+        }
+
+        // Interactive commands are not logged.
+        let logs = temp.path().join("interactive");
+        fs::create_dir(&logs).unwrap();
+        runner.log_dir = Some(logs.clone());
+        let stacks = config.stacks(["web"]).unwrap();
+        runner
+            .with_output(OutputMode::Inherit, &stacks, |output, _| {
+                output.line(stacks[0], Stream::Stdout, "hello");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(fs::read_dir(&logs).unwrap().count(), 0);
+
+        // Logs that cannot be created fail before anything runs.
+        runner.log_dir = Some(temp.path().join("missing"));
+        let error = runner
+            .with_output(OutputMode::Piped, &stacks, |_, _| panic!("Should not run"))
+            .unwrap_err();
+        assert!(matches!(error, Error::Io { .. }));
+    }
+
+    /// Creates a fake compose binary that logs each command to `$LOG`, fails
+    /// `up` when `FAIL` is set and reports its one service as running when
+    /// `RUNNING` is set.
+    fn logging_binary(dir: &std::path::Path) -> String {
+        let path = dir.join("logging-compose");
+        fs::write(
+            &path,
+            r#"#!/bin/sh
+for arg; do
+    case "$arg" in
+        up|down|stop|ps|config) cmd=$arg; break;;
+    esac
+done
+case "$cmd" in
+    config) echo svc;;
+    ps) [ -z "$RUNNING" ] || echo '{"Service":"svc","State":"running"}';;
+    *) echo "$cmd $STACK" >> "$LOG"; [ "$cmd" != up ] || [ -z "$FAIL" ];;
+esac
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn skip_running() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = |greeting: &str| {
+            Config::from_reader(
+                temp.path(),
+                format!("stacks:\n  web:\n    environment:\n      GREETING: {greeting}\n")
+                    .as_bytes(),
+            )
+            .unwrap()
+        };
+        let state = StateFile::for_config(&temp.path().join("stacks.yml"));
+        let up = |config: &Config| {
+            let executor = RecordingExecutor::default()
+                .respond("config", "svc\n")
+                .respond("ps", r#"{"Service":"svc","State":"running"}"#);
+            let mut runner = Runner::new(config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+            runner.skip_running = true;
+            runner.state = Some(state.clone());
+            runner
+                .run_against_stacks("up", &config.stacks(["web"]).unwrap(), &[])
+                .unwrap();
+            executor.commands()
+        };
+
+        // Without a recorded hash a running stack might have changed.
+        let hello = config("hello");
+        assert_eq!(up(&hello), vec![("web".to_string(), "up".to_string())]);
+
+        // Now it was recorded by bringing it up.
+        assert!(up(&hello).is_empty());
+
+        // A change to its environment brings it up again.
+        let goodbye = config("goodbye");
+        assert_eq!(up(&goodbye), vec![("web".to_string(), "up".to_string())]);
+        assert!(up(&goodbye).is_empty());
+    }
+
+    #[test]
+    fn rollback() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["base", "running", "middle", "top"]);
+        let log = temp.path().join("log");
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                command: {}
+                environment:
+                    LOG: {}
+                stacks:
+                    base:
+                        environment:
+                            STACK: base
+                    running:
+                        depends_on:
+                            - base
+                        environment:
+                            STACK: running
+                            RUNNING: \"1\"
+                    middle:
+                        depends_on:
+                            - running
+                        environment:
+                            STACK: middle
+                    top:
+                        depends_on:
+                            - middle
+                        environment:
+                            STACK: top
+                            FAIL: \"1\"
+                ",
+                logging_binary(temp.path()),
+                log.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks_with_dependencies(["top"]).unwrap();
+        let logged = || {
+            let lines: Vec<String> = fs::read_to_string(&log)
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+            fs::remove_file(&log).unwrap();
+            lines
+        };
+
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.skip_running = true;
+        // The running stack is unchanged since it was brought up.
+        let state = StateFile::for_config(&temp.path().join("stacks.yml"));
+        let hash = runner.stack_hash(&config.stacks["running"]).unwrap();
+        state.record_up("running", Some(hash)).unwrap();
+        runner.state = Some(state);
+        let error = runner
+            .up_with_rollback(Some(Rollback::Down), || {
+                runner.run_against_stacks("up", &stacks, &[])
+            })
+            .unwrap_err();
+        assert!(matches!(error, Error::StacksFailed { .. }));
+        assert_eq!(
+            logged(),
+            vec![
+                "up base",
+                "up middle",
+                "up top",
+                "down top",
+                "down middle",
+                "down base"
+            ]
+        );
+        assert_eq!(*runner.already_running.lock().unwrap(), vec!["running"]);
+
+        // Stopping rather than removing.
+        runner
+            .up_with_rollback(Some(Rollback::Stop), || {
+                runner.run_scheduled("up", &stacks, &[], Direction::Dependencies, 4)
+            })
+            .unwrap_err();
+        assert_eq!(
+            logged(),
+            vec![
+                "up base",
+                "up middle",
+                "up top",
+                "stop top",
+                "stop middle",
+                "stop base"
+            ]
+        );
+
+        // Nothing is rolled back unless asked.
+        runner
+            .up_with_rollback(None, || runner.run_against_stacks("up", &stacks, &[]))
+            .unwrap_err();
+        assert_eq!(logged(), vec!["up base", "up middle", "up top"]);
+
+        // Stacks that were already running are brought up again but never
+        // rolled back, even when they aren't skipped.
+        let runner = Runner::new(&config, &Passthrough);
+        runner
+            .up_with_rollback(Some(Rollback::Down), || {
+                runner.run_against_stacks("up", &stacks, &[])
+            })
+            .unwrap_err();
+        assert_eq!(
+            logged(),
+            vec![
+                "up base",
+                "up running",
+                "up middle",
+                "up top",
+                "down top",
+                "down middle",
+                "down base"
+            ]
+        );
+        assert!(runner.already_running.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancelled() {
+        let config = graph();
+        let list: [&str; 0] = [];
+        let stacks = config.stacks_with_dependencies(list).unwrap();
+        let keys: Vec<&str> = stacks.iter().map(|s| s.key.as_str()).collect();
+
+        // Cancelled after the second stack completes.
+        let cancel = Cancellation::default();
+        let timings = Timings::default();
+        let error = run_sequential(&stacks, &cancel, |s| {
+            let result = timings.run(s, &[]);
+            if s.key == keys[1] {
+                cancel.cancel();
+            }
+            result
+        })
+        .unwrap_err();
+        match error {
+            Error::Interrupted {
+                completed,
+                interrupted,
+                not_started,
+            } => {
+                assert_eq!(completed, keys[0..2]);
+                assert!(interrupted.is_empty());
+                assert_eq!(not_started, keys[2..]);
+            }
+            _ => panic!("Unexpected error {}", error),
+        }
+        assert_eq!(timings.runs().len(), 2);
+
+        // Cancelled while the second stack is running, which then fails.
+        let cancel = Cancellation::default();
+        let timings = Timings::default();
+        let error = run_sequential(&stacks, &cancel, |s| {
+            if s.key == keys[1] {
+                cancel.cancel();
+            }
+            timings.run(s, &[keys[1]])
+        })
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Interrupted.\n  Completed: {}\n  Interrupted: {}\n  Not started: {}",
+                keys[0],
+                keys[1],
+                keys[2..].join(", ")
+            )
+        );
+
+        // In parallel mode running stacks finish but nothing new starts.
+        let cancel = Cancellation::default();
+        let timings = Timings::default();
+        let error = schedule(&stacks, Direction::Dependencies, 8, &cancel, |s| {
+            let result = timings.run(s, &[]);
+            if s.key == "base" {
+                cancel.cancel();
+            }
+            result
+        })
+        .unwrap_err();
+        let runs = timings.runs();
+        match error {
+            Error::Interrupted {
+                completed,
+                interrupted,
+                not_started,
+            } => {
+                let mut ran: Vec<String> = runs.keys().cloned().collect();
+                ran.sort();
+                let mut completed = completed;
+                completed.sort();
+                assert_eq!(completed, ran);
+                assert!(interrupted.is_empty());
+                assert!(not_started.contains(&"left".to_string()));
+                assert!(not_started.contains(&"top".to_string()));
+            }
+            _ => panic!("Unexpected error {}", error),
+        }
+        assert!(!runs.contains_key("left"));
+        assert!(!runs.contains_key("right"));
+    }
+
+    #[test]
+    fn absent_stacks() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["running", "stopped", "absent"]);
+        let path = temp.path().join("ps-compose");
+        fs::write(&path, "#!/bin/sh\nprintf '%s' \"$PS\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                r#"
+                command: {}
+                stacks:
+                    running:
+                        environment:
+                            PS: '{{"Service":"web","State":"running"}}'
+                    stopped:
+                        environment:
+                            PS: '{{"Service":"web","State":"exited"}}'
+                    absent:
+                        environment:
+                            PS: ""
+                "#,
+                path.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let runner = Runner::new(&config, &Passthrough);
+        let stacks = config.stacks(["running", "stopped", "absent"]).unwrap();
+        assert_eq!(
+            runner.absent_stacks("restart", &stacks),
+            vec!["absent".to_string()]
+        );
+
+        let entries = runner.summary.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].stack, "absent");
+        assert_eq!(entries[0].command, "restart");
+        assert_eq!(entries[0].outcome, Outcome::Skipped);
+    }
+
+    #[test]
+    fn foreach() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["a", "b", "c"]);
+        let log = temp.path().join("log");
+        let config = Config::from_reader(
+            temp.path(),
+            format!(
+                "
+                environment:
+                    LOG: {}
+                stacks:
+                    a:
+                        environment:
+                            VALUE: first
+                    b:
+                        environment:
+                            VALUE: second
+                    c:
+                        environment:
+                            VALUE: third
+                ",
+                log.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["a", "b", "c"]).unwrap();
+        let logged = || {
+            let lines: Vec<String> = fs::read_to_string(&log)
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+            fs::remove_file(&log).unwrap();
+            lines
+        };
+        let expected = |stack: &str, value: &str| {
+            let dir = temp.path().join(stack);
+            format!("{} {} {} {}", dir.display(), stack, dir.display(), value)
+        };
+
+        let runner = Runner::new(&config, &Passthrough);
+        runner
+            .foreach(
+                &stacks,
+                &args(&[
+                    "sh",
+                    "-c",
+                    "echo \"$(pwd) $STACK_NAME $STACK_DIR $VALUE\" >> \"$LOG\"",
+                ]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            logged(),
+            vec![
+                expected("a", "first"),
+                expected("b", "second"),
+                expected("c", "third")
+            ]
+        );
+
+        // Without keep going the first failure stops the run.
+        let failing = args(&[
+            "sh",
+            "-c",
+            "echo \"$STACK_NAME\" >> \"$LOG\"; [ \"$STACK_NAME\" != b ]",
+        ]);
+        let runner = Runner::new(&config, &Passthrough);
+        assert!(runner.foreach(&stacks, &failing, None).is_err());
+        assert_eq!(logged(), args(&["a", "b"]));
+
+        let runner = Runner::new(&config, &Passthrough);
+        assert!(runner
+            .foreach(&stacks, &failing, Some((Direction::Independent, 1)))
+            .is_err());
+        assert_eq!(logged(), args(&["a", "b", "c"]));
+        let outcomes: Vec<Outcome> = runner.summary.entries().iter().map(|e| e.outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec![Outcome::Ok, Outcome::Failed(Some(1)), Outcome::Ok]
+        );
+    }
+
+    #[test]
+    fn scripts() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+                    depends_on:
+                        - db
+                web:
+                    depends_on:
+                        - agent
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let stacks = config.stacks_with_dependencies(["web"]).unwrap();
+        runner
+            .run_against_stacks("up", &stacks, &args(&["--wait"]))
+            .unwrap();
+        let lines: Vec<String> = executor
+            .runs()
+            .iter()
+            .map(|options| options.command_line())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "docker compose -p db --project-directory /stacks/db up --wait",
+                "sh -c ./agent start",
+                "docker compose -p web --project-directory /stacks/web up --wait",
+            ]
+        );
+        let runs = executor.runs();
+        assert_eq!(runs[1].working_dir, PathBuf::from("/stacks/agent"));
+        assert_eq!(runs[1].environment.get("STACK_NAME"), Some(&"agent".into()));
+
+        // Commands without a script are skipped for script stacks.
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+        runner.run_against_stacks("pull", &stacks, &[]).unwrap();
+        assert_eq!(
+            executor.commands(),
+            vec![
+                ("db".to_string(), "pull".to_string()),
+                ("web".to_string(), "pull".to_string())
+            ]
+        );
+        let outcomes: Vec<(String, Outcome)> = runner
+            .summary
+            .entries()
+            .iter()
+            .map(|e| (e.stack.clone(), e.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("db".to_string(), Outcome::Ok),
+                ("web".to_string(), Outcome::Ok),
+                ("agent".to_string(), Outcome::Skipped)
+            ]
+        );
+    }
+
+    #[test]
+    fn engines() {
+        let config = |command: &str, engine: &str| {
+            Config::from_reader(
+                &PathBuf::from("/stacks"),
+                format!("command: {}\n{}\nstacks:\n  web: {{}}\n", command, engine).as_bytes(),
+            )
+            .unwrap()
+        };
+        let flags = |config: &Config| {
+            let executor = RecordingExecutor::default().respond("version", "2.20.0");
+            let mut runner = Runner::new(config, &Passthrough);
+            runner.executor = &executor;
+            runner.supported_flags(&["--wait", "--build"])
+        };
+
+        assert_eq!(
+            flags(&config("docker compose", "")),
+            args(&["--wait", "--build"])
+        );
+        assert_eq!(flags(&config("podman compose", "")), args(&["--build"]));
+        assert_eq!(flags(&config("podman-compose", "")), args(&["--build"]));
+        assert_eq!(
+            flags(&config("/opt/compose", "engine: podman")),
+            args(&["--build"])
+        );
+        assert_eq!(
+            flags(&config("podman compose", "engine: docker")),
+            args(&["--wait", "--build"])
+        );
+
+        // Standalone docker-compose only supports `--wait` from version 2.
+        let legacy = config("docker-compose", "");
+        let up = |version: &str| {
+            let executor = RecordingExecutor::default().respond("version", version);
+            let mut runner = Runner::new(&legacy, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+            let args = merge(&runner.supported_flags(&["--wait"]), &args(&["-d"]));
+            runner
+                .run_against_stacks("up", &legacy.stacks(["web"]).unwrap(), &args)
+                .unwrap();
+            executor.runs()[0].command_line()
+        };
+        assert_eq!(
+            up("1.29.2"),
+            "docker-compose -p web --project-directory /stacks/web up -d"
+        );
+        assert_eq!(
+            up("2.20.0"),
+            "docker-compose -p web --project-directory /stacks/web up --wait -d"
+        );
+    }
+
+    #[test]
+    fn interactive_commands() {
+        let config =
+            Config::from_reader(&PathBuf::from("/stacks"), "stacks:\n  web: {}\n".as_bytes())
+                .unwrap();
+        let stacks = config.stacks(["web"]).unwrap();
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        // Piping attach would swallow the detach keys and signals.
+        runner
+            .run_against_stacks("attach", &stacks, &args(&["app"]))
+            .unwrap();
+        runner.run_against_stacks("ps", &stacks, &[]).unwrap();
+        assert_eq!(
+            executor
+                .runs()
+                .iter()
+                .map(|run| (run.command.clone(), run.output_mode))
+                .collect::<Vec<_>>(),
+            vec![
+                ("attach".to_string(), OutputMode::Inherit),
+                ("ps".to_string(), OutputMode::Piped),
+            ]
+        );
+        assert_eq!(
+            executor.runs()[0].command_line(),
+            "docker compose -p web --project-directory /stacks/web attach app"
+        );
+    }
+
+    #[test]
+    fn timings() {
+        let config = graph();
+        let executor = RecordingExecutor::default().delay(Duration::from_millis(50));
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let stacks = config.stacks(["base", "left"]).unwrap();
+        runner.run_against_stacks("up", &stacks, &[]).unwrap();
+
+        let report = runner.timings.report();
+        assert!(report.phases.is_empty());
+        let keys: Vec<&str> = report.stacks.iter().map(|s| s.stack.as_str()).collect();
+        assert_eq!(keys, vec!["base", "left"]);
+        for stack in report.stacks.iter() {
+            assert_eq!(stack.phases.keys().collect::<Vec<_>>(), vec!["command"]);
+            let phases: f64 = stack.phases.values().sum();
+            assert!(phases >= 0.05);
+            assert!(stack.total >= phases);
+            assert!(stack.total - phases < 0.04);
+        }
+    }
+
+    #[test]
+    fn placeholders() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db:
+                    name: database
+                web:
+                    directory: sites/web
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let stacks = config.stacks(["db", "web"]).unwrap();
+        let args: Vec<String> = ["app", "backup-{stack}.sh", "{project}", "{dir}", "{{x}}"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        runner.run_against_stacks("run", &stacks, &args).unwrap();
+
+        let lines: Vec<String> = executor.runs().iter().map(|r| r.command_line()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "docker compose -p database --project-directory /stacks/db run app \
+                backup-db.sh database /stacks/db {x}",
+                "docker compose -p web --project-directory /stacks/sites/web run app \
+                backup-web.sh web /stacks/sites/web {x}",
+            ]
+        );
+
+        // Host commands are substituted too.
+        runner
+            .foreach(
+                &stacks,
+                &["tar".to_string(), "{name}.tar".to_string()],
+                None,
+            )
+            .unwrap();
+        let lines: Vec<String> = executor.runs()[2..]
+            .iter()
+            .map(|r| r.command_line())
+            .collect();
+        assert_eq!(lines, vec!["tar database.tar", "tar web.tar"]);
+
+        let error = runner
+            .run_against_stacks("run", &stacks, &["app".to_string(), "{user}".to_string()])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The argument `{user}` for stack `db` uses the unknown placeholder `{user}`. Use \
+            `{{` and `}}` for literal braces."
+        );
+        assert_eq!(executor.runs().len(), 4);
+    }
+
+    #[test]
+    fn verify_up() {
+        let mut config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            verify_up: true
+            stacks:
+                db: {}
+                web:
+                    depends_on:
+                        - db
+                docs:
+                    verify_up: false
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        config.verify_timeout = Some(Duration::from_millis(200));
+        let up = |executor: &RecordingExecutor, keys: &[&str], args: &[&str]| {
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = executor;
+            runner.preflight = false;
+            runner.verify_interval = Duration::from_millis(10);
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            let result = runner.run_against_stacks("up", &config.stacks(keys).unwrap(), &args);
+            (result, runner.timings.report())
+        };
+
+        // Services that start late are waited for.
+        let executor = RecordingExecutor::default()
+            .respond("config", "db\ncache\n")
+            .respond_sequence(
+                "ps",
+                &[
+                    "",
+                    r#"{"Service":"db","State":"running","Health":"starting"}"#,
+                    r#"{"Service":"db","State":"running","Health":"healthy"}
+                    {"Service":"cache","State":"created"}"#,
+                    r#"{"Service":"db","State":"running","Health":"healthy"}
+                    {"Service":"cache","State":"running"}"#,
+                ],
+            );
+        let (result, report) = up(&executor, &["db"], &[]);
+        result.unwrap();
+        assert!(report.stacks[0].phases["verify"] >= 0.03);
+
+        // A service that keeps restarting is never up.
+        let executor = RecordingExecutor::default()
+            .respond("config", "db\n")
+            .respond_sequence(
+                "ps",
+                &[
+                    r#"{"Service":"db","State":"running","Health":"unhealthy"}"#,
+                    r#"{"Service":"db","State":"restarting"}"#,
+                    r#"{"Service":"db","State":"running","Health":"unhealthy"}"#,
+                ],
+            );
+        let (result, _) = up(&executor, &["db", "web"], &[]);
+        let error = result.unwrap_err();
+        assert!(matches!(error, Error::NotReady { ref services, .. } if services == &["db"]));
+        assert_eq!(
+            error.to_string(),
+            "The services db of stack `db` were not up after 0 seconds."
+        );
+        // Dependants are not started.
+        assert_eq!(
+            executor.commands(),
+            vec![("db".to_string(), "up".to_string())]
+        );
+
+        // Interrupting the wait isn't reported as the stack not being ready.
+        let executor = RecordingExecutor::default()
+            .respond("config", "db\n")
+            .respond("ps", r#"{"Service":"db","State":"restarting"}"#);
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.cancel.cancel();
+        let error = runner
+            .wait_until_up(&config.stacks["db"], Duration::from_secs(60))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Interrupted.\n  Interrupted: db");
+
+        // Verification is skipped with `--no-wait` and for stacks that turn it
+        // off.
+        let executor = RecordingExecutor::default()
+            .respond("config", "db\n")
+            .respond("ps", r#"{"Service":"db","State":"exited"}"#);
+        up(&executor, &["db"], &["--no-wait"]).0.unwrap();
+        up(&executor, &["docs"], &[]).0.unwrap();
+        assert!(up(&executor, &["db"], &[]).0.is_err());
+    }
+
+    #[test]
+    fn create_dirs() {
+        let temp = tempfile::tempdir().unwrap();
+        create_stacks(temp.path(), &["web"]);
+        let config = |create_dirs: bool| {
+            let mut config = Config::from_reader(
+                temp.path(),
+                "
+                command: \"true\"
+                stacks:
+                    web: {}
+                    new:
+                        directory: services/new
+                "
+                .as_bytes(),
+            )
+            .unwrap();
+            config.create_dirs = create_dirs;
+            config
+        };
+        let new = temp.path().join("services").join("new");
+        let ls = || vec!["ls".to_string()];
+
+        // Nothing is created without the option.
+        let config_without = config(false);
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config_without, &Passthrough);
+        runner.executor = &executor;
+        let stacks = config_without.stacks(["web", "new"]).unwrap();
+        runner.foreach(&stacks, &ls(), None).unwrap();
+        runner.run_against_stacks("version", &stacks, &[]).unwrap();
+        assert!(!new.exists());
+
+        // Commands that need a compose file explain what is missing.
+        let error = runner.run_against_stacks("up", &stacks, &[]).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains(&format!("new: directory {} does not exist", new.display())));
+        assert!(message.contains("`create_dirs: true`"));
+
+        // With the option directories are created for commands that don't
+        // need a compose file.
+        let config_with = config(true);
+        let mut runner = Runner::new(&config_with, &Passthrough);
+        runner.executor = &executor;
+        let stacks = config_with.stacks(["web", "new"]).unwrap();
+        runner.run_against_stacks("up", &stacks, &[]).unwrap_err();
+        assert!(!new.exists());
+        runner.foreach(&stacks, &ls(), None).unwrap();
+        assert!(new.is_dir());
+
+        fs::remove_dir_all(temp.path().join("services")).unwrap();
+        runner.preflight = false;
+        runner.run_against_stacks("version", &stacks, &[]).unwrap();
+        assert!(new.is_dir());
+    }
+
+    #[test]
+    fn ps_listing() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                website: {}
+                agent:
+                    type: script
+                    up: ./start
+                    down: ./stop
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let executor = RecordingExecutor::default().respond_sequence(
+            "ps",
+            &[
+                "NAME  STATUS\ndb-1  Up\n",
+                "NAME       STATUS\nwebsite-1  Up\n",
+            ],
+        );
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let stacks = config.stacks(["db", "website", "agent"]).unwrap();
+        assert_eq!(
+            runner.ps(&stacks, &["-a".to_string()]).unwrap(),
+            "[agent]   agent is not a compose project\n\
+            [db]      NAME  STATUS\n\
+            [db]      db-1  Up\n\
+            [website] NAME       STATUS\n\
+            [website] website-1  Up\n"
+        );
+        assert!(executor
+            .runs()
+            .iter()
+            .all(|run| run.args == vec![OsString::from("-a")]));
+    }
+
+    #[test]
+    fn published_ports() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                web: {}
+                agent:
+                    type: script
+                    up: ./start
+                    down: ./stop
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let executor = RecordingExecutor::default().respond_sequence(
+            "ps",
+            &[
+                r#"{"Service":"postgres","Publishers":[{"URL":"127.0.0.1","TargetPort":5432,"PublishedPort":5432,"Protocol":"tcp"}]}"#,
+                r#"{"Service":"app","Publishers":[{"URL":"0.0.0.0","TargetPort":80,"PublishedPort":8080,"Protocol":"tcp"}]}"#,
+            ],
+        );
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let stacks = config.stacks(["db", "web", "agent"]).unwrap();
+        assert_eq!(
+            runner.ports(&stacks, None, None).unwrap(),
+            "Stack  Service   Port      Host\n\
+            db     postgres  5432/tcp  127.0.0.1:5432\n\
+            web    app       80/tcp    0.0.0.0:8080\n"
+        );
+        assert_eq!(
+            runner.ports(&stacks, Some("app"), Some(5432)).unwrap(),
+            "No published ports.\n"
+        );
+    }
+
+    #[test]
+    fn deduplicated_pulls() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                api: {}
+                replica:
+                    environment:
+                        DOCKER_HOST: ssh://replica
+                edge:
+                    environment:
+                        DOCKER_DEFAULT_PLATFORM: linux/arm64
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let executor = RecordingExecutor::default().respond_sequence(
+            "config",
+            &[
+                "app:1\npostgres:16\n",
+                "postgres:16\n",
+                "postgres:16\napp:1\n",
+                "postgres:16\n",
+            ],
+        );
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let stacks = config.stacks(["db", "api", "replica", "edge"]).unwrap();
+        let keys: Vec<&str> = stacks.iter().map(|s| s.key.as_str()).collect();
+        assert_eq!(keys, vec!["api", "db", "edge", "replica"]);
+
+        runner
+            .pull_deduplicated(&stacks, &["--quiet".to_string()])
+            .unwrap();
+        let pulls: Vec<(String, String)> = executor
+            .runs()
+            .iter()
+            .map(|run| {
+                (
+                    run.environment["STACK_NAME"].to_string_lossy().into_owned(),
+                    run.command_line(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            pulls,
+            vec![
+                ("api".to_string(), "docker pull --quiet app:1".to_string()),
+                (
+                    "api".to_string(),
+                    "docker pull --quiet postgres:16".to_string()
+                ),
+                (
+                    "edge".to_string(),
+                    "docker pull --platform linux/arm64 --quiet postgres:16".to_string()
+                ),
+                (
+                    "edge".to_string(),
+                    "docker pull --platform linux/arm64 --quiet app:1".to_string()
+                ),
+                (
+                    "replica".to_string(),
+                    "docker pull --quiet postgres:16".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            executor.runs()[4].environment["DOCKER_HOST"],
+            OsString::from("ssh://replica")
+        );
+
+        // A failed pull is reported for the stack it ran as.
+        let executor = RecordingExecutor::default()
+            .respond("config", "postgres:16\n")
+            .fail("api", "pull");
+        runner.executor = &executor;
+        let error = runner.pull_deduplicated(&stacks, &[]).unwrap_err();
+        assert!(matches!(error, Error::StacksFailed { ref failures } if failures.len() == 1));
+    }
+
+    #[test]
+    fn missing_stacks() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                base: {}
+                db:
+                    depends_on: [base]
+                cache: {}
+                web:
+                    depends_on: [db, cache]
+                docs: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks_with_dependencies(["web", "docs"]).unwrap();
+        let keys =
+            |stacks: &[&Stack]| -> Vec<String> { stacks.iter().map(|s| s.key.clone()).collect() };
+        assert_eq!(keys(&stacks), vec!["base", "cache", "docs", "db", "web"]);
+
+        let presence: HashMap<String, Presence> = [
+            ("base", Presence::Running),
+            ("cache", Presence::Stopped),
+            ("db", Presence::Missing),
+            ("web", Presence::Missing),
+        ]
+        .into_iter()
+        .map(|(key, presence)| (key.to_string(), presence))
+        .collect();
+
+        // Stacks that couldn't be checked are kept.
+        let (missing, stopped) = only_missing(&stacks, &presence);
+        assert_eq!(keys(&missing), vec!["docs", "db", "web"]);
+        assert_eq!(stopped, vec![("web", "cache")]);
+
+        let (missing, stopped) = only_missing(&stacks, &HashMap::new());
+        assert_eq!(keys(&missing), keys(&stacks));
+        assert!(stopped.is_empty());
+
+        // The same from canned ps output.
+        let executor = RecordingExecutor::default().respond_sequence(
+            "ps",
+            &[
+                r#"{"Service":"base","State":"running"}"#,
+                r#"[{"Service":"redis","State":"exited"}]"#,
+                "",
+                "[]",
+                "",
+            ],
+        );
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let missing = runner.missing_stacks("up", &stacks);
+        assert_eq!(keys(&missing), vec!["docs", "db", "web"]);
+        let skipped: Vec<String> = runner
+            .summary
+            .entries()
+            .iter()
+            .map(|entry| entry.stack.clone())
+            .collect();
+        assert_eq!(skipped, vec!["base", "cache"]);
+
+        runner.run_against_stacks("up", &missing, &[]).unwrap();
+        assert_eq!(
+            executor.commands(),
+            vec![
+                ("docs".to_string(), "up".to_string()),
+                ("db".to_string(), "up".to_string()),
+                ("web".to_string(), "up".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                cache: {}
+                api:
+                    depends_on: [db, cache]
+                web:
+                    depends_on: [api]
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks_with_dependencies(["web"]).unwrap();
+        let keys =
+            |stacks: &[&Stack]| -> Vec<String> { stacks.iter().map(|s| s.key.clone()).collect() };
+        assert_eq!(keys(&stacks), vec!["cache", "db", "api", "web"]);
+
+        let (kept, skipped) = stack_range(&stacks, Some("db"), None).unwrap();
+        assert_eq!(keys(&kept), vec!["db", "api", "web"]);
+        assert_eq!(keys(&skipped), vec!["cache"]);
+
+        let (kept, skipped) = stack_range(&stacks, None, Some("db")).unwrap();
+        assert_eq!(keys(&kept), vec!["cache", "db"]);
+        assert_eq!(keys(&skipped), vec!["api", "web"]);
+
+        let (kept, skipped) = stack_range(&stacks, Some("db"), Some("api")).unwrap();
+        assert_eq!(keys(&kept), vec!["db", "api"]);
+        assert_eq!(keys(&skipped), vec!["cache", "web"]);
+
+        let (kept, skipped) = stack_range(&stacks, Some("api"), Some("api")).unwrap();
+        assert_eq!(keys(&kept), vec!["api"]);
+        assert_eq!(keys(&skipped), vec!["cache", "db", "web"]);
+
+        let (kept, skipped) = stack_range(&stacks, None, None).unwrap();
+        assert_eq!(keys(&kept), keys(&stacks));
+        assert!(skipped.is_empty());
+
+        // The named stacks must be among those that would run, in order.
+        assert!(matches!(
+            stack_range(&stacks, Some("other"), None),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            stack_range(&stacks[..2], None, Some("web")),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            stack_range(&stacks, Some("api"), Some("db")),
+            Err(Error::Invalid(_))
+        ));
+
+        // Stacks outside of the range are skipped in the summary.
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+        runner.from = Some("db".to_string());
+        runner.until = Some("api".to_string());
+
+        runner.run_against_stacks("up", &stacks, &[]).unwrap();
+        assert_eq!(
+            executor.commands(),
+            vec![
+                ("db".to_string(), "up".to_string()),
+                ("api".to_string(), "up".to_string()),
+            ]
+        );
+        assert_eq!(
+            runner
+                .summary
+                .entries()
+                .iter()
+                .map(|entry| (entry.stack.as_str(), entry.outcome))
+                .collect::<Vec<_>>(),
+            vec![
+                ("db", Outcome::Ok),
+                ("api", Outcome::Ok),
+                ("cache", Outcome::Skipped),
+                ("web", Outcome::Skipped),
+            ]
+        );
+
+        // Nothing runs when a stack isn't in the order.
+        runner.from = Some("other".to_string());
+        assert!(runner.run_against_stacks("up", &stacks, &[]).is_err());
+        assert_eq!(executor.commands().len(), 2);
+    }
+
+    #[test]
+    fn rendered_config() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                api: {}
+                db: {}
+                web: {}
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["api", "db", "web", "agent"]).unwrap();
+
+        let executor = RecordingExecutor::default()
+            .respond_sequence(
+                "config",
+                &[
+                    "name: api\nservices:\n  app: {}\n",
+                    "name: web\nservices:\n  nginx: {}",
+                ],
+            )
+            .fail("db", "config");
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        // The failing stack is reported after the others are written.
+        let mut out = Vec::new();
+        let error = runner
+            .rendered_config(&stacks, false, &mut out)
+            .unwrap_err();
+        assert!(matches!(error, Error::StacksFailed { ref failures } if failures.len() == 1));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "--- # stack: api\nname: api\nservices:\n  app: {}\n\
+            --- # stack: web\nname: web\nservices:\n  nginx: {}\n"
+        );
+
+        let executor = RecordingExecutor::default().respond("config", "app\nworker\n");
+        runner.executor = &executor;
+        let stacks = config.stacks(["api", "agent"]).unwrap();
+        let mut out = Vec::new();
+        runner.rendered_config(&stacks, true, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "--- # stack: api\napp\nworker\n"
+        );
+    }
+
+    #[test]
+    fn list() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                api:
+                    description: The API
+                db: {}
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+                    status: ./agent running
+                cron:
+                    type: script
+                    up: ./cron start
+                    down: ./cron stop
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["api", "db", "agent", "cron"]).unwrap();
+
+        let executor = RecordingExecutor::default()
+            .respond(
+                "ps",
+                r#"{"Service":"app","State":"running","Health":"healthy"}"#,
+            )
+            .fail("db", "ps")
+            .fail("agent", "ps");
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        // Nothing is queried unless the state is asked for.
+        assert_eq!(
+            runner.list(&stacks, false).unwrap(),
+            "Stack  Description\n\
+            agent\n\
+            api    The API\n\
+            cron\n\
+            db\n"
+        );
+        assert!(executor.queries().is_empty());
+
+        assert_eq!(
+            runner.list(&stacks, true).unwrap(),
+            "Stack  State    Description\n\
+            agent  down\n\
+            api    up       The API\n\
+            cron   unknown\n\
+            db     unknown\n"
+        );
+        assert_eq!(executor.queries().len(), 3);
+        assert!(executor.commands().is_empty());
+
+        assert_eq!(
+            runner.list_tree(&stacks, true).unwrap(),
+            "agent [down]\n\
+            api [up]\n\
+            cron [unknown]\n\
+            db [unknown]\n"
+        );
+        assert_eq!(
+            runner.list_tree(&stacks, false).unwrap(),
+            "agent\napi\ncron\ndb\n"
+        );
+        assert_eq!(executor.queries().len(), 6);
+    }
+
+    #[test]
+    fn logs_since_last_up() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            stacks:
+                api: {}
+                db: {}
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["api", "db", "web"]).unwrap();
+
+        // Each stack was brought up at a different time, web never was.
+        let state = StateFile::for_config(&temp.path().join("stacks.yml"));
+        state
+            .update(|state| {
+                for (key, last_up) in [("api", 1700000000), ("db", 1700003600)] {
+                    state.stacks.insert(
+                        key.to_owned(),
+                        StackState {
+                            last_up: Some(last_up),
+                            ..Default::default()
+                        },
+                    );
+                }
+            })
+            .unwrap();
+
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+        runner.state = Some(state);
+        runner
+            .logs_since_last_up(&stacks, &args(&["--tail", "5"]))
+            .unwrap();
+
+        // The stacks' logs are shown at once so may start in any order.
+        let logged: HashMap<String, Vec<String>> = executor
+            .commands()
+            .into_iter()
+            .zip(executor.runs())
+            .map(|((stack, command), options)| {
+                assert_eq!(command, "logs");
+                let args = options
+                    .args
+                    .iter()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect();
+                (stack, args)
+            })
+            .collect();
+        assert_eq!(logged.len(), 3);
+        assert_eq!(
+            logged["api"],
+            args(&["--since", "2023-11-14T22:13:20Z", "--tail", "5"])
+        );
+        assert_eq!(
+            logged["db"],
+            args(&["--since", "2023-11-14T23:13:20Z", "--tail", "5"])
+        );
+        assert_eq!(logged["web"], args(&["--tail", "5"]));
+
+        for given in [&["--since", "1h"][..], &["--since=1h"][..]] {
+            assert!(matches!(
+                runner.logs_since_last_up(&stacks, &args(given)),
+                Err(Error::Invalid(_))
+            ));
+        }
+        assert_eq!(executor.runs().len(), 3);
+    }
+
+    #[test]
+    fn diff() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            stacks:
+                api: {}
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["api", "web"]).unwrap();
+
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.preflight = false;
+        runner.state = Some(StateFile::for_config(&temp.path().join("stacks.yml")));
+
+        let recorded = "services:\n  app:\n    environment:\n      KEY: one\n    image: app:1\n";
+        let executor = RecordingExecutor::default().respond("config", recorded);
+        runner.executor = &executor;
+        runner
+            .run_against_stacks("up", &config.stacks(["api"]).unwrap(), &[])
+            .unwrap();
+
+        assert_eq!(
+            runner.diff(&stacks, true, false).unwrap(),
+            "api  current\nweb  -\n"
+        );
+
+        let executor = RecordingExecutor::default().respond(
+            "config",
+            "services:\n  app:\n    environment:\n      KEY: two\n    image: app:2\n",
+        );
+        runner.executor = &executor;
+        assert_eq!(
+            runner.diff(&stacks, false, false).unwrap(),
+            "api  changed\nweb  -\n"
+        );
+        assert_eq!(
+            runner.diff(&stacks, true, false).unwrap(),
+            "api  changed\nweb  -\n\
+            \n\
+            api:\n\
+            --- recorded\n\
+            +++ current\n\
+            @@ -1,5 +1,5 @@\n \
+            services:\n   \
+            app:\n     \
+            environment:\n\
+            -      KEY: <changed>\n\
+            -    image: app:1\n\
+            +      KEY: <changed>\n\
+            +    image: app:2\n"
+        );
+        assert!(runner
+            .diff(&stacks, true, true)
+            .unwrap()
+            .contains("-      KEY: one\n-    image: app:1\n+      KEY: two\n"));
+
+        runner.state = None;
+        assert!(runner.diff(&stacks, false, false).is_err());
+    }
+
+    #[test]
+    fn verify() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                web:
+                    environment:
+                        GREETING: hello
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["web", "agent"]).unwrap();
+        let rendered = "services:\n  app:\n    environment:\n      GREETING: hello\n";
+        let inspect = |greeting: &str| {
+            format!(
+                r#"[{{"Name":"/web-app-1","Config":{{"Env":["GREETING={}"],"Labels":{{
+                    "com.docker.compose.service":"app",
+                    "com.docker.compose.config-hash":"1111",
+                    "com.docker.compose.project.working_dir":"/stacks/web",
+                    "com.docker.compose.project.config_files":"/stacks/web/compose.yaml"
+                }}}}}}]"#,
+                greeting
+            )
+        };
+        let verify = |executor: &RecordingExecutor| {
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = executor;
+            runner.preflight = false;
+            runner
+                .verify(&stacks)
+                .unwrap()
+                .into_iter()
+                .map(|(stack, verdict)| (stack, verdict.label()))
+                .collect::<Vec<_>>()
+        };
+
+        let executor = RecordingExecutor::default()
+            .respond("ps", "0123abcd\n")
+            .respond_sequence("config", &["app 1111\n", rendered])
+            .respond("inspect", &inspect("hello"));
+        // Script stacks have no containers to compare.
+        assert_eq!(verify(&executor), vec![("web".to_string(), "in sync")]);
+        let inspected: Vec<String> = executor
+            .queries()
+            .iter()
+            .filter(|options| options.command == "inspect")
+            .map(|options| options.command_line())
+            .collect();
+        assert_eq!(inspected, vec!["docker container inspect 0123abcd"]);
+
+        let executor = RecordingExecutor::default()
+            .respond("ps", "0123abcd\n")
+            .respond_sequence("config", &["app 1111\n", rendered])
+            .respond("inspect", &inspect("goodbye"));
+        assert_eq!(verify(&executor)[0], ("web".to_string(), "drifted"));
+
+        let executor = RecordingExecutor::default().respond("ps", "");
+        assert_eq!(verify(&executor)[0], ("web".to_string(), "not running"));
+
+        let executor = RecordingExecutor::default().fail("web", "ps");
+        assert_eq!(verify(&executor)[0], ("web".to_string(), "unknown"));
+    }
+
+    #[test]
+    fn verify_frozen() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp.path().join("web")).unwrap();
+        let pinned = temp.path().join("web/compose.pinned.yml");
+        fs::write(&pinned, "services:\n  app:\n    image: app@sha256:0123\n").unwrap();
+        let mut config =
+            Config::from_reader(temp.path(), "stacks:\n  web: {}\n".as_bytes()).unwrap();
+        config.frozen = true;
+        let stacks = config.stacks(["web"]).unwrap();
+
+        let compose = temp.path().join("web/compose.yaml");
+        let inspect = format!(
+            r#"[{{"Name":"/web-app-1","Config":{{"Env":[],"Labels":{{
+                "com.docker.compose.service":"app",
+                "com.docker.compose.config-hash":"1111",
+                "com.docker.compose.project.working_dir":"{}",
+                "com.docker.compose.project.config_files":"{},{}"
+            }}}}}}]"#,
+            temp.path().join("web").display(),
+            compose.display(),
+            pinned.display()
+        );
+        let executor = RecordingExecutor::default()
+            .respond("ps", "0123abcd\n")
+            .respond_sequence("config", &["app 1111\n", "services:\n  app: {}\n"])
+            .respond("inspect", &inspect);
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let verdicts = runner.verify(&stacks).unwrap();
+        assert_eq!(verdicts[0].1.label(), "in sync");
+
+        // The expected config is rendered with the pinned images as `up` does.
+        let pinned_arg = pinned.into_os_string();
+        let configs: Vec<ExecOptions> = executor
+            .queries()
+            .into_iter()
+            .filter(|options| options.command == "config")
+            .collect();
+        assert_eq!(configs.len(), 2);
+        for options in configs {
+            assert!(
+                options.args().contains(&pinned_arg.as_os_str()),
+                "{}",
+                options.command_line()
+            );
+        }
+    }
+
+    #[test]
+    fn freeze() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp.path().join("web")).unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            stacks:
+                web: {}
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["web", "agent"]).unwrap();
+        let pinned = temp.path().join("web/compose.pinned.yml");
+
+        let rendered =
+            "services:\n  app:\n    image: app:1\n  db:\n    image: postgres@sha256:0123\n";
+        let inspect = |digest: &str| {
+            format!(
+                r#"[{{"RepoTags":["app:1"],"RepoDigests":["app@sha256:{}"]}}]"#,
+                digest
+            )
+        };
+
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.preflight = false;
+
+        // Nothing has been pinned yet.
+        let executor = RecordingExecutor::default()
+            .respond("config", rendered)
+            .respond("inspect", &inspect("1111"));
+        runner.executor = &executor;
+        assert!(matches!(
+            runner.freeze(&stacks, true),
+            Err(Error::StaleImages { ref problems }) if problems.len() == 1
+        ));
+        assert!(!pinned.exists());
+
+        runner.freeze(&stacks, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&pinned).unwrap(),
+            "# Written by `stack freeze`. Used by `stack up` with `--frozen`.\n\
+            services:\n  \
+              app:\n    \
+                image: app@sha256:1111\n  \
+              db:\n    \
+                image: postgres@sha256:0123\n"
+        );
+        runner.freeze(&stacks, true).unwrap();
+
+        // Only images not already pinned are inspected.
+        let queries = executor.queries();
+        let inspected: Vec<String> = queries
+            .iter()
+            .filter(|options| options.command == "inspect")
+            .map(|options| options.command_line())
+            .collect();
+        assert_eq!(inspected[0], "docker image inspect app:1");
+
+        let executor = RecordingExecutor::default()
+            .respond("config", rendered)
+            .respond("inspect", &inspect("2222"));
+        runner.executor = &executor;
+        match runner.freeze(&stacks, true) {
+            Err(Error::StaleImages { problems }) => assert_eq!(
+                problems,
+                vec![(
+                    "web".to_string(),
+                    "app is pinned to app@sha256:1111 but is now app@sha256:2222".to_string()
+                )]
+            ),
+            result => panic!("Unexpected {:?}", result),
+        }
+        assert!(fs::read_to_string(&pinned).unwrap().contains("1111"));
+    }
+
+    #[test]
+    fn backup() {
+        let temp = tempfile::tempdir().unwrap();
+        let dest = temp.path().canonicalize().unwrap();
+        let config = Config::from_reader(
+            &dest,
+            "
+            stacks:
+                shop:
+                    name: store
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stack = config.stacks.get("shop").unwrap();
+
+        let executor = RecordingExecutor::default()
+            .respond("config", "data\ncache\n")
+            .respond("volume", r#"[{"Name":"store_data"}]"#)
+            .respond("ps", r#"{"Service":"app","State":"running"}"#);
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let dir = runner.backup(stack, &dest, true).unwrap();
+        assert_eq!(dir.parent().unwrap(), dest);
+        assert!(dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("shop-"));
+
+        // The stack is stopped around the backup.
+        assert_eq!(
+            executor.commands(),
+            vec![
+                ("shop".to_string(), "stop".to_string()),
+                ("shop".to_string(), "start".to_string())
+            ]
+        );
+
+        let host_commands: Vec<String> = executor
+            .queries()
+            .iter()
+            .filter(|options| options.host)
+            .map(|options| options.command_line())
+            .collect();
+        assert_eq!(
+            host_commands,
+            vec![
+                "docker volume inspect store_data store_cache".to_string(),
+                format!(
+                    "docker run --rm -v store_data:/data:ro -v {}:/backup alpine tar czf \
+                    /backup/data.tar.gz -C /data .",
+                    dir.display()
+                ),
+            ]
+        );
+
+        let manifest =
+            backup::Manifest::parse(&fs::read_to_string(dir.join("manifest.json")).unwrap())
+                .unwrap();
+        assert_eq!(manifest.stack, "shop");
+        assert_eq!(manifest.project, "store");
+        assert!(manifest.hash.is_some());
+        assert_eq!(
+            manifest.volumes,
+            vec![backup::VolumeBackup {
+                volume: "data".to_string(),
+                name: "store_data".to_string(),
+                archive: "data.tar.gz".to_string(),
+                size: None,
+            }]
+        );
+
+        // Without `--stop` the stack is left running.
+        let executor = RecordingExecutor::default()
+            .respond("config", "data\n")
+            .respond("volume", r#"[{"Name":"store_data"}]"#)
+            .respond("ps", r#"{"Service":"app","State":"running"}"#);
+        runner.executor = &executor;
+        runner.backup(stack, &dest, false).unwrap();
+        assert!(executor.commands().is_empty());
+
+        // There must be something to back up.
+        let executor = RecordingExecutor::default().respond("config", "data\n");
+        runner.executor = &executor;
+        assert!(matches!(
+            runner.backup(stack, &dest, false),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn restore() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        let config = Config::from_reader(
+            &root,
+            "
+            stacks:
+                shop:
+                    name: store
+                other: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let shop = config.stacks.get("shop").unwrap();
+
+        let dir = root.join("shop-20240102-030405");
+        fs::create_dir_all(&dir).unwrap();
+        let volume = |volume: &str| backup::VolumeBackup {
+            volume: volume.to_string(),
+            name: format!("store_{}", volume),
+            archive: format!("{}.tar.gz", volume),
+            size: Some(10),
+        };
+        let manifest = backup::Manifest {
+            stack: "shop".to_string(),
+            project: "store".to_string(),
+            created: 1704164645,
+            hash: None,
+            version: "1.0.0".to_string(),
+            volumes: vec![volume("data"), volume("cache")],
+        };
+        fs::write(dir.join("manifest.json"), manifest.render()).unwrap();
+        fs::write(dir.join("data.tar.gz"), "").unwrap();
+        fs::write(dir.join("cache.tar.gz"), "").unwrap();
+
+        let executor = RecordingExecutor::default()
+            .respond("volume", r#"[{"Name":"store_data"}]"#)
+            .respond("ps", r#"{"Service":"app","State":"running"}"#);
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+        let host_commands = |executor: &RecordingExecutor| -> Vec<String> {
+            executor
+                .queries()
+                .iter()
+                .filter(|options| options.host)
+                .map(|options| options.command_line())
+                .collect()
+        };
+
+        // Running stacks are only stopped when asked to.
+        assert!(matches!(
+            runner.restore(shop, &dir, &[], false, false),
+            Err(Error::Invalid(_))
+        ));
+        assert!(host_commands(&executor).is_empty());
+
+        runner.restore(shop, &dir, &[], true, false).unwrap();
+        assert_eq!(
+            executor.commands(),
+            vec![
+                ("shop".to_string(), "stop".to_string()),
+                ("shop".to_string(), "start".to_string())
+            ]
+        );
+        let extract = |volume: &str, archive: &str| {
+            format!(
+                "docker run --rm -v {}:/data -v {}:/backup:ro alpine sh -c find /data \
+                -mindepth 1 -delete && tar xzf \"/backup/$1\" -C /data sh {}",
+                volume,
+                dir.display(),
+                archive
+            )
+        };
+        assert_eq!(
+            host_commands(&executor),
+            vec![
+                "docker volume inspect store_data store_cache".to_string(),
+                extract("store_data", "data.tar.gz"),
+                "docker volume create --label com.docker.compose.project=store --label \
+                com.docker.compose.volume=cache store_cache"
+                    .to_string(),
+                extract("store_cache", "cache.tar.gz"),
+            ]
+        );
+
+        // Restoring to a different stack must be allowed.
+        let other = config.stacks.get("other").unwrap();
+        let executor = RecordingExecutor::default().respond("volume", "[]");
+        runner.executor = &executor;
+        assert!(matches!(
+            runner.restore(other, &dir, &[], false, false),
+            Err(Error::Invalid(_))
+        ));
+        runner
+            .restore(other, &dir, &["data".to_string()], false, true)
+            .unwrap();
+        assert_eq!(
+            host_commands(&executor),
+            vec![
+                "docker volume inspect other_data".to_string(),
+                "docker volume create --label com.docker.compose.project=other --label \
+                com.docker.compose.volume=data other_data"
+                    .to_string(),
+                extract("other_data", "data.tar.gz"),
+            ]
+        );
+
+        assert!(runner
+            .restore(shop, &dir, &["logs".to_string()], false, false)
+            .is_err());
+        fs::remove_file(dir.join("cache.tar.gz")).unwrap();
+        assert!(runner.restore(shop, &dir, &[], false, false).is_err());
+    }
+
+    #[test]
+    fn clean() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            stacks:
+                shop:
+                    name: store
+                web: {}
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["shop", "agent"]).unwrap();
+
+        let images = r#"{"ID":"1111aaaa2222","Repository":"store-app","Tag":"latest","Size":"120MB"}
+{"ID":"3333bbbb4444","Repository":"<none>","Tag":"<none>","Size":"118MB"}
+{"ID":"5555cccc6666","Repository":"<none>","Tag":"<none>","Size":"117MB"}
+{"ID":"7777dddd8888","Repository":"store-app","Tag":"old","Size":"80MB"}"#;
+        let containers = r#"{"Names":"store-job-1","Image":"5555cccc6666","State":"exited"}"#;
+
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.preflight = false;
+        let executor = RecordingExecutor::default()
+            .respond("config", "store-app:latest\nnginx\n")
+            .respond("containers", containers)
+            .respond("images", images);
+        runner.executor = &executor;
+
+        let picked = |candidates: &[crate::clean::Candidate]| -> Vec<String> {
+            candidates
+                .iter()
+                .map(|candidate| format!("{} {}", candidate.stack, candidate.image.reference()))
+                .collect()
+        };
+
+        let candidates = runner.clean_candidates(&stacks, false).unwrap();
+        assert_eq!(picked(&candidates), vec!["shop 3333bbbb4444"]);
+        let candidates = runner.clean_candidates(&stacks, true).unwrap();
+        assert_eq!(
+            picked(&candidates),
+            vec!["shop 3333bbbb4444", "shop store-app:old"]
+        );
+        runner.clean(&stacks, &candidates).unwrap();
+
+        // Every compose stack's images are checked, only the selected stack's
+        // images are listed.
+        let queries = executor.queries();
+        let lines = |command: &str| -> Vec<String> {
+            queries
+                .iter()
+                .filter(|options| options.command == command)
+                .map(|options| options.command_line())
+                .collect()
+        };
+        assert_eq!(lines("config").len(), 4);
+        assert_eq!(
+            lines("images")[0],
+            "docker image ls --filter label=com.docker.compose.project=store --format json"
+        );
+        assert_eq!(
+            lines("clean"),
+            vec!["docker image rm 3333bbbb4444 store-app:old"]
+        );
+        // Only the compose stacks have build caches.
+        assert_eq!(
+            lines("prune"),
+            vec!["docker builder prune --force --filter label=com.docker.compose.project=store"]
+        );
+
+        // Nothing is removed when a stack's images can't be found.
+        let executor = RecordingExecutor::default()
+            .respond("images", images)
+            .fail("web", "config");
+        runner.executor = &executor;
+        assert!(runner.clean_candidates(&stacks, true).is_err());
+        assert!(executor
+            .queries()
+            .iter()
+            .all(|options| options.command != "images"));
+    }
+
+    #[test]
+    fn stack_environment() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            environment:
+                GREETING: hello
+                NAME: everyone
+            stacks:
+                shop:
+                    name: store
+                    environment:
+                        NAME: customers
+                        MESSAGE: ${GREETING} ${NAME}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["shop"]).unwrap();
+        let runner = Runner::new(&config, &Passthrough);
+
+        let environment = runner.stack_environment(stacks[0]).unwrap();
+        assert_eq!(environment["GREETING"], "hello");
+        assert_eq!(environment["NAME"], "customers");
+        assert_eq!(environment["MESSAGE"], "hello customers");
+        assert_eq!(environment["COMPOSE_PROJECT_NAME"], "store");
+
+        // Variables that match where stacks is running are left out.
+        assert!(!environment.contains_key("PATH"));
+    }
+
+    #[test]
+    fn stack_urls() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            environment:
+                HOST: localhost
+            stacks:
+                web:
+                    url: ['http://${HOST}:${WEB_PORT}', 'http://${HOST}:${WEB_PORT}/admin']
+                    environment:
+                        WEB_PORT: 8080
+                api:
+                    url: http://${HOST}:${API_PORT_UNSET_IN_TESTS}
+                db: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let runner = Runner::new(&config, &Passthrough);
+        let stack = |key: &str| config.stacks.get(key).unwrap();
+
+        assert_eq!(
+            runner.stack_urls(stack("web")).unwrap(),
+            vec!["http://localhost:8080", "http://localhost:8080/admin"]
+        );
+        assert!(runner.stack_urls(stack("db")).unwrap().is_empty());
+        assert_eq!(
+            runner.stack_urls(stack("api")).unwrap_err().to_string(),
+            "The url `http://${HOST}:${API_PORT_UNSET_IN_TESTS}` of stack `api` references \
+            `API_PORT_UNSET_IN_TESTS` which is not set."
+        );
+
+        // Listings fall back to the URL as it is configured.
+        assert_eq!(
+            runner.list(&[stack("api")], false).unwrap(),
+            "Stack  URL                                        Description\n\
+            api    http://${HOST}:${API_PORT_UNSET_IN_TESTS}\n"
+        );
+    }
+
+    #[test]
+    fn provided_ports() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db:
+                    provides:
+                        HOST: localhost
+                        EXTERNAL_PORT: { service: postgres, port: 5432 }
+                api:
+                    depends_on: [db]
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let api = config.stacks(["api"]).unwrap();
+        let variable = |options: &ExecOptions, name: &str| {
+            options
+                .environment
+                .get(name)
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+
+        let executor = RecordingExecutor::default().respond("port", "0.0.0.0:49153\n");
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        runner.run_against_stacks("up", &api, &[]).unwrap();
+        let runs = executor.runs();
+        assert_eq!(variable(&runs[0], "DB_HOST").unwrap(), "localhost");
+        assert_eq!(variable(&runs[0], "DB_EXTERNAL_PORT").unwrap(), "49153");
+
+        // The port is looked up from the dependency.
+        let query = executor
+            .queries()
+            .into_iter()
+            .find(|query| query.command == "port")
+            .unwrap();
+        assert_eq!(
+            variable(&query, "COMPOSE_PROJECT_NAME").unwrap(),
+            "db".to_string()
+        );
+        assert!(query.command_line().ends_with(" port postgres 5432"));
+
+        // Only the static values are part of the stack's environment.
+        let environment = runner.stack_environment(api[0]).unwrap();
+        assert_eq!(environment["DB_HOST"], "localhost");
+        assert!(!environment.contains_key("DB_EXTERNAL_PORT"));
+
+        // Ports that aren't published are left out.
+        let executor = RecordingExecutor::default();
+        runner.executor = &executor;
+        runner.run_against_stacks("up", &api, &[]).unwrap();
+        assert_eq!(variable(&executor.runs()[0], "DB_EXTERNAL_PORT"), None);
+    }
+}
@@ -1,13 +1,41 @@
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+//! The stacks config and the order to run stacks in.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
 use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 
+use regex::Regex;
 use serde::de::{self, Error, SeqAccess, Visitor};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_with::formats::SpaceSeparator;
-use serde_with::{serde_as, StringWithSeparator};
+use serde_with::{serde_as, PickFirst, StringWithSeparator};
+
+use crate::backend::BackendKind;
+use crate::cancel::DEFAULT_KILL_GRACE;
+use crate::engine::{self, default_command, Engine};
+use crate::environment::load_env_files;
+use crate::error::{Error as StackError, Result};
+use crate::lock::parse_duration;
+use crate::notify::NotifySettings;
+use crate::paths;
+use crate::preflight::{find_program, has_compose_plugin};
+use crate::provides::{self, Provided};
+use crate::task::{self, Task};
+
+/// How long to wait for services to come up when verifying, by default.
+pub const DEFAULT_VERIFY_TIMEOUT: Duration = Duration::from_secs(60);
+
+thread_local! {
+    /// Errors returned from within a deserializer are converted to strings by
+    /// serde. The structured error is kept here so `Config::from_reader` can
+    /// recover it.
+    static VALIDATION_ERROR: RefCell<Option<StackError>> = const { RefCell::new(None) };
+}
 
-fn deserialize_file<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+fn deserialize_file<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -19,7 +47,7 @@ where
             formatter.write_str("a string or a non-empty list of strings")
         }
 
-        fn visit_seq<S>(self, mut seq: S) -> Result<Option<Vec<String>>, S::Error>
+        fn visit_seq<S>(self, mut seq: S) -> std::result::Result<Option<Vec<String>>, S::Error>
         where
             S: SeqAccess<'de>,
         {
@@ -35,14 +63,14 @@ where
             }
         }
 
-        fn visit_str<E>(self, s: &str) -> Result<Option<Vec<String>>, E>
+        fn visit_str<E>(self, s: &str) -> std::result::Result<Option<Vec<String>>, E>
         where
             E: de::Error,
         {
             Ok(Some(vec![s.to_owned()]))
         }
 
-        fn visit_none<E>(self) -> Result<Option<Vec<String>>, E>
+        fn visit_none<E>(self) -> std::result::Result<Option<Vec<String>>, E>
         where
             E: de::Error,
         {
@@ -53,75 +81,361 @@ where
     deserializer.deserialize_any(FileVisitor {})
 }
 
-#[serde_as]
-#[derive(Deserialize, Eq)]
+/// Whether a field has its default value and so can be left out when
+/// serializing.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Serializes a map in key order so the output is stable.
+fn serialize_sorted<S>(
+    map: &HashMap<String, String>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    map.iter()
+        .collect::<BTreeMap<&String, &String>>()
+        .serialize(serializer)
+}
+
+/// What kind of project a stack is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StackKind {
+    /// A docker compose project.
+    #[default]
+    Compose,
+    /// Something managed by shell scripts rather than compose.
+    Script,
+}
+
+/// The shell commands that manage a script stack.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Scripts {
+    /// Brings the stack up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub up: Option<String>,
+    /// Takes the stack down.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub down: Option<String>,
+    /// Exits successfully when the stack is running, used for `ps` and
+    /// `status`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Shows the stack's logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logs: Option<String>,
+}
+
+impl Scripts {
+    fn is_empty(&self) -> bool {
+        *self == Scripts::default()
+    }
+
+    /// The script to run for a compose subcommand, if there is one.
+    pub fn get(&self, command: &str) -> Option<&str> {
+        match command {
+            "up" => self.up.as_deref(),
+            "down" => self.down.as_deref(),
+            "ps" => self.status.as_deref(),
+            "logs" => self.logs.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// A stack from the config. Serializing leaves out anything worked out when
+/// the config was loaded.
+#[derive(Deserialize, Serialize, Eq)]
 pub struct Stack {
+    /// The stack's key in the config.
     #[serde(skip)]
     pub key: String,
+    /// What manages the stack.
+    #[serde(default, rename = "type", skip_serializing_if = "is_default")]
+    pub kind: StackKind,
+    /// The commands to run for a script stack.
+    #[serde(flatten)]
+    pub scripts: Scripts,
+    /// The compose project name, the key unless set.
     #[serde(default)]
     pub name: String,
+    /// A description of the stack for people.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The stack's directory relative to the base directory, the key unless
+    /// set.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub directory: Option<String>,
-    #[serde(default, deserialize_with = "deserialize_file")]
+    /// The compose files to use relative to the base directory, compose's
+    /// defaults unless set.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_file",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub file: Option<Vec<String>>,
-    #[serde(default, rename = "depends_on")]
+    /// The addresses of the stack's user interfaces, `${VAR}` references are
+    /// expanded with the stack's environment.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_file",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub url: Option<Vec<String>>,
+    /// The keys of the stacks that this stack depends on.
+    #[serde(
+        default,
+        rename = "depends_on",
+        skip_serializing_if = "BTreeSet::is_empty"
+    )]
     pub dependencies: BTreeSet<String>,
+    /// The keys of the stacks that depend on this stack.
     #[serde(skip)]
     pub dependants: BTreeSet<String>,
-    #[serde(default)]
+    /// Environment variables for the stack's commands.
+    #[serde(
+        default,
+        serialize_with = "serialize_sorted",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
     pub environment: HashMap<String, String>,
+    /// Files of environment variables relative to the base directory.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_file",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub env_file: Option<Vec<String>>,
+    /// The variables loaded from `env_file`.
+    #[serde(skip)]
+    pub env_file_environment: HashMap<String, String>,
+    /// Variables given to the stacks that depend on this one, namespaced by
+    /// `provides_prefix`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub provides: BTreeMap<String, Provided>,
+    /// The prefix for the variables in `provides`, the key in upper case
+    /// unless set. May be empty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provides_prefix: Option<String>,
+    /// Host variables, or patterns, this stack may see when the host
+    /// environment is not inherited.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pass_env: Vec<String>,
+    /// Allows `directory` and `file` to point outside of the base directory.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub allow_outside_base: bool,
+    /// How many times to retry failed commands for this stack.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// What the stack is deployed with, overriding the config's backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<BackendKind>,
+    /// How long compose waits for containers to stop before killing them.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stop_grace: Option<Duration>,
+    /// Stacks in the same group never run at the same time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency_group: Option<String>,
+    /// Whether to check that the stack's services came up after `up`,
+    /// overriding the config's `verify_up`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_up: Option<bool>,
+    /// Whether `down` removes containers for services that are no longer in
+    /// the compose file, overriding the config's `remove_orphans`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remove_orphans: Option<bool>,
+    /// Whether the stack is started when all stacks are, otherwise only when
+    /// named or needed by a stack that is started.
+    #[serde(default = "default_autostart", skip_serializing_if = "is_true")]
+    pub autostart: bool,
 }
 
 impl Stack {
+    /// The stack's directory, given the config's base directory.
     pub fn directory(&self, base: &Path) -> PathBuf {
         if let Some(ref dir) = self.directory {
-            base.join(dir)
+            paths::join(base, dir)
         } else {
             base.join(&self.key)
         }
     }
 }
 
+/// Whether a path relative to the base directory would resolve to somewhere
+/// outside of it. This is worked out from the path alone, without touching the
+/// filesystem, so symlinks are not followed.
+pub fn escapes_base(path: &str) -> bool {
+    let mut depth = 0;
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+
+    false
+}
+
+impl Stack {
+    /// Checks that the stack's paths stay inside the base directory.
+    fn check_paths(&self) -> Result<()> {
+        if self.allow_outside_base {
+            return Ok(());
+        }
+
+        let paths = self.directory.iter().chain(self.file.iter().flatten());
+        for path in paths {
+            if escapes_base(path) {
+                return Err(StackError::OutsideBaseDir {
+                    stack: self.key.clone(),
+                    path: path.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that script stacks have the scripts they need and that other
+    /// stacks don't define any.
+    fn check_scripts(&self) -> Result<()> {
+        match self.kind {
+            StackKind::Compose if !self.scripts.is_empty() => Err(StackError::Invalid(format!(
+                "Stack `{}` defines scripts but is not a script stack. Set `type: script` to \
+                use them.",
+                self.key
+            ))),
+            StackKind::Script if self.scripts.up.is_none() || self.scripts.down.is_none() => {
+                Err(StackError::Invalid(format!(
+                    "Script stack `{}` must define both `up` and `down` scripts.",
+                    self.key
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 impl PartialEq for Stack {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key
     }
 }
 
-fn default_command() -> Vec<String> {
-    vec!["docker".to_string(), "compose".to_string()]
+fn deserialize_duration<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(u64),
+        Text(String),
+    }
+
+    match Option::<Raw>::deserialize(deserializer)? {
+        Some(Raw::Seconds(seconds)) => Ok(Some(Duration::from_secs(seconds))),
+        Some(Raw::Text(text)) => parse_duration(&text).map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn serialize_duration<S>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match duration {
+        Some(duration) => serializer.serialize_str(&format!("{}s", duration.as_secs())),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn default_kill_grace() -> u64 {
+    DEFAULT_KILL_GRACE.as_secs()
+}
+
+fn default_inherit_env() -> bool {
+    true
+}
+
+fn default_autostart() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
 }
 
 fn check_dependencies(
     stack: &Stack,
     stacks: &BTreeMap<String, Stack>,
-    seen: &mut HashSet<String>,
-) -> Result<(), String> {
-    seen.insert(stack.key.clone());
+    seen: &mut Vec<String>,
+) -> Result<()> {
+    seen.push(stack.key.clone());
 
     for dep in stack.dependencies.iter() {
         if seen.contains(dep) {
-            return Err(format!(
-                "invalid dependency cycle: \"{}\" cannot depend on \"{}\"",
-                stack.key, dep
-            ));
+            let mut path = seen.clone();
+            path.push(dep.clone());
+            return Err(StackError::DependencyCycle {
+                path,
+                location: None,
+            });
         }
 
         if let Some(inner) = stacks.get(dep) {
             check_dependencies(inner, stacks, seen)?;
         } else {
-            return Err(format!(
-                "invalid dependency: \"{}\" is not a known stack",
-                dep
-            ));
+            return Err(StackError::UnknownDependency {
+                stack: stack.key.clone(),
+                dependency: dep.clone(),
+                location: None,
+            });
         }
     }
 
-    seen.remove(&stack.key);
+    seen.pop();
 
     Ok(())
 }
 
-fn deserialize_stacks<'de, D>(deserializer: D) -> Result<BTreeMap<String, Stack>, D::Error>
+/// Parses a compose command given on the command line. Like `command` in the
+/// config this is either a list, like `[podman, compose]`, or a string that is
+/// split on spaces.
+pub fn parse_command(s: &str) -> std::result::Result<Vec<String>, String> {
+    let command: Vec<String> = if s.trim_start().starts_with('[') {
+        serde_yaml::from_str(s).map_err(|e| e.to_string())?
+    } else {
+        s.split(' ')
+            .filter(|word| !word.is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+
+    if command.is_empty() || command[0].is_empty() {
+        Err("the command cannot be empty".to_owned())
+    } else {
+        Ok(command)
+    }
+}
+
+fn deserialize_stacks<'de, D>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, Stack>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -135,10 +449,14 @@ where
     }
 
     let keys: Vec<String> = stacks.keys().cloned().collect();
-    let mut seen = HashSet::new();
+    let mut seen = Vec::new();
     for key in keys {
         let stack = stacks.get(&key).unwrap();
-        check_dependencies(stack, &stacks, &mut seen).map_err(D::Error::custom)?;
+        if let Err(e) = check_dependencies(stack, &stacks, &mut seen) {
+            let error = D::Error::custom(&e);
+            VALIDATION_ERROR.with(|cell| cell.replace(Some(e)));
+            return Err(error);
+        }
 
         let dependencies = stack.dependencies.clone();
         for dep in dependencies {
@@ -150,18 +468,140 @@ where
     Ok(stacks)
 }
 
+/// The stacks config. Serializing writes the config as it was given, leaving
+/// out anything worked out when it was loaded.
 #[serde_as]
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Config {
+    /// The directory that stack directories and files are relative to.
     #[serde(skip)]
     pub base_dir: PathBuf,
-    #[serde(default = "default_command")]
-    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, String>")]
+    /// The base directory given in the config, if any. See `from_reader`.
+    #[serde(default, rename = "base_dir", skip_serializing_if = "Option::is_none")]
+    pub configured_base_dir: Option<String>,
+    /// The command used to run compose, see `configured_command`.
+    #[serde(skip)]
     pub command: Vec<String>,
+    /// Set by `--binary` or `STACKS_COMMAND` to override the command for a
+    /// run, see `requested_command`.
+    #[serde(skip)]
+    pub command_override: Option<Vec<String>>,
+    /// The command given in the config, if any.
+    #[serde(default, rename = "command", skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<PickFirst<(_, StringWithSeparator::<SpaceSeparator, String>)>>")]
+    pub configured_command: Option<Vec<String>>,
+    /// Options for compose itself, passed before the subcommand.
+    #[serde(skip)]
+    pub compose_args: Vec<String>,
+    /// The container engine, detected from the command when not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub engine: Option<Engine>,
+    /// The stacks by key.
     #[serde(default, deserialize_with = "deserialize_stacks")]
     pub stacks: BTreeMap<String, Stack>,
-    #[serde(default)]
+    /// What stacks are deployed with unless they say otherwise.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub backend: BackendKind,
+    /// Environment variables for every stack's commands.
+    #[serde(
+        default,
+        serialize_with = "serialize_sorted",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
     pub environment: HashMap<String, String>,
+    /// Files of environment variables relative to the base directory.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_file",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub env_file: Option<Vec<String>>,
+    /// The variables loaded from `env_file`.
+    #[serde(skip)]
+    pub env_file_environment: HashMap<String, String>,
+    /// Variables given on the command line.
+    #[serde(skip)]
+    pub cli_environment: HashMap<String, String>,
+    /// Whether commands inherit the environment stacks was run in.
+    #[serde(default = "default_inherit_env")]
+    pub inherit_env: bool,
+    /// Host variables, or patterns like `AWS_*`, to pass through to every
+    /// stack in addition to the essentials when the host environment is not
+    /// inherited.
+    #[serde(
+        default,
+        alias = "env_allowlist",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub pass_env: Vec<String>,
+    /// Whether to warn when a variable listed in `pass_env` is not set.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub warn_missing_env: bool,
+    /// The stacks to use when none are selected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub default_stacks: Vec<String>,
+    /// Seconds to wait after asking commands to terminate before killing them.
+    #[serde(default = "default_kill_grace")]
+    pub kill_grace: u64,
+    /// How long compose waits for containers to stop for stacks that don't
+    /// set their own.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stop_grace: Option<Duration>,
+    /// The oldest version of docker compose that the stacks work with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_compose_version: Option<String>,
+    /// Where to log commands that change stacks, relative to the base
+    /// directory. Defaults to `.stack/history.log` next to the config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_file: Option<PathBuf>,
+    /// The size in bytes beyond which the oldest history entries are dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_max_size: Option<u64>,
+    /// A directory relative to the base directory to save each stack's
+    /// output in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_dir: Option<PathBuf>,
+    /// When to show a desktop notification at the end of a run.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub notify: NotifySettings,
+    /// Whether to check that each stack's services came up after `up`.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub verify_up: bool,
+    /// Whether to create missing stack directories for commands that don't
+    /// need a compose file.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub create_dirs: bool,
+    /// Regular expressions for compose projects that `status --orphans`
+    /// should not report, matched against the whole project name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub orphan_ignore: Vec<String>,
+    /// Whether `down` removes containers for services that are no longer in
+    /// the compose file.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub remove_orphans: bool,
+    /// Whether `up` uses the images pinned by `stack freeze`.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub frozen: bool,
+    /// Set by `--no-remove-orphans` to ignore `remove_orphans` for a run.
+    #[serde(skip)]
+    pub keep_orphans: bool,
+    /// Named sequences of steps run by `stack task`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tasks: BTreeMap<String, Task>,
+    /// How long to wait for services to come up when verifying, a minute
+    /// unless set.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub verify_timeout: Option<Duration>,
 }
 
 fn add_dependencies(stacks: &BTreeMap<String, Stack>, stack: &str, keys: &mut BTreeSet<String>) {
@@ -191,7 +631,7 @@ fn add_dependants(stacks: &BTreeMap<String, Stack>, stack: &str, keys: &mut BTre
 }
 
 impl Config {
-    fn stack_keys<I, S>(&self, list: I) -> Result<BTreeSet<String>, String>
+    fn stack_keys<I, S>(&self, list: I) -> Result<BTreeSet<String>>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
@@ -205,7 +645,9 @@ impl Config {
             if self.stacks.contains_key(key_str) {
                 keys.insert(key_str.to_owned());
             } else {
-                return Err(format!("unknown stack \"{}\"", key_str));
+                return Err(StackError::UnknownStack {
+                    name: key_str.to_owned(),
+                });
             }
         }
 
@@ -217,6 +659,69 @@ impl Config {
         }
     }
 
+    /// The compose command that was asked for. `--binary`, or the
+    /// `STACKS_COMMAND` variable, takes precedence over the `command` in the
+    /// config. Without either the command is detected.
+    pub fn requested_command(&self) -> Option<&[String]> {
+        self.command_override
+            .as_deref()
+            .or(self.configured_command.as_deref())
+    }
+
+    /// Picks the container engine and compose command to use based on the
+    /// programs that can be found in `path`.
+    pub fn detect_engine(&mut self, path: Option<&OsStr>) {
+        let find = |program: &str| find_program(program, &self.base_dir, path);
+        let (engine, command) = engine::detect(
+            self.engine,
+            self.requested_command(),
+            |program| find(program).is_some(),
+            || find("docker").is_some_and(|docker| has_compose_plugin(&docker)),
+        );
+
+        if command != self.command {
+            log::info!("Using {} to run compose", command.join(" "));
+        }
+        self.engine = Some(engine);
+        self.command = command;
+    }
+
+    /// The compiled `orphan_ignore` patterns.
+    pub fn orphan_patterns(&self) -> Result<Vec<Regex>> {
+        self.orphan_ignore
+            .iter()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                    StackError::Invalid(format!(
+                        "The `orphan_ignore` pattern `{}` is invalid: {}",
+                        pattern, e
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    /// Whether to check that the stack's services came up after `up`.
+    pub fn verify_up(&self, stack: &Stack) -> bool {
+        stack.verify_up.unwrap_or(self.verify_up)
+    }
+
+    /// Whether `down` should remove the stack's orphaned containers.
+    pub fn remove_orphans(&self, stack: &Stack) -> bool {
+        !self.keep_orphans && stack.remove_orphans.unwrap_or(self.remove_orphans)
+    }
+
+    /// How long to wait for a stack's services to come up when verifying.
+    pub fn verify_timeout(&self) -> Duration {
+        self.verify_timeout.unwrap_or(DEFAULT_VERIFY_TIMEOUT)
+    }
+
+    /// The container engine that runs the stacks.
+    pub fn engine(&self) -> Engine {
+        self.engine
+            .unwrap_or_else(|| Engine::for_command(&self.command))
+    }
+
     fn stacks_from_known_keys<I>(&self, keys: I) -> Vec<&Stack>
     where
         I: IntoIterator<Item = String>,
@@ -226,13 +731,142 @@ impl Config {
             .collect()
     }
 
-    pub fn from_reader<R: Read>(base_dir: &Path, reader: R) -> Result<Self, String> {
-        let mut config: Config = serde_yaml::from_reader(reader).map_err(|e| e.to_string())?;
-        config.base_dir = base_dir.to_owned();
+    /// Orders the stacks so each one comes after every stack it depends on,
+    /// using Kahn's algorithm over the subgraph of the given keys. Dependencies
+    /// outside of the subgraph are ignored.
+    ///
+    /// Stacks are grouped into levels, a stack's level is the length of the
+    /// longest chain of dependencies beneath it. Levels are ordered lowest
+    /// first and stacks within a level are ordered alphabetically by key.
+    fn ordered(&self, keys: &BTreeSet<String>) -> Result<Vec<&Stack>> {
+        // How many dependencies of each stack are not yet ordered.
+        let mut remaining: HashMap<&str, usize> = HashMap::new();
+
+        for key in keys {
+            let stack = self.stacks.get(key).unwrap();
+            let count = stack
+                .dependencies
+                .iter()
+                .filter(|dep| keys.contains(*dep))
+                .count();
+            remaining.insert(key, count);
+        }
+
+        // Keys come from an ordered set so each level starts sorted.
+        let mut level: Vec<&str> = keys
+            .iter()
+            .map(String::as_str)
+            .filter(|key| remaining[key] == 0)
+            .collect();
+        let mut stacks = Vec::with_capacity(keys.len());
+
+        while !level.is_empty() {
+            let mut next = Vec::new();
+
+            for key in level {
+                let stack = self.stacks.get(key).unwrap();
+                stacks.push(stack);
+
+                for dependant in stack.dependants.iter() {
+                    if let Some(count) = remaining.get_mut(dependant.as_str()) {
+                        *count -= 1;
+                        if *count == 0 {
+                            next.push(dependant.as_str());
+                        }
+                    }
+                }
+            }
+
+            next.sort_unstable();
+            level = next;
+        }
+
+        // Cycles are rejected when the config is parsed so this can only be a
+        // bug.
+        if stacks.len() != keys.len() {
+            let mut unordered: Vec<&str> = remaining
+                .iter()
+                .filter(|(_, count)| **count > 0)
+                .map(|(key, _)| *key)
+                .collect();
+            unordered.sort_unstable();
+            return Err(StackError::Internal(format!(
+                "unable to order the stacks {}",
+                unordered.join(", ")
+            )));
+        }
+
+        Ok(stacks)
+    }
+
+    /// Parses and validates a config, loading any environment files. Paths
+    /// in the config are relative to `base_dir`, or to the `base_dir` given in
+    /// the config which is itself relative to `base_dir`.
+    pub fn from_reader<R: Read>(base_dir: &Path, reader: R) -> Result<Self> {
+        let config = Self::parse(reader)?;
+        let base_dir = match config.configured_base_dir {
+            Some(ref dir) => paths::join(base_dir, dir),
+            None => base_dir.to_owned(),
+        };
+        config.resolve(&base_dir)
+    }
+
+    /// Parses a config without resolving anything that depends on where it is.
+    /// Call `resolve` to finish loading it.
+    pub fn parse<R: Read>(reader: R) -> Result<Self> {
+        VALIDATION_ERROR.with(|cell| cell.take());
+
+        let config: Config = serde_yaml::from_reader(reader).map_err(|e| {
+            let location = e.location().map(Into::into);
+
+            match VALIDATION_ERROR.with(|cell| cell.take()) {
+                Some(StackError::DependencyCycle { path, .. }) => {
+                    StackError::DependencyCycle { path, location }
+                }
+                Some(StackError::UnknownDependency {
+                    stack, dependency, ..
+                }) => StackError::UnknownDependency {
+                    stack,
+                    dependency,
+                    location,
+                },
+                _ => StackError::from(e),
+            }
+        })?;
+
         Ok(config)
     }
 
-    pub fn stacks_with_dependencies<I, S>(&self, list: I) -> Result<Vec<&Stack>, String>
+    /// Finishes loading a parsed config, using `base_dir` as the directory
+    /// that relative paths in the config are relative to. The `base_dir` in
+    /// the config is ignored.
+    pub fn resolve(mut self, base_dir: &Path) -> Result<Self> {
+        self.base_dir = base_dir.to_owned();
+        self.command = self
+            .configured_command
+            .clone()
+            .unwrap_or_else(default_command);
+
+        for stack in self.stacks.values() {
+            stack.check_paths()?;
+            stack.check_scripts()?;
+        }
+        self.orphan_patterns()?;
+        task::validate(&self.tasks, &self.stacks)?;
+        provides::validate(&self.stacks)?;
+
+        self.env_file_environment = load_env_files(base_dir, &self.env_file)?;
+        for stack in self.stacks.values_mut() {
+            stack.env_file_environment = load_env_files(base_dir, &stack.env_file)?;
+        }
+
+        Ok(self)
+    }
+
+    /// The given stacks along with everything they depend on. Each stack comes
+    /// after all of its dependencies, stacks at the same depth are ordered
+    /// alphabetically.
+    pub fn stacks_with_dependencies<I, S>(&self, list: I) -> Result<Vec<&Stack>>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
@@ -248,35 +882,61 @@ impl Config {
             }
         }
 
-        // Now to order them in dependent order.
-        let mut added: HashSet<String> = HashSet::new();
-        let mut stacks = Vec::new();
+        self.ordered(&keys)
+    }
 
-        while stacks.len() != keys.len() {
-            for key in keys.iter().rev() {
-                if added.contains(key) {
-                    // Already added this one.
-                    continue;
-                }
+    /// The keys of the listed stacks that may be started, every stack for an
+    /// empty list. Stacks with autostart disabled are left out unless they are
+    /// named, so that a wildcard, pattern or default selection skips them.
+    pub fn startable_keys<I, S>(&self, list: I, named: &[String]) -> Result<BTreeSet<String>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut keys = self.stack_keys(list)?;
+        keys.retain(|key| {
+            let startable = self.stacks[key].autostart || named.contains(key);
+            if !startable {
+                log::debug!("Leaving out {} which has autostart disabled", key);
+            }
+            startable
+        });
+        Ok(keys)
+    }
 
-                let stack = self.stacks.get(key).unwrap();
-                if stack
-                    .dependencies
-                    .iter()
-                    .all(|dep: &String| added.contains(dep) || !keys.contains(dep))
-                {
-                    // All dependencies we care about are resolved so this stack
-                    // can be added.
-                    stacks.push(stack);
-                    added.insert(key.to_owned());
-                }
+    /// The stacks to start for the given list, as `stacks_with_dependencies`
+    /// does, except that stacks with autostart disabled are only selected
+    /// when named or when a selected stack depends on them.
+    pub fn stacks_to_start<I, S>(&self, list: I, named: &[String]) -> Result<Vec<&Stack>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut keys = self.startable_keys(list, named)?;
+        for key in keys.clone().iter() {
+            add_dependencies(&self.stacks, key, &mut keys);
+        }
+
+        for stack in keys.iter().map(|key| &self.stacks[key]) {
+            if stack.autostart || named.contains(&stack.key) {
+                continue;
+            }
+            if let Some(dependant) = stack.dependants.iter().find(|key| keys.contains(*key)) {
+                log::info!(
+                    "Starting {} which has autostart disabled as {} depends on it",
+                    stack.key,
+                    dependant
+                );
             }
         }
 
-        Ok(stacks)
+        self.ordered(&keys)
     }
 
-    pub fn stacks_with_dependants<I, S>(&self, list: I) -> Result<Vec<&Stack>, String>
+    /// The given stacks along with everything that depends on them. Each stack
+    /// comes after all of its dependencies, stacks at the same depth are
+    /// ordered alphabetically. Reverse the list to take stacks down.
+    pub fn stacks_with_dependants<I, S>(&self, list: I) -> Result<Vec<&Stack>>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
@@ -292,35 +952,12 @@ impl Config {
             }
         }
 
-        // Now to order them in dependant order.
-        let mut added: HashSet<String> = HashSet::new();
-        let mut stacks = Vec::new();
-
-        while stacks.len() != keys.len() {
-            for key in keys.iter().rev() {
-                if added.contains(key) {
-                    // Already added this one.
-                    continue;
-                }
-
-                let stack = self.stacks.get(key).unwrap();
-                if stack
-                    .dependants
-                    .iter()
-                    .all(|dep: &String| added.contains(dep) || !keys.contains(dep))
-                {
-                    // All dependants we care about are resolved so this stack
-                    // can be added.
-                    stacks.insert(0, stack);
-                    added.insert(key.to_owned());
-                }
-            }
-        }
-
-        Ok(stacks)
+        self.ordered(&keys)
     }
 
-    pub fn stacks<I, S>(&self, list: I) -> Result<Vec<&Stack>, String>
+    /// The given stacks, or every stack for an empty list, in the order
+    /// given.
+    pub fn stacks<I, S>(&self, list: I) -> Result<Vec<&Stack>>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
@@ -328,17 +965,18 @@ impl Config {
         Ok(self.stacks_from_known_keys(self.stack_keys(list)?))
     }
 
-    pub fn stack<I, S>(&self, list: I) -> Result<Vec<&Stack>, String>
+    /// The single stack given, failing if the list does not resolve to
+    /// exactly one stack.
+    pub fn stack<I, S>(&self, list: I) -> Result<Vec<&Stack>>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
     {
         let stacks = self.stacks(list)?;
         if stacks.len() != 1 {
-            Err(format!(
-                "Only one stack can be used but {} were provided.",
-                stacks.len()
-            ))
+            Err(StackError::SingleStackRequired {
+                count: stacks.len(),
+            })
         } else {
             Ok(stacks)
         }
@@ -347,17 +985,22 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use super::{Config, Stack};
+    use super::{escapes_base, parse_command, Config, Scripts, Stack, StackKind};
+    use crate::error::{Error, Location};
     use std::path::PathBuf;
 
     fn from_str(s: &str) -> Result<Config, String> {
-        Config::from_reader(&PathBuf::default(), s.as_bytes())
+        Config::from_reader(&PathBuf::default(), s.as_bytes()).map_err(|e| e.to_string())
     }
 
     fn keys(stacks: Vec<&Stack>) -> Vec<String> {
         stacks.iter().map(|s| s.key.clone()).collect()
     }
 
+    fn named(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
     #[test]
     fn name() {
         let config = from_str(
@@ -372,7 +1015,7 @@ mod tests {
 
         let stacks = config.stacks(["foo", "bar"]).unwrap();
         assert_eq!(stacks.len(), 2);
-        let stack = stacks.get(0).unwrap();
+        let stack = stacks.first().unwrap();
         assert_eq!(stack.name, "bar");
         let stack = stacks.get(1).unwrap();
         assert_eq!(stack.name, "baz");
@@ -425,7 +1068,7 @@ mod tests {
         assert_eq!(stacks, vec!["foo".to_string()]);
 
         assert_eq!(
-            config.stacks(["bar", "biz"]).err().unwrap(),
+            config.stacks(["bar", "biz"]).err().unwrap().to_string(),
             "unknown stack \"biz\""
         );
 
@@ -514,4 +1157,752 @@ mod tests {
         let stacks = keys(config.stacks(["bar"]).unwrap());
         assert_eq!(stacks, vec!["bar".to_string()]);
     }
+
+    #[test]
+    fn errors() {
+        let config = from_str(
+            "
+            stacks:
+                foo: {}
+                bar: {}
+            ",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            config.stacks(["foo", "baz"]),
+            Err(Error::UnknownStack { name }) if name == "baz"
+        ));
+        assert!(matches!(
+            config.stack(["foo", "bar"]),
+            Err(Error::SingleStackRequired { count: 2 })
+        ));
+
+        let error = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                foo:
+                    depends_on:
+                        - baz
+            "
+            .as_bytes(),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            error,
+            Error::UnknownDependency { stack, dependency, location: Some(Location { line: 2, column: 13 }) }
+                if stack == "foo" && dependency == "baz"
+        ));
+
+        let error = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                foo:
+                    depends_on:
+                        - bar
+                baz:
+                    depends_on:
+                        - foo
+                bar:
+                    depends_on:
+                        - baz
+            "
+            .as_bytes(),
+        )
+        .err()
+        .unwrap();
+        match error {
+            Error::DependencyCycle { path, .. } => {
+                assert_eq!(path, vec!["bar", "baz", "foo", "bar"]);
+            }
+            _ => panic!("Unexpected error {}", error),
+        }
+
+        let error = Config::from_reader(&PathBuf::default(), "stacks: [".as_bytes())
+            .err()
+            .unwrap();
+        assert!(matches!(error, Error::ConfigParse(_)));
+
+        // Tasks are checked when the config is loaded.
+        let error = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                foo: {}
+            tasks:
+                reset:
+                    - { command: down, stacks: [foo, baz] }
+            "
+            .as_bytes(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Step 1 of task `reset` uses the unknown stack `baz`."
+        );
+    }
+
+    #[test]
+    fn orphan_ignore() {
+        let config = from_str("orphan_ignore: [scratch-.*, tmp]\nstacks: {}").unwrap();
+        let patterns = config.orphan_patterns().unwrap();
+        assert!(patterns[0].is_match("scratch-1"));
+        assert!(!patterns[0].is_match("my-scratch-1"));
+        assert!(patterns[1].is_match("tmp"));
+        assert!(!patterns[1].is_match("tmpfs"));
+
+        let error = from_str("orphan_ignore: ['scratch-(']\nstacks: {}")
+            .err()
+            .unwrap();
+        assert!(error.starts_with("The `orphan_ignore` pattern `scratch-(` is invalid"));
+    }
+
+    #[test]
+    fn env_files() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("global.env"), "SHARED=global\nGLOBAL=1\n").unwrap();
+        std::fs::write(temp.path().join("web.env"), "SHARED=web\n").unwrap();
+
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            env_file: global.env
+            stacks:
+                web:
+                    env_file:
+                        - web.env
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(config.env_file_environment.get("GLOBAL").unwrap(), "1");
+        let web = config.stacks.get("web").unwrap();
+        assert_eq!(web.env_file_environment.get("SHARED").unwrap(), "web");
+
+        let error = Config::from_reader(temp.path(), "env_file: missing.env".as_bytes())
+            .err()
+            .unwrap();
+        assert!(matches!(error, Error::Io { .. }));
+    }
+
+    #[test]
+    fn outside_base() {
+        for path in [
+            "web",
+            "./web",
+            "stacks/web",
+            "stacks/../web",
+            "a/b/../../c",
+            ".",
+        ] {
+            assert!(!escapes_base(path), "{} should be allowed", path);
+        }
+        for path in [
+            "..",
+            "../web",
+            "foo/../../bar",
+            "./../web",
+            "a/./b/../../../c",
+            "/etc",
+            "/home/user/.ssh",
+        ] {
+            assert!(escapes_base(path), "{} should be rejected", path);
+        }
+
+        let error = from_str(
+            "
+            stacks:
+                web:
+                    directory: ../../../home/user/.ssh
+            ",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error,
+            "Stack `web` uses the path `../../../home/user/.ssh` which is outside of the stacks \
+            directory. Set `allow_outside_base: true` on the stack to allow this."
+        );
+
+        assert!(from_str(
+            "
+            stacks:
+                web:
+                    file:
+                        - compose.yml
+                        - /etc/compose.yml
+            ",
+        )
+        .is_err());
+
+        assert!(from_str(
+            "
+            stacks:
+                web:
+                    directory: ../shared/web
+                    file: /etc/compose.yml
+                    allow_outside_base: true
+            ",
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn autostart() {
+        let config = from_str(
+            "
+            stacks:
+                db: {}
+                proxy:
+                    autostart: false
+                    depends_on:
+                        - db
+                app:
+                    depends_on:
+                        - proxy
+                admin:
+                    autostart: false
+                    depends_on:
+                        - db
+                debug:
+                    autostart: false
+                    depends_on:
+                        - admin
+            ",
+        )
+        .unwrap();
+        assert!(!config.stacks["proxy"].autostart);
+        assert!(config.stacks["db"].autostart);
+
+        // Stacks that don't start automatically are only included when a
+        // started stack depends on them, still in dependency order.
+        assert_eq!(
+            keys(config.stacks_to_start(Vec::<String>::new(), &[]).unwrap()),
+            vec!["db", "proxy", "app"]
+        );
+
+        // Named stacks bring their dependencies whatever their autostart.
+        assert_eq!(
+            keys(
+                config
+                    .stacks_to_start(["debug"], &named(&["debug"]))
+                    .unwrap()
+            ),
+            vec!["db", "admin", "debug"]
+        );
+        assert_eq!(
+            keys(
+                config
+                    .stacks_to_start(["app", "admin"], &named(&["app", "admin"]))
+                    .unwrap()
+            ),
+            vec!["db", "admin", "proxy", "app"]
+        );
+
+        // Stacks selected by a pattern or default without being named are
+        // left out, as `--select-regex '^(a|d)'` or `default_stacks: [app,
+        // admin]` would select.
+        assert_eq!(
+            keys(
+                config
+                    .stacks_to_start(["admin", "app", "debug"], &[])
+                    .unwrap()
+            ),
+            vec!["db", "proxy", "app"]
+        );
+        assert_eq!(
+            keys(config.stacks_to_start(["app", "admin"], &[]).unwrap()),
+            vec!["db", "proxy", "app"]
+        );
+        assert_eq!(
+            keys(
+                config
+                    .stacks_to_start(["admin", "app", "debug"], &named(&["debug"]))
+                    .unwrap()
+            ),
+            vec!["db", "admin", "proxy", "app", "debug"]
+        );
+        assert!(config
+            .stacks_to_start(["admin", "debug"], &[])
+            .unwrap()
+            .is_empty());
+
+        // Other commands see every stack.
+        assert_eq!(config.stacks(Vec::<String>::new()).unwrap().len(), 5);
+        assert_eq!(
+            keys(config.stacks_with_dependants(["db"]).unwrap()),
+            vec!["db", "admin", "proxy", "app", "debug"]
+        );
+
+        // Nothing starts when no stack does so automatically.
+        let config = from_str(
+            "
+            stacks:
+                admin:
+                    autostart: false
+            ",
+        )
+        .unwrap();
+        assert!(config
+            .stacks_to_start(Vec::<String>::new(), &[])
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            keys(
+                config
+                    .stacks_to_start(["admin"], &named(&["admin"]))
+                    .unwrap()
+            ),
+            vec!["admin"]
+        );
+    }
+
+    #[test]
+    fn ordering() {
+        let config = from_str(
+            "
+            stacks:
+                top:
+                    depends_on:
+                        - right
+                        - left
+                right:
+                    depends_on:
+                        - base
+                left:
+                    depends_on:
+                        - base
+                base: {}
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            keys(config.stacks_with_dependencies(["top"]).unwrap()),
+            vec!["base", "left", "right", "top"]
+        );
+        assert_eq!(
+            keys(config.stacks_with_dependants(["base"]).unwrap()),
+            vec!["base", "left", "right", "top"]
+        );
+        assert_eq!(
+            keys(config.stacks_with_dependants(["right"]).unwrap()),
+            vec!["right", "top"]
+        );
+
+        let config = from_str(
+            "
+            stacks:
+                zeta: {}
+                alpha:
+                    depends_on:
+                        - zeta
+                gamma: {}
+                beta:
+                    depends_on:
+                        - alpha
+                delta:
+                    depends_on:
+                        - gamma
+                mu: {}
+            ",
+        )
+        .unwrap();
+        let list: [&str; 0] = [];
+        assert_eq!(
+            keys(config.stacks_with_dependencies(list).unwrap()),
+            vec!["gamma", "mu", "zeta", "alpha", "delta", "beta"]
+        );
+        assert_eq!(
+            keys(config.stacks_with_dependants(list).unwrap()),
+            vec!["gamma", "mu", "zeta", "alpha", "delta", "beta"]
+        );
+        assert_eq!(
+            keys(
+                config
+                    .stacks_with_dependencies(["beta", "delta", "mu"])
+                    .unwrap()
+            ),
+            vec!["gamma", "mu", "zeta", "alpha", "delta", "beta"]
+        );
+        assert_eq!(
+            keys(config.stacks_with_dependants(["zeta", "gamma"]).unwrap()),
+            vec!["gamma", "zeta", "alpha", "delta", "beta"]
+        );
+    }
+
+    /// A small xorshift generator so the random graphs are reproducible.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, max: usize) -> usize {
+            (self.next() % max as u64) as usize
+        }
+    }
+
+    /// Builds a random acyclic config. Stacks only depend on stacks created
+    /// before them but names are shuffled so that creation order doesn't match
+    /// key order.
+    fn random_config(random: &mut Random, count: usize) -> Config {
+        let mut names: Vec<String> = (0..count).map(|i| format!("s{:02}", i)).collect();
+        for i in (1..names.len()).rev() {
+            names.swap(i, random.below(i + 1));
+        }
+
+        let mut yaml = "stacks:\n".to_string();
+        for (index, name) in names.iter().enumerate() {
+            yaml.push_str(&format!("  {}:\n    depends_on: [", name));
+            let dependencies: Vec<&str> = names[..index]
+                .iter()
+                .filter(|_| random.below(4) == 0)
+                .map(String::as_str)
+                .collect();
+            yaml.push_str(&dependencies.join(", "));
+            yaml.push_str("]\n");
+        }
+
+        from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn random_graphs() {
+        let mut random = Random(0x2545f4914f6cdd1d);
+
+        for _ in 0..200 {
+            let count = 1 + random.below(15);
+            let config = random_config(&mut random, count);
+            let selection: Vec<String> = config
+                .stacks
+                .keys()
+                .filter(|_| random.below(3) == 0)
+                .cloned()
+                .collect();
+
+            let ordered = keys(config.stacks_with_dependencies(&selection).unwrap());
+            let position = |key: &String| ordered.iter().position(|k| k == key);
+            for key in ordered.iter() {
+                for dependency in config.stacks[key].dependencies.iter() {
+                    assert!(
+                        position(dependency).unwrap() < position(key).unwrap(),
+                        "{} should come before {} in {:?}",
+                        dependency,
+                        key,
+                        ordered
+                    );
+                }
+            }
+            for key in selection.iter() {
+                assert!(ordered.contains(key));
+            }
+
+            let ordered = keys(config.stacks_with_dependants(&selection).unwrap());
+            let position = |key: &String| ordered.iter().position(|k| k == key);
+            for key in ordered.iter() {
+                for dependant in config.stacks[key].dependants.iter() {
+                    let after = position(dependant).unwrap();
+                    assert!(
+                        position(key).unwrap() < after,
+                        "{} should come before {} in {:?}",
+                        key,
+                        dependant,
+                        ordered
+                    );
+                }
+            }
+            for key in selection.iter() {
+                assert!(ordered.contains(key));
+            }
+        }
+    }
+
+    /// Serializes a config, parses the result again and checks that nothing
+    /// changed. Returns the serialized form.
+    fn round_trip(base_dir: &std::path::Path, config: &Config) -> String {
+        let yaml = serde_yaml::to_string(config).unwrap();
+        let parsed = Config::from_reader(base_dir, yaml.as_bytes()).unwrap();
+        assert_eq!(serde_yaml::to_string(&parsed).unwrap(), yaml);
+
+        assert_eq!(
+            parsed.stacks.keys().collect::<Vec<_>>(),
+            config.stacks.keys().collect::<Vec<_>>()
+        );
+        for (key, stack) in config.stacks.iter() {
+            let other = &parsed.stacks[key];
+            assert_eq!(other.name, stack.name);
+            assert_eq!(other.dependencies, stack.dependencies);
+            assert_eq!(other.dependants, stack.dependants);
+            assert_eq!(other.env_file_environment, stack.env_file_environment);
+        }
+        assert_eq!(parsed.command, config.command);
+        assert_eq!(parsed.env_file_environment, config.env_file_environment);
+
+        yaml
+    }
+
+    #[test]
+    fn serialize() {
+        for yaml in [
+            "stacks: {}",
+            "
+            stacks:
+                foo:
+                    name: baz
+                bar: {}
+            ",
+            "
+            stacks:
+                web: {}
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+                    status: ./agent running
+                    depends_on:
+                        - web
+            ",
+        ] {
+            round_trip(&PathBuf::default(), &from_str(yaml).unwrap());
+        }
+
+        let mut random = Random(0x9e3779b97f4a7c15);
+        for _ in 0..50 {
+            let count = 1 + random.below(15);
+            round_trip(&PathBuf::default(), &random_config(&mut random, count));
+        }
+    }
+
+    #[test]
+    fn serialize_everything() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("global.env"), "GLOBAL=1\n").unwrap();
+        std::fs::write(temp.path().join("web.env"), "SHARED=web\n").unwrap();
+
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            command: podman compose
+            engine: podman
+            backend: swarm
+            environment:
+                B: 2
+                A: 1
+            env_file: global.env
+            inherit_env: false
+            pass_env: [HOME, LC_*]
+            warn_missing_env: true
+            default_stacks: [web]
+            kill_grace: 5
+            stop_grace: 2m
+            min_compose_version: 2.20.0
+            history_file: history.log
+            history_max_size: 1000
+            log_dir: logs
+            remove_orphans: true
+            notify:
+                enabled: true
+                threshold: 1m
+            stacks:
+                db:
+                    name: database
+                    description: The database
+                    directory: data
+                    file: [a.yml, b.yml]
+                    environment:
+                        PORT: 5432
+                    env_file: web.env
+                    pass_env: [USER]
+                    allow_outside_base: true
+                    retries: 3
+                    backend: compose
+                    stop_grace: 30
+                    concurrency_group: storage
+                    remove_orphans: false
+                web:
+                    file: compose.yml
+                    depends_on: [db]
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+                    status: ./agent running
+                    logs: ./agent logs
+                    depends_on: [web, db]
+            tasks:
+                reset:
+                    description: Start again
+                    steps:
+                        - { command: down, stacks: [db], args: [-v] }
+                        - { run: ./seed, stack: web }
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let yaml = round_trip(temp.path(), &config);
+
+        // Fields are written in their canonical forms.
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        let list = |s: &str| serde_yaml::from_str::<serde_yaml::Value>(s).unwrap();
+        assert_eq!(value["command"], list("[podman, compose]"));
+        assert_eq!(value["stop_grace"], "120s");
+        assert_eq!(value["notify"]["threshold"], "60s");
+        assert_eq!(value["env_file"], list("[global.env]"));
+        assert_eq!(value["stacks"]["web"]["file"], list("[compose.yml]"));
+        assert_eq!(value["stacks"]["db"]["stop_grace"], "30s");
+        assert_eq!(value["stacks"]["agent"]["depends_on"], list("[db, web]"));
+
+        // Anything worked out when loading is left out.
+        let web = value["stacks"]["web"].as_mapping().unwrap();
+        let fields: Vec<&str> = web.keys().map(|key| key.as_str().unwrap()).collect();
+        assert_eq!(fields, vec!["name", "file", "depends_on"]);
+        assert!(!yaml.contains("dependants"));
+        assert!(!yaml.contains("SHARED"));
+
+        let parsed = Config::from_reader(temp.path(), yaml.as_bytes()).unwrap();
+        let db = &parsed.stacks["db"];
+        assert_eq!(db.name, "database");
+        assert_eq!(db.stop_grace, Some(std::time::Duration::from_secs(30)));
+        assert_eq!(db.concurrency_group.as_deref(), Some("storage"));
+        assert_eq!(db.environment["PORT"], "5432");
+        assert_eq!(parsed.engine, config.engine);
+        assert_eq!(parsed.backend, config.backend);
+        assert_eq!(parsed.notify, config.notify);
+        assert!(!parsed.inherit_env);
+        assert_eq!(parsed.kill_grace, 5);
+        assert_eq!(parsed.pass_env, config.pass_env);
+        assert_eq!(parsed.history_max_size, Some(1000));
+        assert_eq!(parsed.tasks, config.tasks);
+    }
+
+    #[test]
+    fn scripts() {
+        let config = from_str(
+            "
+            stacks:
+                web: {}
+                agent:
+                    type: script
+                    up: ./agent start
+                    down: ./agent stop
+                    status: ./agent running
+                    depends_on:
+                        - web
+            ",
+        )
+        .unwrap();
+
+        let web = config.stacks.get("web").unwrap();
+        assert_eq!(web.kind, StackKind::Compose);
+        assert_eq!(web.scripts, Scripts::default());
+
+        let agent = config.stacks.get("agent").unwrap();
+        assert_eq!(agent.kind, StackKind::Script);
+        assert_eq!(agent.scripts.get("up"), Some("./agent start"));
+        assert_eq!(agent.scripts.get("down"), Some("./agent stop"));
+        assert_eq!(agent.scripts.get("ps"), Some("./agent running"));
+        assert_eq!(agent.scripts.get("logs"), None);
+        assert_eq!(agent.scripts.get("pull"), None);
+        assert_eq!(
+            keys(config.stacks_with_dependencies(["agent"]).unwrap()),
+            vec!["web", "agent"]
+        );
+
+        assert_eq!(
+            from_str(
+                "
+                stacks:
+                    agent:
+                        type: script
+                        up: ./agent start
+                "
+            )
+            .err(),
+            Some("Script stack `agent` must define both `up` and `down` scripts.".to_string())
+        );
+
+        assert_eq!(
+            from_str(
+                "
+                stacks:
+                    web:
+                        up: ./start
+                "
+            )
+            .err(),
+            Some(
+                "Stack `web` defines scripts but is not a script stack. Set `type: script` to \
+                use them."
+                    .to_string()
+            )
+        );
+
+        assert!(from_str(
+            "
+            stacks:
+                agent:
+                    type: shell
+            "
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn commands() {
+        let strings =
+            |list: &[&str]| -> Vec<String> { list.iter().map(|s| s.to_string()).collect() };
+
+        assert_eq!(
+            parse_command("/usr/local/bin/wrapper  docker compose"),
+            Ok(strings(&["/usr/local/bin/wrapper", "docker", "compose"]))
+        );
+        assert_eq!(
+            parse_command("[\"/opt/my tools/compose\", --verbose]"),
+            Ok(strings(&["/opt/my tools/compose", "--verbose"]))
+        );
+        assert!(parse_command("").is_err());
+        assert!(parse_command("[]").is_err());
+        assert!(parse_command("[unclosed").is_err());
+    }
+
+    #[test]
+    fn command_precedence() {
+        let temp = tempfile::tempdir().unwrap();
+        let strings =
+            |list: &[&str]| -> Vec<String> { list.iter().map(|s| s.to_string()).collect() };
+        let detect = |yaml: &str, command_override: Option<&[&str]>| {
+            let mut config = Config::from_reader(temp.path(), yaml.as_bytes()).unwrap();
+            config.command_override = command_override.map(strings);
+            // Nothing can be found so detection falls back to the default.
+            config.detect_engine(Some(temp.path().as_os_str()));
+            config.command
+        };
+
+        let configured = "command: podman compose\nstacks: {}";
+        assert_eq!(detect("stacks: {}", None), strings(&["docker", "compose"]));
+        assert_eq!(detect(configured, None), strings(&["podman", "compose"]));
+        assert_eq!(
+            detect("stacks: {}", Some(&["wrapper"])),
+            strings(&["wrapper"])
+        );
+        assert_eq!(
+            detect(configured, Some(&["wrapper", "compose"])),
+            strings(&["wrapper", "compose"])
+        );
+
+        // The override isn't part of the config as written.
+        let mut config = Config::from_reader(temp.path(), configured.as_bytes()).unwrap();
+        config.command_override = Some(strings(&["wrapper"]));
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert!(!yaml.contains("wrapper"));
+    }
 }
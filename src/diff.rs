@@ -0,0 +1,397 @@
+use std::fmt::Write;
+
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+/// What to replace changed environment values with.
+const REDACTED: &str = "<changed>";
+
+/// What to replace unchanged environment values shown as context with.
+const HIDDEN: &str = "<hidden>";
+
+/// How a unified diff is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Unchanged lines to show around each change.
+    pub context: usize,
+    /// The most lines of the diff to show before truncating it.
+    pub max_lines: usize,
+    pub color: bool,
+    /// Show the values of environment variables that changed rather than
+    /// redacting them.
+    pub show_secrets: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            context: 3,
+            max_lines: 200,
+            color: false,
+            show_secrets: false,
+        }
+    }
+}
+
+/// A line of a diff, by its index in the old or new text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Same(usize, usize),
+    Removed(usize),
+    Added(usize),
+}
+
+/// Finds the shortest set of changes from the old lines to the new lines.
+pub fn changes(old: &[&str], new: &[&str]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, old, new) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        match tag {
+            DiffTag::Equal => {
+                changes.extend(old_range.zip(new_range).map(|(i, j)| Change::Same(i, j)))
+            }
+            DiffTag::Delete | DiffTag::Insert | DiffTag::Replace => {
+                changes.extend(old_range.map(Change::Removed));
+                changes.extend(new_range.map(Change::Added));
+            }
+        }
+    }
+
+    changes
+}
+
+/// Marks the lines of a rendered compose config that are entries in a
+/// service's `environment`, in either the mapping or the list form.
+pub fn environment_lines(lines: &[&str]) -> Vec<bool> {
+    let indent = |line: &str| line.len() - line.trim_start().len();
+
+    let mut marks = vec![false; lines.len()];
+    let mut block: Option<usize> = None;
+    for (index, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Lists may be at the same indentation as their key.
+        if let Some(start) = block {
+            let entry = indent(line) > start
+                || (indent(line) == start && line.trim_start().starts_with("- "));
+            if entry {
+                marks[index] = true;
+                continue;
+            }
+            block = None;
+        }
+
+        if line.trim() == "environment:" {
+            block = Some(indent(line));
+        }
+    }
+
+    marks
+}
+
+/// Replaces the value of an environment entry with the placeholder.
+pub fn redact(line: &str, placeholder: &str) -> String {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+
+    if let Some(entry) = trimmed.strip_prefix("- ") {
+        return match entry.split_once('=') {
+            Some((key, _)) => format!("{}- {}={}", indent, key, placeholder),
+            None => line.to_owned(),
+        };
+    }
+
+    match trimmed.split_once(':') {
+        Some((key, _)) => format!("{}{}: {}", indent, key, placeholder),
+        None => line.to_owned(),
+    }
+}
+
+/// Renders a unified diff between two versions of a rendered compose config,
+/// or `None` if they are the same. Environment values are redacted unless
+/// asked not to be, including those shown unchanged around a change, and long
+/// diffs are truncated.
+pub fn unified(old: &str, new: &str, options: &DiffOptions) -> Option<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let changes = changes(&old_lines, &new_lines);
+    if changes
+        .iter()
+        .all(|change| matches!(change, Change::Same(..)))
+    {
+        return None;
+    }
+
+    let old_environment = environment_lines(&old_lines);
+    let new_environment = environment_lines(&new_lines);
+    let line = |change: &Change| -> String {
+        let (sign, text, secret) = match *change {
+            Change::Same(i, _) => (' ', old_lines[i], old_environment[i]),
+            Change::Removed(i) => ('-', old_lines[i], old_environment[i]),
+            Change::Added(j) => ('+', new_lines[j], new_environment[j]),
+        };
+        let text = match (secret && !options.show_secrets, sign) {
+            (false, _) => text.to_owned(),
+            (true, ' ') => redact(text, HIDDEN),
+            (true, _) => redact(text, REDACTED),
+        };
+
+        match (options.color, sign) {
+            (true, '-') => format!("\x1b[31m-{}\x1b[0m", text),
+            (true, '+') => format!("\x1b[32m+{}\x1b[0m", text),
+            _ => format!("{}{}", sign, text),
+        }
+    };
+
+    // Group the changes into hunks with context around them, merging hunks
+    // whose context would overlap.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, change) in changes.iter().enumerate() {
+        if matches!(change, Change::Same(..)) {
+            continue;
+        }
+        let start = index.saturating_sub(options.context);
+        let end = (index + options.context + 1).min(changes.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let count = |changes: &[Change], side: fn(&Change) -> bool| {
+        changes.iter().filter(|change| side(change)).count()
+    };
+    let old: fn(&Change) -> bool = |change| !matches!(change, Change::Added(_));
+    let new: fn(&Change) -> bool = |change| !matches!(change, Change::Removed(_));
+
+    let mut lines = vec!["--- recorded".to_owned(), "+++ current".to_owned()];
+    for (start, end) in hunks {
+        let hunk = &changes[start..end];
+
+        // Empty ranges are numbered by the line before them.
+        let range = |side: fn(&Change) -> bool| {
+            let before = count(&changes[..start], side);
+            match count(hunk, side) {
+                0 => format!("{},0", before),
+                length => format!("{},{}", before + 1, length),
+            }
+        };
+        let header = format!("@@ -{} +{} @@", range(old), range(new));
+        lines.push(if options.color {
+            format!("\x1b[36m{}\x1b[0m", header)
+        } else {
+            header
+        });
+        lines.extend(hunk.iter().map(line));
+    }
+
+    let mut diff = String::new();
+    for line in lines.iter().take(options.max_lines) {
+        let _ = writeln!(diff, "{}", line);
+    }
+    if lines.len() > options.max_lines {
+        let _ = writeln!(
+            diff,
+            "... {} more lines of the diff not shown",
+            lines.len() - options.max_lines
+        );
+    }
+
+    Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changes, environment_lines, redact, unified, Change, DiffOptions};
+
+    const RECORDED: &str = "\
+name: web
+services:
+  app:
+    environment:
+      API_KEY: abc123
+      DEBUG: \"false\"
+    image: app:1
+    ports:
+      - mode: ingress
+        target: 80
+        published: \"8080\"
+  worker:
+    environment:
+      - TOKEN=secret
+    image: worker:1
+";
+
+    const CURRENT: &str = "\
+name: web
+services:
+  app:
+    environment:
+      API_KEY: def456
+      DEBUG: \"false\"
+    image: app:2
+    ports:
+      - mode: ingress
+        target: 80
+        published: \"8080\"
+  worker:
+    environment:
+      - TOKEN=other
+    image: worker:1
+";
+
+    #[test]
+    fn changed_lines() {
+        assert_eq!(
+            changes(&["a", "b", "c"], &["a", "c", "d"]),
+            vec![
+                Change::Same(0, 0),
+                Change::Removed(1),
+                Change::Same(2, 1),
+                Change::Added(2),
+            ]
+        );
+        assert_eq!(changes(&[], &["a"]), vec![Change::Added(0)]);
+        assert_eq!(changes(&["a"], &[]), vec![Change::Removed(0)]);
+
+        // Large configs don't need memory for every pair of lines.
+        let old: Vec<String> = (0..100_000).map(|i| format!("line{}", i)).collect();
+        let mut new = old.clone();
+        new[50_000] = "changed".to_string();
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let new: Vec<&str> = new.iter().map(String::as_str).collect();
+        let changed: Vec<Change> = changes(&old, &new)
+            .into_iter()
+            .filter(|change| !matches!(change, Change::Same(..)))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![Change::Removed(50_000), Change::Added(50_000)]
+        );
+    }
+
+    #[test]
+    fn environments() {
+        let lines: Vec<&str> = RECORDED.lines().collect();
+        let marked: Vec<&str> = environment_lines(&lines)
+            .into_iter()
+            .zip(&lines)
+            .filter(|(marked, _)| *marked)
+            .map(|(_, line)| *line)
+            .collect();
+        assert_eq!(
+            marked,
+            vec![
+                "      API_KEY: abc123",
+                "      DEBUG: \"false\"",
+                "      - TOKEN=secret"
+            ]
+        );
+
+        // Lists can be at the same indentation as their key.
+        assert_eq!(
+            environment_lines(&["environment:", "- A=1", "image: app"]),
+            vec![false, true, false]
+        );
+
+        assert_eq!(
+            redact("      API_KEY: abc123", "<changed>"),
+            "      API_KEY: <changed>"
+        );
+        assert_eq!(
+            redact("      - TOKEN=secret", "<changed>"),
+            "      - TOKEN=<changed>"
+        );
+        assert_eq!(redact("      - TOKEN", "<changed>"), "      - TOKEN");
+    }
+
+    #[test]
+    fn redacted() {
+        assert_eq!(
+            unified(RECORDED, CURRENT, &DiffOptions::default()).unwrap(),
+            "\
+--- recorded
++++ current
+@@ -2,14 +2,14 @@
+ services:
+   app:
+     environment:
+-      API_KEY: <changed>
++      API_KEY: <changed>
+       DEBUG: <hidden>
+-    image: app:1
++    image: app:2
+     ports:
+       - mode: ingress
+         target: 80
+         published: \"8080\"
+   worker:
+     environment:
+-      - TOKEN=<changed>
++      - TOKEN=<changed>
+     image: worker:1
+"
+        );
+
+        let options = DiffOptions {
+            context: 1,
+            show_secrets: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unified(RECORDED, CURRENT, &options).unwrap(),
+            "\
+--- recorded
++++ current
+@@ -4,5 +4,5 @@
+     environment:
+-      API_KEY: abc123
++      API_KEY: def456
+       DEBUG: \"false\"
+-    image: app:1
++    image: app:2
+     ports:
+@@ -13,3 +13,3 @@
+     environment:
+-      - TOKEN=secret
++      - TOKEN=other
+     image: worker:1
+"
+        );
+
+        assert_eq!(unified(RECORDED, RECORDED, &DiffOptions::default()), None);
+    }
+
+    #[test]
+    fn formatting() {
+        let options = DiffOptions {
+            color: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unified("a: 1\n", "a: 2\nb: 3\n", &options).unwrap(),
+            "--- recorded\n+++ current\n\
+            \x1b[36m@@ -1,1 +1,2 @@\x1b[0m\n\
+            \x1b[31m-a: 1\x1b[0m\n\
+            \x1b[32m+a: 2\x1b[0m\n\
+            \x1b[32m+b: 3\x1b[0m\n"
+        );
+
+        // Empty ranges start at the line before.
+        assert_eq!(
+            unified("", "a: 1\n", &DiffOptions::default()).unwrap(),
+            "--- recorded\n+++ current\n@@ -0,0 +1,1 @@\n+a: 1\n"
+        );
+
+        let old: String = (0..100).map(|i| format!("line{}\n", i)).collect();
+        let new: String = (0..100).map(|i| format!("changed{}\n", i)).collect();
+        let options = DiffOptions {
+            max_lines: 10,
+            ..Default::default()
+        };
+        let diff = unified(&old, &new, &options).unwrap();
+        assert_eq!(diff.lines().count(), 11);
+        assert!(diff.ends_with("... 193 more lines of the diff not shown\n"));
+    }
+}
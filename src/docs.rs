@@ -0,0 +1,340 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, Command};
+
+use crate::error::{Error, Result};
+
+/// The page that links to every subcommand.
+pub const INDEX: &str = "index.md";
+
+/// The name of the page for a subcommand.
+pub fn page_name(subcommand: &Command) -> String {
+    format!("{}.md", subcommand.get_name())
+}
+
+/// The subcommands that get a page, leaving out hidden ones and clap's
+/// `help`.
+fn subcommands(command: &Command) -> Vec<&Command> {
+    command
+        .get_subcommands()
+        .filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help")
+        .collect()
+}
+
+/// The arguments to document, `globals` is whether to include the global
+/// options that every subcommand accepts.
+fn arguments(command: &Command, globals: bool) -> (Vec<&Arg>, Vec<&Arg>) {
+    command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .filter(|arg| !matches!(arg.get_id().as_str(), "help" | "version"))
+        .filter(|arg| globals || !arg.is_global_set())
+        .partition(|arg| arg.is_positional())
+}
+
+/// Table cells can't contain newlines or unescaped pipes.
+fn cell(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('|', "\\|")
+}
+
+/// How the argument is written on the command line, like `-n, --count <COUNT>`.
+fn signature(arg: &Arg) -> String {
+    let value = match arg.get_value_names() {
+        Some(names) => names
+            .iter()
+            .map(|name| format!("<{}>", name))
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => format!("<{}>", arg.get_id().as_str().to_uppercase()),
+    };
+    let repeated = arg
+        .get_num_args()
+        .is_some_and(|range| range.max_values() > 1);
+
+    if arg.is_positional() {
+        return if repeated {
+            format!("{}...", value)
+        } else {
+            value
+        };
+    }
+
+    let mut names = Vec::new();
+    if let Some(short) = arg.get_short() {
+        names.push(format!("-{}", short));
+    }
+    if let Some(long) = arg.get_long() {
+        names.push(format!("--{}", long));
+    }
+    let mut signature = names.join(", ");
+    if arg.get_action().takes_values() {
+        let _ = write!(signature, " {}", value);
+    }
+    signature
+}
+
+/// The argument's help along with its possible values, default and
+/// environment variable.
+fn description(arg: &Arg) -> String {
+    let mut description = arg
+        .get_long_help()
+        .or_else(|| arg.get_help())
+        .map(|help| help.to_string())
+        .unwrap_or_default();
+    let mut add = |text: String| {
+        if !description.is_empty() && !description.ends_with('.') {
+            description.push('.');
+        }
+        if !description.is_empty() {
+            description.push(' ');
+        }
+        description.push_str(&text);
+    };
+
+    if arg.get_action().takes_values() {
+        let values: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| format!("`{}`", value.get_name()))
+            .collect();
+        if !values.is_empty() {
+            add(format!("One of {}.", values.join(", ")));
+        }
+
+        let defaults: Vec<String> = arg
+            .get_default_values()
+            .iter()
+            .map(|value| format!("`{}`", value.to_string_lossy()))
+            .collect();
+        if !defaults.is_empty() {
+            add(format!("Defaults to {}.", defaults.join(", ")));
+        }
+    }
+
+    if let Some(env) = arg.get_env() {
+        add(format!("Also set by `{}`.", env.to_string_lossy()));
+    }
+
+    cell(&description)
+}
+
+fn write_table(page: &mut String, heading: &str, args: &[&Arg]) {
+    if args.is_empty() {
+        return;
+    }
+
+    let _ = writeln!(page, "## {}\n", heading);
+    let _ = writeln!(page, "| {} | Description |", heading.trim_end_matches('s'));
+    let _ = writeln!(page, "| --- | --- |");
+    for arg in args {
+        let _ = writeln!(page, "| `{}` | {} |", signature(arg), description(arg));
+    }
+    page.push('\n');
+}
+
+fn write_usage(page: &mut String, command: &mut Command) {
+    let usage = command.render_usage().to_string();
+    let usage = usage.trim().trim_start_matches("Usage:").trim();
+    let _ = writeln!(page, "## Usage\n\n```\n{}\n```\n", usage);
+}
+
+/// The text describing a command, the long form if there is one.
+fn about(command: &Command) -> String {
+    command
+        .get_long_about()
+        .or_else(|| command.get_about())
+        .map(|about| about.to_string())
+        .unwrap_or_default()
+}
+
+/// Renders the index page, listing every subcommand and the global options.
+/// The command must have been built.
+pub fn render_index(command: &mut Command) -> String {
+    let mut page = format!("# {}\n\n", command.get_name());
+    let about = about(command);
+    if !about.is_empty() {
+        let _ = writeln!(page, "{}\n", about.trim());
+    }
+    write_usage(&mut page, command);
+
+    let _ = writeln!(page, "## Commands\n");
+    let _ = writeln!(page, "| Command | Description |");
+    let _ = writeln!(page, "| --- | --- |");
+    for subcommand in subcommands(command) {
+        let _ = writeln!(
+            page,
+            "| [`{}`]({}) | {} |",
+            subcommand.get_name(),
+            page_name(subcommand),
+            cell(
+                &subcommand
+                    .get_about()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default()
+            )
+        );
+    }
+    page.push('\n');
+
+    let (positionals, options) = arguments(command, true);
+    write_table(&mut page, "Arguments", &positionals);
+    write_table(&mut page, "Global options", &options);
+
+    page.truncate(page.trim_end().len());
+    page.push('\n');
+    page
+}
+
+/// Renders the page for a subcommand. The parent must have been built.
+pub fn render_page(parent: &Command, subcommand: &mut Command) -> String {
+    let mut page = format!("# {} {}\n\n", parent.get_name(), subcommand.get_name());
+    let about = about(subcommand);
+    if !about.is_empty() {
+        let _ = writeln!(page, "{}\n", about.trim());
+    }
+    write_usage(&mut page, subcommand);
+
+    let (positionals, options) = arguments(subcommand, false);
+    write_table(&mut page, "Arguments", &positionals);
+    write_table(&mut page, "Options", &options);
+
+    let _ = writeln!(
+        page,
+        "The [global options]({}#global-options) can also be given.",
+        INDEX
+    );
+    page
+}
+
+/// Writes a markdown page for each subcommand of the command into the
+/// directory along with an index linking to them, returning the files
+/// written.
+pub fn generate(command: &Command, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut command = command.clone();
+    command.build();
+
+    fs::create_dir_all(dir)
+        .map_err(|e| Error::io(format!("Unable to create {}", dir.display()), e))?;
+    let write = |name: &str, contents: String| -> Result<PathBuf> {
+        let path = dir.join(name);
+        fs::write(&path, contents)
+            .map_err(|e| Error::io(format!("Unable to write {}", path.display()), e))?;
+        Ok(path)
+    };
+
+    let mut written = vec![write(INDEX, render_index(&mut command))?];
+    let names: Vec<String> = subcommands(&command)
+        .into_iter()
+        .map(|subcommand| subcommand.get_name().to_owned())
+        .collect();
+    for name in names {
+        let mut subcommand = command.find_subcommand(&name).unwrap().clone();
+        let page = render_page(&command, &mut subcommand);
+        written.push(write(&page_name(&subcommand), page)?);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, render_index, render_page, INDEX};
+    use clap::{Arg, ArgAction, Command};
+
+    fn command() -> Command {
+        Command::new("tool")
+            .about("Does things")
+            .arg(
+                Arg::new("file")
+                    .short('f')
+                    .long("file")
+                    .env("TOOL_FILE")
+                    .global(true)
+                    .help("The config file"),
+            )
+            .subcommand(
+                Command::new("run")
+                    .about("Runs things")
+                    .long_about("Runs things.\n\nPipes | are escaped.")
+                    .arg(
+                        Arg::new("count")
+                            .short('n')
+                            .long("count")
+                            .default_value("20")
+                            .help("How many"),
+                    )
+                    .arg(
+                        Arg::new("format")
+                            .long("format")
+                            .value_parser(["dot", "json"])
+                            .help("The format"),
+                    )
+                    .arg(
+                        Arg::new("quiet")
+                            .long("quiet")
+                            .action(ArgAction::SetTrue)
+                            .help("Say less"),
+                    )
+                    .arg(Arg::new("args").num_args(1..).help("Passed through")),
+            )
+            .subcommand(Command::new("secret").hide(true))
+    }
+
+    #[test]
+    fn pages() {
+        let mut command = command();
+        command.build();
+
+        assert_eq!(
+            render_index(&mut command),
+            "# tool\n\n\
+            Does things\n\n\
+            ## Usage\n\n\
+            ```\ntool [OPTIONS] [COMMAND]\n```\n\n\
+            ## Commands\n\n\
+            | Command | Description |\n\
+            | --- | --- |\n\
+            | [`run`](run.md) | Runs things |\n\n\
+            ## Global options\n\n\
+            | Global option | Description |\n\
+            | --- | --- |\n\
+            | `-f, --file <FILE>` | The config file. Also set by `TOOL_FILE`. |\n"
+        );
+
+        let mut run = command.find_subcommand("run").unwrap().clone();
+        assert_eq!(
+            render_page(&command, &mut run),
+            "# tool run\n\n\
+            Runs things.\n\nPipes | are escaped.\n\n\
+            ## Usage\n\n\
+            ```\ntool run [OPTIONS] [args]...\n```\n\n\
+            ## Arguments\n\n\
+            | Argument | Description |\n\
+            | --- | --- |\n\
+            | `<ARGS>...` | Passed through |\n\n\
+            ## Options\n\n\
+            | Option | Description |\n\
+            | --- | --- |\n\
+            | `-n, --count <COUNT>` | How many. Defaults to `20`. |\n\
+            | `--format <FORMAT>` | The format. One of `dot`, `json`. |\n\
+            | `--quiet` | Say less |\n\n\
+            The [global options](index.md#global-options) can also be given.\n"
+        );
+    }
+
+    #[test]
+    fn files() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("docs");
+        let written = generate(&command(), &dir).unwrap();
+
+        assert_eq!(written, vec![dir.join(INDEX), dir.join("run.md")]);
+        assert!(!dir.join("secret.md").exists());
+    }
+}
@@ -0,0 +1,248 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// The container engine that runs compose projects.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    #[default]
+    Docker,
+    Podman,
+}
+
+/// Flags that stacks adds to commands that podman's compose does not support.
+const PODMAN_UNSUPPORTED_FLAGS: [&str; 1] = ["--wait"];
+
+/// The compose command used when none is configured.
+pub fn default_command() -> Vec<String> {
+    vec!["docker".to_string(), "compose".to_string()]
+}
+
+impl Engine {
+    /// The engine that a compose command runs on, judged by its program.
+    pub fn for_command(command: &[String]) -> Self {
+        let name = command
+            .first()
+            .and_then(|program| Path::new(program).file_stem())
+            .and_then(|name| name.to_str());
+
+        match name {
+            Some("podman" | "podman-compose") => Engine::Podman,
+            _ => Engine::Docker,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+        }
+    }
+
+    /// Whether the engine's compose supports a flag that stacks would add.
+    pub fn supports(&self, flag: &str) -> bool {
+        match self {
+            Engine::Docker => true,
+            Engine::Podman => !PODMAN_UNSUPPORTED_FLAGS.contains(&flag),
+        }
+    }
+
+    /// The arguments that ask the engine's daemon whether it is running.
+    pub fn info_args(&self) -> &'static [&'static str] {
+        match self {
+            Engine::Docker => &["info", "--format", "{{.ServerVersion}}"],
+            Engine::Podman => &["info"],
+        }
+    }
+}
+
+/// Works out the engine to use and the compose command to run it with. An
+/// explicit command is always used as is. Otherwise podman is used when
+/// configured or when docker is missing but podman is present, with
+/// `podman compose` preferred over `podman-compose`. Docker falls back to a
+/// standalone `docker-compose` when the compose plugin is missing.
+///
+/// `exists` reports whether a program can be found and `plugin` whether
+/// `docker compose` works, it is only called when needed.
+pub fn detect<F, P>(
+    configured: Option<Engine>,
+    command: Option<&[String]>,
+    exists: F,
+    plugin: P,
+) -> (Engine, Vec<String>)
+where
+    F: Fn(&str) -> bool,
+    P: FnOnce() -> bool,
+{
+    if let Some(command) = command {
+        let engine = configured.unwrap_or_else(|| Engine::for_command(command));
+        return (engine, command.to_vec());
+    }
+
+    let engine = configured.unwrap_or_else(|| {
+        if !exists("docker") && (exists("podman") || exists("podman-compose")) {
+            Engine::Podman
+        } else {
+            Engine::Docker
+        }
+    });
+
+    let command = match engine {
+        Engine::Podman if !exists("podman") && exists("podman-compose") => {
+            vec!["podman-compose".to_string()]
+        }
+        Engine::Podman => vec!["podman".to_string(), "compose".to_string()],
+        Engine::Docker if !plugin() && exists("docker-compose") => {
+            vec!["docker-compose".to_string()]
+        }
+        Engine::Docker => default_command(),
+    };
+
+    (engine, command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect, Engine};
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn for_command() {
+        assert_eq!(
+            Engine::for_command(&strings(&["docker", "compose"])),
+            Engine::Docker
+        );
+        assert_eq!(
+            Engine::for_command(&strings(&["/usr/bin/podman", "compose"])),
+            Engine::Podman
+        );
+        assert_eq!(
+            Engine::for_command(&strings(&["podman-compose"])),
+            Engine::Podman
+        );
+        assert_eq!(
+            Engine::for_command(&strings(&["docker-compose"])),
+            Engine::Docker
+        );
+        assert_eq!(Engine::for_command(&[]), Engine::Docker);
+    }
+
+    #[test]
+    fn detection() {
+        let installed =
+            |programs: &'static [&'static str]| move |name: &str| programs.contains(&name);
+
+        // Docker is preferred when present.
+        assert_eq!(
+            detect(None, None, installed(&["docker", "podman"]), || true),
+            (Engine::Docker, strings(&["docker", "compose"]))
+        );
+        assert_eq!(
+            detect(None, None, installed(&[]), || false),
+            (Engine::Docker, strings(&["docker", "compose"]))
+        );
+        assert_eq!(
+            detect(None, None, installed(&["podman"]), || false),
+            (Engine::Podman, strings(&["podman", "compose"]))
+        );
+        assert_eq!(
+            detect(None, None, installed(&["podman-compose"]), || false),
+            (Engine::Podman, strings(&["podman-compose"]))
+        );
+        assert_eq!(
+            detect(None, None, installed(&["podman", "podman-compose"]), || {
+                false
+            }),
+            (Engine::Podman, strings(&["podman", "compose"]))
+        );
+
+        // The engine can be configured.
+        assert_eq!(
+            detect(
+                Some(Engine::Podman),
+                None,
+                installed(&["docker", "podman"]),
+                || true
+            ),
+            (Engine::Podman, strings(&["podman", "compose"]))
+        );
+        assert_eq!(
+            detect(Some(Engine::Docker), None, installed(&["podman"]), || false),
+            (Engine::Docker, strings(&["docker", "compose"]))
+        );
+
+        // An explicit command is kept.
+        let command = strings(&["/opt/podman/bin/podman", "compose"]);
+        assert_eq!(
+            detect(None, Some(&command), installed(&["docker"]), || false),
+            (Engine::Podman, command.clone())
+        );
+        assert_eq!(
+            detect(Some(Engine::Docker), Some(&command), installed(&[]), || {
+                false
+            }),
+            (Engine::Docker, command.clone())
+        );
+    }
+
+    #[test]
+    fn fallback() {
+        let installed =
+            |programs: &'static [&'static str]| move |name: &str| programs.contains(&name);
+        let standalone = strings(&["docker-compose"]);
+
+        // The plugin is used when it works.
+        assert_eq!(
+            detect(None, None, installed(&["docker", "docker-compose"]), || {
+                true
+            }),
+            (Engine::Docker, strings(&["docker", "compose"]))
+        );
+        assert_eq!(
+            detect(None, None, installed(&["docker", "docker-compose"]), || {
+                false
+            }),
+            (Engine::Docker, standalone.clone())
+        );
+        assert_eq!(
+            detect(None, None, installed(&["docker-compose"]), || false),
+            (Engine::Docker, standalone.clone())
+        );
+        assert_eq!(
+            detect(
+                Some(Engine::Docker),
+                None,
+                installed(&["docker-compose"]),
+                || false
+            ),
+            (Engine::Docker, standalone.clone())
+        );
+
+        // Without a standalone compose the plugin is still used so the error
+        // makes sense.
+        assert_eq!(
+            detect(None, None, installed(&["docker"]), || false),
+            (Engine::Docker, strings(&["docker", "compose"]))
+        );
+
+        // An explicit command never falls back, the plugin isn't even probed.
+        let command = strings(&["docker", "compose"]);
+        assert_eq!(
+            detect(None, Some(&command), installed(&["docker-compose"]), || {
+                panic!("probed the plugin")
+            }),
+            (Engine::Docker, command.clone())
+        );
+    }
+
+    #[test]
+    fn flags() {
+        assert!(Engine::Docker.supports("--wait"));
+        assert!(!Engine::Podman.supports("--wait"));
+        assert!(Engine::Podman.supports("--build"));
+    }
+}
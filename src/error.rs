@@ -0,0 +1,422 @@
+//! The errors that stacks reports.
+
+use std::fmt;
+use std::io;
+use std::process::{ExitCode, ExitStatus};
+
+use thiserror::Error;
+
+use crate::placeholders::PlaceholderError;
+
+/// A position in the config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// The line, starting at 1.
+    pub line: usize,
+    /// The column, starting at 1.
+    pub column: usize,
+}
+
+impl From<serde_yaml::Location> for Location {
+    fn from(location: serde_yaml::Location) -> Self {
+        Self {
+            line: location.line(),
+            column: location.column(),
+        }
+    }
+}
+
+/// Formats an optional location as a suffix to an error message.
+struct At<'a>(&'a Option<Location>);
+
+impl fmt::Display for At<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(location) => write!(f, " at line {} column {}", location.line, location.column),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Formats a list of stacks for the interrupted message.
+struct Stacks<'a>(&'a str, &'a [String]);
+
+impl fmt::Display for Stacks<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1.is_empty() {
+            Ok(())
+        } else {
+            write!(f, "\n  {}: {}", self.0, self.1.join(", "))
+        }
+    }
+}
+
+/// Formats the failures from several stacks, one per line.
+struct Failures<'a>(&'a [Error]);
+
+impl fmt::Display for Failures<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in self.0 {
+            match error {
+                Error::CommandFailed { stack, .. } => write!(f, "\n  {}: {}", stack, error)?,
+                _ => write!(f, "\n  {}", error)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts the status of a failed command into the exit code to use, signals
+/// are reported as 128 plus the signal number like a shell.
+fn status_code(status: &ExitStatus) -> ExitCode {
+    if let Some(code) = status.code() {
+        // Exit codes outside of the range we can return must still be failures.
+        return match u8::try_from(code) {
+            Ok(0) | Err(_) => ExitCode::FAILURE,
+            Ok(code) => ExitCode::from(code),
+        };
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return ExitCode::from(128u8.saturating_add(signal as u8));
+        }
+    }
+
+    ExitCode::FAILURE
+}
+
+/// Formats a problem with each of several stacks, one per line.
+struct Problems<'a>(&'a [(String, String)]);
+
+impl fmt::Display for Problems<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (stack, problem) in self.0 {
+            write!(f, "\n  {}: {}", stack, problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats the last link of a dependency cycle.
+struct Cycle<'a>(&'a [String]);
+
+impl fmt::Display for Cycle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            [.., stack, dependency] => {
+                write!(f, "\"{}\" cannot depend on \"{}\"", stack, dependency)
+            }
+            _ => write!(f, "{}", self.0.join(" -> ")),
+        }
+    }
+}
+
+/// Everything that can go wrong.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The config file could not be parsed.
+    #[error("{0}")]
+    ConfigParse(serde_yaml::Error),
+
+    /// A stack depends on a stack that does not exist.
+    #[error(
+        "invalid dependency: \"{dependency}\" is not a known stack{}",
+        At(location)
+    )]
+    UnknownDependency {
+        /// The stack with the dependency.
+        stack: String,
+        /// The stack that does not exist.
+        dependency: String,
+        /// Where in the config the problem is, if known.
+        location: Option<Location>,
+    },
+
+    /// The dependencies of the stacks form a cycle. The path lists the stacks
+    /// in the cycle, ending with the stack that was seen again.
+    #[error("invalid dependency cycle: {}{}", Cycle(path), At(location))]
+    DependencyCycle {
+        /// The stacks in the cycle.
+        path: Vec<String>,
+        /// Where in the config the problem is, if known.
+        location: Option<Location>,
+    },
+
+    /// A stack was selected that does not exist.
+    #[error("unknown stack \"{name}\"")]
+    UnknownStack {
+        /// The stack that was selected.
+        name: String,
+    },
+
+    /// A command that only works with a single stack was given several.
+    #[error("Only one stack can be used but {count} were provided.")]
+    SingleStackRequired {
+        /// How many stacks were selected.
+        count: usize,
+    },
+
+    /// The program used to run docker compose could not be found.
+    #[error(
+        "Could not find `{program}` to run docker compose. Install it or set the `command` \
+        key in the stacks config to an alternative such as `docker-compose` or `podman compose`."
+    )]
+    ProgramNotFound {
+        /// The program that was looked for.
+        program: String,
+    },
+
+    /// A stack's path points outside of the base directory.
+    #[error(
+        "Stack `{stack}` uses the path `{path}` which is outside of the stacks directory. Set \
+        `allow_outside_base: true` on the stack to allow this."
+    )]
+    OutsideBaseDir {
+        /// The stack with the path.
+        stack: String,
+        /// The path as given in the config.
+        path: String,
+    },
+
+    /// An environment variable references a variable that is not set.
+    #[error(
+        "The environment variable `{key}` for stack `{stack}` references `{variable}` which is \
+        not set. Use `$$` for a literal `$`."
+    )]
+    UnsetVariable {
+        /// The stack the variable is for.
+        stack: String,
+        /// The variable whose value has the reference.
+        key: String,
+        /// The variable that is referenced.
+        variable: String,
+    },
+
+    /// Environment variables reference each other in a cycle.
+    #[error("The environment variable `{key}` for stack `{stack}` references itself.")]
+    VariableCycle {
+        /// The stack the variable is for.
+        stack: String,
+        /// A variable in the cycle.
+        key: String,
+    },
+
+    /// An argument has a placeholder that cannot be substituted.
+    #[error(
+        "The argument `{arg}` for stack `{stack}` {problem}. Use `{{{{` and `}}}}` for literal \
+        braces."
+    )]
+    InvalidPlaceholder {
+        /// The stack the argument was for.
+        stack: String,
+        /// The argument as given.
+        arg: String,
+        /// What is wrong with it.
+        problem: PlaceholderError,
+    },
+
+    /// A command has no equivalent for the stack's backend.
+    #[error("`{command}` is not supported for stack `{stack}` which uses the {backend} backend.")]
+    UnsupportedCommand {
+        /// The stack the command was for.
+        stack: String,
+        /// The compose subcommand.
+        command: String,
+        /// The name of the stack's backend.
+        backend: &'static str,
+    },
+
+    /// Some stacks do not have a compose project to run.
+    #[error("Some stacks cannot be run:{}", Problems(problems))]
+    MissingComposeFiles {
+        /// Each stack along with what is wrong with it.
+        problems: Vec<(String, String)>,
+    },
+
+    /// The docker daemon could not be reached.
+    #[error("Cannot connect to the Docker daemon at {}; is it running?", endpoints.join(", "))]
+    DaemonUnreachable {
+        /// The daemons that could not be reached.
+        endpoints: Vec<String>,
+    },
+
+    /// The installed docker compose is older than the configured minimum.
+    #[error(
+        "docker compose {version} is older than the minimum version {minimum} set by \
+        `min_compose_version`."
+    )]
+    ComposeTooOld {
+        /// The installed version.
+        version: String,
+        /// The configured minimum version.
+        minimum: String,
+    },
+
+    /// A command could not be started.
+    #[error("Error running docker compose: {source}")]
+    Spawn {
+        /// The command line that was run.
+        command: String,
+        /// Why it could not be started.
+        #[source]
+        source: io::Error,
+    },
+
+    /// A command exited unsuccessfully.
+    #[error("Error running command `{command}`: {status}")]
+    CommandFailed {
+        /// The stack the command was run against.
+        stack: String,
+        /// The command line that was run.
+        command: String,
+        /// How the command exited.
+        status: ExitStatus,
+    },
+
+    /// A stack's services did not come up after `up`.
+    #[error(
+        "The services {} of stack `{stack}` were not up after {timeout} seconds.",
+        services.join(", ")
+    )]
+    NotReady {
+        /// The stack that was brought up.
+        stack: String,
+        /// The services that were not running or not healthy.
+        services: Vec<String>,
+        /// The configured timeout, in seconds.
+        timeout: u64,
+    },
+
+    /// Commands failed while running against several stacks.
+    #[error("Commands failed for {} of the stacks:{}", failures.len(), Failures(failures))]
+    StacksFailed {
+        /// The error for each stack that failed.
+        failures: Vec<Error>,
+    },
+
+    /// The user interrupted the run.
+    #[error(
+        "Interrupted.{}{}{}",
+        Stacks("Completed", completed),
+        Stacks("Interrupted", interrupted),
+        Stacks("Not started", not_started)
+    )]
+    Interrupted {
+        /// The stacks that finished.
+        completed: Vec<String>,
+        /// The stacks that were running.
+        interrupted: Vec<String>,
+        /// The stacks that never started.
+        not_started: Vec<String>,
+    },
+
+    /// Another run of stacks holds the lock.
+    #[error(
+        "Another stack command is already running against this config ({holder}). Pass \
+        `--wait-lock` to wait for it or remove {path} if it is stale."
+    )]
+    Locked {
+        /// The lock file.
+        path: String,
+        /// A description of the run holding the lock.
+        holder: String,
+    },
+
+    /// The images pinned by `freeze` are not the current images.
+    #[error(
+        "The pinned images are out of date:{}\nRun `stack freeze` to pin the current images.",
+        Problems(problems)
+    )]
+    StaleImages {
+        /// Each stack along with how its images differ.
+        problems: Vec<(String, String)>,
+    },
+
+    /// The running containers of some stacks don't match their config.
+    #[error(
+        "The running containers don't match the config of {}. Run `stack verify --verbose` to \
+        see how.",
+        stacks.join(", ")
+    )]
+    Drifted {
+        /// The stacks that drifted.
+        stacks: Vec<String>,
+    },
+
+    /// A stacks config given as a URL could not be fetched and there was no
+    /// cached copy to use instead.
+    #[error("Failed to fetch the stacks config from {url}: {reason}")]
+    FetchFailed {
+        /// The URL of the config.
+        url: String,
+        /// What went wrong.
+        reason: String,
+    },
+
+    /// The user declined to continue.
+    #[error("Aborted.")]
+    Aborted,
+
+    /// Some file operation failed.
+    #[error("{message}: {source}")]
+    Io {
+        /// What was being done.
+        message: String,
+        /// The underlying error.
+        #[source]
+        source: io::Error,
+    },
+
+    /// Any other problem with the arguments or configuration.
+    #[error("{0}")]
+    Invalid(String),
+
+    /// Something that should be impossible happened.
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl Error {
+    /// An `Io` error with a message saying what was being done.
+    pub fn io<S: Into<String>>(message: S, source: io::Error) -> Self {
+        Error::Io {
+            message: message.into(),
+            source,
+        }
+    }
+
+    /// Usage and configuration errors exit with 2, failures while running
+    /// commands with 1 and interruptions with 130 like a shell. A command that
+    /// failed for a single stack passes through the command's own exit code.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::Interrupted { .. } => ExitCode::from(130),
+            Error::CommandFailed { status, .. } => status_code(status),
+            Error::Spawn { .. }
+            | Error::DaemonUnreachable { .. }
+            | Error::NotReady { .. }
+            | Error::StacksFailed { .. }
+            | Error::Locked { .. }
+            | Error::FetchFailed { .. }
+            | Error::StaleImages { .. }
+            | Error::Drifted { .. }
+            | Error::Aborted
+            | Error::Internal(_) => ExitCode::FAILURE,
+            _ => ExitCode::from(2),
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(error: serde_yaml::Error) -> Self {
+        Error::ConfigParse(error)
+    }
+}
+
+/// A result with stacks' error.
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,236 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Stack;
+use crate::output::{OutputHandler, Stream};
+use crate::progress::Reporter;
+
+/// The version of the event schema, bumped for incompatible changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// An event written to the progress file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Always the first event written by a run.
+    Header {
+        version: u32,
+        pid: u32,
+    },
+    StackStarted {
+        stack: String,
+    },
+    /// A line of output, only written when requested.
+    Output {
+        stack: String,
+        stream: String,
+        line: String,
+    },
+    StackFinished {
+        stack: String,
+        success: bool,
+        /// How long the stack took in seconds.
+        duration: f64,
+    },
+    RunFinished {
+        success: bool,
+        duration: f64,
+    },
+}
+
+/// Appends events to a file as NDJSON, flushing after each one.
+pub struct EventFile {
+    file: Mutex<File>,
+    /// Whether to include each line of output.
+    output: bool,
+}
+
+impl EventFile {
+    /// Opens the file for appending and writes the header event.
+    pub fn create(path: &Path, output: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let events = Self {
+            file: Mutex::new(file),
+            output,
+        };
+
+        events.emit(&Event::Header {
+            version: SCHEMA_VERSION,
+            pid: std::process::id(),
+        });
+        Ok(events)
+    }
+
+    pub fn emit(&self, event: &Event) {
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("Unable to serialize progress event: {}", e);
+                return;
+            }
+        };
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+            log::warn!("Unable to write progress event: {}", e);
+        }
+    }
+}
+
+/// Writes events to the file while passing output and progress on to the
+/// inner handlers.
+pub struct Recorded<'a> {
+    events: &'a EventFile,
+    output: &'a dyn OutputHandler,
+    reporter: &'a dyn Reporter,
+}
+
+impl<'a> Recorded<'a> {
+    pub fn new(
+        events: &'a EventFile,
+        output: &'a dyn OutputHandler,
+        reporter: &'a dyn Reporter,
+    ) -> Self {
+        Self {
+            events,
+            output,
+            reporter,
+        }
+    }
+}
+
+impl Reporter for Recorded<'_> {
+    fn begin(&self, total: usize) {
+        self.reporter.begin(total);
+    }
+
+    fn started(&self, stack: &Stack) {
+        self.events.emit(&Event::StackStarted {
+            stack: stack.key.clone(),
+        });
+        self.reporter.started(stack);
+    }
+
+    fn finished(&self, stack: &Stack, success: bool, duration: Duration) {
+        self.events.emit(&Event::StackFinished {
+            stack: stack.key.clone(),
+            success,
+            duration: duration.as_secs_f64(),
+        });
+        self.reporter.finished(stack, success, duration);
+    }
+
+    fn end(&self) {
+        self.reporter.end();
+    }
+}
+
+impl OutputHandler for Recorded<'_> {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        if self.events.output {
+            self.events.emit(&Event::Output {
+                stack: stack.key.clone(),
+                stream: match stream {
+                    Stream::Stdout => "stdout",
+                    Stream::Stderr => "stderr",
+                }
+                .to_string(),
+                line: line.to_owned(),
+            });
+        }
+        self.output.line(stack, stream, line);
+    }
+
+    fn finished(&self, stack: &Stack, success: bool) {
+        self.output.finished(stack, success);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventFile, SCHEMA_VERSION};
+    use std::fs;
+
+    #[test]
+    fn serialization() {
+        let events = vec![
+            (
+                Event::Header {
+                    version: SCHEMA_VERSION,
+                    pid: 12,
+                },
+                r#"{"event":"header","version":1,"pid":12}"#,
+            ),
+            (
+                Event::StackStarted {
+                    stack: "web".to_string(),
+                },
+                r#"{"event":"stack_started","stack":"web"}"#,
+            ),
+            (
+                Event::Output {
+                    stack: "web".to_string(),
+                    stream: "stderr".to_string(),
+                    line: "Pulling".to_string(),
+                },
+                r#"{"event":"output","stack":"web","stream":"stderr","line":"Pulling"}"#,
+            ),
+            (
+                Event::StackFinished {
+                    stack: "web".to_string(),
+                    success: false,
+                    duration: 1.5,
+                },
+                r#"{"event":"stack_finished","stack":"web","success":false,"duration":1.5}"#,
+            ),
+            (
+                Event::RunFinished {
+                    success: true,
+                    duration: 3.0,
+                },
+                r#"{"event":"run_finished","success":true,"duration":3.0}"#,
+            ),
+        ];
+
+        for (event, json) in events {
+            assert_eq!(serde_json::to_string(&event).unwrap(), json);
+            assert_eq!(serde_json::from_str::<Event>(json).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn file() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("events.json");
+        fs::write(
+            &path,
+            "{\"event\":\"run_finished\",\"success\":true,\"duration\":1.0}\n",
+        )
+        .unwrap();
+
+        let events = EventFile::create(&path, false).unwrap();
+        events.emit(&Event::StackStarted {
+            stack: "web".to_string(),
+        });
+
+        // Events are appended and visible immediately.
+        let lines: Vec<Event> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(matches!(lines[1], Event::Header { version: 1, .. }));
+        assert_eq!(
+            lines[2],
+            Event::StackStarted {
+                stack: "web".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Running the commands that stacks builds.
+
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+#[cfg(windows)]
+use std::os::windows::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+use crate::cancel::Cancellation;
+use crate::config::Stack;
+use crate::error::{Error, Result};
+use crate::exec::{ExecOptions, OutputMode};
+use crate::output::{wait_piped, OutputHandler};
+use crate::paths::is_batch_file;
+
+/// Runs the commands that stacks builds.
+pub trait Executor: Sync {
+    /// Runs a command against a stack until it exits. Piped output is passed
+    /// to the handler.
+    fn run(
+        &self,
+        exec_options: &ExecOptions,
+        stack: &Stack,
+        output: &dyn OutputHandler,
+        cancel: &Cancellation,
+    ) -> Result<ExitStatus>;
+
+    /// Runs a command to completion and captures what it printed to stdout.
+    fn output(&self, exec_options: &ExecOptions) -> Result<Output>;
+}
+
+/// The status of a process that exited with the code, for executors that
+/// don't spawn anything.
+#[cfg(unix)]
+pub(crate) fn exit_status(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code << 8)
+}
+
+#[cfg(windows)]
+pub(crate) fn exit_status(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code as u32)
+}
+
+/// Builds the command to run for the options.
+fn build_command(exec_options: &ExecOptions) -> Command {
+    log::debug!("Executing `{}`", exec_options.command_line());
+
+    // Batch files can only be run by the command interpreter.
+    let mut command = if cfg!(windows) && is_batch_file(exec_options.program()) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(exec_options.program());
+        command
+    } else {
+        Command::new(exec_options.program())
+    };
+    command.args(exec_options.args());
+    if !exec_options.inherit_env {
+        command.env_clear();
+    }
+    for (k, v) in exec_options.environment.iter() {
+        command.env(k, v);
+    }
+
+    command.current_dir(&exec_options.working_dir);
+    command
+}
+
+/// Runs commands as child processes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessExecutor;
+
+impl Executor for ProcessExecutor {
+    fn run(
+        &self,
+        exec_options: &ExecOptions,
+        stack: &Stack,
+        output: &dyn OutputHandler,
+        cancel: &Cancellation,
+    ) -> Result<ExitStatus> {
+        let mut command = build_command(exec_options);
+
+        let result = if exec_options.output_mode == OutputMode::Inherit {
+            command
+                .spawn()
+                .and_then(|mut child| cancel.wait(&mut child, false))
+        } else {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+            // Run in a separate process group so that the whole group can be
+            // stopped when cancelled. Interactive commands must stay in the
+            // foreground group to be able to use the terminal.
+            #[cfg(unix)]
+            command.process_group(0);
+
+            command
+                .spawn()
+                .and_then(|child| wait_piped(child, stack, output, |c| cancel.wait(c, true)))
+        };
+
+        result.map_err(|source| Error::Spawn {
+            command: exec_options.command_line(),
+            source,
+        })
+    }
+
+    fn output(&self, exec_options: &ExecOptions) -> Result<Output> {
+        build_command(exec_options)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .map_err(|source| Error::Spawn {
+                command: exec_options.command_line(),
+                source,
+            })
+    }
+}
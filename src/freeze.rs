@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// The compose override file in a stack's directory that pins its images.
+pub const PINNED_FILE: &str = "compose.pinned.yml";
+
+const HEADER: &str = "# Written by `stack freeze`. Used by `stack up` with `--frozen`.\n";
+
+/// A service's pinned image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinnedService {
+    pub image: String,
+}
+
+/// The contents of a stack's pinned file, a compose override that replaces
+/// each service's image with a reference by digest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pinned {
+    #[serde(default)]
+    pub services: BTreeMap<String, PinnedService>,
+}
+
+impl Pinned {
+    pub fn parse(content: &str) -> Result<Self> {
+        serde_yaml::from_str(content)
+            .map_err(|e| Error::Invalid(format!("Invalid {}: {}", PINNED_FILE, e)))
+    }
+
+    pub fn render(&self) -> String {
+        format!("{}{}", HEADER, serde_yaml::to_string(self).unwrap())
+    }
+
+    /// Describes each service whose pinned image differs from the other's.
+    pub fn differences(&self, current: &Pinned) -> Vec<String> {
+        let mut services: Vec<&String> = self
+            .services
+            .keys()
+            .chain(current.services.keys())
+            .collect();
+        services.sort();
+        services.dedup();
+
+        services
+            .into_iter()
+            .filter_map(|service| {
+                match (self.services.get(service), current.services.get(service)) {
+                    (Some(locked), Some(now)) if locked == now => None,
+                    (Some(locked), Some(now)) => Some(format!(
+                        "{} is pinned to {} but is now {}",
+                        service, locked.image, now.image
+                    )),
+                    (None, Some(_)) => Some(format!("{} is not pinned", service)),
+                    (Some(_), None) => Some(format!("{} no longer has an image", service)),
+                    (None, None) => None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// An image as reported by `docker image inspect`.
+#[derive(Debug, Clone, Deserialize)]
+struct Inspected {
+    #[serde(rename = "RepoTags", default)]
+    repo_tags: Option<Vec<String>>,
+    #[serde(rename = "RepoDigests", default)]
+    repo_digests: Option<Vec<String>>,
+}
+
+/// Normalises an image reference so that references to the same image in
+/// the docker hub compare equal, with the implied `latest` tag added.
+pub fn normalize(image: &str) -> String {
+    let image = image
+        .strip_prefix("docker.io/library/")
+        .or_else(|| image.strip_prefix("docker.io/"))
+        .unwrap_or(image);
+    let image = match image.split_once('/') {
+        Some(("library", rest)) if !rest.contains('/') => rest,
+        _ => image,
+    };
+
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if image.contains('@') || name.contains(':') {
+        image.to_owned()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+/// The repository of an image reference, without any tag or digest.
+fn repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap_or(image);
+    match image.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => image,
+    }
+}
+
+/// Finds the image of each service in a rendered compose config. Services
+/// that are only built have no image.
+pub fn service_images(rendered: &str) -> Result<BTreeMap<String, String>> {
+    #[derive(Deserialize)]
+    struct Service {
+        image: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct Project {
+        #[serde(default)]
+        services: BTreeMap<String, Service>,
+    }
+
+    let project: Project = serde_yaml::from_str(rendered)
+        .map_err(|e| Error::Invalid(format!("Invalid compose config: {}", e)))?;
+    Ok(project
+        .services
+        .into_iter()
+        .filter_map(|(service, config)| config.image.map(|image| (service, image)))
+        .collect())
+}
+
+/// Resolves each service's image to a reference by its repository digest
+/// using the output of `docker image inspect`. Images that are already
+/// referenced by digest are kept as they are.
+pub fn resolve(images: &BTreeMap<String, String>, inspect_output: &str) -> Result<Pinned> {
+    let inspected: Vec<Inspected> = serde_json::from_str(inspect_output)
+        .map_err(|e| Error::Invalid(format!("Invalid image inspect output: {}", e)))?;
+
+    let mut pinned = Pinned::default();
+    for (service, image) in images {
+        if image.contains('@') {
+            pinned.services.insert(
+                service.clone(),
+                PinnedService {
+                    image: image.clone(),
+                },
+            );
+            continue;
+        }
+
+        let wanted = normalize(image);
+        let digests = inspected
+            .iter()
+            .find(|entry| {
+                entry
+                    .repo_tags
+                    .iter()
+                    .flatten()
+                    .any(|tag| normalize(tag) == wanted)
+            })
+            .and_then(|entry| entry.repo_digests.as_ref())
+            .ok_or_else(|| {
+                Error::Invalid(format!(
+                    "The image {} of service {} has not been pulled.",
+                    image, service
+                ))
+            })?;
+
+        let digest = digests
+            .iter()
+            .find(|reference| repository(&normalize(reference)) == repository(&wanted))
+            .or_else(|| digests.first())
+            .and_then(|reference| reference.split_once('@'))
+            .map(|(_, digest)| digest)
+            .ok_or_else(|| {
+                Error::Invalid(format!(
+                    "The image {} of service {} has no digest, only images pulled from a \
+                    registry can be pinned.",
+                    image, service
+                ))
+            })?;
+
+        pinned.services.insert(
+            service.clone(),
+            PinnedService {
+                image: format!("{}@{}", repository(image), digest),
+            },
+        );
+    }
+
+    Ok(pinned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, resolve, service_images, Pinned, PinnedService};
+    use std::collections::BTreeMap;
+
+    const RENDERED: &str = "
+name: web
+services:
+  app:
+    image: registry.example.com:5000/team/app:1.2
+  proxy:
+    image: nginx
+  cache:
+    image: docker.io/library/redis:7
+  db:
+    image: postgres@sha256:0123
+  builder:
+    build:
+      context: .
+";
+
+    const INSPECT: &str = r#"[
+        {
+            "Id": "sha256:aaa",
+            "RepoTags": ["registry.example.com:5000/team/app:1.2"],
+            "RepoDigests": ["registry.example.com:5000/team/app@sha256:1111"]
+        },
+        {
+            "Id": "sha256:bbb",
+            "RepoTags": ["nginx:latest", "nginx:1.25"],
+            "RepoDigests": ["mirror.example.com/nginx@sha256:9999", "nginx@sha256:2222"]
+        },
+        {
+            "Id": "sha256:ccc",
+            "RepoTags": ["docker.io/library/redis:7"],
+            "RepoDigests": ["docker.io/library/redis@sha256:3333"]
+        }
+    ]"#;
+
+    fn pinned(images: &[(&str, &str)]) -> Pinned {
+        Pinned {
+            services: images
+                .iter()
+                .map(|(service, image)| {
+                    (
+                        service.to_string(),
+                        PinnedService {
+                            image: image.to_string(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn references() {
+        assert_eq!(normalize("nginx"), "nginx:latest");
+        assert_eq!(normalize("docker.io/library/nginx:1.25"), "nginx:1.25");
+        assert_eq!(normalize("library/nginx"), "nginx:latest");
+        assert_eq!(normalize("docker.io/team/app"), "team/app:latest");
+        assert_eq!(
+            normalize("registry.example.com:5000/app"),
+            "registry.example.com:5000/app:latest"
+        );
+        assert_eq!(normalize("nginx@sha256:2222"), "nginx@sha256:2222");
+    }
+
+    #[test]
+    fn resolution() {
+        let images = service_images(RENDERED).unwrap();
+        assert_eq!(images.len(), 4);
+        assert_eq!(images["proxy"], "nginx");
+
+        assert_eq!(
+            resolve(&images, INSPECT).unwrap(),
+            pinned(&[
+                ("app", "registry.example.com:5000/team/app@sha256:1111"),
+                ("cache", "docker.io/library/redis@sha256:3333"),
+                ("db", "postgres@sha256:0123"),
+                ("proxy", "nginx@sha256:2222"),
+            ])
+        );
+
+        // Images must have been pulled from a registry.
+        let mut images = BTreeMap::new();
+        images.insert("app".to_string(), "local:dev".to_string());
+        assert!(resolve(&images, INSPECT).is_err());
+        assert!(resolve(
+            &images,
+            r#"[{"RepoTags": ["local:dev"], "RepoDigests": []}]"#
+        )
+        .is_err());
+        assert!(resolve(&BTreeMap::new(), "[]").unwrap().services.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let locked = pinned(&[
+            ("app", "registry.example.com:5000/team/app@sha256:1111"),
+            ("proxy", "nginx@sha256:2222"),
+        ]);
+        let rendered = locked.render();
+        assert_eq!(
+            rendered,
+            "# Written by `stack freeze`. Used by `stack up` with `--frozen`.\n\
+            services:\n  \
+              app:\n    \
+                image: registry.example.com:5000/team/app@sha256:1111\n  \
+              proxy:\n    \
+                image: nginx@sha256:2222\n"
+        );
+        assert_eq!(Pinned::parse(&rendered).unwrap(), locked);
+        assert!(Pinned::parse("services: []").is_err());
+
+        assert!(locked.differences(&locked).is_empty());
+        let current = pinned(&[
+            ("app", "registry.example.com:5000/team/app@sha256:4444"),
+            ("cache", "redis@sha256:3333"),
+        ]);
+        assert_eq!(
+            locked.differences(&current),
+            vec![
+                "app is pinned to registry.example.com:5000/team/app@sha256:1111 but is now \
+                registry.example.com:5000/team/app@sha256:4444",
+                "cache is not pinned",
+                "proxy no longer has an image",
+            ]
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::config::{Config, Stack};
+use crate::state::utc;
+
+/// How to print the dependency graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT.
+    Dot,
+    /// A mermaid flowchart.
+    Mermaid,
+    /// A JSON document with lists of nodes and edges.
+    Json,
+}
+
+/// A stack in the graph.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Node {
+    pub key: String,
+    /// The compose project name.
+    pub name: String,
+    pub directory: String,
+}
+
+/// A dependency, `from` depends on `to`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    /// The config file the graph was built from, none for stdin.
+    pub config: Option<String>,
+    /// When the graph was generated, in RFC 3339 format.
+    pub generated_at: String,
+}
+
+/// The dependency graph of some stacks. Nodes are in dependency order and
+/// edges are sorted so the output is stable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Graph {
+    pub metadata: Metadata,
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+impl Graph {
+    /// Builds the graph of the given stacks. Dependencies on stacks that are
+    /// not included are left out.
+    pub fn new(
+        config: &Config,
+        stacks: &[&Stack],
+        config_file: Option<&Path>,
+        timestamp: u64,
+    ) -> Self {
+        let [year, month, day, hour, minute, second] = utc(timestamp);
+
+        let nodes: Vec<Node> = stacks
+            .iter()
+            .map(|stack| Node {
+                key: stack.key.clone(),
+                name: stack.name.clone(),
+                directory: stack.directory(&config.base_dir).display().to_string(),
+            })
+            .collect();
+
+        let mut edges: Vec<Edge> = stacks
+            .iter()
+            .flat_map(|stack| {
+                stack
+                    .dependencies
+                    .iter()
+                    .filter(|dep| nodes.iter().any(|node| &node.key == *dep))
+                    .map(|dep| Edge {
+                        from: stack.key.clone(),
+                        to: dep.clone(),
+                    })
+            })
+            .collect();
+        edges.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+
+        Graph {
+            metadata: Metadata {
+                config: config_file.map(|path| path.display().to_string()),
+                generated_at: format!(
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                    year, month, day, hour, minute, second
+                ),
+            },
+            nodes,
+            edges,
+        }
+    }
+
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.dot(),
+            GraphFormat::Mermaid => self.mermaid(),
+            GraphFormat::Json => serde_json::to_string_pretty(self).unwrap() + "\n",
+        }
+    }
+
+    fn dot(&self) -> String {
+        let mut out = "digraph stacks {\n".to_string();
+        for node in self.nodes.iter() {
+            let _ = writeln!(out, "    {:?};", node.key);
+        }
+        for edge in self.edges.iter() {
+            let _ = writeln!(out, "    {:?} -> {:?};", edge.from, edge.to);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn mermaid(&self) -> String {
+        // Mermaid ids can't contain every character that keys can so nodes are
+        // numbered and labelled with their key.
+        let id = |key: &str| self.nodes.iter().position(|node| node.key == key).unwrap();
+
+        let mut out = "flowchart TD\n".to_string();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "    n{}[\"{}\"]",
+                index,
+                node.key.replace('"', "#quot;")
+            );
+        }
+        for edge in self.edges.iter() {
+            let _ = writeln!(out, "    n{} --> n{}", id(&edge.from), id(&edge.to));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Edge, Graph, GraphFormat};
+    use crate::config::Config;
+    use std::path::{Path, PathBuf};
+
+    fn diamond() -> Config {
+        Config::from_reader(
+            &PathBuf::from("/srv"),
+            "
+            stacks:
+                base: {}
+                left:
+                    depends_on:
+                        - base
+                right:
+                    name: right-project
+                    directory: services/right
+                    depends_on:
+                        - base
+                top:
+                    depends_on:
+                        - left
+                        - right
+                alone: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn edge(from: &str, to: &str) -> Edge {
+        Edge {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn model() {
+        let config = diamond();
+        let stacks = config.stacks_with_dependencies(["top"]).unwrap();
+        let graph = Graph::new(&config, &stacks, Some(Path::new("/srv/stacks.yml")), 0);
+
+        let keys: Vec<&str> = graph.nodes.iter().map(|node| node.key.as_str()).collect();
+        assert_eq!(keys, vec!["base", "left", "right", "top"]);
+        assert_eq!(graph.nodes[2].name, "right-project");
+        assert_eq!(
+            Path::new(&graph.nodes[2].directory),
+            Path::new("/srv").join("services").join("right")
+        );
+        assert_eq!(
+            graph.edges,
+            vec![
+                edge("left", "base"),
+                edge("right", "base"),
+                edge("top", "left"),
+                edge("top", "right"),
+            ]
+        );
+        assert_eq!(graph.metadata.generated_at, "1970-01-01T00:00:00Z");
+
+        // Dependencies outside of the selection are dropped.
+        let stacks = config.stacks(["top", "left"]).unwrap();
+        let graph = Graph::new(&config, &stacks, None, 0);
+        assert_eq!(graph.edges, vec![edge("top", "left")]);
+    }
+
+    #[test]
+    fn json() {
+        let config = diamond();
+        let stacks = config.stacks_with_dependencies(["left"]).unwrap();
+        let graph = Graph::new(&config, &stacks, None, 1700000000);
+
+        let json = graph.render(GraphFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "metadata": {
+                    "config": null,
+                    "generated_at": "2023-11-14T22:13:20Z"
+                },
+                "nodes": [
+                    {
+                        "key": "base",
+                        "name": "base",
+                        "directory": Path::new("/srv").join("base").display().to_string()
+                    },
+                    {
+                        "key": "left",
+                        "name": "left",
+                        "directory": Path::new("/srv").join("left").display().to_string()
+                    }
+                ],
+                "edges": [{ "from": "left", "to": "base" }]
+            })
+        );
+
+        // Rendering is stable.
+        assert_eq!(
+            Graph::new(&config, &stacks, None, 1700000000).render(GraphFormat::Json),
+            json
+        );
+    }
+
+    #[test]
+    fn text_formats() {
+        let config = diamond();
+        let stacks = config.stacks(["left", "base"]).unwrap();
+        let graph = Graph::new(&config, &stacks, None, 0);
+
+        assert_eq!(
+            graph.render(GraphFormat::Dot),
+            "digraph stacks {\n    \"base\";\n    \"left\";\n    \"left\" -> \"base\";\n}\n"
+        );
+        assert_eq!(
+            graph.render(GraphFormat::Mermaid),
+            "flowchart TD\n    n0[\"base\"]\n    n1[\"left\"]\n    n1 --> n0\n"
+        );
+    }
+}
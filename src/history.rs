@@ -0,0 +1,303 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::state::{utc, Lock, STATE_DIR};
+use crate::summary::format_duration;
+
+/// One invocation of stacks that changed stacks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the command started, in seconds since the epoch.
+    pub timestamp: u64,
+    pub user: String,
+    /// The arguments stacks was run with, without the program name.
+    pub args: Vec<String>,
+    /// The stacks the command was run against.
+    pub stacks: Vec<String>,
+    pub success: bool,
+    /// The error the command failed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// How long the command took in seconds.
+    pub duration: f64,
+}
+
+/// The current user, as best as can be told.
+pub fn current_user() -> String {
+    ["USER", "USERNAME", "LOGNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|user| !user.is_empty()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Formats seconds since the epoch as a UTC date and time.
+fn format_timestamp(timestamp: u64) -> String {
+    let [year, month, day, hour, minute, second] = utc(timestamp);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Parses the history log, skipping any lines that cannot be understood.
+pub fn parse(content: &str) -> Vec<Entry> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Skipping invalid history entry: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Renders history entries for display, one per line.
+pub fn render(entries: &[Entry]) -> String {
+    let mut output = String::new();
+    for entry in entries {
+        let _ = writeln!(
+            output,
+            "{}  {}  {:<6}  {}  stack {}",
+            format_timestamp(entry.timestamp),
+            entry.user,
+            if entry.success { "ok" } else { "failed" },
+            format_duration(Duration::from_secs_f64(entry.duration)),
+            entry.args.join(" ")
+        );
+        if !entry.stacks.is_empty() {
+            let _ = writeln!(output, "    stacks: {}", entry.stacks.join(", "));
+        }
+        if let Some(ref error) = entry.error {
+            let _ = writeln!(output, "    error: {}", error);
+        }
+    }
+    output
+}
+
+/// The append-only log of commands that changed stacks.
+#[derive(Debug, Clone)]
+pub struct HistoryLog {
+    path: PathBuf,
+    /// When the log grows beyond this many bytes the oldest entries are
+    /// dropped.
+    max_size: Option<u64>,
+}
+
+impl HistoryLog {
+    pub fn new(path: PathBuf, max_size: Option<u64>) -> Self {
+        Self { path, max_size }
+    }
+
+    /// The default history log for the given stacks config file.
+    pub fn for_config(stacks_file: &Path, max_size: Option<u64>) -> Self {
+        let dir = stacks_file.parent().unwrap_or(Path::new("."));
+        Self::new(dir.join(STATE_DIR).join("history.log"), max_size)
+    }
+
+    /// Appends an entry to the log.
+    pub fn append(&self, entry: &Entry) -> Result<()> {
+        let io_error = |e| Error::io(format!("Failed to write {}", self.path.display()), e);
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| Error::Invalid(format!("Failed to serialize history: {}", e)))?;
+        line.push('\n');
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(io_error)?;
+        }
+
+        // Truncation rewrites the log so appends must wait until it is done.
+        let _lock = Lock::acquire(&self.path.with_extension("lock")).map_err(io_error)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(io_error)?;
+
+        self.truncate().map_err(io_error)
+    }
+
+    /// Drops the oldest entries until the log fits in the maximum size. The
+    /// newest entry is always kept, even if it is larger than that. The caller
+    /// must hold the lock.
+    fn truncate(&self) -> io::Result<()> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size as usize,
+            None => return Ok(()),
+        };
+        if fs::metadata(&self.path)?.len() as usize <= max_size {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(&self.path)?;
+        let newest = content
+            .trim_end_matches('\n')
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let mut start = 0;
+        while content.len() - start > max_size && start < newest {
+            match content[start..].find('\n') {
+                Some(index) => start += index + 1,
+                None => break,
+            }
+        }
+
+        let temp = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        File::create(&temp)?.write_all(&content.as_bytes()[start..])?;
+        fs::rename(&temp, &self.path)
+    }
+
+    /// Reads the last `count` entries from the log.
+    pub fn last(&self, count: usize) -> Result<Vec<Entry>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(Error::io(
+                    format!("Failed to read {}", self.path.display()),
+                    e,
+                ))
+            }
+        };
+
+        let mut entries = parse(&content);
+        let skip = entries.len().saturating_sub(count);
+        Ok(entries.split_off(skip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_timestamp, parse, render, Entry, HistoryLog};
+    use std::fs;
+    use std::thread;
+
+    fn entry(timestamp: u64, args: &[&str], success: bool) -> Entry {
+        Entry {
+            timestamp,
+            user: "dave".to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            stacks: vec!["db".to_string(), "web".to_string()],
+            success,
+            error: (!success).then(|| "Error running command `up`: exit status: 1".to_string()),
+            duration: 2.5,
+        }
+    }
+
+    #[test]
+    fn serialization() {
+        assert_eq!(
+            serde_json::to_string(&entry(1700000000, &["db", "restart"], true)).unwrap(),
+            r#"{"timestamp":1700000000,"user":"dave","args":["db","restart"],"stacks":["db","web"],"success":true,"duration":2.5}"#
+        );
+
+        let failed = entry(1700000000, &["up"], false);
+        let json = serde_json::to_string(&failed).unwrap();
+        assert!(json.contains(r#""error":"Error running command `up`: exit status: 1""#));
+        assert_eq!(serde_json::from_str::<Entry>(&json).unwrap(), failed);
+
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1700000000), "2023-11-14 22:13:20 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+    }
+
+    #[test]
+    fn history() {
+        let fixture = r#"{"timestamp":1700000000,"user":"dave","args":["db","restart"],"stacks":["db","web"],"success":true,"duration":2.5}
+not a history entry
+{"timestamp":1700003600,"user":"sam","args":["up"],"stacks":["db"],"success":false,"error":"Aborted.","duration":75.0}
+
+"#;
+        let entries = parse(fixture);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].user, "sam");
+
+        assert_eq!(
+            render(&entries),
+            "\
+2023-11-14 22:13:20 UTC  dave  ok      2.5s  stack db restart
+    stacks: db, web
+2023-11-14 23:13:20 UTC  sam  failed  1m15s  stack up
+    stacks: db
+    error: Aborted.
+"
+        );
+
+        let temp = tempfile::tempdir().unwrap();
+        let log = HistoryLog::for_config(&temp.path().join("stacks.yml"), None);
+        assert!(log.last(10).unwrap().is_empty());
+
+        fs::create_dir_all(temp.path().join(".stack")).unwrap();
+        fs::write(temp.path().join(".stack/history.log"), fixture).unwrap();
+        assert_eq!(log.last(1).unwrap(), vec![entries[1].clone()]);
+
+        log.append(&entry(1700007200, &["down"], true)).unwrap();
+        let last = log.last(10).unwrap();
+        assert_eq!(last.len(), 3);
+        assert_eq!(last[2].args, vec!["down".to_string()]);
+    }
+
+    #[test]
+    fn truncation() {
+        let temp = tempfile::tempdir().unwrap();
+        let line_length = serde_json::to_string(&entry(1700000000, &["up"], true))
+            .unwrap()
+            .len() as u64
+            + 1;
+        let log = HistoryLog::new(temp.path().join("history.log"), Some(line_length * 3));
+
+        for index in 0..5 {
+            log.append(&entry(1700000000 + index, &["up"], true))
+                .unwrap();
+        }
+
+        let timestamps: Vec<u64> = log.last(10).unwrap().iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, vec![1700000002, 1700000003, 1700000004]);
+
+        // The newest entry is kept even when it doesn't fit.
+        let log = HistoryLog::new(temp.path().join("small.log"), Some(line_length / 2));
+        for index in 0..3 {
+            log.append(&entry(1700000000 + index, &["up"], true))
+                .unwrap();
+        }
+        let timestamps: Vec<u64> = log.last(10).unwrap().iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, vec![1700000002]);
+    }
+
+    #[test]
+    fn concurrent_appends() {
+        let temp = tempfile::tempdir().unwrap();
+        let line_length = serde_json::to_string(&entry(1700000000, &["up"], true))
+            .unwrap()
+            .len() as u64
+            + 1;
+        let path = temp.path().join("history.log");
+
+        // Appends that land while another process truncates are not lost.
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let log = HistoryLog::new(path.clone(), Some(line_length * 20));
+                scope.spawn(move || {
+                    for index in 0..25 {
+                        log.append(&entry(1700000000 + thread * 100 + index, &["up"], true))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let log = HistoryLog::new(path, None);
+        assert_eq!(log.last(100).unwrap().len(), 20);
+    }
+}
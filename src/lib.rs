@@ -0,0 +1,131 @@
+//! The library behind the `stack` command, for running inter-dependent docker
+//! compose projects.
+//!
+//! A [`Config`](config::Config) describes the stacks and how they depend on
+//! each other. It can work out the order to run stacks in and build the
+//! [`ExecOptions`](exec::ExecOptions) for a compose command, which an
+//! [`Executor`](executor::Executor) runs.
+//!
+//! ```
+//! use std::path::Path;
+//!
+//! use stacks::config::Config;
+//!
+//! # fn main() -> stacks::error::Result<()> {
+//! let config = Config::from_reader(
+//!     Path::new("/srv/stacks"),
+//!     "
+//!     stacks:
+//!       db: {}
+//!       cache: {}
+//!       web:
+//!         depends_on:
+//!           - db
+//!           - cache
+//!     "
+//!     .as_bytes(),
+//! )?;
+//!
+//! // Dependencies come before the stacks that need them.
+//! let order: Vec<&str> = config
+//!     .stacks_with_dependencies(["web"])?
+//!     .iter()
+//!     .map(|stack| stack.key.as_str())
+//!     .collect();
+//! assert_eq!(order, ["cache", "db", "web"]);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only the `config`, `error`, `exec` and `executor` modules are a stable API,
+//! the rest support the command line tool.
+#![warn(missing_docs)]
+
+pub mod config;
+pub mod error;
+pub mod exec;
+pub mod executor;
+
+#[doc(hidden)]
+pub mod args;
+#[doc(hidden)]
+pub mod backend;
+#[doc(hidden)]
+pub mod backup;
+#[doc(hidden)]
+pub mod cancel;
+#[doc(hidden)]
+pub mod clean;
+#[doc(hidden)]
+pub mod commands;
+#[doc(hidden)]
+pub mod diff;
+#[doc(hidden)]
+pub mod docs;
+#[doc(hidden)]
+pub mod engine;
+#[doc(hidden)]
+pub mod environment;
+#[doc(hidden)]
+pub mod events;
+#[doc(hidden)]
+pub mod freeze;
+#[doc(hidden)]
+pub mod graph;
+#[doc(hidden)]
+pub mod history;
+#[doc(hidden)]
+pub mod list;
+#[doc(hidden)]
+pub mod lock;
+#[doc(hidden)]
+pub mod notify;
+#[doc(hidden)]
+pub mod open;
+#[doc(hidden)]
+pub mod output;
+#[doc(hidden)]
+pub mod paths;
+#[doc(hidden)]
+pub mod picker;
+#[doc(hidden)]
+pub mod placeholders;
+#[doc(hidden)]
+pub mod ports;
+#[doc(hidden)]
+pub mod preflight;
+#[doc(hidden)]
+pub mod progress;
+#[doc(hidden)]
+pub mod provides;
+#[doc(hidden)]
+pub mod pull;
+#[doc(hidden)]
+pub mod record;
+#[doc(hidden)]
+pub mod remote;
+#[doc(hidden)]
+pub mod retry;
+#[doc(hidden)]
+pub mod schema;
+#[doc(hidden)]
+pub mod selection;
+#[doc(hidden)]
+pub mod state;
+#[doc(hidden)]
+pub mod status;
+#[doc(hidden)]
+pub mod summary;
+#[doc(hidden)]
+pub mod task;
+#[cfg(any(test, feature = "testing"))]
+#[doc(hidden)]
+pub mod testing;
+#[doc(hidden)]
+pub mod timings;
+#[doc(hidden)]
+pub mod verify;
+#[doc(hidden)]
+pub mod version;
+#[doc(hidden)]
+pub mod watch;
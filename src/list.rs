@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::config::Stack;
+use crate::status::RunState;
+
+/// A stack in the listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub stack: String,
+    pub description: String,
+    /// Only known when the state was asked for.
+    pub state: Option<RunState>,
+    /// The stack's URLs, with any variables expanded.
+    pub urls: Vec<String>,
+}
+
+impl Entry {
+    pub fn new(stack: &Stack, state: Option<RunState>, urls: Vec<String>) -> Self {
+        Self {
+            stack: stack.key.clone(),
+            description: stack.description.clone().unwrap_or_default(),
+            state,
+            urls,
+        }
+    }
+}
+
+/// Renders the stacks as a table, with a state column if any entry has a
+/// state and a URL column if any entry has a URL.
+pub fn render(entries: &[Entry]) -> String {
+    let with_state = entries.iter().any(|entry| entry.state.is_some());
+    let with_urls = entries.iter().any(|entry| !entry.urls.is_empty());
+
+    let row = |stack: &str, state: &str, url: &str, description: &str| -> Vec<String> {
+        let mut row = vec![stack.to_owned()];
+        if with_state {
+            row.push(state.to_owned());
+        }
+        if with_urls {
+            row.push(url.to_owned());
+        }
+        row.push(description.to_owned());
+        row
+    };
+
+    let header = row("Stack", "State", "URL", "Description");
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|entry| {
+            row(
+                &entry.stack,
+                entry.state.unwrap_or(RunState::Unknown).label(),
+                &entry.urls.join(", "),
+                &entry.description,
+            )
+        })
+        .collect();
+
+    let mut widths = vec![0; header.len()];
+    for row in [&header].into_iter().chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(rows.iter()) {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(widths.iter()) {
+            let _ = write!(line, "{:<width$}  ", cell, width = width);
+        }
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+
+    table
+}
+
+/// Marks a stack whose dependencies were already shown in the tree.
+const REFERENCE: &str = "(*)";
+
+/// Renders the stacks as a forest, each stack that nothing else in the list
+/// depends on with its dependencies nested beneath it. Stacks that were
+/// already shown are marked rather than repeated, dependencies outside of the
+/// list are left out and states are shown for the stacks that have one.
+pub fn render_tree(stacks: &[&Stack], states: &HashMap<String, RunState>) -> String {
+    let included: BTreeMap<&str, &Stack> = stacks
+        .iter()
+        .map(|stack| (stack.key.as_str(), *stack))
+        .collect();
+
+    let mut tree = Tree {
+        included: &included,
+        states,
+        shown: HashSet::new(),
+        referenced: false,
+        output: String::new(),
+    };
+    for root in included.values().filter(|stack| {
+        !stack
+            .dependants
+            .iter()
+            .any(|key| included.contains_key(key.as_str()))
+    }) {
+        tree.node(root, "", None);
+    }
+
+    if tree.referenced {
+        let _ = writeln!(
+            tree.output,
+            "\n{} already shown above with its dependencies",
+            REFERENCE
+        );
+    }
+
+    tree.output
+}
+
+struct Tree<'a> {
+    included: &'a BTreeMap<&'a str, &'a Stack>,
+    states: &'a HashMap<String, RunState>,
+    shown: HashSet<&'a str>,
+    referenced: bool,
+    output: String,
+}
+
+impl<'a> Tree<'a> {
+    /// Writes the stack and its dependencies, `last` is whether the stack is
+    /// the last child of its parent and is `None` for a root.
+    fn node(&mut self, stack: &'a Stack, prefix: &str, last: Option<bool>) {
+        let (branch, indent) = match last {
+            None => ("", ""),
+            Some(false) => ("├── ", "│   "),
+            Some(true) => ("└── ", "    "),
+        };
+
+        let mut line = format!("{}{}{}", prefix, branch, stack.key);
+        if let Some(state) = self.states.get(&stack.key) {
+            let _ = write!(line, " [{}]", state.label());
+        }
+
+        if !self.shown.insert(&stack.key) {
+            self.referenced = true;
+            let _ = writeln!(self.output, "{} {}", line, REFERENCE);
+            return;
+        }
+        let _ = writeln!(self.output, "{}", line);
+
+        let dependencies: Vec<&'a Stack> = stack
+            .dependencies
+            .iter()
+            .filter_map(|key| self.included.get(key.as_str()).copied())
+            .collect();
+        let prefix = format!("{}{}", prefix, indent);
+        for (index, dependency) in dependencies.iter().enumerate() {
+            self.node(dependency, &prefix, Some(index + 1 == dependencies.len()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, render_tree, Entry};
+    use crate::config::Config;
+    use crate::status::RunState;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn config(yaml: &str) -> Config {
+        Config::from_reader(&PathBuf::from("/stacks"), yaml.as_bytes()).unwrap()
+    }
+
+    fn entry(stack: &str, description: &str, state: Option<RunState>) -> Entry {
+        Entry {
+            stack: stack.to_string(),
+            description: description.to_string(),
+            state,
+            urls: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn table() {
+        assert_eq!(
+            render(&[entry("db", "The database", None), entry("web", "", None),]),
+            "Stack  Description\n\
+            db     The database\n\
+            web\n"
+        );
+
+        assert_eq!(
+            render(&[
+                entry("db", "The database", Some(RunState::Up)),
+                entry("web", "", Some(RunState::Partial)),
+                entry("agent", "Runs jobs", Some(RunState::Unknown)),
+            ]),
+            "Stack  State    Description\n\
+            db     up       The database\n\
+            web    partial\n\
+            agent  unknown  Runs jobs\n"
+        );
+
+        let with_urls = |mut entry: Entry, urls: &[&str]| {
+            entry.urls = urls.iter().map(|url| url.to_string()).collect();
+            entry
+        };
+        assert_eq!(
+            render(&[
+                with_urls(
+                    entry("web", "The site", Some(RunState::Up)),
+                    &["http://localhost:8080", "http://localhost:8080/admin"]
+                ),
+                entry("db", "The database", Some(RunState::Down)),
+            ]),
+            "Stack  State  URL                                                 Description\n\
+            web    up     http://localhost:8080, http://localhost:8080/admin  The site\n\
+            db     down                                                       The database\n"
+        );
+    }
+
+    #[test]
+    fn diamond_tree() {
+        let config = config(
+            "
+            stacks:
+                base: {}
+                left:
+                    depends_on:
+                        - base
+                right:
+                    depends_on:
+                        - base
+                top:
+                    depends_on:
+                        - left
+                        - right
+                alone: {}
+            ",
+        );
+        let stacks = config.stacks(Vec::<String>::new()).unwrap();
+
+        assert_eq!(
+            render_tree(&stacks, &HashMap::new()),
+            "alone\n\
+            top\n\
+            ├── left\n\
+            │   └── base\n\
+            └── right\n\
+            \x20   └── base (*)\n\
+            \n\
+            (*) already shown above with its dependencies\n"
+        );
+
+        // Only the stacks in the list are shown.
+        let stacks = config.stacks(["left", "base"]).unwrap();
+        assert_eq!(
+            render_tree(&stacks, &HashMap::new()),
+            "left\n\
+            └── base\n"
+        );
+    }
+
+    #[test]
+    fn forest() {
+        let config = config(
+            "
+            stacks:
+                db: {}
+                cache: {}
+                api:
+                    depends_on:
+                        - db
+                        - cache
+                web:
+                    depends_on:
+                        - api
+                reports:
+                    depends_on:
+                        - api
+                backup:
+                    depends_on:
+                        - db
+            ",
+        );
+        let stacks = config.stacks(Vec::<String>::new()).unwrap();
+        let states: HashMap<String, RunState> = [
+            ("db", RunState::Up),
+            ("cache", RunState::Up),
+            ("api", RunState::Partial),
+            ("web", RunState::Down),
+            ("reports", RunState::Down),
+            ("backup", RunState::Unknown),
+        ]
+        .into_iter()
+        .map(|(key, state)| (key.to_string(), state))
+        .collect();
+
+        let tree = render_tree(&stacks, &states);
+        assert_eq!(
+            tree,
+            "backup [unknown]\n\
+            └── db [up]\n\
+            reports [down]\n\
+            └── api [partial]\n\
+            \x20   ├── cache [up]\n\
+            \x20   └── db [up] (*)\n\
+            web [down]\n\
+            └── api [partial] (*)\n\
+            \n\
+            (*) already shown above with its dependencies\n"
+        );
+
+        // The output doesn't depend on the order the stacks are given in.
+        let mut reversed = stacks.clone();
+        reversed.reverse();
+        assert_eq!(render_tree(&reversed, &states), tree);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::state::{now, STATE_DIR};
+use crate::status::humanize;
+
+/// How often a held lock is retried while waiting for it.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Parses a duration such as `30s`, `5m` or `1h`. A bare number is seconds.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    let (number, scale) = match s.char_indices().last() {
+        Some((index, 's')) => (&s[..index], 1),
+        Some((index, 'm')) => (&s[..index], 60),
+        Some((index, 'h')) => (&s[..index], 60 * 60),
+        _ => (s, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .map(|n| Duration::from_secs(n * scale))
+        .map_err(|_| format!("Invalid duration \"{}\"", s))
+}
+
+/// Who holds the lock, written into the lock file by the holder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holder {
+    pub pid: u32,
+    pub command: String,
+    /// When the lock was taken, in seconds since the epoch.
+    pub started: u64,
+}
+
+impl Holder {
+    fn describe(&self) -> String {
+        format!(
+            "pid {} running `{}`, started {}",
+            self.pid,
+            self.command,
+            humanize(now().saturating_sub(self.started))
+        )
+    }
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor is valid for the lifetime of the file.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let error = io::Error::last_os_error();
+    if error.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(error)
+    }
+}
+
+/// Without flock concurrent runs are not prevented.
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// An advisory lock preventing other runs of stacks from changing the same
+/// stacks at the same time. Released when dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// The lock file for the given stacks config file.
+    pub fn path_for_config(stacks_file: &Path) -> PathBuf {
+        let dir = stacks_file.parent().unwrap_or(Path::new("."));
+        dir.join(STATE_DIR).join("lock")
+    }
+
+    /// Takes the lock, waiting up to `wait` for any other run to release it.
+    pub fn acquire(path: &Path, command: &str, wait: Option<Duration>) -> Result<Self> {
+        let io_error = |e| Error::io(format!("Failed to lock {}", path.display()), e);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(io_error)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(io_error)?;
+
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        let mut waiting = false;
+        while !try_lock(&file).map_err(io_error)? {
+            let holder = Self::holder(&mut file);
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => {
+                    if !waiting {
+                        log::info!("Waiting for another stack run ({}) to finish…", holder);
+                        waiting = true;
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                _ => {
+                    return Err(Error::Locked {
+                        path: path.display().to_string(),
+                        holder,
+                    })
+                }
+            }
+        }
+
+        let holder = Holder {
+            pid: std::process::id(),
+            command: command.to_owned(),
+            started: now(),
+        };
+        let content = serde_json::to_string(&holder)
+            .map_err(|e| Error::Invalid(format!("Failed to serialize lock: {}", e)))?;
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| file.write_all(content.as_bytes()))
+            .map_err(io_error)?;
+
+        Ok(Self { _file: file })
+    }
+
+    /// Describes the holder of the lock from the lock file.
+    fn holder(file: &mut File) -> String {
+        let mut content = String::new();
+        let _ = file
+            .rewind()
+            .and_then(|_| file.read_to_string(&mut content));
+        match serde_json::from_str::<Holder>(&content) {
+            Ok(holder) => holder.describe(),
+            Err(_) => "unknown holder".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_duration, RunLock};
+    use crate::error::Error;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5 days").is_err());
+    }
+
+    #[test]
+    fn exclusion() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = RunLock::path_for_config(&temp.path().join("stacks.yml"));
+
+        let lock = RunLock::acquire(&path, "up", None).unwrap();
+
+        // A second run fails immediately and says who holds the lock.
+        match RunLock::acquire(&path, "down", None) {
+            Err(Error::Locked { holder, .. }) => assert_eq!(
+                holder,
+                format!("pid {} running `up`, started just now", std::process::id())
+            ),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // Or times out.
+        assert!(matches!(
+            RunLock::acquire(&path, "down", Some(Duration::from_millis(200))),
+            Err(Error::Locked { .. })
+        ));
+
+        // Or waits for the lock to be released.
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                drop(lock);
+            });
+
+            RunLock::acquire(&path, "down", Some(Duration::from_secs(10))).unwrap();
+        });
+
+        // The lock is free again once released.
+        RunLock::acquire(&path, "restart", None).unwrap();
+    }
+}
@@ -1,45 +1,80 @@
-mod commands;
-mod config;
-mod exec;
 mod program;
 
-use std::{env::current_dir, fs::File, path::PathBuf};
+use std::{
+    env::{self, current_dir},
+    fs::{self, File, OpenOptions},
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
-use flexi_logger::{LevelFilter, LogSpecBuilder, Logger};
+use flexi_logger::{
+    detailed_format, Duplicate, FileSpec, LevelFilter, LogSpecBuilder, LogSpecification, Logger,
+    LoggerHandle,
+};
 
-use program::Program;
+use program::{Commands, Program};
+use stacks::{
+    cancel::Cancellation,
+    commands::Runner,
+    config::{parse_command, Config},
+    error::{Error, Result},
+    events::{Event, EventFile},
+    exec::{FOREGROUND_COMMANDS, MUTATING_COMMANDS},
+    history::{self, HistoryLog},
+    lock::RunLock,
+    notify::{self, DesktopNotifier},
+    output::{Captured, Filtered, JsonEvents, LineFilter, OutputHandler, Passthrough},
+    paths,
+    record::FileRecorder,
+    remote::{self, RemoteConfig},
+    schema,
+    selection::{expand_last, SelectionStore},
+    state::{self, StateFile},
+    summary,
+};
 
-use config::Config;
+/// The names of the config file to look for in a directory, in priority order.
+const CONFIG_NAMES: [&str; 3] = ["stacks.yml", "stacks.yaml", ".stacks.yml"];
 
-fn stacks_file(file: &Option<String>) -> Result<PathBuf, String> {
-    let mut dir = current_dir().map_err(|e| format!("Current directory is invalid: {}", e))?;
+fn find_in_directory(dir: &Path) -> Option<PathBuf> {
+    CONFIG_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|target| target.is_file())
+}
 
+fn stacks_file(cwd: &Path, file: &Option<String>) -> Result<PathBuf> {
     match file {
         Some(path) => {
-            let mut target = dir;
-            target.push(path);
-            target = target
-                .canonicalize()
-                .map_err(|e| format!("Invalid path: {}", e))?;
+            let target =
+                paths::canonicalize(cwd.join(path)).map_err(|e| Error::io("Invalid path", e))?;
 
             if target.is_file() {
                 Ok(target)
+            } else if target.is_dir() {
+                find_in_directory(&target).ok_or_else(|| {
+                    Error::Invalid(format!(
+                        "The directory {} does not contain any of {}.",
+                        path,
+                        CONFIG_NAMES.join(", ")
+                    ))
+                })
             } else {
-                Err(format!(
+                Err(Error::Invalid(format!(
                     "The file {} does not exist or is not a file.",
                     path
-                ))
+                )))
             }
         }
         None => {
+            let mut dir = cwd.to_path_buf();
+
             loop {
-                let mut target = dir.clone();
-                target.push("stacks.yml");
-                if target.is_file() {
-                    return target
-                        .canonicalize()
-                        .map_err(|e| format!("Invalid path: {}", e));
+                if let Some(target) = find_in_directory(&dir) {
+                    return paths::canonicalize(target).map_err(|e| Error::io("Invalid path", e));
                 }
 
                 dir = match dir.parent() {
@@ -48,38 +83,680 @@ fn stacks_file(file: &Option<String>) -> Result<PathBuf, String> {
                 }
             }
 
-            Err(
-                "No stacks.yml file present in the current directory or any of its parents."
-                    .to_string(),
-            )
+            Err(Error::Invalid(format!(
+                "None of {} are present in the current directory or any of its parents.",
+                CONFIG_NAMES.join(", ")
+            )))
         }
     }
 }
 
-fn run() -> Result<(), String> {
-    let args = Program::parse();
+/// Loads the config either from the stacks file or from the given reader when
+/// the file is `-`. Returns the path to the stacks file if there was one.
+fn load_config<R: Read>(
+    cwd: &Path,
+    file: &Option<String>,
+    base_dir: &Option<String>,
+    stdin: R,
+    remote: &RemoteConfig,
+) -> Result<(Option<PathBuf>, Config)> {
+    let (stacks_file, config, default_dir) = match file.as_deref() {
+        Some("-") => {
+            log::debug!("Loading stacks from stdin");
+            (None, Config::parse(stdin)?, Some(cwd.to_owned()))
+        }
+        Some(url) if remote::is_url(url) => {
+            let cached = remote.fetch(url)?;
+            log::debug!("Loading stacks from {} ({})", url, cached.display());
+            let f = File::open(&cached)
+                .map_err(|e| Error::io(format!("Failed to open file {}", cached.display()), e))?;
+            (None, Config::parse(f)?, None)
+        }
+        _ => {
+            let stacks_file = stacks_file(cwd, file)?;
+            log::debug!("Loading stacks from {}", stacks_file.display());
+            let f = File::open(&stacks_file).map_err(|e| {
+                Error::io(format!("Failed to open file {}", stacks_file.display()), e)
+            })?;
+            let parent = stacks_file.parent().unwrap().to_owned();
+            (Some(stacks_file), Config::parse(f)?, Some(parent))
+        }
+    };
+
+    // `--base-dir` wins over the `base_dir` in the config, which is relative
+    // to the directory containing the config or the current directory. A
+    // config from a URL has no directory of its own so one of them is needed.
+    let base_dir = match (base_dir, &config.configured_base_dir, default_dir) {
+        (Some(dir), _, _) => cwd.join(dir),
+        (None, Some(dir), default) => paths::join(default.as_deref().unwrap_or(cwd), dir),
+        (None, None, Some(default)) => default,
+        (None, None, None) => {
+            return Err(Error::Invalid(format!(
+                "The stacks config from {} doesn't set `base_dir` so `--base-dir` must be given.",
+                file.as_deref().unwrap_or_default()
+            )))
+        }
+    };
+
+    Ok((stacks_file, config.resolve(&base_dir)?))
+}
+
+/// Changes the working directory for `--chdir`. A relative directory is
+/// relative to `cwd`. Returns the new working directory.
+fn change_directory(cwd: &Path, dir: &Path) -> Result<PathBuf> {
+    let target = cwd.join(dir);
+    if !target.is_dir() {
+        return Err(Error::Invalid(format!(
+            "Cannot change to {}, it is not a directory.",
+            dir.display()
+        )));
+    }
+
+    env::set_current_dir(&target)
+        .map_err(|e| Error::io(format!("Cannot change to {}", dir.display()), e))?;
+    current_dir().map_err(|e| Error::io("Current directory is invalid", e))
+}
 
-    Logger::with(
-        LogSpecBuilder::new()
-            .default(LevelFilter::Error)
-            .module("stack", args.verbose.log_level_filter())
-            .build(),
+fn log_spec(level: LevelFilter) -> LogSpecification {
+    LogSpecBuilder::new()
+        .default(LevelFilter::Error)
+        .module("stack", level)
+        .build()
+}
+
+/// Appends a line to the log file marking the start of this invocation.
+fn write_log_header(path: &Path) -> io::Result<()> {
+    let [year, month, day, hour, minute, second] = state::utc(state::now());
+    let args: Vec<String> = env::args().collect();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "==== {:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z {} ====",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        args.join(" ")
     )
-    .start()
-    .unwrap();
+}
+
+/// Logs to stderr at the requested verbosity and, when a log file is given,
+/// everything at debug level to the file too. Problems with the log file only
+/// cause a warning. The returned handle must be kept alive while logging.
+fn start_logging(args: &Program) -> Option<LoggerHandle> {
+    let level = args.verbose.log_level_filter();
+
+    let to_file = |path: &Path| -> std::result::Result<LoggerHandle, String> {
+        write_log_header(path).map_err(|e| e.to_string())?;
+        Logger::with(log_spec(level.max(LevelFilter::Debug)))
+            .log_to_file(FileSpec::try_from(path).map_err(|e| e.to_string())?)
+            .append()
+            .format_for_files(detailed_format)
+            .duplicate_to_stderr(Duplicate::from(level))
+            .start()
+            .map_err(|e| e.to_string())
+    };
+
+    let mut warning = None;
+    if let Some(ref path) = args.globals.log_file {
+        let path = match current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => path.clone(),
+        };
+        match to_file(&path) {
+            Ok(handle) => return Some(handle),
+            Err(e) => warning = Some(format!("Unable to log to {}: {}", path.display(), e)),
+        }
+    }
+
+    let handle = Logger::with(log_spec(level)).start().ok();
+    if let Some(warning) = warning {
+        log::warn!("{}", warning);
+    }
+    handle
+}
+
+fn run(mut args: Program) -> Result<()> {
+    args.globals.apply_ci(env::var_os("CI").as_deref());
+
+    // The schema and documentation don't depend on any config.
+    match args.command {
+        Commands::Schema => {
+            println!("{}", schema::render());
+            return Ok(());
+        }
+        Commands::GenerateDocs { ref dir } => return program::generate_docs(dir),
+        _ => {}
+    }
+
+    let cwd = current_dir().map_err(|e| Error::io("Current directory is invalid", e))?;
+    let remote = RemoteConfig::from_env(args.fetch_timeout);
+    let (stacks_file, mut config) =
+        load_config(&cwd, &args.file, &args.base_dir, io::stdin(), &remote)?;
+
+    // The previous selection can only be tracked for config files on disk.
+    let store = stacks_file.as_ref().and(SelectionStore::from_env());
+    let load_last = || match (&store, &stacks_file) {
+        (Some(store), Some(stacks_file)) => store.load(stacks_file),
+        _ => Ok(None),
+    };
+    let selection = expand_last(&args.globals.selection(&config, &cwd)?, load_last)?;
+    let named = expand_last(&args.globals.named(&config, &cwd)?, load_last)?;
+
+    if let Some(ref binary) = args.binary {
+        let command = parse_command(binary)
+            .map_err(|e| Error::Invalid(format!("Invalid `--binary` \"{}\": {}", binary, e)))?;
+        config.command_override = Some(command);
+    }
+    config.detect_engine(env::var_os("PATH").as_deref());
+    config.compose_args = args.globals.compose_args(config.engine());
+    config.cli_environment = args.globals.env.iter().cloned().collect();
+    if args.globals.clean_env {
+        config.inherit_env = false;
+    }
+    if args.globals.create_dirs {
+        config.create_dirs = true;
+    }
+    if args.globals.frozen {
+        config.frozen = true;
+    }
+    config.keep_orphans = args.globals.no_remove_orphans;
+
+    // Catch unknown stacks before attempting to run anything.
+    config.stacks(&selection)?;
+
+    let captured = Captured::new(&Passthrough);
+    let output: &dyn OutputHandler = if args.globals.quiet_success {
+        &captured
+    } else {
+        &Passthrough
+    };
+
+    // Invalid patterns fail before anything is run.
+    let filtered = match args.command {
+        Commands::Logs {
+            ref grep,
+            invert_grep,
+            ..
+        } if !grep.is_empty() => Some(Filtered::new(LineFilter::new(grep, invert_grep)?, output)),
+        _ => None,
+    };
+    let output: &dyn OutputHandler = match filtered {
+        Some(ref filtered) => filtered,
+        None => output,
+    };
+
+    // JSON events go straight to stdout so they can be consumed as they are.
+    let json_events = match args.command {
+        Commands::Events { json: true, .. } => Some(JsonEvents::new(&Passthrough)),
+        _ => None,
+    };
+    let output: &dyn OutputHandler = match json_events {
+        Some(ref json_events) => json_events,
+        None => output,
+    };
+
+    let recorder = FileRecorder::from_env();
+
+    let mut runner = Runner::new(&config, output);
+    runner.cancel = Cancellation::with_grace(Duration::from_secs(config.kill_grace));
+    runner.cancel.install_handler()?;
+    runner.prefix = !args.globals.no_prefix;
+    runner.preflight = !args.globals.no_preflight;
+    // Nothing is spawned while recording, including the preflight checks.
+    if let Some(ref recorder) = recorder {
+        log::debug!("Recording commands to {}", recorder.path().display());
+        runner.executor = recorder;
+        runner.preflight = false;
+    }
+    runner.strict_version = args.globals.strict_version;
+    runner.skip_running = args.globals.skip_running;
+    runner.from = args.globals.from.clone();
+    runner.until = args.globals.until.clone();
+    runner.named = named;
+    runner.config_file = stacks_file.clone();
+    runner.state = stacks_file.as_deref().map(StateFile::for_config);
+    runner.history = match (&config.history_file, &stacks_file) {
+        (Some(path), _) => Some(HistoryLog::new(
+            config.base_dir.join(path),
+            config.history_max_size,
+        )),
+        (None, Some(stacks_file)) => {
+            Some(HistoryLog::for_config(stacks_file, config.history_max_size))
+        }
+        (None, None) => None,
+    };
+    runner.retry.retries = args.globals.retries;
+    runner.retry.all = args.globals.retry_all;
+    runner.color = args.globals.color(io::stdout().is_terminal());
+    runner.progress =
+        !args.globals.no_progress && io::stdout().is_terminal() && io::stderr().is_terminal();
+    if json_events.is_some() {
+        runner.prefix = false;
+        runner.progress = false;
+    }
+
+    // Only runs against a config file on disk can be locked.
+    let _lock = match &stacks_file {
+        Some(stacks_file) if MUTATING_COMMANDS.contains(&args.command.name()) => {
+            Some(RunLock::acquire(
+                &RunLock::path_for_config(stacks_file),
+                args.command.name(),
+                args.globals.wait_lock,
+            )?)
+        }
+        _ => None,
+    };
+
+    if let Some(ref path) = args.globals.progress_file {
+        let path = cwd.join(path);
+        runner.events = Some(
+            EventFile::create(&path, args.globals.progress_output)
+                .map_err(|e| Error::io(format!("Failed to open {}", path.display()), e))?,
+        );
+    }
+
+    let timestamp = state::now();
+    runner.log_dir = match args.globals.log_dir.as_ref() {
+        Some(dir) => Some(cwd.join(dir)),
+        None => config.log_dir.as_ref().map(|dir| config.base_dir.join(dir)),
+    }
+    .map(|dir| {
+        let [year, month, day, hour, minute, second] = state::utc(timestamp);
+        let dir = dir.join(format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            year, month, day, hour, minute, second
+        ));
+        fs::create_dir_all(&dir)
+            .map(|_| dir.clone())
+            .map_err(|e| Error::io(format!("Failed to create {}", dir.display()), e))
+    })
+    .transpose()?;
+
+    let start = Instant::now();
+    let result = args.command.run(&args.globals, &selection, &runner);
+
+    if let Some(ref history_log) = runner.history {
+        if MUTATING_COMMANDS.contains(&args.command.name()) {
+            let entry = history::Entry {
+                timestamp,
+                user: history::current_user(),
+                args: env::args().skip(1).collect(),
+                stacks: runner.summary.keys(),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                duration: start.elapsed().as_secs_f64(),
+            };
+            if let Err(e) = history_log.append(&entry) {
+                log::warn!("Unable to record history: {}", e);
+            }
+        }
+    }
+
+    let foreground = FOREGROUND_COMMANDS.contains(&args.command.name());
+    if !foreground && args.globals.show_summary(runner.summary.stacks()) {
+        eprint!("\n{}", runner.summary.render());
+        if let Some(hint) = runner.summary.resume_hint() {
+            eprintln!("After fixing the failure resume with `{}`.", hint);
+        }
+    }
+
+    if ["up", "restart"].contains(&args.command.name()) {
+        let endpoints: Vec<(String, Vec<String>)> = runner
+            .summary
+            .succeeded(args.command.name())
+            .into_iter()
+            .filter_map(|key| config.stacks.get(&key))
+            .filter_map(|stack| match runner.stack_urls(stack) {
+                Ok(urls) => Some((stack.key.clone(), urls)),
+                Err(e) => {
+                    log::warn!("{}", e);
+                    None
+                }
+            })
+            .collect();
+        let endpoints = summary::render_endpoints(&endpoints);
+        if !endpoints.is_empty() {
+            eprint!("\n{}", endpoints);
+        }
+    }
+
+    if let Some(ref path) = args.globals.timings {
+        let report = runner.timings.report();
+        eprint!("\n{}", report.render());
+        let path = cwd.join(path);
+        if let Err(e) = fs::write(&path, serde_json::to_string_pretty(&report).unwrap()) {
+            log::warn!("Unable to write timings to {}: {}", path.display(), e);
+        }
+    }
+
+    let elapsed = start.elapsed();
+    if !foreground && config.notify.should_notify(args.globals.notify, elapsed) {
+        notify::send(
+            &DesktopNotifier,
+            args.command.name(),
+            result.is_ok(),
+            &runner.summary.entries(),
+            elapsed,
+        );
+    }
+
+    if let Some(ref events) = runner.events {
+        events.emit(&Event::RunFinished {
+            success: result.is_ok(),
+            duration: start.elapsed().as_secs_f64(),
+        });
+    }
 
-    let stacks_file = stacks_file(&args.file)?;
-    log::debug!("Loading stacks from {}", stacks_file.display());
-    let f = File::open(&stacks_file)
-        .map_err(|e| format!("Failed to open file {}: {}", stacks_file.display(), e))?;
+    // Only mention the log directory if something was logged.
+    if let Some(ref dir) = runner.log_dir {
+        if fs::remove_dir(dir).is_err() {
+            eprintln!("Output was saved in {}", dir.display());
+        }
+    }
 
-    let config = Config::from_reader(stacks_file.parent().unwrap(), f)?;
+    if captured.suppressed() > 0 {
+        eprintln!(
+            "Suppressed {} lines of output from successful stacks.",
+            captured.suppressed()
+        );
+    }
+    if let Some(suppressed) = filtered.as_ref().map(Filtered::suppressed) {
+        eprintln!(
+            "Suppressed {} lines that didn't match `--grep`.",
+            suppressed
+        );
+    }
+    result?;
 
-    args.command.run(&args.globals, &config)
+    if args.globals.explicit() && !selection.is_empty() {
+        if let (Some(store), Some(stacks_file)) = (&store, &stacks_file) {
+            if let Err(e) = store.save(stacks_file, &selection) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    Ok(())
 }
 
-fn main() {
-    if let Err(e) = run() {
-        eprintln!("{}", e);
+fn main() -> ExitCode {
+    let args = Program::parse();
+
+    // Everything, including the log file, is relative to the new directory.
+    if let Some(ref dir) = args.chdir {
+        let changed = current_dir()
+            .map_err(|e| Error::io("Current directory is invalid", e))
+            .and_then(|cwd| change_directory(&cwd, dir));
+        if let Err(error) = changed {
+            eprintln!("{}", error);
+            return error.exit_code();
+        }
+    }
+
+    let _logger = start_logging(&args);
+
+    let start = Instant::now();
+    let name = args.command.name();
+    let result = run(args);
+    log::debug!(
+        "{} {} after {}",
+        name,
+        if result.is_ok() { "finished" } else { "failed" },
+        summary::format_duration(start.elapsed())
+    );
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            log::debug!("{}", error);
+            eprintln!("{}", error);
+            error.exit_code()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{change_directory, load_config, stacks_file};
+    use stacks::error::Error;
+    use stacks::remote::RemoteConfig;
+    use stacks::testing::HttpServer;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn file() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("infra/sub")).unwrap();
+        fs::write(root.join("infra/custom.yml"), "").unwrap();
+
+        assert_eq!(
+            stacks_file(&root, &Some("infra/custom.yml".to_string())).unwrap(),
+            root.join("infra/custom.yml")
+        );
+
+        let error = stacks_file(&root, &Some("infra/missing.yml".to_string()))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Invalid path: "));
+    }
+
+    #[test]
+    fn directory() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("infra")).unwrap();
+        fs::create_dir_all(root.join("other")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("infra/stacks.yml"), "").unwrap();
+        fs::write(root.join("other/stacks.yaml"), "").unwrap();
+
+        // Relative directories.
+        assert_eq!(
+            stacks_file(&root, &Some("infra".to_string())).unwrap(),
+            root.join("infra/stacks.yml")
+        );
+        assert_eq!(
+            stacks_file(&root.join("empty"), &Some("../other".to_string())).unwrap(),
+            root.join("other/stacks.yaml")
+        );
+
+        // Absolute directories.
+        assert_eq!(
+            stacks_file(
+                &root.join("empty"),
+                &Some(root.join("infra").to_str().unwrap().to_string())
+            )
+            .unwrap(),
+            root.join("infra/stacks.yml")
+        );
+
+        assert_eq!(
+            stacks_file(&root, &Some("empty".to_string()))
+                .unwrap_err()
+                .to_string(),
+            "The directory empty does not contain any of stacks.yml, stacks.yaml, .stacks.yml."
+        );
+    }
+
+    #[test]
+    fn discovery() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+
+        for name in ["stacks.yml", "stacks.yaml", ".stacks.yml"] {
+            let dir = root.join(name.replace('.', "_"));
+            fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+            fs::write(dir.join(name), "").unwrap();
+
+            assert_eq!(stacks_file(&dir, &None).unwrap(), dir.join(name));
+            assert_eq!(
+                stacks_file(&dir.join("nested/deeper"), &None).unwrap(),
+                dir.join(name)
+            );
+        }
+
+        let dir = root.join("priority");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join(".stacks.yml"), "").unwrap();
+        fs::write(dir.join("stacks.yaml"), "").unwrap();
+        assert_eq!(
+            stacks_file(&dir.join("nested"), &None).unwrap(),
+            dir.join("stacks.yaml")
+        );
+        fs::write(dir.join("stacks.yml"), "").unwrap();
+        assert_eq!(stacks_file(&dir, &None).unwrap(), dir.join("stacks.yml"));
+
+        // The closest directory wins even with a lower priority name.
+        fs::write(dir.join("nested/.stacks.yml"), "").unwrap();
+        assert_eq!(
+            stacks_file(&dir.join("nested"), &None).unwrap(),
+            dir.join("nested/.stacks.yml")
+        );
+    }
+
+    #[test]
+    fn stdin() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        let remote = RemoteConfig::new(&root.join("cache"), Duration::from_secs(10), None);
+        let stdin = "
+            stacks:
+                web: {}
+                db:
+                    directory: data/db
+            ";
+
+        let (file, config) = load_config(
+            &root,
+            &Some("-".to_string()),
+            &None,
+            stdin.as_bytes(),
+            &remote,
+        )
+        .unwrap();
+        assert_eq!(file, None);
+        assert_eq!(config.base_dir, root);
+        assert_eq!(
+            config
+                .stacks
+                .get("web")
+                .unwrap()
+                .directory(&config.base_dir),
+            root.join("web")
+        );
+
+        let (_, config) = load_config(
+            &root,
+            &Some("-".to_string()),
+            &Some("projects".to_string()),
+            stdin.as_bytes(),
+            &remote,
+        )
+        .unwrap();
+        assert_eq!(config.base_dir, root.join("projects"));
+        assert_eq!(
+            config.stacks.get("db").unwrap().directory(&config.base_dir),
+            root.join("projects/data/db")
+        );
+
+        // A file on disk is not consulted.
+        fs::write(root.join("stacks.yml"), "stacks:\n  other: {}\n").unwrap();
+        let (_, config) = load_config(
+            &root,
+            &Some("-".to_string()),
+            &None,
+            stdin.as_bytes(),
+            &remote,
+        )
+        .unwrap();
+        assert!(config.stacks.contains_key("web"));
+        assert!(!config.stacks.contains_key("other"));
+
+        // Whereas normally the base directory is where the file is.
+        let (file, config) = load_config(&root, &None, &None, "".as_bytes(), &remote).unwrap();
+        assert_eq!(file, Some(root.join("stacks.yml")));
+        assert_eq!(config.base_dir, root);
+        assert!(config.stacks.contains_key("other"));
+    }
+
+    #[test]
+    fn remote() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        let remote = RemoteConfig::new(&root.join("cache"), Duration::from_secs(10), None);
+        let config = "HTTP/1.1 200 OK\r\n\r\nstacks:\n  web: {}\n";
+        let located = "HTTP/1.1 200 OK\r\n\r\nbase_dir: /srv/stacks\nstacks:\n  web: {}\n";
+        let server = HttpServer::start(&[config, config, located, located]);
+        let url = Some(server.url("/stacks.yml"));
+
+        // The base directory must be given somehow.
+        match load_config(&root, &url, &None, "".as_bytes(), &remote) {
+            Err(Error::Invalid(message)) => assert!(message.contains("`--base-dir`")),
+            result => panic!("Unexpected {:?}", result.map(|(file, _)| file)),
+        }
+
+        let (file, config) = load_config(
+            &root,
+            &url,
+            &Some("projects".to_string()),
+            "".as_bytes(),
+            &remote,
+        )
+        .unwrap();
+        assert_eq!(file, None);
+        assert_eq!(config.base_dir, root.join("projects"));
+        assert!(config.stacks.contains_key("web"));
+
+        let (_, config) = load_config(&root, &url, &None, "".as_bytes(), &remote).unwrap();
+        assert_eq!(config.base_dir, PathBuf::from("/srv/stacks"));
+
+        // `--base-dir` takes precedence.
+        let (_, config) = load_config(
+            &root,
+            &url,
+            &Some("projects".to_string()),
+            "".as_bytes(),
+            &remote,
+        )
+        .unwrap();
+        assert_eq!(config.base_dir, root.join("projects"));
+        server.requests();
+
+        // The `base_dir` in a local config is relative to the config.
+        fs::create_dir_all(root.join("infra")).unwrap();
+        fs::write(
+            root.join("infra/stacks.yml"),
+            "base_dir: ../services\nstacks:\n  web: {}\n",
+        )
+        .unwrap();
+        let (_, config) = load_config(
+            &root,
+            &Some("infra/stacks.yml".to_string()),
+            &None,
+            "".as_bytes(),
+            &remote,
+        )
+        .unwrap();
+        assert_eq!(config.base_dir, root.join("infra").join("../services"));
+    }
+
+    #[test]
+    fn missing_chdir() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::write(temp.path().join("file"), "").unwrap();
+
+        for dir in ["missing", "file"] {
+            let error = change_directory(temp.path(), dir.as_ref()).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Cannot change to {}, it is not a directory.", dir)
+            );
+        }
     }
 }
@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::lock::parse_duration;
+use crate::summary::{format_duration, Entry, Outcome};
+
+/// How long a run must take before a notification is sent, by default.
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(30);
+
+fn deserialize_threshold<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).map_err(de::Error::custom)
+}
+
+fn serialize_threshold<S>(
+    threshold: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format!("{}s", threshold.as_secs()))
+}
+
+fn default_threshold() -> Duration {
+    DEFAULT_THRESHOLD
+}
+
+/// When to send a desktop notification at the end of a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NotifySettings {
+    /// Whether to notify without `--notify`.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Runs that are quicker than this are not worth a notification.
+    #[serde(
+        default = "default_threshold",
+        deserialize_with = "deserialize_threshold",
+        serialize_with = "serialize_threshold"
+    )]
+    pub threshold: Duration,
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+impl NotifySettings {
+    /// Whether a run that took `elapsed` should send a notification.
+    pub fn should_notify(&self, requested: bool, elapsed: Duration) -> bool {
+        (requested || self.enabled) && elapsed >= self.threshold
+    }
+}
+
+/// Describes how a run went, e.g. `up completed, 12 ok, 1 failed, 4m32s`.
+pub fn message(command: &str, success: bool, entries: &[Entry], elapsed: Duration) -> String {
+    let count =
+        |wanted: fn(&Outcome) -> bool| entries.iter().filter(|e| wanted(&e.outcome)).count();
+    let ok = count(|o| *o == Outcome::Ok);
+    let failed = count(|o| matches!(o, Outcome::Failed(_)));
+    let skipped = count(|o| *o == Outcome::Skipped);
+
+    let mut parts = vec![format!(
+        "{} {}",
+        command,
+        if success { "completed" } else { "failed" }
+    )];
+    if !entries.is_empty() {
+        parts.push(format!("{} ok", ok));
+        parts.push(format!("{} failed", failed));
+    }
+    if skipped > 0 {
+        parts.push(format!("{} skipped", skipped));
+    }
+    parts.push(format_duration(elapsed));
+
+    parts.join(", ")
+}
+
+/// Shows notifications to the user.
+pub trait Notifier {
+    fn notify(&self, title: &str, body: &str) -> std::result::Result<(), String>;
+}
+
+/// Shows notifications on the desktop with `notify-send` on Linux and
+/// `osascript` on macOS, rather than linking a notification library and its
+/// D-Bus dependencies for a rarely used feature. Elsewhere notifications are
+/// dropped.
+pub struct DesktopNotifier;
+
+impl Notifier for DesktopNotifier {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn notify(&self, title: &str, body: &str) -> std::result::Result<(), String> {
+        use std::io::ErrorKind;
+        use std::process::{Command, Stdio};
+
+        let program = if cfg!(target_os = "macos") {
+            "osascript"
+        } else {
+            "notify-send"
+        };
+        let mut command = Command::new(program);
+        if cfg!(target_os = "macos") {
+            command.args([
+                "-e",
+                &format!("display notification {:?} with title {:?}", body, title),
+            ]);
+        } else {
+            command.args([title, body]);
+        }
+
+        match command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+        {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("{} failed: {}", program, status)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::debug!("Unable to run {}: {}", program, e);
+                Err(format!("`{}` is not installed", program))
+            }
+            Err(e) => Err(format!("Unable to run {}: {}", program, e)),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn notify(&self, _title: &str, _body: &str) -> std::result::Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Sends the notification for a run. Failing to deliver it is only logged.
+pub fn send(
+    notifier: &dyn Notifier,
+    command: &str,
+    success: bool,
+    entries: &[Entry],
+    elapsed: Duration,
+) {
+    let body = message(command, success, entries, elapsed);
+    if let Err(e) = notifier.notify("stack", &body) {
+        log::warn!("Unable to show a notification: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{message, send, Notifier, NotifySettings};
+    use crate::summary::{Entry, Outcome};
+    use std::cell::RefCell;
+    use std::time::{Duration, Instant};
+
+    fn entry(stack: &str, outcome: Outcome) -> Entry {
+        let now = Instant::now();
+        Entry {
+            stack: stack.to_string(),
+            command: "up".to_string(),
+            outcome,
+            timing: (outcome != Outcome::Skipped).then_some((now, now)),
+        }
+    }
+
+    #[test]
+    fn threshold() {
+        let settings = NotifySettings::default();
+        assert!(!settings.should_notify(false, Duration::from_secs(60)));
+        assert!(settings.should_notify(true, Duration::from_secs(60)));
+        assert!(settings.should_notify(true, Duration::from_secs(30)));
+        assert!(!settings.should_notify(true, Duration::from_secs(29)));
+
+        let settings: NotifySettings =
+            serde_yaml::from_str("enabled: true\nthreshold: 1m").unwrap();
+        assert_eq!(settings.threshold, Duration::from_secs(60));
+        assert!(settings.should_notify(false, Duration::from_secs(90)));
+        assert!(!settings.should_notify(true, Duration::from_secs(59)));
+
+        assert!(serde_yaml::from_str::<NotifySettings>("threshold: soon").is_err());
+    }
+
+    #[test]
+    fn messages() {
+        let entries = vec![
+            entry("db", Outcome::Ok),
+            entry("api", Outcome::Ok),
+            entry("web", Outcome::Failed(Some(1))),
+        ];
+        assert_eq!(
+            message("up", false, &entries, Duration::from_secs(272)),
+            "up failed, 2 ok, 1 failed, 4m32s"
+        );
+
+        let entries = vec![entry("db", Outcome::Ok), entry("web", Outcome::Skipped)];
+        assert_eq!(
+            message("restart", true, &entries, Duration::from_secs(45)),
+            "restart completed, 1 ok, 0 failed, 1 skipped, 45.0s"
+        );
+
+        assert_eq!(
+            message("pull", true, &[], Duration::from_millis(31500)),
+            "pull completed, 31.5s"
+        );
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        sent: RefCell<Vec<(String, String)>>,
+        fail: bool,
+    }
+
+    impl Notifier for Recorder {
+        fn notify(&self, title: &str, body: &str) -> Result<(), String> {
+            self.sent
+                .borrow_mut()
+                .push((title.to_string(), body.to_string()));
+            if self.fail {
+                Err("no notification daemon".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn sending() {
+        let recorder = Recorder::default();
+        send(&recorder, "up", true, &[], Duration::from_secs(40));
+        assert_eq!(
+            *recorder.sent.borrow(),
+            vec![("stack".to_string(), "up completed, 40.0s".to_string())]
+        );
+
+        // Failures are not fatal.
+        let recorder = Recorder {
+            fail: true,
+            ..Default::default()
+        };
+        send(&recorder, "up", true, &[], Duration::from_secs(40));
+        assert_eq!(recorder.sent.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::config::Stack;
+use crate::error::{Error, Result};
+
+/// Opens URLs for the user.
+pub trait Opener: Sync {
+    fn open(&self, url: &str) -> std::result::Result<(), String>;
+}
+
+/// Opens URLs in the default browser with `open` on macOS, `start` on
+/// Windows and `xdg-open` elsewhere.
+pub struct SystemOpener;
+
+impl Opener for SystemOpener {
+    fn open(&self, url: &str) -> std::result::Result<(), String> {
+        use std::process::{Command, Stdio};
+
+        let mut command = if cfg!(target_os = "macos") {
+            Command::new("open")
+        } else if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            // The first quoted argument to `start` is the window title.
+            command.args(["/C", "start", ""]);
+            command
+        } else {
+            Command::new("xdg-open")
+        };
+
+        match command
+            .arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+        {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(status.to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// Picks which of the stack's URLs to open, the first unless a 1-based index
+/// is given.
+pub fn pick_url<'a>(stack: &Stack, urls: &'a [String], index: Option<usize>) -> Result<&'a str> {
+    if urls.is_empty() {
+        return Err(Error::Invalid(format!(
+            "Stack `{}` has no `url`, add one to its entry in the stacks config to open it.",
+            stack.key
+        )));
+    }
+
+    let index = index.unwrap_or(1);
+    match index.checked_sub(1).and_then(|i| urls.get(i)) {
+        Some(url) => Ok(url),
+        None => {
+            let listed: Vec<String> = urls
+                .iter()
+                .enumerate()
+                .map(|(i, url)| format!("{}: {}", i + 1, url))
+                .collect();
+            Err(Error::Invalid(format!(
+                "Stack `{}` has no url {}, it has {}.",
+                stack.key,
+                index,
+                listed.join(", ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pick_url;
+    use crate::config::Config;
+    use std::path::PathBuf;
+
+    #[test]
+    fn picking() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stack = &config.stacks["web"];
+        let urls = vec![
+            "http://localhost:8080".to_string(),
+            "http://localhost:8080/admin".to_string(),
+        ];
+
+        assert_eq!(
+            pick_url(stack, &urls, None).unwrap(),
+            "http://localhost:8080"
+        );
+        assert_eq!(
+            pick_url(stack, &urls, Some(2)).unwrap(),
+            "http://localhost:8080/admin"
+        );
+        assert_eq!(
+            pick_url(stack, &urls, Some(3)).unwrap_err().to_string(),
+            "Stack `web` has no url 3, it has 1: http://localhost:8080, \
+            2: http://localhost:8080/admin."
+        );
+        assert!(pick_url(stack, &urls, Some(0)).is_err());
+        assert_eq!(
+            pick_url(stack, &[], None).unwrap_err().to_string(),
+            "Stack `web` has no `url`, add one to its entry in the stacks config to open it."
+        );
+    }
+}
@@ -0,0 +1,840 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use regex::Regex;
+
+use crate::config::Stack;
+use crate::error::{Error, Result};
+
+/// Which of a child's output streams a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Receives the output of the commands run against stacks.
+pub trait OutputHandler: Sync {
+    /// Called for each line of output with the line ending removed.
+    fn line(&self, stack: &Stack, stream: Stream, line: &str);
+
+    /// Called when a stack's command has finished.
+    fn finished(&self, _stack: &Stack, _success: bool) {}
+}
+
+/// Records output for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recorder {
+    pub lines: std::sync::Mutex<Vec<(String, Stream, String)>>,
+}
+
+#[cfg(test)]
+impl OutputHandler for Recorder {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        self.lines
+            .lock()
+            .unwrap()
+            .push((stack.key.clone(), stream, line.to_owned()));
+    }
+}
+
+#[cfg(test)]
+impl Recorder {
+    pub fn stream(&self, stream: Stream) -> Vec<String> {
+        self.lines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s, _)| *s == stream)
+            .map(|(_, _, line)| line.clone())
+            .collect()
+    }
+}
+
+/// Writes output straight through to our own stdout and stderr.
+pub struct Passthrough;
+
+impl OutputHandler for Passthrough {
+    fn line(&self, _stack: &Stack, stream: Stream, line: &str) {
+        // There is nowhere to report failures to write output.
+        let _ = match stream {
+            Stream::Stdout => writeln!(io::stdout().lock(), "{}", line),
+            Stream::Stderr => writeln!(io::stderr().lock(), "{}", line),
+        };
+    }
+}
+
+/// The colours cycled through for stack prefixes, these match the colours that
+/// docker compose uses for services.
+const COLORS: [&str; 6] = ["36", "33", "32", "35", "34", "96"];
+
+/// Handles carriage returns in a line of output. Progress displays redraw a
+/// line by writing a carriage return followed by the new content. Since each
+/// line gets a prefix these cannot be passed through so only the final content
+/// of the line is kept. A trailing carriage return (from a `\r\n` line ending)
+/// is dropped. Returns `None` if nothing remains of a line that contained
+/// carriage returns.
+pub fn final_content(line: &str) -> Option<&str> {
+    if !line.contains('\r') {
+        return Some(line);
+    }
+
+    line.split('\r').rfind(|s| !s.is_empty())
+}
+
+/// Adds a prefix to a line of output, applying the carriage return handling
+/// from `final_content`.
+pub fn prefix_line(prefix: &str, line: &str) -> Option<String> {
+    final_content(line).map(|content| format!("{} {}", prefix, content))
+}
+
+/// Prefixes every line of output with the name of the stack it came from.
+pub struct Prefixed<'a> {
+    inner: &'a dyn OutputHandler,
+    prefixes: HashMap<String, String>,
+}
+
+impl<'a> Prefixed<'a> {
+    pub fn new(stacks: &[&Stack], color: bool, inner: &'a dyn OutputHandler) -> Self {
+        let width = stacks.iter().map(|s| s.key.len() + 2).max().unwrap_or(0);
+
+        let prefixes = stacks
+            .iter()
+            .enumerate()
+            .map(|(index, stack)| {
+                let tag = format!("{:<width$}", format!("[{}]", stack.key), width = width);
+                let prefix = if color {
+                    format!("\x1b[{}m{}\x1b[0m", COLORS[index % COLORS.len()], tag)
+                } else {
+                    tag
+                };
+
+                (stack.key.clone(), prefix)
+            })
+            .collect();
+
+        Self { inner, prefixes }
+    }
+}
+
+impl OutputHandler for Prefixed<'_> {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        let prefix = match self.prefixes.get(&stack.key) {
+            Some(prefix) => prefix.clone(),
+            None => format!("[{}]", stack.key),
+        };
+
+        if let Some(line) = prefix_line(&prefix, line) {
+            self.inner.line(stack, stream, &line);
+        }
+    }
+
+    fn finished(&self, stack: &Stack, success: bool) {
+        self.inner.finished(stack, success);
+    }
+}
+
+/// Decides which lines of output to keep. A line is kept if it matches any of
+/// the patterns, or with `invert` if it matches none of them.
+#[derive(Debug, Clone)]
+pub struct LineFilter {
+    patterns: Vec<Regex>,
+    invert: bool,
+}
+
+impl LineFilter {
+    pub fn new<S: AsRef<str>>(patterns: &[S], invert: bool) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern.as_ref()).map_err(|e| {
+                    Error::Invalid(format!(
+                        "The `--grep` pattern `{}` is invalid: {}",
+                        pattern.as_ref(),
+                        e
+                    ))
+                })
+            })
+            .collect::<Result<Vec<Regex>>>()?;
+
+        Ok(Self { patterns, invert })
+    }
+
+    /// Whether to keep the line.
+    pub fn keep(&self, line: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(line)) != self.invert
+    }
+}
+
+/// Only passes on the lines of standard output that the filter keeps. Errors
+/// on standard error are always passed on. When wrapped by `Prefixed` lines
+/// are matched with their prefix.
+pub struct Filtered<'a> {
+    inner: &'a dyn OutputHandler,
+    filter: LineFilter,
+    suppressed: AtomicUsize,
+}
+
+impl<'a> Filtered<'a> {
+    pub fn new(filter: LineFilter, inner: &'a dyn OutputHandler) -> Self {
+        Self {
+            inner,
+            filter,
+            suppressed: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of lines that were filtered out.
+    pub fn suppressed(&self) -> usize {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+}
+
+impl OutputHandler for Filtered<'_> {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        if stream == Stream::Stderr || self.filter.keep(line) {
+            self.inner.line(stack, stream, line);
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn finished(&self, stack: &Stack, success: bool) {
+        self.inner.finished(stack, success);
+    }
+}
+
+/// Adds the stack to a JSON event from `docker compose events --json`,
+/// returning it as compact JSON. `None` when the line isn't a JSON object.
+pub fn tag_event(stack: &Stack, line: &str) -> Option<String> {
+    let mut event: serde_json::Value = serde_json::from_str(line).ok()?;
+    event.as_object_mut()?.insert(
+        "stack".to_owned(),
+        serde_json::Value::String(stack.key.clone()),
+    );
+    Some(event.to_string())
+}
+
+/// Passes on the events printed by `docker compose events --json` with the
+/// stack that each came from added. Lines that aren't JSON objects, like one
+/// cut short when compose is stopped, are passed on as errors unchanged.
+pub struct JsonEvents<'a> {
+    inner: &'a dyn OutputHandler,
+}
+
+impl<'a> JsonEvents<'a> {
+    pub fn new(inner: &'a dyn OutputHandler) -> Self {
+        Self { inner }
+    }
+}
+
+impl OutputHandler for JsonEvents<'_> {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        if stream == Stream::Stderr {
+            self.inner.line(stack, stream, line);
+        } else if !line.trim().is_empty() {
+            match tag_event(stack, line) {
+                Some(event) => self.inner.line(stack, Stream::Stdout, &event),
+                None => self.inner.line(stack, Stream::Stderr, line),
+            }
+        }
+    }
+
+    fn finished(&self, stack: &Stack, success: bool) {
+        self.inner.finished(stack, success);
+    }
+}
+
+/// A log file for each stack that output is copied into.
+pub struct LogFiles {
+    files: HashMap<String, Mutex<File>>,
+}
+
+impl LogFiles {
+    /// Creates `<stack>.log` in the directory for each stack. Existing files
+    /// are appended to.
+    pub fn create(dir: &Path, stacks: &[&Stack]) -> io::Result<Self> {
+        let files = stacks
+            .iter()
+            .map(|stack| {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(dir.join(format!("{}.log", stack.key)))?;
+                Ok((stack.key.clone(), Mutex::new(file)))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self { files })
+    }
+}
+
+/// Copies each stack's output into its log file before passing it on.
+pub struct Tee<'a> {
+    files: &'a LogFiles,
+    inner: &'a dyn OutputHandler,
+}
+
+impl<'a> Tee<'a> {
+    pub fn new(files: &'a LogFiles, inner: &'a dyn OutputHandler) -> Self {
+        Self { files, inner }
+    }
+}
+
+impl OutputHandler for Tee<'_> {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        if let (Some(file), Some(content)) = (self.files.files.get(&stack.key), final_content(line))
+        {
+            // A failure to log should not interrupt the command.
+            let _ = writeln!(file.lock().unwrap(), "{}", content);
+        }
+
+        self.inner.line(stack, stream, line);
+    }
+
+    fn finished(&self, stack: &Stack, success: bool) {
+        self.inner.finished(stack, success);
+    }
+}
+
+/// The amount of output to hold in memory for a stack before spilling it to a
+/// temporary file.
+const SPILL_THRESHOLD: usize = 1024 * 1024;
+
+/// Output captured for a single stack.
+#[derive(Default)]
+struct Capture {
+    lines: Vec<(Stream, String)>,
+    size: usize,
+    count: usize,
+    spill: Option<(PathBuf, File)>,
+}
+
+impl Capture {
+    fn push(&mut self, stream: Stream, line: &str, threshold: usize, key: &str) -> io::Result<()> {
+        self.count += 1;
+
+        if self.spill.is_none() && self.size + line.len() > threshold {
+            let path = env::temp_dir().join(format!(
+                "stack-{}-{}-{}.log",
+                process::id(),
+                key,
+                SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut file = File::create(&path)?;
+            for (stream, line) in self.lines.drain(..) {
+                write_spilled(&mut file, stream, &line)?;
+            }
+            self.spill = Some((path, file));
+        }
+
+        match self.spill {
+            Some((_, ref mut file)) => write_spilled(file, stream, line),
+            None => {
+                self.size += line.len();
+                self.lines.push((stream, line.to_owned()));
+                Ok(())
+            }
+        }
+    }
+
+    fn replay(self, stack: &Stack, inner: &dyn OutputHandler) -> io::Result<()> {
+        match self.spill {
+            Some((path, file)) => {
+                drop(file);
+                let result = File::open(&path).and_then(|file| {
+                    for line in BufReader::new(file).lines() {
+                        let line = line?;
+                        match line.split_at(1) {
+                            ("E", line) => inner.line(stack, Stream::Stderr, line),
+                            (_, line) => inner.line(stack, Stream::Stdout, line),
+                        }
+                    }
+                    Ok(())
+                });
+                let _ = fs::remove_file(&path);
+                result
+            }
+            None => {
+                for (stream, line) in self.lines {
+                    inner.line(stack, stream, &line);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn discard(self) {
+        if let Some((path, _)) = self.spill {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn write_spilled(file: &mut File, stream: Stream, line: &str) -> io::Result<()> {
+    let marker = match stream {
+        Stream::Stdout => "O",
+        Stream::Stderr => "E",
+    };
+
+    // Lines cannot contain newlines so they are safe to use as separators.
+    writeln!(file, "{}{}", marker, line)
+}
+
+/// Captures the output of each stack, only passing it on if the stack's
+/// command fails.
+pub struct Captured<'a> {
+    inner: &'a dyn OutputHandler,
+    threshold: usize,
+    captures: Mutex<HashMap<String, Capture>>,
+    suppressed: AtomicUsize,
+}
+
+impl<'a> Captured<'a> {
+    pub fn new(inner: &'a dyn OutputHandler) -> Self {
+        Self::with_threshold(inner, SPILL_THRESHOLD)
+    }
+
+    fn with_threshold(inner: &'a dyn OutputHandler, threshold: usize) -> Self {
+        Self {
+            inner,
+            threshold,
+            captures: Default::default(),
+            suppressed: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of lines of output that were discarded.
+    pub fn suppressed(&self) -> usize {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+}
+
+impl OutputHandler for Captured<'_> {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        let mut captures = self.captures.lock().unwrap();
+        let capture = captures.entry(stack.key.clone()).or_default();
+        if let Err(e) = capture.push(stream, line, self.threshold, &stack.key) {
+            log::warn!("Failed to capture output for {}: {}", stack.key, e);
+        }
+    }
+
+    fn finished(&self, stack: &Stack, success: bool) {
+        let capture = self.captures.lock().unwrap().remove(&stack.key);
+
+        if let Some(capture) = capture {
+            if success {
+                self.suppressed.fetch_add(capture.count, Ordering::Relaxed);
+                capture.discard();
+            } else {
+                self.inner.line(
+                    stack,
+                    Stream::Stderr,
+                    &format!("==== Output from failed stack {} ====", stack.key),
+                );
+                if let Err(e) = capture.replay(stack, self.inner) {
+                    log::warn!("Failed to replay output for {}: {}", stack.key, e);
+                }
+            }
+        }
+
+        self.inner.finished(stack, success);
+    }
+}
+
+/// Reads lines from a reader and forwards them to the handler. A final line
+/// without a line ending is still forwarded.
+pub fn forward<R: Read>(
+    reader: R,
+    stack: &Stack,
+    stream: Stream,
+    handler: &dyn OutputHandler,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::new();
+
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            return Ok(());
+        }
+
+        if buffer.last() == Some(&b'\n') {
+            buffer.pop();
+        }
+
+        handler.line(stack, stream, &String::from_utf8_lossy(&buffer));
+    }
+}
+
+/// Forwards the piped output of a child process to the handler while waiting
+/// for the child to exit.
+pub fn wait_piped<W>(
+    mut child: Child,
+    stack: &Stack,
+    handler: &dyn OutputHandler,
+    wait: W,
+) -> io::Result<ExitStatus>
+where
+    W: FnOnce(&mut Child) -> io::Result<ExitStatus>,
+{
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    thread::scope(|scope| {
+        let stdout = scope.spawn(|| match stdout {
+            Some(reader) => forward(reader, stack, Stream::Stdout, handler),
+            None => Ok(()),
+        });
+        let stderr = scope.spawn(|| match stderr {
+            Some(reader) => forward(reader, stack, Stream::Stderr, handler),
+            None => Ok(()),
+        });
+
+        let status = wait(&mut child);
+        stdout.join().unwrap()?;
+        stderr.join().unwrap()?;
+        status
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        final_content, prefix_line, tag_event, wait_piped, Captured, Filtered, JsonEvents,
+        LineFilter, OutputHandler, Prefixed, Recorder, Stream,
+    };
+    use crate::config::Config;
+    use crate::error::Error;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+
+    fn run(script: &str, recorder: &dyn OutputHandler) -> Option<i32> {
+        let config =
+            Config::from_reader(&PathBuf::default(), "stacks:\n  web: {}\n".as_bytes()).unwrap();
+        let stack = config.stacks.get("web").unwrap();
+
+        let child = Command::new("sh")
+            .args(["-c", script])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        wait_piped(child, stack, recorder, |c| c.wait())
+            .unwrap()
+            .code()
+    }
+
+    #[test]
+    fn lines() {
+        let recorder = Recorder::default();
+        let code = run(
+            "echo one; echo error >&2; echo two; printf 'partial'; exit 3",
+            &recorder,
+        );
+
+        assert_eq!(code, Some(3));
+        assert_eq!(
+            recorder.stream(Stream::Stdout),
+            vec!["one".to_string(), "two".to_string(), "partial".to_string()]
+        );
+        assert_eq!(recorder.stream(Stream::Stderr), vec!["error".to_string()]);
+        assert!(recorder
+            .lines
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(stack, _, _)| stack == "web"));
+    }
+
+    #[test]
+    fn empty() {
+        let recorder = Recorder::default();
+        assert_eq!(run("exit 0", &recorder), Some(0));
+        assert!(recorder.lines.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn large() {
+        // More output than fits in a pipe buffer on both streams at once.
+        let recorder = Recorder::default();
+        let code = run(
+            "i=0; while [ $i -lt 5000 ]; do echo \"line $i\"; echo \"error $i\" >&2; i=$((i+1)); done",
+            &recorder,
+        );
+
+        assert_eq!(code, Some(0));
+        let stdout = recorder.stream(Stream::Stdout);
+        assert_eq!(stdout.len(), 5000);
+        assert_eq!(stdout[4999], "line 4999");
+        assert_eq!(recorder.stream(Stream::Stderr).len(), 5000);
+    }
+
+    #[test]
+    fn carriage_returns() {
+        assert_eq!(final_content("plain"), Some("plain"));
+        assert_eq!(final_content(""), Some(""));
+        assert_eq!(final_content("windows\r"), Some("windows"));
+        assert_eq!(final_content("\r"), None);
+        assert_eq!(final_content("\r\r\r"), None);
+
+        let progress = " Container web-db-1  Starting\r Container web-db-1  Started\r";
+        assert_eq!(
+            final_content(progress),
+            Some(" Container web-db-1  Started")
+        );
+
+        let progress = "Pulling 10%\rPulling 55%\rPulling 100%";
+        assert_eq!(final_content(progress), Some("Pulling 100%"));
+
+        assert_eq!(
+            prefix_line("[web]", "hello"),
+            Some("[web] hello".to_string())
+        );
+        assert_eq!(prefix_line("[web]", ""), Some("[web] ".to_string()));
+        assert_eq!(
+            prefix_line("[db] ", "\r[+] Running 0/1\r[+] Running 1/1\r"),
+            Some("[db]  [+] Running 1/1".to_string())
+        );
+        assert_eq!(prefix_line("[db]", "\r\r"), None);
+    }
+
+    #[test]
+    fn prefixed() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "stacks:\n  web: {}\n  database: {}\n".as_bytes(),
+        )
+        .unwrap();
+        let web = config.stacks.get("web").unwrap();
+        let database = config.stacks.get("database").unwrap();
+
+        let recorder = Recorder::default();
+        let prefixed = Prefixed::new(&[web, database], false, &recorder);
+        prefixed.line(web, Stream::Stdout, "one");
+        prefixed.line(database, Stream::Stderr, "two");
+        prefixed.line(web, Stream::Stdout, "50%\r100%");
+        prefixed.line(web, Stream::Stdout, "\r");
+
+        assert_eq!(
+            recorder.stream(Stream::Stdout),
+            vec!["[web]      one".to_string(), "[web]      100%".to_string()]
+        );
+        assert_eq!(
+            recorder.stream(Stream::Stderr),
+            vec!["[database] two".to_string()]
+        );
+
+        let recorder = Recorder::default();
+        let prefixed = Prefixed::new(&[web, database], true, &recorder);
+        prefixed.line(web, Stream::Stdout, "one");
+        prefixed.line(database, Stream::Stdout, "two");
+        assert_eq!(
+            recorder.stream(Stream::Stdout),
+            vec![
+                "\x1b[36m[web]     \x1b[0m one".to_string(),
+                "\x1b[33m[database]\x1b[0m two".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let filter = LineFilter::new(&["req-42", "^panic"], false).unwrap();
+        assert!(filter.keep("GET /api req-42 200"));
+        assert!(filter.keep("panic: boom"));
+        assert!(!filter.keep("GET /api req-43 200"));
+        assert!(!filter.keep("app panic: boom"));
+
+        let filter = LineFilter::new(&["health", "metrics"], true).unwrap();
+        assert!(!filter.keep("GET /health"));
+        assert!(!filter.keep("GET /metrics"));
+        assert!(filter.keep("GET /api"));
+
+        assert!(matches!(
+            LineFilter::new(&["req-("], false),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn filtered() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "stacks:\n  web: {}\n  db: {}\n".as_bytes(),
+        )
+        .unwrap();
+        let web = config.stacks.get("web").unwrap();
+        let db = config.stacks.get("db").unwrap();
+
+        // Filtering happens after prefixing so kept lines keep their prefix.
+        let recorder = Recorder::default();
+        let filtered = Filtered::new(LineFilter::new(&["req-42"], false).unwrap(), &recorder);
+        let prefixed = Prefixed::new(&[web, db], false, &filtered);
+        prefixed.line(web, Stream::Stdout, "start req-42");
+        prefixed.line(db, Stream::Stdout, "query req-41");
+        prefixed.line(db, Stream::Stdout, "query req-42");
+        prefixed.line(web, Stream::Stdout, "end req-41");
+        prefixed.line(db, Stream::Stderr, "no such service");
+
+        assert_eq!(
+            recorder.stream(Stream::Stdout),
+            vec![
+                "[web] start req-42".to_string(),
+                "[db]  query req-42".to_string()
+            ]
+        );
+        assert_eq!(
+            recorder.stream(Stream::Stderr),
+            vec!["[db]  no such service".to_string()]
+        );
+        assert_eq!(filtered.suppressed(), 2);
+    }
+
+    #[test]
+    fn json_events() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "stacks:\n  web: {}\n  db: {}\n".as_bytes(),
+        )
+        .unwrap();
+        let web = config.stacks.get("web").unwrap();
+        let db = config.stacks.get("db").unwrap();
+
+        assert_eq!(
+            tag_event(
+                web,
+                r#"{ "action": "start", "service": "app", "attributes": { "name": "web-app-1" } }"#
+            )
+            .unwrap(),
+            r#"{"action":"start","attributes":{"name":"web-app-1"},"service":"app","stack":"web"}"#
+        );
+        // A stack field from compose is replaced.
+        assert_eq!(
+            tag_event(db, r#"{"stack":"other"}"#).unwrap(),
+            r#"{"stack":"db"}"#
+        );
+        assert_eq!(tag_event(web, r#"{"action":"sta"#), None);
+        assert_eq!(tag_event(web, "[1, 2]"), None);
+        assert_eq!(tag_event(web, "not json"), None);
+
+        // Events from different stacks are passed on as they arrive.
+        let recorder = Recorder::default();
+        let events = JsonEvents::new(&recorder);
+        events.line(web, Stream::Stdout, r#"{"action":"create"}"#);
+        events.line(db, Stream::Stdout, r#"{"action":"die"}"#);
+        events.line(web, Stream::Stdout, r#"{"action":"sta"#);
+        events.line(db, Stream::Stderr, "no such service");
+        events.line(db, Stream::Stdout, "");
+        events.line(web, Stream::Stdout, r#"{"action":"start"}"#);
+
+        assert_eq!(
+            recorder.stream(Stream::Stdout),
+            vec![
+                r#"{"action":"create","stack":"web"}"#.to_string(),
+                r#"{"action":"die","stack":"db"}"#.to_string(),
+                r#"{"action":"start","stack":"web"}"#.to_string(),
+            ]
+        );
+        assert_eq!(
+            recorder.stream(Stream::Stderr),
+            vec![
+                r#"{"action":"sta"#.to_string(),
+                "no such service".to_string()
+            ]
+        );
+
+        // An event written in pieces is only passed on once its line is
+        // complete.
+        let recorder = Recorder::default();
+        let code = run(
+            r#"printf '{"action":'; sleep 0.1; printf '"kill"}\n{"act'; sleep 0.1; printf 'ion":"die"}\n'"#,
+            &JsonEvents::new(&recorder),
+        );
+        assert_eq!(code, Some(0));
+        assert_eq!(
+            recorder.stream(Stream::Stdout),
+            vec![
+                r#"{"action":"kill","stack":"web"}"#.to_string(),
+                r#"{"action":"die","stack":"web"}"#.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn captured() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "stacks:\n  web: {}\n  db: {}\n".as_bytes(),
+        )
+        .unwrap();
+        let web = config.stacks.get("web").unwrap();
+        let db = config.stacks.get("db").unwrap();
+
+        let recorder = Recorder::default();
+        let captured = Captured::new(&recorder);
+        captured.line(web, Stream::Stdout, "web one");
+        captured.line(db, Stream::Stdout, "db one");
+        captured.line(web, Stream::Stderr, "web two");
+        captured.line(db, Stream::Stderr, "db two");
+        assert!(recorder.lines.lock().unwrap().is_empty());
+
+        captured.finished(web, true);
+        assert!(recorder.lines.lock().unwrap().is_empty());
+        assert_eq!(captured.suppressed(), 2);
+
+        captured.finished(db, false);
+        assert_eq!(
+            recorder.stream(Stream::Stderr),
+            vec![
+                "==== Output from failed stack db ====".to_string(),
+                "db two".to_string()
+            ]
+        );
+        assert_eq!(recorder.stream(Stream::Stdout), vec!["db one".to_string()]);
+        assert_eq!(captured.suppressed(), 2);
+    }
+
+    #[test]
+    fn spilled() {
+        let config =
+            Config::from_reader(&PathBuf::default(), "stacks:\n  web: {}\n".as_bytes()).unwrap();
+        let web = config.stacks.get("web").unwrap();
+
+        let recorder = Recorder::default();
+        let captured = Captured::with_threshold(&recorder, 20);
+        for i in 0..10 {
+            captured.line(web, Stream::Stdout, &format!("line {}", i));
+        }
+        captured.line(web, Stream::Stderr, "error");
+        captured.finished(web, false);
+
+        let mut expected: Vec<String> = (0..10).map(|i| format!("line {}", i)).collect();
+        assert_eq!(recorder.stream(Stream::Stdout), expected);
+        expected = vec![
+            "==== Output from failed stack web ====".to_string(),
+            "error".to_string(),
+        ];
+        assert_eq!(recorder.stream(Stream::Stderr), expected);
+
+        let recorder = Recorder::default();
+        let captured = Captured::with_threshold(&recorder, 20);
+        for i in 0..10 {
+            captured.line(web, Stream::Stdout, &format!("line {}", i));
+        }
+        captured.finished(web, true);
+        assert!(recorder.lines.lock().unwrap().is_empty());
+        assert_eq!(captured.suppressed(), 10);
+    }
+}
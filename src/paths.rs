@@ -0,0 +1,120 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Windows paths longer than this need the verbatim prefix.
+const MAX_PATH: usize = 260;
+
+/// Strips the `\\?\` prefix that Windows adds to canonical paths when the
+/// path means the same without it. Docker and many other tools don't
+/// understand the prefix.
+fn strip_verbatim(path: &str) -> Option<&str> {
+    let stripped = path.strip_prefix(r"\\?\")?;
+    let bytes = stripped.as_bytes();
+
+    // Only simple drive paths can lose the prefix, `\\?\UNC\` and device paths
+    // are left alone.
+    let is_drive =
+        bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\';
+    if !is_drive || stripped.len() >= MAX_PATH {
+        return None;
+    }
+
+    // Without the prefix these would be interpreted differently.
+    let reinterpreted = stripped[3..]
+        .split('\\')
+        .any(|component| component == "." || component == ".." || component.ends_with(['.', ' ']));
+    (!reinterpreted).then_some(stripped)
+}
+
+/// Replaces forward slashes with the separator.
+fn with_separator(path: &str, separator: char) -> String {
+    if separator == '/' {
+        path.to_owned()
+    } else {
+        path.replace('/', &separator.to_string())
+    }
+}
+
+/// Canonicalizes a path without the verbatim prefix on Windows.
+pub fn canonicalize<P: AsRef<Path>>(path: P) -> io::Result<PathBuf> {
+    let path = fs::canonicalize(path)?;
+
+    if cfg!(windows) {
+        if let Some(stripped) = path.to_str().and_then(strip_verbatim) {
+            return Ok(PathBuf::from(stripped));
+        }
+    }
+
+    Ok(path)
+}
+
+/// Joins a path from the config, which may use forward slashes, onto a base
+/// using the platform's separators throughout.
+pub fn join(base: &Path, path: &str) -> PathBuf {
+    base.join(with_separator(path, std::path::MAIN_SEPARATOR))
+}
+
+/// The directory that stacks caches things in, `stack` in the user's cache
+/// directory.
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+
+    Some(cache.join("stack"))
+}
+
+/// Whether the program is a batch file that must be run through `cmd /C`.
+pub fn is_batch_file(program: &OsStr) -> bool {
+    Path::new(program)
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("bat") || ext.eq_ignore_ascii_case("cmd"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_batch_file, strip_verbatim, with_separator};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn verbatim() {
+        assert_eq!(
+            strip_verbatim(r"\\?\C:\Users\dev\stacks.yml"),
+            Some(r"C:\Users\dev\stacks.yml")
+        );
+        assert_eq!(strip_verbatim(r"\\?\d:\"), Some(r"d:\"));
+        assert_eq!(strip_verbatim(r"C:\Users\dev"), None);
+        assert_eq!(strip_verbatim("/home/dev/stacks.yml"), None);
+        assert_eq!(strip_verbatim(r"\\?\UNC\server\share\stacks.yml"), None);
+        assert_eq!(strip_verbatim(r"\\?\GLOBALROOT\Device\Foo"), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\dir\name."), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\dir\..\name"), None);
+
+        let long = format!(r"\\?\C:\{}", "a".repeat(300));
+        assert_eq!(strip_verbatim(&long), None);
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(with_separator("web/compose.yml", '/'), "web/compose.yml");
+        assert_eq!(
+            with_separator("web/config/compose.yml", '\\'),
+            r"web\config\compose.yml"
+        );
+        assert_eq!(with_separator(r"web\compose.yml", '\\'), r"web\compose.yml");
+    }
+
+    #[test]
+    fn batch_files() {
+        assert!(is_batch_file(OsStr::new("deploy.cmd")));
+        assert!(is_batch_file(OsStr::new(r"C:\tools\COMPOSE.BAT")));
+        assert!(!is_batch_file(OsStr::new("docker")));
+        assert!(!is_batch_file(OsStr::new("docker.exe")));
+        assert!(!is_batch_file(OsStr::new("scripts.cmd/compose")));
+    }
+}
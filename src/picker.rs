@@ -0,0 +1,144 @@
+use std::io::{BufRead, Write};
+
+use crate::config::Stack;
+use crate::error::{Error, Result};
+
+fn list<W: Write>(output: &mut W, stacks: &[&Stack]) -> Result<()> {
+    for (index, stack) in stacks.iter().enumerate() {
+        match stack.description {
+            Some(ref description) => {
+                writeln!(output, "{:>3}) {} - {}", index + 1, stack.key, description)
+            }
+            None => writeln!(output, "{:>3}) {}", index + 1, stack.key),
+        }
+        .map_err(|e| Error::io("Failed to display stacks", e))?;
+    }
+
+    Ok(())
+}
+
+/// Asks the user to pick one of the given stacks. The user may either enter
+/// the number of a stack from the list or some text to filter the list by.
+pub fn pick<'a, R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    stacks: &[&'a Stack],
+) -> Result<&'a Stack> {
+    let mut candidates: Vec<&'a Stack> = stacks.to_vec();
+
+    loop {
+        list(output, &candidates)?;
+        write!(output, "Select a stack (number or filter): ")
+            .and_then(|_| output.flush())
+            .map_err(|e| Error::io("Failed to display stacks", e))?;
+
+        let mut answer = String::new();
+        input
+            .read_line(&mut answer)
+            .map_err(|e| Error::io("Failed to read selection", e))?;
+        let answer = answer.trim();
+
+        if answer.is_empty() {
+            return Err(Error::Aborted);
+        }
+
+        if let Ok(index) = answer.parse::<usize>() {
+            if index >= 1 && index <= candidates.len() {
+                return Ok(candidates[index - 1]);
+            }
+        }
+
+        let filter = answer.to_lowercase();
+        let filtered: Vec<&'a Stack> = candidates
+            .iter()
+            .filter(|s| s.key.to_lowercase().contains(&filter))
+            .copied()
+            .collect();
+
+        match filtered.len() {
+            0 => {
+                writeln!(output, "No stacks match \"{}\".", answer)
+                    .map_err(|e| Error::io("Failed to display stacks", e))?;
+            }
+            1 => return Ok(filtered[0]),
+            _ => candidates = filtered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pick;
+    use crate::config::{Config, Stack};
+    use std::path::PathBuf;
+
+    fn config() -> Config {
+        Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                api:
+                    description: The API server
+                web: {}
+                webhooks: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn run(config: &Config, input: &str) -> (Result<String, String>, String) {
+        let stacks: Vec<&Stack> = config.stacks.values().collect();
+        let mut output = Vec::new();
+        let result = pick(&mut input.as_bytes(), &mut output, &stacks)
+            .map(|s| s.key.clone())
+            .map_err(|e| e.to_string());
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn numbered() {
+        let config = config();
+
+        let (result, output) = run(&config, "2\n");
+        assert_eq!(result, Ok("web".to_string()));
+        assert_eq!(
+            output,
+            "  1) api - The API server\n  2) web\n  3) webhooks\nSelect a stack (number or filter): "
+        );
+
+        let (result, _) = run(&config, "1\n");
+        assert_eq!(result, Ok("api".to_string()));
+    }
+
+    #[test]
+    fn filtered() {
+        let config = config();
+
+        let (result, _) = run(&config, "API\n");
+        assert_eq!(result, Ok("api".to_string()));
+
+        // Filtering to multiple stacks renumbers them.
+        let (result, output) = run(&config, "web\n2\n");
+        assert_eq!(result, Ok("webhooks".to_string()));
+        assert!(output.ends_with("  1) web\n  2) webhooks\nSelect a stack (number or filter): "));
+
+        let (result, output) = run(&config, "foo\n3\n");
+        assert_eq!(result, Ok("webhooks".to_string()));
+        assert!(output.contains("No stacks match \"foo\"."));
+
+        let (result, _) = run(&config, "4\n");
+        assert_eq!(result, Err("Aborted.".to_string()));
+    }
+
+    #[test]
+    fn cancelled() {
+        let config = config();
+
+        let (result, _) = run(&config, "\n");
+        assert_eq!(result, Err("Aborted.".to_string()));
+
+        let (result, _) = run(&config, "");
+        assert_eq!(result, Err("Aborted.".to_string()));
+    }
+}
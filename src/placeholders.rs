@@ -0,0 +1,143 @@
+use std::fmt;
+
+/// Why an argument's placeholders could not be substituted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlaceholderError {
+    /// `{name}` is not a known placeholder.
+    Unknown(String),
+    /// A `{` has no matching `}`.
+    Unclosed,
+    /// A `}` has no matching `{`.
+    Unmatched,
+}
+
+impl fmt::Display for PlaceholderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaceholderError::Unknown(name) => {
+                write!(f, "uses the unknown placeholder `{{{}}}`", name)
+            }
+            PlaceholderError::Unclosed => write!(f, "has a `{{` with no closing `}}`"),
+            PlaceholderError::Unmatched => write!(f, "has a `}}` with no opening `{{`"),
+        }
+    }
+}
+
+/// Replaces the `{placeholder}`s in an argument with their values. `{{` and
+/// `}}` are literal braces.
+pub fn substitute<F>(arg: &str, lookup: F) -> Result<String, PlaceholderError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut substituted = String::with_capacity(arg.len());
+    let mut rest = arg;
+
+    while let Some(index) = rest.find(['{', '}']) {
+        substituted.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        if let Some(after) = rest.strip_prefix("{{") {
+            substituted.push('{');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            substituted.push('}');
+            rest = after;
+        } else if let Some(inner) = rest.strip_prefix('{') {
+            let (name, after) = inner.split_once('}').ok_or(PlaceholderError::Unclosed)?;
+            if name.contains('{') {
+                return Err(PlaceholderError::Unclosed);
+            }
+            let value = lookup(name).ok_or_else(|| PlaceholderError::Unknown(name.to_owned()))?;
+            substituted.push_str(&value);
+            rest = after;
+        } else {
+            return Err(PlaceholderError::Unmatched);
+        }
+    }
+
+    substituted.push_str(rest);
+    Ok(substituted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{substitute, PlaceholderError};
+
+    fn values(name: &str) -> Option<String> {
+        match name {
+            "stack" => Some("web".to_string()),
+            "dir" => Some("/stacks/web".to_string()),
+            "empty" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(substitute("up", values).unwrap(), "up");
+        assert_eq!(substitute("", values).unwrap(), "");
+        assert_eq!(
+            substitute("backup-{stack}.sh", values).unwrap(),
+            "backup-web.sh"
+        );
+        assert_eq!(substitute("{stack}", values).unwrap(), "web");
+        assert_eq!(
+            substitute("{dir}/{stack}.tar", values).unwrap(),
+            "/stacks/web/web.tar"
+        );
+        assert_eq!(substitute("{stack}{stack}", values).unwrap(), "webweb");
+        assert_eq!(substitute("a{empty}b", values).unwrap(), "ab");
+
+        // Values are not substituted again.
+        let braces = |_: &str| Some("{stack}".to_string());
+        assert_eq!(substitute("{x}", braces).unwrap(), "{stack}");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(substitute("{{", values).unwrap(), "{");
+        assert_eq!(substitute("}}", values).unwrap(), "}");
+        assert_eq!(substitute("{{stack}}", values).unwrap(), "{stack}");
+        assert_eq!(substitute("{{{{.Name}}}}", values).unwrap(), "{{.Name}}");
+        assert_eq!(substitute("{{{stack}}}", values).unwrap(), "{web}");
+        assert_eq!(substitute("x}}{stack}", values).unwrap(), "x}web");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            substitute("{unknown}", values),
+            Err(PlaceholderError::Unknown("unknown".to_string()))
+        );
+        assert_eq!(
+            substitute("{}", values),
+            Err(PlaceholderError::Unknown(String::new()))
+        );
+        assert_eq!(
+            substitute("{Stack}", values),
+            Err(PlaceholderError::Unknown("Stack".to_string()))
+        );
+        // Go templates need their braces escaped.
+        assert_eq!(substitute("{{.Name}}", values), Ok("{.Name}".to_string()));
+        assert_eq!(
+            substitute("{.Name}", values),
+            Err(PlaceholderError::Unknown(".Name".to_string()))
+        );
+
+        assert_eq!(
+            substitute("{stack", values),
+            Err(PlaceholderError::Unclosed)
+        );
+        assert_eq!(substitute("{", values), Err(PlaceholderError::Unclosed));
+        assert_eq!(
+            substitute("{a{stack}", values),
+            Err(PlaceholderError::Unclosed)
+        );
+        assert_eq!(substitute("}", values), Err(PlaceholderError::Unmatched));
+        assert_eq!(substitute("a}b", values), Err(PlaceholderError::Unmatched));
+        assert_eq!(
+            substitute("{stack}}", values),
+            Err(PlaceholderError::Unmatched)
+        );
+    }
+}
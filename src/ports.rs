@@ -0,0 +1,362 @@
+use std::fmt::Write;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::error::Result;
+use crate::status::parse_ps_as;
+
+/// Accepts a port given either as a number or as a string, different compose
+/// versions use both.
+fn deserialize_port<'de, D>(deserializer: D) -> std::result::Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u16),
+        Text(String),
+    }
+
+    match Port::deserialize(deserializer)? {
+        Port::Number(port) => Ok(port),
+        Port::Text(text) if text.is_empty() => Ok(0),
+        Port::Text(text) => text.parse().map_err(de::Error::custom),
+    }
+}
+
+fn deserialize_publishers<'de, D>(deserializer: D) -> std::result::Result<Vec<Publisher>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<Vec<Publisher>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A port on a container as listed in `Publishers`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+struct Publisher {
+    #[serde(rename = "URL", default)]
+    url: String,
+    #[serde(rename = "TargetPort", deserialize_with = "deserialize_port")]
+    target_port: u16,
+    /// Zero when the port is only exposed.
+    #[serde(
+        rename = "PublishedPort",
+        default,
+        deserialize_with = "deserialize_port"
+    )]
+    published_port: u16,
+    #[serde(rename = "Protocol", default)]
+    protocol: String,
+}
+
+/// The parts of a container from `docker compose ps --format json` that say
+/// which ports it publishes. Newer versions of compose list `Publishers`,
+/// older ones only have the `Ports` summary that `docker ps` shows.
+#[derive(Debug, Clone, Deserialize)]
+struct Container {
+    #[serde(rename = "Service")]
+    service: String,
+    #[serde(
+        rename = "Publishers",
+        default,
+        deserialize_with = "deserialize_publishers"
+    )]
+    publishers: Vec<Publisher>,
+    #[serde(rename = "Ports", default)]
+    ports: String,
+}
+
+/// A container port that is published on the host.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Binding {
+    pub stack: String,
+    pub service: String,
+    pub container_port: u16,
+    pub protocol: String,
+    /// The host address that the port is published on.
+    pub address: String,
+    pub host_port: u16,
+}
+
+impl Binding {
+    /// The address and port on the host, IPv6 addresses are bracketed.
+    pub fn host(&self) -> String {
+        let address = match self.address.as_str() {
+            "" => "0.0.0.0",
+            address => address,
+        };
+        if address.contains(':') {
+            format!("[{}]:{}", address, self.host_port)
+        } else {
+            format!("{}:{}", address, self.host_port)
+        }
+    }
+}
+
+/// Expands a port or a range of ports like `8000-8002`.
+fn port_range(ports: &str) -> Option<Vec<u16>> {
+    match ports.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (start.parse::<u16>().ok()?, end.parse::<u16>().ok()?);
+            (start <= end).then(|| (start..=end).collect())
+        }
+        None => Some(vec![ports.parse().ok()?]),
+    }
+}
+
+/// Parses a `Ports` summary like `0.0.0.0:8080->80/tcp, :::8080->80/tcp`.
+/// Ports that are only exposed and entries that can't be understood are left
+/// out.
+fn parse_summary(summary: &str) -> Vec<Publisher> {
+    summary
+        .split(',')
+        .map(str::trim)
+        .filter_map(|entry| {
+            let (host, container) = entry.split_once("->")?;
+            let (targets, protocol) = container.split_once('/').unwrap_or((container, "tcp"));
+            let (address, published) = host.rsplit_once(':')?;
+            let address = address.trim_start_matches('[').trim_end_matches(']');
+
+            let targets = port_range(targets)?;
+            let published = port_range(published)?;
+            if targets.len() != published.len() {
+                return None;
+            }
+
+            Some(
+                targets
+                    .into_iter()
+                    .zip(published)
+                    .map(|(target_port, published_port)| Publisher {
+                        url: address.to_owned(),
+                        target_port,
+                        published_port,
+                        protocol: protocol.to_owned(),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .flatten()
+        .collect()
+}
+
+/// Lists the published ports of a stack from the output of
+/// `docker compose ps --format json`.
+pub fn parse_bindings(stack: &str, output: &str) -> Result<Vec<Binding>> {
+    let containers: Vec<Container> = parse_ps_as(output)?;
+
+    let mut bindings: Vec<Binding> = containers
+        .into_iter()
+        .flat_map(|container| {
+            let publishers = if container.publishers.is_empty() {
+                parse_summary(&container.ports)
+            } else {
+                container.publishers
+            };
+
+            let service = container.service;
+            publishers
+                .into_iter()
+                .filter(|publisher| publisher.published_port != 0)
+                .map(move |publisher| Binding {
+                    stack: stack.to_owned(),
+                    service: service.clone(),
+                    container_port: publisher.target_port,
+                    protocol: if publisher.protocol.is_empty() {
+                        "tcp".to_owned()
+                    } else {
+                        publisher.protocol
+                    },
+                    address: publisher.url,
+                    host_port: publisher.published_port,
+                })
+        })
+        .collect();
+
+    bindings.sort();
+    bindings.dedup();
+    Ok(bindings)
+}
+
+/// Keeps the bindings for the service and where either the container or the
+/// host port is the port.
+pub fn filter(bindings: Vec<Binding>, service: Option<&str>, port: Option<u16>) -> Vec<Binding> {
+    bindings
+        .into_iter()
+        .filter(|binding| service.is_none_or(|service| binding.service == service))
+        .filter(|binding| {
+            port.is_none_or(|port| binding.container_port == port || binding.host_port == port)
+        })
+        .collect()
+}
+
+/// Renders the bindings as a table.
+pub fn render(bindings: &[Binding]) -> String {
+    if bindings.is_empty() {
+        return "No published ports.\n".to_string();
+    }
+
+    let header = [
+        "Stack".to_string(),
+        "Service".to_string(),
+        "Port".to_string(),
+        "Host".to_string(),
+    ];
+    let rows: Vec<[String; 4]> = bindings
+        .iter()
+        .map(|binding| {
+            [
+                binding.stack.clone(),
+                binding.service.clone(),
+                format!("{}/{}", binding.container_port, binding.protocol),
+                binding.host(),
+            ]
+        })
+        .collect();
+
+    let mut widths = [0; 4];
+    for row in [&header].into_iter().chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(rows.iter()) {
+        let _ = writeln!(
+            table,
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{filter, parse_bindings, render, Binding};
+
+    fn binding(service: &str, container_port: u16, address: &str, host_port: u16) -> Binding {
+        Binding {
+            stack: "web".to_string(),
+            service: service.to_string(),
+            container_port,
+            protocol: "tcp".to_string(),
+            address: address.to_string(),
+            host_port,
+        }
+    }
+
+    #[test]
+    fn publishers() {
+        // One object per line with numeric ports, as printed by compose 2.21
+        // and later. Exposed ports have a published port of 0.
+        let bindings = parse_bindings(
+            "web",
+            r#"{"Service":"app","Publishers":[{"URL":"0.0.0.0","TargetPort":80,"PublishedPort":8080,"Protocol":"tcp"},{"URL":"::","TargetPort":80,"PublishedPort":8080,"Protocol":"tcp"},{"URL":"","TargetPort":9000,"PublishedPort":0,"Protocol":"tcp"}]}
+            {"Service":"db","Publishers":null}
+            {"Service":"worker"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            bindings,
+            vec![
+                binding("app", 80, "0.0.0.0", 8080),
+                binding("app", 80, "::", 8080)
+            ]
+        );
+
+        // A single array as printed by older versions, ports may be strings
+        // and the protocol may be missing.
+        let bindings = parse_bindings(
+            "web",
+            r#"[{"Service":"app","Publishers":[{"URL":"127.0.0.1","TargetPort":"443","PublishedPort":"8443"}]},
+                {"Service":"app","Publishers":[{"URL":"127.0.0.1","TargetPort":443,"PublishedPort":8443,"Protocol":"tcp"}]},
+                {"Service":"dns","Publishers":[{"URL":"0.0.0.0","TargetPort":53,"PublishedPort":53,"Protocol":"udp"}]}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            bindings,
+            vec![
+                binding("app", 443, "127.0.0.1", 8443),
+                Binding {
+                    protocol: "udp".to_string(),
+                    ..binding("dns", 53, "0.0.0.0", 53)
+                }
+            ]
+        );
+
+        assert!(parse_bindings("web", "").unwrap().is_empty());
+        assert!(parse_bindings(
+            "web",
+            r#"{"Service":"app","Publishers":[{"TargetPort":"http"}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn summaries() {
+        // Versions without publishers only have the same summary as
+        // `docker ps`.
+        let bindings = parse_bindings(
+            "web",
+            r#"[{"Service":"app","Ports":"0.0.0.0:8080->80/tcp, :::8080->80/tcp, 9000/tcp"},
+                {"Service":"range","Ports":"127.0.0.1:7000-7001->5000-5001/udp"},
+                {"Service":"db","Ports":""}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            bindings,
+            vec![
+                binding("app", 80, "0.0.0.0", 8080),
+                binding("app", 80, "::", 8080),
+                Binding {
+                    protocol: "udp".to_string(),
+                    ..binding("range", 5000, "127.0.0.1", 7000)
+                },
+                Binding {
+                    protocol: "udp".to_string(),
+                    ..binding("range", 5001, "127.0.0.1", 7001)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn table() {
+        let bindings = vec![
+            binding("app", 80, "0.0.0.0", 8080),
+            binding("app", 80, "::", 8080),
+            binding("admin", 9000, "127.0.0.1", 9001),
+        ];
+
+        assert_eq!(
+            render(&bindings),
+            "Stack  Service  Port      Host\n\
+            web    app      80/tcp    0.0.0.0:8080\n\
+            web    app      80/tcp    [::]:8080\n\
+            web    admin    9000/tcp  127.0.0.1:9001\n"
+        );
+        assert_eq!(render(&[]), "No published ports.\n");
+
+        assert_eq!(
+            filter(bindings.clone(), Some("admin"), None),
+            vec![bindings[2].clone()]
+        );
+        assert_eq!(filter(bindings.clone(), None, Some(8080)).len(), 2);
+        assert_eq!(
+            filter(bindings.clone(), None, Some(9000)),
+            vec![bindings[2].clone()]
+        );
+        assert!(filter(bindings.clone(), Some("app"), Some(9000)).is_empty());
+        assert_eq!(filter(bindings.clone(), None, None), bindings);
+    }
+}
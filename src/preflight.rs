@@ -0,0 +1,547 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config::{Config, Stack};
+use crate::engine::Engine;
+use crate::error::{Error, Result};
+use crate::paths;
+
+/// The compose files that docker compose looks for in a project directory.
+pub const COMPOSE_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yml",
+    "docker-compose.yaml",
+];
+
+/// The compose file that docker compose would use in the project directory,
+/// the first it looks for if there are none.
+pub fn default_compose_file(project_directory: &Path) -> PathBuf {
+    COMPOSE_FILES
+        .iter()
+        .map(|name| project_directory.join(name))
+        .find(|file| file.is_file())
+        .unwrap_or_else(|| project_directory.join(COMPOSE_FILES[0]))
+}
+
+/// Checks whether a path is a file that can be executed.
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    if path.is_file() {
+        return true;
+    }
+
+    // Windows finds programs without their extension.
+    let extensions = env::var("PATHEXT").unwrap_or(".EXE;.CMD;.BAT;.COM".to_string());
+    extensions.split(';').any(|ext| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(ext);
+        Path::new(&name).is_file()
+    })
+}
+
+/// Whether the program is given as a path rather than a name to look up.
+pub fn is_path(program: &str) -> bool {
+    Path::new(program).components().count() > 1
+}
+
+/// Finds the program that would be run. Paths are resolved relative to the
+/// base directory, plain names are looked up in the `PATH` given.
+pub fn find_program(program: &str, base_dir: &Path, path: Option<&OsStr>) -> Option<PathBuf> {
+    if is_path(program) {
+        let target = base_dir.join(program);
+        return is_executable(&target).then_some(target);
+    }
+
+    env::split_paths(path?)
+        .map(|dir| dir.join(program))
+        .find(|target| is_executable(target))
+}
+
+/// Whether the docker compose plugin works with the given docker program.
+pub fn has_compose_plugin(docker: &Path) -> bool {
+    Command::new(docker)
+        .args(["compose", "version"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Checks that each of the programs needed exists before anything is run.
+pub fn check_programs<'a, I>(programs: I, base_dir: &Path, path: Option<&OsStr>) -> Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut checked: Vec<&str> = Vec::new();
+
+    for program in programs {
+        if checked.contains(&program) {
+            continue;
+        }
+
+        if find_program(program, base_dir, path).is_none() {
+            return Err(Error::ProgramNotFound {
+                program: program.to_owned(),
+            });
+        }
+
+        checked.push(program);
+    }
+
+    Ok(())
+}
+
+/// The separator docker compose uses between the files in `COMPOSE_FILE` when
+/// `COMPOSE_PATH_SEPARATOR` isn't set.
+const COMPOSE_PATH_SEPARATOR: &str = if cfg!(windows) { ";" } else { ":" };
+
+/// Describes why the stack's compose project cannot be found, if it cannot.
+/// Without configured files the `COMPOSE_FILE` variable from `lookup`, the
+/// environment the stack's commands run with, chooses the files to use.
+pub fn missing_compose_file<F>(stack: &Stack, base_dir: &Path, lookup: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let directory = stack.directory(base_dir);
+    if !directory.is_dir() {
+        return Some(format!(
+            "directory {} does not exist, `--create-dirs` or `create_dirs: true` will create it \
+            for commands that don't need a compose file",
+            directory.display()
+        ));
+    }
+
+    if let Some(ref files) = stack.file {
+        return files
+            .iter()
+            .map(|file| paths::join(base_dir, file))
+            .find(|file| !file.is_file())
+            .map(|file| format!("compose file {} does not exist", file.display()));
+    }
+
+    if let Some(files) = lookup("COMPOSE_FILE").filter(|files| !files.is_empty()) {
+        let separator = lookup("COMPOSE_PATH_SEPARATOR")
+            .filter(|separator| !separator.is_empty())
+            .unwrap_or(COMPOSE_PATH_SEPARATOR.to_string());
+        return files
+            .split(separator.as_str())
+            .filter(|file| !file.is_empty())
+            .map(|file| directory.join(file))
+            .find(|file| !file.is_file())
+            .map(|file| {
+                format!(
+                    "compose file {} from COMPOSE_FILE does not exist",
+                    file.display()
+                )
+            });
+    }
+
+    if COMPOSE_FILES
+        .iter()
+        .any(|name| directory.join(name).is_file())
+    {
+        None
+    } else {
+        Some(format!("no compose file found in {}", directory.display()))
+    }
+}
+
+/// Checks that every stack has a compose project, reporting all of the stacks
+/// that do not. `lookup` finds the variables each stack's commands run with.
+pub fn check_compose_files<F>(stacks: &[&Stack], base_dir: &Path, lookup: F) -> Result<()>
+where
+    F: Fn(&Stack, &str) -> Option<String>,
+{
+    let problems: Vec<(String, String)> = stacks
+        .iter()
+        .filter_map(|stack| {
+            missing_compose_file(stack, base_dir, |name| lookup(stack, name))
+                .map(|problem| (stack.key.clone(), problem))
+        })
+        .collect();
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::MissingComposeFiles { problems })
+    }
+}
+
+/// The docker daemon that a stack's commands talk to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub context: Option<String>,
+    pub host: Option<String>,
+}
+
+impl Endpoint {
+    /// The endpoint for a stack, taking into account the environment variables
+    /// set for the stack, then the config and finally our own environment.
+    pub fn for_stack<F>(config: &Config, stack: &Stack, lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |name: &str| {
+            stack
+                .environment
+                .get(name)
+                .or_else(|| config.environment.get(name))
+                .cloned()
+                .or_else(|| lookup(name))
+                .filter(|value| !value.is_empty())
+        };
+
+        // An explicit host overrides any context.
+        match var("DOCKER_HOST") {
+            Some(host) => Endpoint {
+                context: None,
+                host: Some(host),
+            },
+            None => Endpoint {
+                context: var("DOCKER_CONTEXT"),
+                host: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.host, &self.context) {
+            (Some(host), _) => f.write_str(host),
+            (None, Some(context)) => write!(f, "context \"{}\"", context),
+            (None, None) => f.write_str("the default docker host"),
+        }
+    }
+}
+
+/// The program that can be used to query the daemon, only docker and podman
+/// are known. `podman-compose` is queried through `podman`.
+pub fn daemon_program(command: &[String]) -> Option<&str> {
+    let program = command.first()?;
+    let name = Path::new(program).file_stem()?.to_str()?;
+    match name {
+        "docker" | "podman" => Some(program.as_str()),
+        "podman-compose" => Some("podman"),
+        _ => None,
+    }
+}
+
+/// Asks the daemon at the endpoint for its details to see if it is running.
+pub fn probe_daemon(
+    program: &str,
+    engine: Engine,
+    endpoint: &Endpoint,
+) -> std::result::Result<(), String> {
+    let mut command = Command::new(program);
+    command
+        .args(engine.info_args())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if let Some(ref host) = endpoint.host {
+        command
+            .env("DOCKER_HOST", host)
+            .env_remove("DOCKER_CONTEXT");
+    }
+    if let Some(ref context) = endpoint.context {
+        command.env("DOCKER_CONTEXT", context);
+    }
+
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(status.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Checks that each distinct endpoint is reachable, reporting all of those
+/// that are not.
+pub fn check_daemons<F>(endpoints: &[Endpoint], probe: F) -> Result<()>
+where
+    F: Fn(&Endpoint) -> std::result::Result<(), String>,
+{
+    let mut checked: Vec<&Endpoint> = Vec::new();
+    let mut unreachable = Vec::new();
+
+    for endpoint in endpoints {
+        if checked.contains(&endpoint) {
+            continue;
+        }
+        checked.push(endpoint);
+
+        if let Err(e) = probe(endpoint) {
+            log::debug!("Failed to reach the docker daemon at {}: {}", endpoint, e);
+            unreachable.push(endpoint.to_string());
+        }
+    }
+
+    if unreachable.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::DaemonUnreachable {
+            endpoints: unreachable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_compose_files, check_daemons, check_programs, daemon_program, find_program, Endpoint,
+    };
+    use crate::config::{Config, Stack};
+    use crate::error::Error;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    #[test]
+    fn programs() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("tools")).unwrap();
+        for file in ["bin/compose", "tools/local"] {
+            fs::write(root.join(file), "#!/bin/sh\n").unwrap();
+            fs::set_permissions(root.join(file), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(root.join("bin/data"), "").unwrap();
+        let path = env::join_paths([root.join("missing"), root.join("bin")]).unwrap();
+        let path = Some(path.as_os_str());
+
+        // Looked up in the path.
+        assert_eq!(
+            find_program("compose", root, path),
+            Some(root.join("bin/compose"))
+        );
+        assert_eq!(find_program("data", root, path), None);
+        assert_eq!(find_program("docker", root, path), None);
+        assert_eq!(find_program("compose", root, None), None);
+
+        // Absolute paths.
+        let absolute = root.join("tools/local");
+        assert_eq!(
+            find_program(absolute.to_str().unwrap(), root, path),
+            Some(absolute.clone())
+        );
+        assert_eq!(
+            find_program(root.join("tools/missing").to_str().unwrap(), root, path),
+            None
+        );
+
+        // Relative paths are relative to the base directory.
+        assert_eq!(
+            find_program("tools/local", root, path),
+            Some(root.join("tools/local"))
+        );
+        assert_eq!(find_program("./tools/local", &root.join("bin"), path), None);
+
+        check_programs(["compose", "compose", "tools/local"], root, path).unwrap();
+        match check_programs(["compose", "docker"], root, path) {
+            Err(Error::ProgramNotFound { program }) => assert_eq!(program, "docker"),
+            _ => panic!("Expected docker to be missing"),
+        }
+    }
+
+    #[test]
+    fn compose_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("web")).unwrap();
+        fs::write(root.join("web/compose.yaml"), "").unwrap();
+        fs::create_dir_all(root.join("db")).unwrap();
+        fs::write(root.join("db/docker-compose.yml"), "").unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("custom.yml"), "").unwrap();
+
+        let config = Config::from_reader(
+            root,
+            "
+            stacks:
+                web: {}
+                db: {}
+                custom:
+                    directory: web
+                    file: custom.yml
+                empty: {}
+                typo:
+                    directory: wbe
+                missing:
+                    directory: web
+                    file:
+                        - custom.yml
+                        - other.yml
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let none = |_: &Stack, _: &str| None;
+        check_compose_files(&config.stacks(["web", "db", "custom"]).unwrap(), root, none).unwrap();
+
+        match check_compose_files(
+            &config.stacks(["web", "empty", "typo", "missing"]).unwrap(),
+            root,
+            none,
+        ) {
+            Err(Error::MissingComposeFiles { problems }) => {
+                let mut stacks: Vec<&str> = problems.iter().map(|(s, _)| s.as_str()).collect();
+                stacks.sort();
+                assert_eq!(stacks, vec!["empty", "missing", "typo"]);
+                for (stack, problem) in problems.iter() {
+                    match stack.as_str() {
+                        "empty" => assert!(problem.starts_with("no compose file found in")),
+                        "typo" => {
+                            assert!(problem.contains("wbe does not exist"));
+                            assert!(problem.contains("`create_dirs: true`"));
+                        }
+                        _ => assert!(problem.ends_with("other.yml does not exist")),
+                    }
+                }
+            }
+            _ => panic!("Expected compose files to be missing"),
+        }
+
+        // COMPOSE_FILE chooses the files in the stack's directory.
+        fs::write(root.join("empty/app.yml"), "").unwrap();
+        fs::write(root.join("empty/override.yml"), "").unwrap();
+        let empty = config.stacks(["empty"]).unwrap();
+        let compose_file = |files: &'static str, separator: Option<&'static str>| {
+            move |_: &Stack, name: &str| match name {
+                "COMPOSE_FILE" => Some(files.to_string()),
+                "COMPOSE_PATH_SEPARATOR" => separator.map(str::to_string),
+                _ => None,
+            }
+        };
+        check_compose_files(&empty, root, compose_file("app.yml", None)).unwrap();
+        check_compose_files(&empty, root, compose_file("app.yml:override.yml", None)).unwrap();
+        check_compose_files(
+            &empty,
+            root,
+            compose_file("app.yml,override.yml", Some(",")),
+        )
+        .unwrap();
+        match check_compose_files(&empty, root, compose_file("app.yml:other.yml", None)) {
+            Err(Error::MissingComposeFiles { problems }) => assert!(problems[0]
+                .1
+                .ends_with("other.yml from COMPOSE_FILE does not exist")),
+            _ => panic!("Expected other.yml to be missing"),
+        }
+
+        // Configured files take precedence.
+        check_compose_files(
+            &config.stacks(["custom"]).unwrap(),
+            root,
+            compose_file("other.yml", None),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn endpoints() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            environment:
+                DOCKER_CONTEXT: shared
+            stacks:
+                web: {}
+                db:
+                    environment:
+                        DOCKER_HOST: tcp://db-host:2375
+                cache:
+                    environment:
+                        DOCKER_CONTEXT: \"\"
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stack = |key: &str| config.stacks.get(key).unwrap();
+        let none = |_: &str| None;
+
+        let web = Endpoint::for_stack(&config, stack("web"), none);
+        assert_eq!(web.context.as_deref(), Some("shared"));
+        assert_eq!(web.to_string(), "context \"shared\"");
+        let db = Endpoint::for_stack(&config, stack("db"), none);
+        assert_eq!(db.host.as_deref(), Some("tcp://db-host:2375"));
+        assert_eq!(db.context, None);
+        let cache = Endpoint::for_stack(&config, stack("cache"), |name| {
+            (name == "DOCKER_HOST").then(|| "unix:///run/docker.sock".to_string())
+        });
+        assert_eq!(cache.to_string(), "unix:///run/docker.sock");
+        let cache = Endpoint::for_stack(&config, stack("cache"), none);
+        assert_eq!(cache.to_string(), "the default docker host");
+
+        assert_eq!(
+            daemon_program(&["docker".to_string(), "compose".to_string()]),
+            Some("docker")
+        );
+        assert_eq!(
+            daemon_program(&["/usr/bin/podman".to_string(), "compose".to_string()]),
+            Some("/usr/bin/podman")
+        );
+        assert_eq!(daemon_program(&["docker-compose".to_string()]), None);
+        assert_eq!(
+            daemon_program(&["podman-compose".to_string()]),
+            Some("podman")
+        );
+    }
+
+    #[test]
+    fn daemons() {
+        let local = Endpoint {
+            context: None,
+            host: None,
+        };
+        let remote = Endpoint {
+            context: None,
+            host: Some("tcp://remote:2375".to_string()),
+        };
+        let endpoints = vec![local.clone(), remote.clone(), local.clone(), remote.clone()];
+
+        // Reachable, each endpoint is only checked once.
+        let probed = RefCell::new(Vec::new());
+        check_daemons(&endpoints, |e| {
+            probed.borrow_mut().push(e.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(*probed.borrow(), vec![local.clone(), remote.clone()]);
+
+        // Unreachable.
+        let error = check_daemons(&endpoints, |_| Err("down".to_string())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cannot connect to the Docker daemon at the default docker host, tcp://remote:2375; is it running?"
+        );
+
+        // Mixed.
+        match check_daemons(&endpoints, |e| match e.host {
+            Some(_) => Err("down".to_string()),
+            None => Ok(()),
+        }) {
+            Err(Error::DaemonUnreachable { endpoints }) => {
+                assert_eq!(endpoints, vec!["tcp://remote:2375".to_string()])
+            }
+            _ => panic!("Expected the remote daemon to be unreachable"),
+        }
+    }
+}
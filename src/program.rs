@@ -1,18 +1,71 @@
 use std::collections::HashSet;
+use std::env;
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, ColorChoice, CommandFactory, Parser, Subcommand};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use regex::Regex;
 
-use crate::{commands::run_against_stacks, config::Config};
+use stacks::{
+    args::{has_flag, merge},
+    clean,
+    commands::{
+        confirm_image_removal, confirm_volume_removal, implicit_stacks, removes_volumes, Direction,
+        Rollback, Runner,
+    },
+    config::{Config, Stack},
+    docs,
+    engine::Engine,
+    environment::{parse_assignment, render_environment, EnvFormat},
+    error::{Error, Result},
+    exec::OutputMode,
+    graph::GraphFormat,
+    lock::parse_duration,
+    paths, picker, schema, selection,
+    task::{self, Step},
+    verify::{self, Verdict},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None, subcommand_precedence_over_arg(true))]
 pub struct Program {
+    /// Run as if stacks was started in this directory. Everything else,
+    /// including the config file and `STACKS_FILE`, is relative to it.
+    #[arg(short = 'C', long = "chdir", value_name = "DIR")]
+    pub chdir: Option<PathBuf>,
+
     /// The location of the stacks config file. By default looks for stacks.yml
-    /// in the current and parent directories.
+    /// in the current and parent directories. Use `-` to read from stdin. An
+    /// http or https URL is fetched, sending `STACKS_FILE_TOKEN` as a bearer
+    /// token if set.
     #[arg(short, long, env = "STACKS_FILE")]
     pub file: Option<String>,
 
+    /// The directory that stack directories are relative to. Defaults to the
+    /// `base_dir` in the config, the directory containing the stacks config
+    /// file or the current directory when reading the config from stdin.
+    #[arg(long)]
+    pub base_dir: Option<String>,
+
+    /// The command to run compose with instead of the one in the config, for
+    /// instance a wrapper script. Either a string split on spaces or a list
+    /// like `[podman, compose]`.
+    #[arg(long, env = "STACKS_COMMAND", value_name = "COMMAND")]
+    pub binary: Option<String>,
+
+    /// How long to wait when fetching the stacks config from a URL.
+    #[arg(
+        long,
+        env = "STACKS_FETCH_TIMEOUT",
+        value_name = "DURATION",
+        default_value = "30s",
+        value_parser = parse_duration
+    )]
+    pub fetch_timeout: Duration,
+
     #[clap(flatten)]
     pub verbose: Verbosity<InfoLevel>,
 
@@ -23,32 +76,359 @@ pub struct Program {
     pub command: Commands,
 }
 
+/// Whether an environment variable like `CI` is set to something other than a
+/// false value.
+fn enabled(value: &OsStr) -> bool {
+    !matches!(
+        value.to_string_lossy().to_lowercase().as_str(),
+        "" | "0" | "false" | "no" | "off"
+    )
+}
+
 #[derive(Args, Debug)]
 pub struct GlobalArguments {
     /// A comma separated list of stacks to apply the command to. If not present
-    /// or `*` is given then all stacks are used.
+    /// then the `STACKS_STACKS` environment variable or the `default_stacks`
+    /// from the config are used. If none of those are set or `*` is given then
+    /// all stacks are used. `@last` selects the stacks used by the previous
+    /// command.
     stacks: Option<String>,
+
+    /// Also select the stacks whose keys match a regular expression. May be
+    /// given more than once to select the stacks matching any of them.
+    #[arg(long, value_name = "REGEX", value_parser = selection::parse_regex)]
+    pub select_regex: Vec<Regex>,
+
+    /// Set an environment variable for every stack, overriding the config.
+    #[arg(short, long = "env", value_name = "KEY=VALUE", value_parser = parse_assignment)]
+    pub env: Vec<(String, String)>,
+
+    /// Do not pass the environment stacks is run in on to commands, other than
+    /// a few essential variables.
+    #[arg(long)]
+    pub clean_env: bool,
+
+    /// Create missing stack directories for commands that don't need a
+    /// compose file.
+    #[arg(long)]
+    pub create_dirs: bool,
+
+    /// Bring stacks up with the images pinned by `stack freeze`.
+    #[arg(long)]
+    pub frozen: bool,
+
+    /// Don't pass `--remove-orphans` to `down` even if the config asks for it.
+    #[arg(long)]
+    pub no_remove_orphans: bool,
+
+    /// Assume yes as the answer to any confirmation prompts.
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Use defaults suited to CI: no colour, prompts or live progress, only
+    /// the output of failed commands and always a summary. Implied when the
+    /// `CI` environment variable is set.
+    #[arg(long, overrides_with = "no_ci")]
+    pub ci: bool,
+
+    /// Do not use the CI defaults even if the `CI` environment variable is
+    /// set.
+    #[arg(long)]
+    pub no_ci: bool,
+
+    /// When to colour output, both from stacks and docker compose.
+    #[arg(long, value_enum, value_name = "WHEN")]
+    pub color: Option<ColorChoice>,
+
+    /// Never prompt to pick a stack when a command needs a single stack but
+    /// none was given.
+    #[arg(long)]
+    pub no_interactive: bool,
+
+    /// Bring stacks up and down in parallel where their dependencies allow.
+    #[arg(long)]
+    pub parallel: bool,
+
+    /// Do not bring up stacks whose services are all already running and
+    /// healthy, unless their configuration changed since they were brought up.
+    #[arg(long)]
+    pub skip_running: bool,
+
+    /// Skip the stacks that would run before this one, to resume a run that
+    /// failed part way through.
+    #[arg(long, value_name = "STACK")]
+    pub from: Option<String>,
+
+    /// Skip the stacks that would run after this one.
+    #[arg(long, value_name = "STACK")]
+    pub until: Option<String>,
+
+    /// When `up` or `restart` fails take down the stacks that were started,
+    /// either with `down` (the default) or `stop`.
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "down")]
+    pub rollback: Option<Rollback>,
+
+    /// The maximum number of stacks to run at once when running in parallel.
+    #[arg(long, default_value_t = 4)]
+    pub max_parallel: usize,
+
+    /// Do not prefix output with the stack name when running against multiple
+    /// stacks.
+    #[arg(long)]
+    pub no_prefix: bool,
+
+    /// Only show the output of commands that fail.
+    #[arg(long, overrides_with = "no_quiet_success")]
+    pub quiet_success: bool,
+
+    /// Show the output of commands that succeed, even with `--ci`.
+    #[arg(long)]
+    pub no_quiet_success: bool,
+
+    /// How many times to retry commands that fail. Overrides the `retries`
+    /// set for each stack. Only pull, build, ps and images are retried unless
+    /// `--retry-all` is given.
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// Allow any command to be retried.
+    #[arg(long)]
+    pub retry_all: bool,
+
+    /// Do not print a summary of each stack's result and duration after
+    /// running against several stacks.
+    #[arg(long)]
+    pub no_summary: bool,
+
+    /// Write how long each phase took for each stack to a JSON file and show
+    /// a table of them at the end of the run.
+    #[arg(long, value_name = "PATH")]
+    pub timings: Option<PathBuf>,
+
+    /// Show a desktop notification when the run finishes if it took longer
+    /// than the `notify` threshold in the config.
+    #[arg(long)]
+    pub notify: bool,
+
+    /// Skip the checks that the compose program, compose files and docker
+    /// daemon are all present before running commands.
+    #[arg(long)]
+    pub no_preflight: bool,
+
+    /// Fail rather than warn when docker compose is older than the configured
+    /// `min_compose_version`.
+    #[arg(long)]
+    pub strict_version: bool,
+
+    /// Do not show which stacks are in progress when running against several
+    /// stacks.
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// Save each stack's output to `<stack>.log` in a new timestamped
+    /// directory inside this directory.
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Append NDJSON progress events to this file as stacks start and finish.
+    #[arg(long)]
+    pub progress_file: Option<PathBuf>,
+
+    /// Include each line of output in the progress file.
+    #[arg(long, requires = "progress_file")]
+    pub progress_output: bool,
+
+    /// Append stack's own log, including every command run, to this file at
+    /// debug level whatever the verbosity.
+    #[arg(long, value_name = "PATH", env = "STACKS_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// How long to wait for another run against the same config to finish,
+    /// e.g. `30s` or `5m`. Fails immediately if not given.
+    #[arg(long, value_parser = parse_duration)]
+    pub wait_lock: Option<Duration>,
 }
 
 impl GlobalArguments {
-    pub fn stacks(&self) -> Vec<&str> {
-        match self.stacks {
-            Some(ref s) => {
-                if s.is_empty() || s == "*" {
-                    Vec::new()
-                } else {
-                    s.split(',').collect()
-                }
+    /// Applies the defaults of `--ci`, or of the `CI` environment variable
+    /// when it is set, to any options that were not given explicitly.
+    pub fn apply_ci(&mut self, ci_env: Option<&OsStr>) {
+        self.ci = !self.no_ci && (self.ci || ci_env.is_some_and(enabled));
+        if !self.ci {
+            return;
+        }
+
+        self.color.get_or_insert(ColorChoice::Never);
+        // Destructive commands fail rather than prompt unless `--yes` is given.
+        self.no_interactive = true;
+        self.no_progress = true;
+        if !self.no_quiet_success {
+            self.quiet_success = true;
+        }
+    }
+
+    /// Whether output should be coloured, `terminal` is whether stdout is a
+    /// terminal.
+    pub fn color(&self, terminal: bool) -> bool {
+        match self.color {
+            Some(ColorChoice::Always) => true,
+            Some(ColorChoice::Never) => false,
+            Some(ColorChoice::Auto) | None => terminal && env::var_os("NO_COLOR").is_none(),
+        }
+    }
+
+    /// The options to pass to compose itself.
+    pub fn compose_args(&self, engine: Engine) -> Vec<String> {
+        match (self.color, engine) {
+            (Some(ColorChoice::Never), Engine::Docker) => {
+                vec!["--ansi".to_string(), "never".to_string()]
             }
-            None => Vec::new(),
+            _ => Vec::new(),
         }
     }
+
+    /// Whether to print the summary after running against some stacks. In CI
+    /// it is shown even for a single stack.
+    pub fn show_summary(&self, stacks: usize) -> bool {
+        !self.no_summary && (stacks > 1 || (self.ci && stacks > 0))
+    }
+
+    /// The selection of stacks to use taking into account the environment,
+    /// config defaults and `--select-regex`, with `.` expanded to the stack
+    /// containing `cwd`.
+    pub fn selection(&self, config: &Config, cwd: &Path) -> Result<Vec<String>> {
+        let env = env::var(selection::STACKS_VAR).ok();
+        let keys: Vec<&str> = config.stacks.keys().map(String::as_str).collect();
+        let selected = selection::select(
+            self.stacks.as_deref(),
+            env.as_deref(),
+            &config.default_stacks,
+            &self.select_regex,
+            &keys,
+        )?;
+
+        selection::expand_current(&selected, cwd, || stack_directories(config))
+    }
+
+    /// Whether the stacks were chosen on the command line, either listed or with
+    /// `--select-regex`, rather than coming from the environment or config
+    /// defaults. Only those selections are remembered for `@last`.
+    pub fn explicit(&self) -> bool {
+        self.stacks.is_some() || !self.select_regex.is_empty()
+    }
+
+    /// The stacks named on the command line, with `.` expanded to the stack
+    /// containing `cwd`. Unlike `selection` this leaves out everything
+    /// selected by `*`, `--select-regex`, the environment or config defaults.
+    pub fn named(&self, config: &Config, cwd: &Path) -> Result<Vec<String>> {
+        let named = self
+            .stacks
+            .as_deref()
+            .map(selection::parse)
+            .unwrap_or_default();
+        selection::expand_current(&named, cwd, || stack_directories(config))
+    }
+}
+
+/// The canonical directory of each stack. Directories that don't exist can't
+/// contain anything so are left out.
+fn stack_directories(config: &Config) -> Vec<(String, PathBuf)> {
+    config
+        .stacks
+        .values()
+        .filter_map(|stack| {
+            let dir = paths::canonicalize(stack.directory(&config.base_dir)).ok()?;
+            Some((stack.key.clone(), dir))
+        })
+        .collect()
+}
+
+/// Writes the markdown documentation for the command line to a directory.
+pub fn generate_docs(dir: &Path) -> Result<()> {
+    let written = docs::generate(&Program::command(), dir)?;
+    log::info!("Wrote {} pages to {}", written.len(), dir.display());
+    Ok(())
+}
+
+/// Parses the command of a task's step.
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct TaskStep {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// Runs each step of the task in turn, stopping at the first that fails.
+fn run_task(name: &str, globals: &GlobalArguments, runner: &Runner) -> Result<()> {
+    let config = runner.config;
+    let task = config.tasks.get(name).ok_or_else(|| {
+        Error::Invalid(format!(
+            "There is no task `{}`, see `stack task --list`.",
+            name
+        ))
+    })?;
+
+    for (index, step) in task.steps.iter().enumerate() {
+        log::info!(
+            "Task {} step {}/{}: {}",
+            name,
+            index + 1,
+            task.steps.len(),
+            step.describe()
+        );
+
+        match step {
+            Step::Command(step) => {
+                let parsed =
+                    TaskStep::try_parse_from([&step.command].into_iter().chain(step.args.iter()))
+                        .map_err(|e| {
+                        Error::Invalid(format!(
+                            "Step {} of task `{}` is invalid: {}",
+                            index + 1,
+                            name,
+                            e.render()
+                        ))
+                    })?;
+                // The stacks of a step are all named.
+                parsed
+                    .command
+                    .run_named(globals, &step.stacks, &step.stacks, runner)?;
+            }
+            Step::Run(step) => {
+                let stacks = config.stacks([&step.stack])?;
+                let command = ["sh", "-c", &step.run].map(str::to_owned);
+                runner.foreach(&stacks, &command, None)?;
+            }
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
+    /// Attach to a service's running container
+    Attach {
+        /// Arguments to pass through to docker compose
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Archive the named volumes of a stack
+    Backup {
+        /// The directory to write the backup to
+        #[arg(long, default_value = ".")]
+        dest: PathBuf,
+        /// Stop the stack while its volumes are archived
+        #[arg(long)]
+        stop: bool,
+    },
     /// Build or rebuild services
     Build {
+        /// Also build the stacks that the selected stacks depend on, each
+        /// after its dependencies. With `--parallel` only stacks that don't
+        /// depend on each other build at the same time
+        #[arg(long)]
+        ordered: bool,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
@@ -60,29 +440,129 @@ pub enum Commands {
         args: Vec<String>,
     },
     /// Creates containers for a service.
+    ///
+    /// Each stack's dependencies are created first. Stacks with
+    /// `autostart: false` are left out unless they are named or a stack that
+    /// is created depends on them.
     Create {
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Show which stacks' configuration has changed since they were last
+    /// brought up
+    Diff {
+        /// Show what changed in each changed stack's rendered compose config
+        #[arg(long)]
+        details: bool,
+        /// Show the values of environment variables that changed
+        #[arg(long, requires = "details")]
+        show_secrets: bool,
+    },
     /// Stop and remove containers, networks
+    ///
+    /// Stacks are taken down after the stacks that depend on them.
+    /// `--remove-orphans` is added when the config's `remove_orphans` asks
+    /// for it and `--timeout` from the stack's `stop_grace` unless either is
+    /// given.
     Down {
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Print the environment that commands for a stack run with, where it
+    /// differs from the current environment
+    Env {
+        /// Print `export` lines for a shell to evaluate, the same as
+        /// `--format shell`
+        #[arg(long, conflicts_with = "format")]
+        export: bool,
+        /// How to print the variables
+        #[arg(long, value_enum, default_value = "dotenv")]
+        format: EnvFormat,
+    },
     /// Receive real time events from containers.
+    ///
+    /// The events of every selected stack are followed at the same time.
     Events {
+        /// Print each event as a line of JSON with a `stack` field saying
+        /// which stack it came from
+        #[arg(long)]
+        json: bool,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
     /// Execute a command in a running container.
     Exec {
+        /// Allocate a TTY even when stdin or stdout is not a terminal. Without
+        /// one `-T` is passed to compose
+        #[arg(long)]
+        tty: bool,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Run a host command in the directory of each stack.
+    Foreach {
+        /// Run in dependency order rather than selection order
+        #[arg(long)]
+        ordered: bool,
+        /// Continue with the remaining stacks when the command fails
+        #[arg(long)]
+        keep_going: bool,
+        /// The command to run
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = true,
+            value_name = "COMMAND"
+        )]
+        command: Vec<String>,
+    },
+    /// Pin the image of each service to its current digest in the stack's
+    /// compose.pinned.yml, which `up --frozen` uses
+    Freeze {
+        /// Check that the pinned images are current rather than pinning them
+        #[arg(long)]
+        check: bool,
+    },
+    /// Write markdown documentation for every command to a directory
+    #[command(hide = true)]
+    GenerateDocs {
+        /// The directory to write the pages to
+        dir: PathBuf,
+    },
+    /// Remove the dangling images and build cache left behind by the stacks'
+    /// builds and pulls, listing the images first
+    Clean {
+        /// Also remove the stacks' tagged images that no compose config or
+        /// container uses
+        #[arg(long)]
+        all_unused: bool,
+    },
+    /// Print the stacks config, or the compose config of the selected stacks
+    Config {
+        /// Print the config that docker compose resolves for each selected
+        /// stack
+        #[arg(long)]
+        rendered: bool,
+        /// Print the names of each selected stack's services
+        #[arg(long, conflicts_with = "rendered")]
+        services: bool,
+    },
+    /// Print the dependency graph of the stacks without running anything
+    Graph {
+        /// The format to print the graph in
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    /// Show the most recent commands that changed stacks
+    History {
+        /// The number of entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        count: usize,
+    },
     /// List images used by the created containers
     Images {
         /// Arguments to pass through to docker compose
@@ -95,32 +575,93 @@ pub enum Commands {
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
-    /// View output from containers
+    /// List the configured stacks
+    List {
+        /// Add a column showing whether each stack is up, partially up or
+        /// down
+        #[arg(long)]
+        state: bool,
+        /// Show every stack that nothing depends on with its dependencies
+        /// nested beneath it
+        #[arg(long)]
+        tree: bool,
+    },
+    /// View output from the containers of the stacks and their dependencies,
+    /// all at once
     Logs {
+        /// Only show lines matching this regular expression. May be given
+        /// more than once to show lines matching any of them
+        #[arg(long, value_name = "PATTERN")]
+        grep: Vec<String>,
+        /// Show the lines that don't match `--grep` instead
+        #[arg(long, requires = "grep")]
+        invert_grep: bool,
+        /// Only show what was logged since the stack was last brought up,
+        /// as recorded in the state file. Can't be combined with `--since`
+        #[arg(long)]
+        since_last_up: bool,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Open the stack's url in the browser
+    Open {
+        /// Which of the stack's urls to open, counting from 1. The first by
+        /// default
+        index: Option<usize>,
+        /// Print the url instead of opening it
+        #[arg(long)]
+        print: bool,
+    },
     /// Pause services
     Pause {
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
-    /// Print the public port for a port binding.
+    /// Print the public port for a port binding. Without arguments lists the
+    /// published ports of every selected stack like `ports`.
     Port {
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// List the ports that the selected stacks publish on the host
+    Ports {
+        /// Only list the ports of this service
+        #[arg(long)]
+        service: Option<String>,
+        /// Only list bindings with this container or host port
+        #[arg(long)]
+        port: Option<u16>,
+    },
     /// List containers
     Ps {
+        /// Redraw the output every interval, 2s by default, highlighting the
+        /// rows that changed until interrupted
+        #[arg(
+            long,
+            value_name = "INTERVAL",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "2s",
+            value_parser = parse_duration
+        )]
+        watch: Option<Duration>,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
     /// Pull service images
+    ///
+    /// Failed pulls are retried as many times as `--retries`, or the stack's
+    /// `retries`, allows.
     Pull {
+        /// Pull each image that several stacks share only once, with the
+        /// engine's `pull` rather than compose. Arguments are passed to that
+        /// instead
+        #[arg(long)]
+        dedupe: bool,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
@@ -131,8 +672,31 @@ pub enum Commands {
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Restore a stack's volumes from a backup made by `backup`
+    Restore {
+        /// The directory of the backup
+        #[arg(long, value_name = "DIR")]
+        from: PathBuf,
+        /// Only restore these volumes
+        #[arg(long, value_delimiter = ',', value_name = "VOLUMES")]
+        volumes: Vec<String>,
+        /// Stop the stack if it is running and start it again afterwards
+        #[arg(long)]
+        force_stop: bool,
+        /// Restore a backup that was made of a different stack
+        #[arg(long)]
+        allow_mismatch: bool,
+    },
     /// Restart service containers
+    ///
+    /// The stacks and everything that depends on them are taken down, then
+    /// they and their dependencies are brought back up in dependency order
+    /// with `up --wait` where compose supports it. Stacks without any
+    /// containers are left alone unless `--all` is given.
     Restart {
+        /// Also restart stacks that have no containers
+        #[arg(long)]
+        all: bool,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
@@ -145,22 +709,63 @@ pub enum Commands {
     },
     /// Run a one-off command on a service.
     Run {
+        /// Allocate a TTY even when stdin or stdout is not a terminal. Without
+        /// one `-T` is passed to compose
+        #[arg(long)]
+        tty: bool,
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Print the JSON Schema for the stacks config file
+    Schema,
     /// Start services
+    ///
+    /// Starts the existing containers of a single stack, which is picked
+    /// interactively when none is given.
     Start {
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Show whether stacks are running, when they were last brought up and
+    /// whether their configuration has changed since
+    Status {
+        /// Also list compose projects on the host that aren't configured
+        /// stacks
+        #[arg(long)]
+        orphans: bool,
+        /// Redraw the output every interval, 2s by default, highlighting the
+        /// rows that changed until interrupted
+        #[arg(
+            long,
+            value_name = "INTERVAL",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "2s",
+            value_parser = parse_duration
+        )]
+        watch: Option<Duration>,
+    },
     /// Stop services
+    ///
+    /// Stacks are stopped after the stacks that depend on them, waiting for
+    /// the stack's `stop_grace` unless a timeout is given.
     Stop {
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Run the steps of a task from the config in order, stopping at the
+    /// first that fails
+    Task {
+        /// The task to run
+        #[arg(required_unless_present = "list")]
+        name: Option<String>,
+        /// List the tasks and their descriptions instead
+        #[arg(long, conflicts_with = "name")]
+        list: bool,
+    },
     /// Display the running processes
     Top {
         /// Arguments to pass through to docker compose
@@ -174,114 +779,555 @@ pub enum Commands {
         args: Vec<String>,
     },
     /// Create and start containers detached
+    ///
+    /// Each stack is brought up after the stacks it depends on. `--wait` is
+    /// passed to docker compose, when it is new enough to support it, so
+    /// that a stack's services are running and healthy before its
+    /// dependants start. Stacks with `autostart: false` are left out unless
+    /// they are named or a stack that is brought up depends on them.
     Up {
+        /// Only bring up stacks that don't have any containers yet
+        #[arg(long)]
+        only_missing: bool,
+        /// Run in the foreground attached to the containers' output, Ctrl-C
+        /// stops them. Only a single stack can run this way, pass `--no-deps`
+        /// through to leave out its dependencies
+        #[arg(long, conflicts_with = "only_missing")]
+        foreground: bool,
+        /// Stop all containers when any container stops, with `--foreground`
+        #[arg(long, requires = "foreground")]
+        abort_on_container_exit: bool,
+        /// Arguments to pass through to docker compose
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Check whether the running containers still match the config. Pass
+    /// `--verbose` to see how drifted stacks differ
+    Verify {
+        /// Exit with a failure if any stack has drifted
+        #[arg(long)]
+        exit_code: bool,
+    },
+    /// Watch the build context and rebuild or sync services as files change
+    /// until interrupted
+    Watch {
         /// Arguments to pass through to docker compose
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
 }
 
+/// The arguments for `up --foreground`. Compose stays in the foreground unless
+/// told to detach so nothing is added to wait for the services.
+fn foreground_up_args(args: &[String], abort_on_container_exit: bool) -> Result<Vec<String>> {
+    if let Some(flag) = ["-d", "--detach", "--wait"]
+        .into_iter()
+        .find(|flag| has_flag(args, &[flag]))
+    {
+        return Err(Error::Invalid(format!(
+            "`{}` can't be used with `up --foreground` which stays in the foreground.",
+            flag
+        )));
+    }
+
+    let mut args = args.to_vec();
+    if abort_on_container_exit && !has_flag(&args, &["--abort-on-container-exit"]) {
+        args.insert(0, "--abort-on-container-exit".to_owned());
+    }
+    Ok(args)
+}
+
+/// Resolves the single stack that a command should be run against. When no
+/// stack was selected and we are attached to a terminal the user is asked to
+/// pick one.
+fn single_stack<'a>(
+    globals: &GlobalArguments,
+    selection: &[String],
+    config: &'a Config,
+) -> Result<Vec<&'a Stack>> {
+    if selection.is_empty()
+        && config.stacks.len() > 1
+        && !globals.no_interactive
+        && io::stdin().is_terminal()
+        && io::stdout().is_terminal()
+    {
+        let stacks: Vec<&Stack> = config.stacks.values().collect();
+        let stack = picker::pick(&mut io::stdin().lock(), &mut io::stderr(), &stacks)?;
+        return Ok(vec![stack]);
+    }
+
+    config.stack(selection)
+}
+
 fn reverse<T>(stacks: Vec<T>) -> Vec<T> {
     stacks.into_iter().rev().collect()
 }
 
 impl Commands {
-    pub fn run(&self, globals: &GlobalArguments, config: &Config) -> Result<(), String> {
+    /// The name of the command as given on the command line.
+    pub fn name(&self) -> &'static str {
         match self {
-            Commands::Build { args } => {
-                let stacks = config.stacks(globals.stacks())?;
-                run_against_stacks("build", config, &stacks, args)
+            Commands::Attach { .. } => "attach",
+            Commands::Backup { .. } => "backup",
+            Commands::Build { .. } => "build",
+            Commands::Clean { .. } => "clean",
+            Commands::Config { .. } => "config",
+            Commands::Cp { .. } => "cp",
+            Commands::Create { .. } => "create",
+            Commands::Diff { .. } => "diff",
+            Commands::Down { .. } => "down",
+            Commands::Env { .. } => "env",
+            Commands::Events { .. } => "events",
+            Commands::Exec { .. } => "exec",
+            Commands::Foreach { .. } => "foreach",
+            Commands::Freeze { .. } => "freeze",
+            Commands::GenerateDocs { .. } => "generate-docs",
+            Commands::Graph { .. } => "graph",
+            Commands::History { .. } => "history",
+            Commands::Images { .. } => "images",
+            Commands::Kill { .. } => "kill",
+            Commands::List { .. } => "list",
+            Commands::Logs { .. } => "logs",
+            Commands::Open { .. } => "open",
+            Commands::Pause { .. } => "pause",
+            Commands::Port { .. } => "port",
+            Commands::Ports { .. } => "ports",
+            Commands::Ps { .. } => "ps",
+            Commands::Pull { .. } => "pull",
+            Commands::Push { .. } => "push",
+            Commands::Restart { .. } => "restart",
+            Commands::Restore { .. } => "restore",
+            Commands::Rm { .. } => "rm",
+            Commands::Run { .. } => "run",
+            Commands::Schema => "schema",
+            Commands::Start { .. } => "start",
+            Commands::Status { .. } => "status",
+            Commands::Stop { .. } => "stop",
+            Commands::Task { .. } => "task",
+            Commands::Top { .. } => "top",
+            Commands::Unpause { .. } => "unpause",
+            Commands::Up { .. } => "up",
+            Commands::Verify { .. } => "verify",
+            Commands::Watch { .. } => "watch",
+        }
+    }
+
+    pub fn run(
+        &self,
+        globals: &GlobalArguments,
+        selection: &[String],
+        runner: &Runner,
+    ) -> Result<()> {
+        self.run_named(globals, selection, &runner.named, runner)
+    }
+
+    /// Runs the command, `named` being the stacks in the selection that were
+    /// named rather than matched by a wildcard, pattern or default.
+    fn run_named(
+        &self,
+        globals: &GlobalArguments,
+        selection: &[String],
+        named: &[String],
+        runner: &Runner,
+    ) -> Result<()> {
+        let config = runner.config;
+
+        match self {
+            Commands::Attach { args } => {
+                let stacks = single_stack(globals, selection, config)?;
+                runner.run_attached("attach", &stacks, args, false)
+            }
+            Commands::Backup { dest, stop } => {
+                let stacks = single_stack(globals, selection, config)?;
+                let dir = runner.backup(stacks[0], dest, *stop)?;
+                println!("Backed up {} to {}", stacks[0].key, dir.display());
+                Ok(())
+            }
+            Commands::Build { ordered, args } => {
+                let (stacks, direction) = if *ordered {
+                    (
+                        config.stacks_with_dependencies(selection)?,
+                        Direction::Dependencies,
+                    )
+                } else {
+                    (config.stacks(selection)?, Direction::Independent)
+                };
+
+                if globals.parallel {
+                    runner.run_scheduled("build", &stacks, args, direction, globals.max_parallel)
+                } else {
+                    runner.run_against_stacks("build", &stacks, args)
+                }
+            }
+            Commands::Config {
+                rendered: false,
+                services: false,
+            } => {
+                print!("{}", serde_yaml::to_string(config)?);
+                Ok(())
+            }
+            Commands::Config { services, .. } => {
+                let stacks = config.stacks(selection)?;
+                runner.rendered_config(&stacks, *services, &mut io::stdout().lock())
             }
             Commands::Cp { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("cp", config, &stacks, args)
+                let stacks = single_stack(globals, selection, config)?;
+                runner.run_against_stacks("cp", &stacks, args)
             }
             Commands::Create { args } => {
-                let stacks = config.stacks_with_dependencies(globals.stacks())?;
-                run_against_stacks("create", config, &stacks, args)
+                let stacks = config.stacks_to_start(selection, named)?;
+                runner.run_against_stacks("create", &stacks, args)
+            }
+            Commands::Clean { all_unused } => {
+                let stacks = config.stacks(selection)?;
+                let candidates = runner.clean_candidates(&stacks, *all_unused)?;
+                print!("{}", clean::render(&candidates));
+                if !candidates.is_empty()
+                    && (globals.yes || confirm_image_removal(!globals.no_interactive)?)
+                {
+                    runner.clean(&stacks, &candidates)?;
+                }
+                Ok(())
+            }
+            Commands::Diff {
+                details,
+                show_secrets,
+            } => {
+                let stacks = config.stacks(selection)?;
+                print!("{}", runner.diff(&stacks, *details, *show_secrets)?);
+                Ok(())
+            }
+            Commands::Env { export, format } => {
+                let stacks = single_stack(globals, selection, config)?;
+                let format = if *export { EnvFormat::Shell } else { *format };
+                let environment = runner.stack_environment(stacks[0])?;
+                print!(
+                    "{}",
+                    render_environment(&environment, format).map_err(Error::Invalid)?
+                );
+                Ok(())
             }
             Commands::Down { args } => {
-                let stacks = reverse(config.stacks_with_dependants(globals.stacks())?);
-                run_against_stacks("down", config, &stacks, args)
+                let stacks = reverse(config.stacks_with_dependants(selection)?);
+                if !globals.yes && removes_volumes(args) {
+                    let implicit = implicit_stacks(selection, &stacks);
+                    if !implicit.is_empty() {
+                        confirm_volume_removal(&stacks, &implicit, !globals.no_interactive)?;
+                    }
+                }
+                if globals.parallel {
+                    runner.run_scheduled(
+                        "down",
+                        &stacks,
+                        args,
+                        Direction::Dependants,
+                        globals.max_parallel,
+                    )
+                } else {
+                    runner.run_against_stacks("down", &stacks, args)
+                }
             }
-            Commands::Events { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("events", config, &stacks, args)
+            Commands::Events { json, args } => {
+                let stacks = config.stacks(selection)?;
+                if !json && stacks.len() == 1 {
+                    return runner.run_in_mode(OutputMode::Inherit, "events", &stacks, args);
+                }
+
+                let args = if *json {
+                    merge(&["--json".to_owned()], args)
+                } else {
+                    args.clone()
+                };
+                runner.run_scheduled(
+                    "events",
+                    &stacks,
+                    &args,
+                    Direction::Independent,
+                    stacks.len(),
+                )
+            }
+            Commands::Exec { tty, args } => {
+                let stacks = single_stack(globals, selection, config)?;
+                runner.run_attached("exec", &stacks, args, *tty)
+            }
+            Commands::Foreach {
+                ordered,
+                keep_going,
+                command,
+            } => {
+                let mut stacks = config.stacks(selection)?;
+                let direction = if *ordered {
+                    let keys: HashSet<String> = stacks.iter().map(|s| s.key.clone()).collect();
+                    stacks = config
+                        .stacks_with_dependencies(selection)?
+                        .into_iter()
+                        .filter(|s| keys.contains(&s.key))
+                        .collect();
+                    Direction::Dependencies
+                } else {
+                    Direction::Independent
+                };
+
+                let parallel = if globals.parallel {
+                    Some((direction, globals.max_parallel))
+                } else if *keep_going {
+                    Some((direction, 1))
+                } else {
+                    None
+                };
+                runner.foreach(&stacks, command, parallel)
             }
-            Commands::Exec { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("exec", config, &stacks, args)
+            Commands::Freeze { check } => {
+                let stacks = config.stacks(selection)?;
+                runner.freeze(&stacks, *check)
             }
+            Commands::GenerateDocs { dir } => generate_docs(dir),
+            Commands::Graph { format } => {
+                let stacks = config.stacks_with_dependencies(selection)?;
+                runner.graph(&stacks, *format)
+            }
+            Commands::History { count } => runner.history(*count),
             Commands::Images { args } => {
-                let stacks = config.stacks(globals.stacks())?;
-                run_against_stacks("images", config, &stacks, args)
+                let stacks = config.stacks(selection)?;
+                runner.run_against_stacks("images", &stacks, args)
             }
             Commands::Kill { args } => {
-                let stacks = reverse(config.stacks_with_dependants(globals.stacks())?);
-                run_against_stacks("kill", config, &stacks, args)
+                let stacks = reverse(config.stacks_with_dependants(selection)?);
+                runner.run_against_stacks("kill", &stacks, args)
+            }
+            Commands::List { state, tree: false } => {
+                let stacks = config.stacks(selection)?;
+                print!("{}", runner.list(&stacks, *state)?);
+                Ok(())
+            }
+            Commands::List { state, tree: true } => {
+                let stacks = config.stacks_with_dependencies(selection)?;
+                print!("{}", runner.list_tree(&stacks, *state)?);
+                Ok(())
+            }
+            Commands::Logs {
+                since_last_up,
+                args,
+                ..
+            } => {
+                let stacks = config.stacks_with_dependencies(selection)?;
+                if *since_last_up {
+                    runner.logs_since_last_up(&stacks, args)
+                } else {
+                    runner.logs(&stacks, args)
+                }
             }
-            Commands::Logs { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("logs", config, &stacks, args)
+            Commands::Open { index, print } => {
+                let stacks = single_stack(globals, selection, config)?;
+                runner.open(stacks[0], *index, *print)
             }
             Commands::Pause { args } => {
-                let stacks = reverse(config.stacks_with_dependants(globals.stacks())?);
-                run_against_stacks("pause", config, &stacks, args)
+                let stacks = reverse(config.stacks_with_dependants(selection)?);
+                runner.run_against_stacks("pause", &stacks, args)
+            }
+            Commands::Port { args } if args.is_empty() => {
+                let stacks = config.stacks(selection)?;
+                print!("{}", runner.ports(&stacks, None, None)?);
+                Ok(())
             }
             Commands::Port { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("port", config, &stacks, args)
+                let stacks = single_stack(globals, selection, config)?;
+                runner.run_against_stacks("port", &stacks, args)
             }
-            Commands::Ps { args } => {
-                let stacks = config.stacks(globals.stacks())?;
-                run_against_stacks("ps", config, &stacks, args)
+            Commands::Ports { service, port } => {
+                let stacks = config.stacks(selection)?;
+                print!("{}", runner.ports(&stacks, service.as_deref(), *port)?);
+                Ok(())
             }
-            Commands::Pull { args } => {
-                let stacks = config.stacks(globals.stacks())?;
-                run_against_stacks("pull", config, &stacks, args)
+            Commands::Ps { watch, args } => {
+                let stacks = config.stacks(selection)?;
+                match watch {
+                    Some(interval) => runner.watch(*interval, || runner.ps(&stacks, args)),
+                    None => runner.run_against_stacks("ps", &stacks, args),
+                }
+            }
+            Commands::Pull { dedupe, args } => {
+                let stacks = config.stacks(selection)?;
+                if *dedupe {
+                    runner.pull_deduplicated(&stacks, args)
+                } else {
+                    runner.run_against_stacks("pull", &stacks, args)
+                }
             }
             Commands::Push { args } => {
-                let stacks = config.stacks(globals.stacks())?;
-                run_against_stacks("push", config, &stacks, args)
-            }
-            Commands::Restart { args } => {
-                let stacks = reverse(config.stacks_with_dependants(globals.stacks())?);
-                run_against_stacks("down", config, &stacks, args)?;
-                let stacks = reverse(stacks);
-                let mut up_stacks = config.stacks_with_dependencies(globals.stacks())?;
-                let first_keys: HashSet<String> = up_stacks.iter().map(|s| s.key.clone()).collect();
-                up_stacks.extend(stacks.into_iter().filter(|s| !first_keys.contains(&s.key)));
-                run_against_stacks("up", config, &up_stacks, &vec!["--wait".to_string()])
+                let stacks = config.stacks(selection)?;
+                runner.run_against_stacks("push", &stacks, args)
+            }
+            Commands::Restart { all, args } => {
+                let up_args = runner.supported_flags(&["--wait"]);
+                // Stacks that don't start automatically are left alone unless
+                // they are named.
+                let keys = config.startable_keys(selection, named)?;
+                if keys.is_empty() {
+                    return Ok(());
+                }
+                let (mut down_stacks, mut up_stacks) = if selection.is_empty() {
+                    let stacks = config.stacks_to_start(selection, named)?;
+                    (reverse(stacks.clone()), stacks)
+                } else {
+                    let down_stacks = reverse(config.stacks_with_dependants(&keys)?);
+                    let mut up_stacks = config.stacks_with_dependencies(&keys)?;
+                    let first_keys: HashSet<String> =
+                        up_stacks.iter().map(|s| s.key.clone()).collect();
+                    up_stacks.extend(
+                        down_stacks
+                            .iter()
+                            .rev()
+                            .filter(|s| !first_keys.contains(&s.key))
+                            .copied()
+                            .collect::<Vec<&Stack>>(),
+                    );
+                    (down_stacks, up_stacks)
+                };
+
+                // Don't start stacks that were deliberately left off.
+                if !all {
+                    let absent = runner.absent_stacks("restart", &up_stacks);
+                    down_stacks.retain(|s| !absent.contains(&s.key));
+                    up_stacks.retain(|s| !absent.contains(&s.key));
+                }
+
+                runner.run_against_stacks("down", &down_stacks, args)?;
+                runner.up_with_rollback(globals.rollback, || {
+                    runner.run_against_stacks("up", &up_stacks, &up_args)
+                })
+            }
+            Commands::Restore {
+                from,
+                volumes,
+                force_stop,
+                allow_mismatch,
+            } => {
+                let stacks = single_stack(globals, selection, config)?;
+                runner.restore(stacks[0], from, volumes, *force_stop, *allow_mismatch)
             }
             Commands::Rm { args } => {
-                let stacks = reverse(config.stacks_with_dependants(globals.stacks())?);
-                run_against_stacks("rm", config, &stacks, args)
+                let stacks = reverse(config.stacks_with_dependants(selection)?);
+                runner.run_against_stacks("rm", &stacks, args)
+            }
+            Commands::Run { tty, args } => {
+                let stacks = single_stack(globals, selection, config)?;
+                runner.run_attached("run", &stacks, args, *tty)
             }
-            Commands::Run { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("run", config, &stacks, args)
+            Commands::Schema => {
+                println!("{}", schema::render());
+                Ok(())
             }
             Commands::Start { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("start", config, &stacks, args)
+                let stacks = single_stack(globals, selection, config)?;
+                runner.run_against_stacks("start", &stacks, args)
+            }
+            Commands::Status { orphans, watch } => {
+                let stacks = config.stacks(selection)?;
+                let sample = || {
+                    let mut output = runner.status(&stacks)?;
+                    if *orphans {
+                        output.push('\n');
+                        output.push_str(&runner.orphans()?);
+                    }
+                    Ok(output)
+                };
+
+                match watch {
+                    Some(interval) => runner.watch(*interval, sample),
+                    None => sample().map(|output| print!("{}", output)),
+                }
             }
             Commands::Stop { args } => {
-                let stacks = config.stack(globals.stacks())?;
-                run_against_stacks("stop", config, &stacks, args)
+                let stacks = reverse(config.stacks_with_dependants(selection)?);
+                runner.run_against_stacks("stop", &stacks, args)
             }
+            Commands::Task { name, list } => match name {
+                Some(name) if !*list => run_task(name, globals, runner),
+                _ => {
+                    print!("{}", task::render_list(&config.tasks));
+                    Ok(())
+                }
+            },
             Commands::Top { args } => {
-                let stacks = config.stacks(globals.stacks())?;
-                run_against_stacks("top", config, &stacks, args)
+                let stacks = config.stacks(selection)?;
+                runner.run_against_stacks("top", &stacks, args)
             }
             Commands::Unpause { args } => {
-                let stacks = config.stacks_with_dependencies(globals.stacks())?;
-                run_against_stacks("unpause", config, &stacks, args)
+                let stacks = config.stacks_with_dependencies(selection)?;
+                runner.run_against_stacks("unpause", &stacks, args)
+            }
+            Commands::Up {
+                foreground: true,
+                abort_on_container_exit,
+                args,
+                ..
+            } => {
+                let stacks = if has_flag(args, &["--no-deps"]) {
+                    config.stacks(selection)?
+                } else {
+                    config.stacks_to_start(selection, named)?
+                };
+                if stacks.len() != 1 {
+                    return Err(Error::Invalid(format!(
+                        "`up --foreground` can only run a single stack but {} stacks are \
+                        selected ({}). Bring them up without `--foreground` and follow one with \
+                        `stack <stack> logs -f`, or pass `--no-deps` to leave out dependencies.",
+                        stacks.len(),
+                        stacks
+                            .iter()
+                            .map(|stack| stack.key.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )));
+                }
+
+                let args = foreground_up_args(args, *abort_on_container_exit)?;
+                runner.run_in_mode(OutputMode::Inherit, "up", &stacks, &args)
+            }
+            Commands::Up {
+                only_missing, args, ..
+            } => {
+                let args = merge(&runner.supported_flags(&["--wait"]), args);
+                let mut stacks = config.stacks_to_start(selection, named)?;
+                if *only_missing {
+                    stacks = runner.missing_stacks("up", &stacks);
+                }
+                runner.up_with_rollback(globals.rollback, || {
+                    if globals.parallel {
+                        runner.run_scheduled(
+                            "up",
+                            &stacks,
+                            &args,
+                            Direction::Dependencies,
+                            globals.max_parallel,
+                        )
+                    } else {
+                        runner.run_against_stacks("up", &stacks, &args)
+                    }
+                })
             }
-            Commands::Up { args } => {
-                let mut args = args.clone();
-                args.insert(0, "--wait".to_string());
-                let stacks = config.stacks_with_dependencies(globals.stacks())?;
-                run_against_stacks("up", config, &stacks, &args)
+            Commands::Verify { exit_code } => {
+                let stacks = config.stacks(selection)?;
+                let verdicts = runner.verify(&stacks)?;
+                print!(
+                    "{}",
+                    verify::render(&verdicts, log::log_enabled!(log::Level::Debug))
+                );
+
+                let drifted: Vec<String> = verdicts
+                    .into_iter()
+                    .filter(|(_, verdict)| matches!(verdict, Verdict::Drifted(_)))
+                    .map(|(stack, _)| stack)
+                    .collect();
+                if *exit_code && !drifted.is_empty() {
+                    return Err(Error::Drifted { stacks: drifted });
+                }
+                Ok(())
+            }
+            Commands::Watch { args } => {
+                let stacks = single_stack(globals, selection, config)?;
+                runner.run_foreground("watch", &stacks, args)
             }
         }
     }
@@ -289,21 +1335,1381 @@ impl Commands {
 
 #[cfg(test)]
 mod tests {
-    use super::Program;
-    use clap::Parser;
+    use super::{Commands, Program};
+    use clap::ColorChoice;
+    use clap::{CommandFactory, Parser};
+    use stacks::commands::Runner;
+    use stacks::config::Config;
+    use stacks::engine::Engine;
+    use stacks::exec::OutputMode;
+    use stacks::output::Passthrough;
+    use stacks::selection;
+    use stacks::testing::{RecordingExecutor, RecordingOpener};
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn selected(program: &Program) -> Vec<String> {
+        selection::resolve(program.globals.stacks.as_deref(), None, &[])
+    }
+
+    #[test]
+    fn select_regex() {
+        let program = Program::parse_from([
+            "stack",
+            "--select-regex",
+            "^team-a-",
+            "--select-regex",
+            "api$",
+            "db",
+            "pull",
+        ]);
+        let patterns: Vec<&str> = program
+            .globals
+            .select_regex
+            .iter()
+            .map(|regex| regex.as_str())
+            .collect();
+        assert_eq!(patterns, vec!["^team-a-", "api$"]);
+        assert_eq!(selected(&program), vec!["db".to_string()]);
+
+        // Invalid patterns are rejected while parsing.
+        let error = Program::try_parse_from(["stack", "--select-regex", "team-(a", "pull"])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unclosed group"), "{}", error);
+    }
+
+    #[test]
+    fn explicit_selection() {
+        // Default selections aren't remembered so don't overwrite `@last`.
+        assert!(!Program::parse_from(["stack", "up"]).globals.explicit());
+        assert!(Program::parse_from(["stack", "db", "up"])
+            .globals
+            .explicit());
+        assert!(Program::parse_from(["stack", "*", "up"]).globals.explicit());
+        assert!(
+            Program::parse_from(["stack", "--select-regex", "^team-a-", "up"])
+                .globals
+                .explicit()
+        );
+    }
 
     #[test]
     fn stacks() {
         let program = Program::parse_from(["stack", "up"]);
         assert_eq!(program.file, None);
-        assert_eq!(program.globals.stacks(), Vec::<&str>::new());
+        assert_eq!(selected(&program), Vec::<String>::new());
 
         let program = Program::parse_from(["stack", "-f", "foo", "*", "down"]);
         assert_eq!(program.file, Some("foo".to_string()));
-        assert_eq!(program.globals.stacks(), Vec::<&str>::new());
+        assert_eq!(selected(&program), Vec::<String>::new());
 
         let program = Program::parse_from(["stack", "bar", "up"]);
         assert_eq!(program.file, None);
-        assert_eq!(program.globals.stacks(), vec!["bar"]);
+        assert_eq!(selected(&program), vec!["bar".to_string()]);
+    }
+
+    #[test]
+    fn watch() {
+        let watch = |args: &[&str]| match Program::parse_from(args).command {
+            Commands::Ps { watch, args } => (watch, args),
+            Commands::Status { watch, .. } => (watch, Vec::new()),
+            command => panic!("Unexpected {:?}", command),
+        };
+
+        assert_eq!(watch(&["stack", "ps"]), (None, vec![]));
+        assert_eq!(
+            watch(&["stack", "status", "--watch"]),
+            (Some(Duration::from_secs(2)), vec![])
+        );
+        assert_eq!(
+            watch(&["stack", "status", "--watch=1m"]),
+            (Some(Duration::from_secs(60)), vec![])
+        );
+
+        // Anything after the flag is passed to compose.
+        assert_eq!(
+            watch(&["stack", "ps", "--watch", "web"]),
+            (Some(Duration::from_secs(2)), vec!["web".to_string()])
+        );
+        assert_eq!(
+            watch(&["stack", "ps", "--watch=5s", "-a"]),
+            (Some(Duration::from_secs(5)), vec!["-a".to_string()])
+        );
+
+        assert!(Program::try_parse_from(["stack", "status", "--watch=soon"]).is_err());
+    }
+
+    #[test]
+    fn logs_grep() {
+        let logs = |args: &[&str]| match Program::parse_from(args).command {
+            Commands::Logs {
+                grep,
+                invert_grep,
+                args,
+                ..
+            } => (grep, invert_grep, args),
+            command => panic!("Unexpected {:?}", command),
+        };
+        let strings =
+            |list: &[&str]| -> Vec<String> { list.iter().map(|s| s.to_string()).collect() };
+
+        assert_eq!(
+            logs(&[
+                "stack",
+                "logs",
+                "--grep",
+                "req-1",
+                "--grep=req-2",
+                "-f",
+                "--grep"
+            ]),
+            (
+                strings(&["req-1", "req-2"]),
+                false,
+                strings(&["-f", "--grep"])
+            )
+        );
+        assert_eq!(
+            logs(&["stack", "logs", "--invert-grep", "--grep", "health"]),
+            (strings(&["health"]), true, vec![])
+        );
+        assert!(Program::try_parse_from(["stack", "logs", "--invert-grep"]).is_err());
+    }
+
+    fn config() -> Config {
+        Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                cache: {}
+                api:
+                    depends_on:
+                        - db
+                        - cache
+                web:
+                    depends_on:
+                        - api
+                other: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    /// Runs stacks with the arguments and returns the commands it ran.
+    fn run(executor: RecordingExecutor, args: &[&str]) -> Vec<(String, String)> {
+        run_config(config(), executor, args)
+    }
+
+    /// Runs stacks with a different config.
+    fn run_config(
+        config: Config,
+        executor: RecordingExecutor,
+        args: &[&str],
+    ) -> Vec<(String, String)> {
+        run_with(config, &executor, args);
+        executor.commands()
+    }
+
+    /// Runs stacks with an executor that can still be inspected afterwards.
+    fn run_with(config: Config, executor: &RecordingExecutor, args: &[&str]) {
+        let program = Program::parse_from(args);
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = executor;
+        runner.preflight = false;
+        runner.terminal = false;
+        runner.from = program.globals.from.clone();
+        runner.until = program.globals.until.clone();
+        runner.named = selected(&program);
+
+        let _ = program
+            .command
+            .run(&program.globals, &selected(&program), &runner);
+    }
+
+    fn commands(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(stack, command)| (stack.to_string(), command.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn up_order() {
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "web", "up"]),
+            commands(&[("cache", "up"), ("db", "up"), ("api", "up"), ("web", "up")])
+        );
+
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "db", "up"]),
+            commands(&[("db", "up")])
+        );
+
+        // A failure stops the stacks that depend on it.
+        assert_eq!(
+            run(
+                RecordingExecutor::default().fail("db", "up"),
+                &["stack", "web", "up"]
+            ),
+            commands(&[("cache", "up"), ("db", "up")])
+        );
+    }
+
+    #[test]
+    fn autostart() {
+        let config = || {
+            Config::from_reader(
+                &PathBuf::from("/stacks"),
+                "
+                stacks:
+                    db: {}
+                    proxy:
+                        autostart: false
+                    web:
+                        depends_on:
+                            - db
+                    admin:
+                        autostart: false
+                        depends_on:
+                            - db
+                "
+                .as_bytes(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            run_config(config(), RecordingExecutor::default(), &["stack", "up"]),
+            commands(&[("db", "up"), ("web", "up")])
+        );
+        assert_eq!(
+            run_config(
+                config(),
+                RecordingExecutor::default(),
+                &["stack", "*", "create"]
+            ),
+            commands(&[("db", "create"), ("web", "create")])
+        );
+        assert_eq!(
+            run_config(
+                config(),
+                RecordingExecutor::default(),
+                &["stack", "admin,proxy", "up"]
+            ),
+            commands(&[("db", "up"), ("proxy", "up"), ("admin", "up")])
+        );
+
+        let running =
+            || RecordingExecutor::default().respond("ps", r#"{"Service":"svc","State":"running"}"#);
+        assert_eq!(
+            run_config(config(), running(), &["stack", "restart"]),
+            commands(&[("web", "down"), ("db", "down"), ("db", "up"), ("web", "up"),])
+        );
+        assert_eq!(
+            run_config(config(), running(), &["stack", "db", "restart"]),
+            commands(&[
+                ("web", "down"),
+                ("admin", "down"),
+                ("db", "down"),
+                ("db", "up"),
+                ("admin", "up"),
+                ("web", "up"),
+            ])
+        );
+
+        // Stacks matched by a pattern or selected by default only start when
+        // named.
+        let up = |config: Config, args: &[&str]| {
+            let program = Program::parse_from(args);
+            let cwd = PathBuf::from("/stacks");
+            let executor = RecordingExecutor::default();
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+            runner.named = program.globals.named(&config, &cwd).unwrap();
+
+            let selection = program.globals.selection(&config, &cwd).unwrap();
+            program
+                .command
+                .run(&program.globals, &selection, &runner)
+                .unwrap();
+            executor.commands()
+        };
+        assert_eq!(
+            up(
+                config(),
+                &["stack", "--select-regex", "^(admin|web)$", "up"]
+            ),
+            commands(&[("db", "up"), ("web", "up")])
+        );
+        assert_eq!(
+            up(
+                config(),
+                &["stack", "--select-regex", "^web$", "admin", "create"]
+            ),
+            commands(&[("db", "create"), ("admin", "create"), ("web", "create")])
+        );
+        let mut defaults = config();
+        defaults.default_stacks = vec!["admin".to_string(), "web".to_string()];
+        assert_eq!(
+            up(defaults, &["stack", "up"]),
+            commands(&[("db", "up"), ("web", "up")])
+        );
+        assert_eq!(
+            up(config(), &["stack", "--select-regex", "^admin$", "restart"]),
+            commands(&[])
+        );
+
+        // Other commands ignore it.
+        assert_eq!(
+            run_config(config(), RecordingExecutor::default(), &["stack", "down"]),
+            commands(&[
+                ("web", "down"),
+                ("admin", "down"),
+                ("proxy", "down"),
+                ("db", "down"),
+            ])
+        );
+    }
+
+    #[test]
+    fn options() {
+        let config = config();
+        let executor = RecordingExecutor::default();
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        let stacks = config.stacks(["api"]).unwrap();
+        runner
+            .run_against_stacks("logs", &stacks, &["-f".to_string()])
+            .unwrap();
+
+        let runs = executor.runs();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(
+            runs[0].command_line(),
+            "docker compose -p api --project-directory /stacks/api logs -f"
+        );
+        assert_eq!(runs[0].working_dir, PathBuf::from("/stacks/api"));
+    }
+
+    #[test]
+    fn remove_orphans() {
+        let run = |yaml: &str, args: &[&str]| {
+            let mut config =
+                Config::from_reader(&PathBuf::from("/stacks"), yaml.as_bytes()).unwrap();
+            let program = Program::parse_from(args);
+            config.keep_orphans = program.globals.no_remove_orphans;
+            let executor = RecordingExecutor::default();
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+            runner.terminal = false;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .unwrap();
+            executor
+                .runs()
+                .iter()
+                .map(|run| {
+                    let line = run.command_line();
+                    let start = line.find(&format!(" {}", run.command)).unwrap() + 1;
+                    line[start..].to_string()
+                })
+                .collect::<Vec<String>>()
+        };
+
+        let unset = "stacks:\n  web: {}";
+        let global = "remove_orphans: true\nstacks:\n  web: {}";
+        let stack = "stacks:\n  web:\n    remove_orphans: true";
+        let overridden = "remove_orphans: true\nstacks:\n  web:\n    remove_orphans: false";
+
+        for (yaml, injected) in [
+            (unset, false),
+            (global, true),
+            (stack, true),
+            (overridden, false),
+        ] {
+            let expected = if injected {
+                "down --remove-orphans"
+            } else {
+                "down"
+            };
+            assert_eq!(run(yaml, &["stack", "down"]), vec![expected]);
+            assert_eq!(
+                run(yaml, &["stack", "restart", "--all"]),
+                vec![expected, "up --wait"]
+            );
+
+            // The flag turns it off for the run.
+            assert_eq!(
+                run(yaml, &["stack", "--no-remove-orphans", "down"]),
+                vec!["down"]
+            );
+            assert_eq!(
+                run(yaml, &["stack", "--no-remove-orphans", "restart", "--all"]),
+                vec!["down", "up --wait"]
+            );
+
+            // It is never given twice.
+            assert_eq!(
+                run(yaml, &["stack", "down", "--remove-orphans", "-v"]),
+                vec!["down --remove-orphans -v"]
+            );
+            assert_eq!(
+                run(
+                    yaml,
+                    &["stack", "--no-remove-orphans", "down", "--remove-orphans"]
+                ),
+                vec!["down --remove-orphans"]
+            );
+            assert_eq!(
+                run(yaml, &["stack", "restart", "--all", "--remove-orphans"]),
+                vec!["down --remove-orphans", "up --wait"]
+            );
+        }
+    }
+
+    #[test]
+    fn down_order() {
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "db", "down"]),
+            commands(&[("web", "down"), ("api", "down"), ("db", "down")])
+        );
+
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "web", "down"]),
+            commands(&[("web", "down")])
+        );
+    }
+
+    #[test]
+    fn stop_order() {
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "db", "stop"]),
+            commands(&[("web", "stop"), ("api", "stop"), ("db", "stop")])
+        );
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "web", "stop"]),
+            commands(&[("web", "stop")])
+        );
+    }
+
+    #[test]
+    fn ranged_order() {
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "--from", "db", "web", "up"]
+            ),
+            commands(&[("db", "up"), ("api", "up"), ("web", "up")])
+        );
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "--until", "api", "web", "up"]
+            ),
+            commands(&[("cache", "up"), ("db", "up"), ("api", "up")])
+        );
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "--from", "db", "--until", "api", "web", "up"]
+            ),
+            commands(&[("db", "up"), ("api", "up")])
+        );
+
+        // The range follows the order that the command runs in.
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "--from", "api", "db", "down"]
+            ),
+            commands(&[("api", "down"), ("db", "down")])
+        );
+
+        // Stacks that wouldn't run can't be named.
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "--from", "other", "web", "up"]
+            ),
+            commands(&[])
+        );
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "--from", "web", "--until", "db", "web", "up"]
+            ),
+            commands(&[])
+        );
+    }
+
+    #[test]
+    fn restart_order() {
+        let running =
+            || RecordingExecutor::default().respond("ps", r#"{"Service":"svc","State":"running"}"#);
+
+        // Dependants are taken down first then everything is brought back up
+        // in dependency order.
+        assert_eq!(
+            run(running(), &["stack", "api", "restart"]),
+            commands(&[
+                ("web", "down"),
+                ("api", "down"),
+                ("cache", "up"),
+                ("db", "up"),
+                ("api", "up"),
+                ("web", "up"),
+            ])
+        );
+
+        // Stacks without containers are left alone.
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "api", "restart"]),
+            Vec::new()
+        );
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "api", "restart", "--all"]
+            ),
+            commands(&[
+                ("web", "down"),
+                ("api", "down"),
+                ("cache", "up"),
+                ("db", "up"),
+                ("api", "up"),
+                ("web", "up"),
+            ])
+        );
+
+        // Nothing is brought up if taking down fails.
+        assert_eq!(
+            run(running().fail("api", "down"), &["stack", "api", "restart"]),
+            commands(&[("web", "down"), ("api", "down")])
+        );
+    }
+
+    #[test]
+    fn build_order() {
+        assert_eq!(
+            run(RecordingExecutor::default(), &["stack", "web", "build"]),
+            commands(&[("web", "build")])
+        );
+        assert_eq!(
+            run(
+                RecordingExecutor::default(),
+                &["stack", "web", "build", "--ordered", "--pull"]
+            ),
+            commands(&[
+                ("cache", "build"),
+                ("db", "build"),
+                ("api", "build"),
+                ("web", "build")
+            ])
+        );
+
+        // A failed build stops the stacks that build on it.
+        assert_eq!(
+            run(
+                RecordingExecutor::default().fail("db", "build"),
+                &["stack", "web", "build", "--ordered"]
+            ),
+            commands(&[("cache", "build"), ("db", "build")])
+        );
+    }
+
+    #[test]
+    fn parallel_build_order() {
+        // Independent stacks build at the same time but dependants wait for
+        // everything they depend on to finish.
+        let executor = RecordingExecutor::default().overlap(2);
+        run_with(
+            config(),
+            &executor,
+            &["stack", "--parallel", "web", "build", "--ordered"],
+        );
+        let ordered = executor.commands();
+        let mut first: Vec<&str> = ordered[..2].iter().map(|(s, _)| s.as_str()).collect();
+        first.sort();
+        assert_eq!(first, vec!["cache", "db"]);
+        assert_eq!(
+            ordered[2..],
+            commands(&[("api", "build"), ("web", "build")])
+        );
+        let mut concurrency = executor.concurrency();
+        concurrency[..2].sort();
+        assert_eq!(concurrency, vec![1, 2, 1, 1]);
+
+        // Without ordering nothing waits.
+        let executor = RecordingExecutor::default().overlap(3);
+        run_with(
+            config(),
+            &executor,
+            &["stack", "--parallel", "web,api,db", "build"],
+        );
+        assert_eq!(executor.commands().len(), 3);
+        assert_eq!(executor.concurrency().into_iter().max(), Some(3));
+
+        // Failures skip dependants but not independent stacks.
+        assert_eq!(
+            run(
+                RecordingExecutor::default().fail("db", "build"),
+                &[
+                    "stack",
+                    "--parallel",
+                    "--max-parallel=1",
+                    "web,other",
+                    "build",
+                    "--ordered"
+                ]
+            ),
+            commands(&[("cache", "build"), ("db", "build"), ("other", "build")])
+        );
+    }
+
+    #[test]
+    fn foreach_order() {
+        let stacks = |args: &[&str]| -> Vec<String> {
+            run(RecordingExecutor::default(), args)
+                .into_iter()
+                .map(|(stack, _)| stack)
+                .collect()
+        };
+
+        assert_eq!(
+            stacks(&["stack", "web,api,db", "foreach", "--", "ls"]),
+            vec!["api", "db", "web"]
+        );
+        assert_eq!(
+            stacks(&["stack", "web,api,db", "foreach", "--ordered", "--", "ls"]),
+            vec!["db", "api", "web"]
+        );
+    }
+
+    #[test]
+    fn tty() {
+        let config = config();
+        let argv = |args: &[&str], terminal: bool| {
+            let executor = RecordingExecutor::default();
+            let program = Program::parse_from(args);
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+            runner.terminal = terminal;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .unwrap();
+            let runs = executor.runs();
+            assert_eq!(runs.len(), 1);
+            (
+                runs[0].output_mode,
+                runs[0]
+                    .args
+                    .iter()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect::<Vec<String>>(),
+            )
+        };
+
+        // Without a terminal exec and run don't try to allocate a TTY.
+        assert_eq!(
+            argv(&["stack", "web", "exec", "web", "sh"], false),
+            (
+                OutputMode::Inherit,
+                vec!["-T".into(), "web".into(), "sh".into()]
+            )
+        );
+        assert_eq!(
+            argv(&["stack", "web", "run", "--rm", "web"], false),
+            (
+                OutputMode::Inherit,
+                vec!["-T".into(), "--rm".into(), "web".into()]
+            )
+        );
+        assert_eq!(
+            argv(&["stack", "web", "exec", "web", "sh"], true),
+            (OutputMode::Inherit, vec!["web".into(), "sh".into()])
+        );
+
+        // Unless already given.
+        assert_eq!(
+            argv(&["stack", "web", "exec", "-T", "web", "sh"], false),
+            (
+                OutputMode::Inherit,
+                vec!["-T".into(), "web".into(), "sh".into()]
+            )
+        );
+        assert_eq!(
+            argv(&["stack", "web", "exec", "--no-TTY", "web", "sh"], false),
+            (
+                OutputMode::Inherit,
+                vec!["--no-TTY".into(), "web".into(), "sh".into()]
+            )
+        );
+        assert_eq!(
+            argv(&["stack", "web", "run", "--rm", "-T", "web"], false).1,
+            vec!["--rm", "-T", "web"]
+        );
+        assert_eq!(
+            argv(
+                &["stack", "web", "run", "-e", "A=1", "--no-TTY", "web"],
+                false
+            )
+            .1,
+            vec!["-e", "A=1", "--no-TTY", "web"]
+        );
+
+        // Flags for the container's command are not compose's.
+        assert_eq!(
+            argv(&["stack", "web", "exec", "postgres", "psql", "-T"], false).1,
+            vec!["-T", "postgres", "psql", "-T"]
+        );
+        assert_eq!(
+            argv(
+                &["stack", "web", "run", "-u", "root", "web", "ls", "--no-TTY"],
+                false
+            )
+            .1,
+            vec!["-T", "-u", "root", "web", "ls", "--no-TTY"]
+        );
+
+        // A TTY can be asked for regardless.
+        assert_eq!(
+            argv(&["stack", "web", "exec", "--tty", "web", "sh"], false).1,
+            vec!["web", "sh"]
+        );
+        assert_eq!(
+            argv(&["stack", "web", "run", "--tty", "--rm", "web"], false).1,
+            vec!["--rm", "web"]
+        );
+        assert_eq!(
+            argv(&["stack", "web", "run", "--rm", "web", "--tty"], false).1,
+            vec!["-T", "--rm", "web", "--tty"]
+        );
+
+        // Compose's own `--interactive` is passed through.
+        assert_eq!(
+            argv(
+                &["stack", "web", "exec", "--interactive", "web", "sh"],
+                false
+            )
+            .1,
+            vec!["-T", "--interactive", "web", "sh"]
+        );
+        assert_eq!(
+            argv(&["stack", "web", "run", "-i", "--rm", "web"], true).1,
+            vec!["-i", "--rm", "web"]
+        );
+
+        // Other commands are piped.
+        assert_eq!(
+            argv(&["stack", "web", "events"], false),
+            (OutputMode::Inherit, vec![])
+        );
+        assert_eq!(
+            argv(
+                &["stack", "web", "attach", "--detach-keys", "ctrl-x", "app"],
+                false
+            ),
+            (
+                OutputMode::Inherit,
+                vec!["--detach-keys".into(), "ctrl-x".into(), "app".into()]
+            )
+        );
+        assert_eq!(
+            argv(&["stack", "web", "watch", "--no-up", "app"], false),
+            (OutputMode::Inherit, vec!["--no-up".into(), "app".into()])
+        );
+        assert_eq!(
+            argv(&["stack", "other", "logs", "-f"], false),
+            (OutputMode::Piped, vec!["-f".into()])
+        );
+    }
+
+    #[test]
+    fn events_command() {
+        let config = config();
+        let events = |args: &[&str]| {
+            let executor = RecordingExecutor::default();
+            let program = Program::parse_from(args);
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .unwrap();
+            let mut runs: Vec<(String, OutputMode, String)> = executor
+                .runs()
+                .iter()
+                .map(|run| {
+                    let args: Vec<String> = run
+                        .args
+                        .iter()
+                        .map(|arg| arg.to_string_lossy().into_owned())
+                        .collect();
+                    (
+                        run.environment["COMPOSE_PROJECT_NAME"]
+                            .to_string_lossy()
+                            .into_owned(),
+                        run.output_mode,
+                        args.join(" "),
+                    )
+                })
+                .collect();
+            // The stacks run at the same time.
+            runs.sort_by(|a, b| a.0.cmp(&b.0));
+            runs
+        };
+
+        assert_eq!(
+            events(&["stack", "web", "events"]),
+            vec![("web".to_string(), OutputMode::Inherit, String::new())]
+        );
+        assert_eq!(
+            events(&["stack", "web,db", "events", "--since", "1h"]),
+            vec![
+                (
+                    "db".to_string(),
+                    OutputMode::Piped,
+                    "--since 1h".to_string()
+                ),
+                (
+                    "web".to_string(),
+                    OutputMode::Piped,
+                    "--since 1h".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            events(&["stack", "web", "events", "--json"]),
+            vec![("web".to_string(), OutputMode::Piped, "--json".to_string())]
+        );
+    }
+
+    #[test]
+    fn logs_since_last_up() {
+        let config = config();
+        let logs = |args: &[&str]| {
+            let executor = RecordingExecutor::default();
+            let program = Program::parse_from(args);
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .map(|_| {
+                    executor
+                        .runs()
+                        .iter()
+                        .map(|run| {
+                            run.args
+                                .iter()
+                                .map(|arg| arg.to_string_lossy().into_owned())
+                                .collect::<Vec<_>>()
+                                .join(" ")
+                        })
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        // Without a state file nothing has a recorded time.
+        assert_eq!(
+            logs(&["stack", "web", "logs", "--since-last-up", "--tail", "5"]).unwrap(),
+            vec!["--tail 5".to_string(); 4]
+        );
+        assert!(logs(&["stack", "web", "logs", "--since-last-up", "--since", "1h"]).is_err());
+        assert!(logs(&["stack", "web", "logs", "--since-last-up", "--since=1h"]).is_err());
+        assert_eq!(
+            logs(&["stack", "web", "logs", "--since", "1h"]).unwrap(),
+            vec!["--since 1h".to_string(); 4]
+        );
+    }
+
+    #[test]
+    fn logs_selection() {
+        let logs = |args: &[&str]| {
+            let mut stacks: Vec<String> = run(RecordingExecutor::default(), args)
+                .into_iter()
+                .map(|(stack, _)| stack)
+                .collect();
+            stacks.sort();
+            stacks
+        };
+
+        // The logs of the stacks that the selection depends on are shown too.
+        assert_eq!(
+            logs(&["stack", "web", "logs", "-f"]),
+            vec!["api", "cache", "db", "web"]
+        );
+        assert_eq!(logs(&["stack", "db,other", "logs"]), vec!["db", "other"]);
+        assert_eq!(logs(&["stack", "logs"]).len(), 5);
+    }
+
+    #[test]
+    fn watch_command() {
+        let config = config();
+        let watch = |executor: RecordingExecutor, args: &[&str]| {
+            let program = Program::parse_from(args);
+            assert_eq!(program.command.name(), "watch");
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>(
+                executor
+                    .runs()
+                    .iter()
+                    .map(|run| {
+                        let args: Vec<String> = run
+                            .args
+                            .iter()
+                            .map(|arg| arg.to_string_lossy().into_owned())
+                            .collect();
+                        (run.output_mode, args.join(" "))
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        // Only the stack itself is watched, with nothing added.
+        assert_eq!(
+            watch(RecordingExecutor::default(), &["stack", "web", "watch"]).unwrap(),
+            vec![(OutputMode::Inherit, String::new())]
+        );
+        assert_eq!(
+            watch(
+                RecordingExecutor::default().respond("version", "2.24.5\n"),
+                &["stack", "web", "watch", "--no-up", "app"]
+            )
+            .unwrap(),
+            vec![(OutputMode::Inherit, "--no-up app".to_string())]
+        );
+        assert!(watch(RecordingExecutor::default(), &["stack", "web,db", "watch"]).is_err());
+
+        // Compose must be new enough to have watch.
+        assert_eq!(
+            watch(
+                RecordingExecutor::default().respond("version", "v2.21.0\n"),
+                &["stack", "web", "watch"]
+            )
+            .unwrap_err(),
+            "`watch` needs docker compose 2.22.0 or later but 2.21.0 is installed."
+        );
+    }
+
+    #[test]
+    fn generate_docs() {
+        let temp = tempfile::tempdir().unwrap();
+        let program = Program::parse_from(["stack", "generate-docs", "docs"]);
+        assert!(matches!(program.command, Commands::GenerateDocs { .. }));
+
+        super::generate_docs(temp.path()).unwrap();
+        let index = std::fs::read_to_string(temp.path().join("index.md")).unwrap();
+
+        let command = Program::command();
+        for subcommand in command.get_subcommands() {
+            let link = format!("]({}.md)", subcommand.get_name());
+            if subcommand.is_hide_set() {
+                assert!(!index.contains(&link));
+                assert!(!temp
+                    .path()
+                    .join(format!("{}.md", subcommand.get_name()))
+                    .exists());
+            } else {
+                assert!(
+                    index.contains(&link),
+                    "{} isn't linked",
+                    subcommand.get_name()
+                );
+                assert!(temp
+                    .path()
+                    .join(format!("{}.md", subcommand.get_name()))
+                    .is_file());
+            }
+        }
+        assert!(index.contains("## Global options"));
+        assert!(index.contains("| `--parallel` |"));
+
+        let up = std::fs::read_to_string(temp.path().join("up.md")).unwrap();
+        assert!(up.starts_with("# stacks up\n"));
+        assert!(up.contains("`--wait` is passed to docker compose"));
+        assert!(up.contains("| `--only-missing` |"));
+        // Global options are only on the index.
+        assert!(!up.contains("| `--parallel` |"));
+    }
+
+    #[test]
+    fn verify_exit_code() {
+        let config = config();
+        let verify = |args: &[&str]| {
+            let executor = RecordingExecutor::default()
+                .respond("ps", "0123abcd\n")
+                .respond("config", "services:\n  postgres:\n    image: postgres\n")
+                .respond(
+                    "inspect",
+                    r#"[{"Name":"/db-redis-1","Config":{"Labels":{
+                        "com.docker.compose.service":"redis"
+                    }}}]"#,
+                );
+            let program = Program::parse_from(args);
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .map_err(|e| e.to_string())
+        };
+
+        // Drift is only a failure when asked for.
+        assert!(verify(&["stack", "db", "verify"]).is_ok());
+        assert_eq!(
+            verify(&["stack", "db", "verify", "--exit-code"]).unwrap_err(),
+            "The running containers don't match the config of db. Run `stack verify --verbose` \
+            to see how."
+        );
+    }
+
+    #[test]
+    fn open() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            environment:
+                HOST: localhost
+            stacks:
+                web:
+                    url: ['http://${HOST}:8080', 'http://${HOST}:8080/admin']
+                db: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let open = |args: &[&str]| {
+            let opener = RecordingOpener::default();
+            let program = Program::try_parse_from(args).map_err(|e| e.to_string())?;
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.opener = &opener;
+            runner.preflight = false;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>(opener.opened())
+        };
+
+        assert_eq!(
+            open(&["stack", "web", "open"]).unwrap(),
+            vec!["http://localhost:8080"]
+        );
+        assert_eq!(
+            open(&["stack", "web", "open", "2"]).unwrap(),
+            vec!["http://localhost:8080/admin"]
+        );
+        // Printing doesn't open anything.
+        assert!(open(&["stack", "web", "open", "--print"])
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            open(&["stack", "db", "open"]).unwrap_err(),
+            "Stack `db` has no `url`, add one to its entry in the stacks config to open it."
+        );
+        assert!(open(&["stack", "web,db", "open"]).is_err());
+    }
+
+    #[test]
+    fn up_foreground() {
+        let config = config();
+        let up = |args: &[&str]| {
+            let executor = RecordingExecutor::default();
+            let program = Program::try_parse_from(args).map_err(|e| e.to_string())?;
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = &executor;
+            runner.preflight = false;
+
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>(
+                executor
+                    .runs()
+                    .iter()
+                    .map(|run| {
+                        let args: Vec<String> = run
+                            .args
+                            .iter()
+                            .map(|arg| arg.to_string_lossy().into_owned())
+                            .collect();
+                        (run.output_mode, args.join(" "))
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        // In the foreground there is no `--wait` and compose has the terminal.
+        assert_eq!(
+            up(&["stack", "db", "up", "--foreground"]).unwrap(),
+            vec![(OutputMode::Inherit, String::new())]
+        );
+        assert_eq!(
+            up(&[
+                "stack",
+                "db",
+                "up",
+                "--foreground",
+                "--abort-on-container-exit",
+                "--build"
+            ])
+            .unwrap(),
+            vec![(
+                OutputMode::Inherit,
+                "--abort-on-container-exit --build".to_string()
+            )]
+        );
+        assert_eq!(
+            up(&["stack", "db", "up", "--foreground", "--build"]).unwrap(),
+            vec![(OutputMode::Inherit, "--build".to_string())]
+        );
+
+        // Dependencies count towards the single stack unless left out.
+        let error = up(&["stack", "api", "up", "--foreground"]).unwrap_err();
+        assert!(
+            error.starts_with(
+                "`up --foreground` can only run a single stack but 3 stacks are \
+                selected (cache, db, api)."
+            ),
+            "{}",
+            error
+        );
+        assert!(error.contains("`stack <stack> logs -f`"), "{}", error);
+        assert_eq!(
+            up(&["stack", "api", "up", "--foreground", "--no-deps"]).unwrap(),
+            vec![(OutputMode::Inherit, "--no-deps".to_string())]
+        );
+        assert!(
+            up(&["stack", "db,cache", "up", "--foreground", "--no-deps"])
+                .unwrap_err()
+                .contains("2 stacks are selected")
+        );
+
+        // Detaching contradicts staying in the foreground.
+        assert_eq!(
+            up(&["stack", "db", "up", "--foreground", "-d"]).unwrap_err(),
+            "`-d` can't be used with `up --foreground` which stays in the foreground."
+        );
+        assert!(up(&["stack", "db", "up", "--foreground", "--wait"])
+            .unwrap_err()
+            .contains("`--wait`"));
+
+        // The flags only make sense together.
+        assert!(up(&["stack", "db", "up", "--abort-on-container-exit"]).is_err());
+        assert!(up(&["stack", "db", "up", "--foreground", "--only-missing"]).is_err());
+
+        // Compose's own `--attach <service>` is passed through.
+        assert_eq!(
+            up(&["stack", "db", "up", "--attach", "app"]).unwrap(),
+            vec![(OutputMode::Piped, "--wait --attach app".to_string())]
+        );
+        assert_eq!(
+            up(&["stack", "db", "up", "--foreground", "--attach", "app"]).unwrap(),
+            vec![(OutputMode::Inherit, "--attach app".to_string())]
+        );
+
+        // Without `--foreground` nothing changes.
+        assert_eq!(
+            up(&["stack", "db", "up"]).unwrap(),
+            vec![(OutputMode::Piped, "--wait".to_string())]
+        );
+    }
+
+    #[test]
+    fn ci() {
+        let parse = |args: &[&str], ci_env: Option<&str>| {
+            let mut program = Program::parse_from(args);
+            program.globals.apply_ci(ci_env.map(OsStr::new));
+            program.globals
+        };
+
+        let globals = parse(&["stack", "--ci", "up"], None);
+        assert!(globals.ci);
+        assert_eq!(globals.color, Some(ColorChoice::Never));
+        assert!(!globals.color(true));
+        assert_eq!(
+            globals.compose_args(Engine::Docker),
+            vec!["--ansi".to_string(), "never".to_string()]
+        );
+        assert!(globals.compose_args(Engine::Podman).is_empty());
+        assert!(globals.no_interactive);
+        assert!(!globals.yes);
+        assert!(globals.no_progress);
+        assert!(globals.quiet_success);
+        assert!(globals.show_summary(1));
+        assert!(!globals.show_summary(0));
+
+        // The environment variable turns it on unless it is false.
+        for value in ["true", "1", "yes"] {
+            assert!(parse(&["stack", "up"], Some(value)).ci);
+        }
+        for value in ["", "0", "false", "FALSE"] {
+            assert!(!parse(&["stack", "up"], Some(value)).ci);
+        }
+
+        let globals = parse(&["stack", "--no-ci", "up"], Some("true"));
+        assert!(!globals.ci);
+        assert_eq!(globals.color, None);
+        assert!(globals.compose_args(Engine::Docker).is_empty());
+        assert!(!globals.no_interactive);
+        assert!(!globals.no_progress);
+        assert!(!globals.quiet_success);
+        assert!(!globals.show_summary(1));
+        assert!(globals.show_summary(2));
+
+        // Explicit options win.
+        let globals = parse(
+            &[
+                "stack",
+                "--ci",
+                "--color",
+                "always",
+                "--no-quiet-success",
+                "--no-summary",
+                "up",
+            ],
+            None,
+        );
+        assert!(globals.ci);
+        assert!(globals.color(false));
+        assert!(globals.compose_args(Engine::Docker).is_empty());
+        assert!(!globals.quiet_success);
+        assert!(!globals.show_summary(3));
+
+        let globals = parse(
+            &["stack", "--quiet-success", "--no-quiet-success", "up"],
+            None,
+        );
+        assert!(!globals.quiet_success);
+        let globals = parse(
+            &["stack", "--no-quiet-success", "--quiet-success", "up"],
+            None,
+        );
+        assert!(globals.quiet_success);
+    }
+
+    #[test]
+    fn ci_volume_removal() {
+        let config = config();
+        let executor = RecordingExecutor::default();
+        let mut program = Program::parse_from(["stack", "--ci", "db", "down", "--volumes"]);
+        program.globals.apply_ci(None);
+        let mut runner = Runner::new(&config, &Passthrough);
+        runner.executor = &executor;
+        runner.preflight = false;
+
+        // Removing the volumes of dependants needs an explicit `--yes`.
+        let error = program
+            .command
+            .run(&program.globals, &selected(&program), &runner)
+            .unwrap_err();
+        assert!(error.to_string().contains("pass --yes to continue"));
+        assert!(executor.commands().is_empty());
+
+        let mut program =
+            Program::parse_from(["stack", "--ci", "--yes", "db", "down", "--volumes"]);
+        program.globals.apply_ci(None);
+        program
+            .command
+            .run(&program.globals, &selected(&program), &runner)
+            .unwrap();
+        assert_eq!(executor.commands().len(), 3);
+    }
+
+    #[test]
+    fn tasks() {
+        let config = Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                api:
+                    depends_on: [db]
+            tasks:
+                reset-db:
+                    description: Recreate the database
+                    steps:
+                        - { command: down, stacks: [db], args: [-v] }
+                        - { command: up, stacks: [api] }
+                        - { run: ./migrate.sh, stack: api }
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let run = |executor: &RecordingExecutor, args: &[&str]| {
+            let program = Program::parse_from(args);
+            let mut runner = Runner::new(&config, &Passthrough);
+            runner.executor = executor;
+            runner.preflight = false;
+            runner.terminal = false;
+            program
+                .command
+                .run(&program.globals, &selected(&program), &runner)
+        };
+
+        // Steps behave as the commands do, removing volumes from dependants
+        // needs confirmation.
+        let executor = RecordingExecutor::default();
+        assert!(run(&executor, &["stack", "task", "reset-db"]).is_err());
+        assert!(executor.commands().is_empty());
+
+        run(&executor, &["stack", "--yes", "task", "reset-db"]).unwrap();
+        assert_eq!(
+            executor.commands(),
+            commands(&[
+                ("api", "down"),
+                ("db", "down"),
+                ("db", "up"),
+                ("api", "up"),
+                ("api", "foreach")
+            ])
+        );
+        let runs = executor.runs();
+        assert!(runs[0].command_line().ends_with("down -v"));
+        assert_eq!(runs[4].command_line(), "sh -c ./migrate.sh");
+        assert_eq!(runs[4].working_dir, PathBuf::from("/stacks/api"));
+
+        // A failing step stops the task.
+        let executor = RecordingExecutor::default().fail("db", "up");
+        assert!(run(&executor, &["stack", "--yes", "task", "reset-db"]).is_err());
+        assert_eq!(
+            executor.commands(),
+            commands(&[("api", "down"), ("db", "down"), ("db", "up")])
+        );
+
+        let executor = RecordingExecutor::default();
+        assert!(run(&executor, &["stack", "task", "missing"]).is_err());
+        assert!(executor.commands().is_empty());
+
+        run(&executor, &["stack", "task", "--list"]).unwrap();
+        assert!(Program::try_parse_from(["stack", "task"]).is_err());
     }
 }
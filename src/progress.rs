@@ -0,0 +1,263 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Stack;
+use crate::output::{OutputHandler, Stream};
+use crate::summary::format_duration;
+
+/// How often the progress display is redrawn.
+const TICK: Duration = Duration::from_millis(100);
+
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Receives updates as commands are run against stacks.
+pub trait Reporter: Sync {
+    /// Called before running a command against the given number of stacks.
+    fn begin(&self, _total: usize) {}
+
+    /// Called when a stack's command starts.
+    fn started(&self, stack: &Stack);
+
+    /// Called when a stack's command has finished.
+    fn finished(&self, stack: &Stack, success: bool, duration: Duration);
+
+    /// Called once all stacks are complete.
+    fn end(&self) {}
+}
+
+/// Reports progress through log messages.
+pub struct LogReporter;
+
+impl Reporter for LogReporter {
+    fn started(&self, stack: &Stack) {
+        log::info!("Starting {}…", stack.key);
+    }
+
+    fn finished(&self, stack: &Stack, success: bool, duration: Duration) {
+        if success {
+            log::info!("{} done in {}", stack.key, format_duration(duration));
+        } else {
+            log::info!("{} failed after {}", stack.key, format_duration(duration));
+        }
+    }
+}
+
+/// An event seen by the `Events` reporter.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Begin(usize),
+    Started(String),
+    Finished(String, bool),
+    End,
+}
+
+/// Records the events reported for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Events {
+    pub events: Mutex<Vec<Event>>,
+}
+
+#[cfg(test)]
+impl Reporter for Events {
+    fn begin(&self, total: usize) {
+        self.events.lock().unwrap().push(Event::Begin(total));
+    }
+
+    fn started(&self, stack: &Stack) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(Event::Started(stack.key.clone()));
+    }
+
+    fn finished(&self, stack: &Stack, success: bool, _duration: Duration) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(Event::Finished(stack.key.clone(), success));
+    }
+
+    fn end(&self) {
+        self.events.lock().unwrap().push(Event::End);
+    }
+}
+
+#[derive(Default)]
+struct State {
+    total: usize,
+    completed: usize,
+    running: Vec<(String, Instant)>,
+    /// How many lines are currently drawn at the bottom of the terminal.
+    drawn: usize,
+    tick: usize,
+    /// Set once the display has been removed.
+    stopped: bool,
+}
+
+/// The lines showing the stacks in progress and the overall count.
+fn status_lines(state: &State, now: Instant) -> Vec<String> {
+    let spinner = SPINNER[state.tick % SPINNER.len()];
+    let mut lines: Vec<String> = state
+        .running
+        .iter()
+        .map(|(key, start)| format!("{} {} {}", spinner, key, format_duration(now - *start)))
+        .collect();
+    lines.push(format!("{}/{} stacks", state.completed, state.total));
+    lines
+}
+
+/// A live display on stderr of the stacks that are running. Output from the
+/// commands is passed through to the inner handler with the display cleared
+/// and then redrawn beneath it.
+pub struct Progress<'a> {
+    inner: &'a dyn OutputHandler,
+    state: Mutex<State>,
+}
+
+impl<'a> Progress<'a> {
+    /// Runs the function with a progress display that is redrawn regularly
+    /// until the function returns.
+    pub fn run<R, F>(inner: &'a dyn OutputHandler, f: F) -> R
+    where
+        F: FnOnce(&Progress) -> R,
+    {
+        let progress = Progress {
+            inner,
+            state: Mutex::new(State::default()),
+        };
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    thread::sleep(TICK);
+                    progress.update(|state| state.tick += 1);
+                }
+            });
+
+            let result = f(&progress);
+            done.store(true, Ordering::SeqCst);
+
+            let mut state = progress.state.lock().unwrap();
+            progress.clear(&mut state);
+            state.stopped = true;
+            result
+        })
+    }
+
+    fn clear(&self, state: &mut State) {
+        if state.drawn > 0 {
+            let _ = write!(io::stderr().lock(), "\x1b[{}A\x1b[J", state.drawn);
+            state.drawn = 0;
+        }
+    }
+
+    fn draw(&self, state: &mut State) {
+        if state.stopped {
+            return;
+        }
+
+        let lines = status_lines(state, Instant::now());
+        let mut stderr = io::stderr().lock();
+        for line in lines.iter() {
+            let _ = writeln!(stderr, "\x1b[2K{}", line);
+        }
+        let _ = stderr.flush();
+        state.drawn = lines.len();
+    }
+
+    /// Updates the state and redraws the display.
+    fn update<F: FnOnce(&mut State)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        self.clear(&mut state);
+        self.draw(&mut state);
+    }
+
+    /// Clears the display while running the function, drawing it again after.
+    fn suspend<F: FnOnce()>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        self.clear(&mut state);
+        f();
+        self.draw(&mut state);
+    }
+}
+
+impl Reporter for Progress<'_> {
+    fn begin(&self, total: usize) {
+        self.update(|state| {
+            state.total += total;
+        });
+    }
+
+    fn started(&self, stack: &Stack) {
+        self.update(|state| state.running.push((stack.key.clone(), Instant::now())));
+    }
+
+    fn finished(&self, stack: &Stack, success: bool, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.running.retain(|(key, _)| key != &stack.key);
+        state.completed += 1;
+
+        self.clear(&mut state);
+        let mark = if success { '✓' } else { '✗' };
+        let _ = writeln!(
+            io::stderr().lock(),
+            "{} {} {}",
+            mark,
+            stack.key,
+            format_duration(duration)
+        );
+        self.draw(&mut state);
+    }
+}
+
+impl OutputHandler for Progress<'_> {
+    fn line(&self, stack: &Stack, stream: Stream, line: &str) {
+        self.suspend(|| {
+            self.inner.line(stack, stream, line);
+            // Make sure the line is visible before the display is redrawn.
+            let _ = io::stdout().flush();
+        });
+    }
+
+    fn finished(&self, stack: &Stack, success: bool) {
+        self.suspend(|| self.inner.finished(stack, success));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{status_lines, State};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn status() {
+        let now = Instant::now();
+        let state = State {
+            total: 12,
+            completed: 4,
+            running: vec![
+                ("web".to_string(), now - Duration::from_millis(2500)),
+                ("db".to_string(), now - Duration::from_secs(61)),
+            ],
+            drawn: 0,
+            tick: 1,
+            stopped: false,
+        };
+
+        assert_eq!(
+            status_lines(&state, now),
+            vec![
+                "⠙ web 2.5s".to_string(),
+                "⠙ db 1m01s".to_string(),
+                "4/12 stacks".to_string()
+            ]
+        );
+    }
+}
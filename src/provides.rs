@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::config::Stack;
+use crate::error::{Error, Result};
+
+/// A port published by one of a stack's services, looked up with
+/// `docker compose port` when a dependant runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PublishedPort {
+    pub service: String,
+    /// The port in the container.
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+}
+
+impl PublishedPort {
+    /// The arguments for `docker compose port`.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(ref protocol) = self.protocol {
+            args.push("--protocol".to_owned());
+            args.push(protocol.clone());
+        }
+        args.push(self.service.clone());
+        args.push(self.port.to_string());
+        args
+    }
+}
+
+/// A value a stack provides to its dependants, either given in the config or
+/// the host port that a service's port is published on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Provided {
+    Value(String),
+    Port(PublishedPort),
+}
+
+/// Values may be given as any scalar, like in `environment`.
+impl<'de> Deserialize<'de> for Provided {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(
+            untagged,
+            expecting = "expected a string, number, boolean or `{ service, port }`"
+        )]
+        enum Raw {
+            Text(String),
+            Integer(i64),
+            Float(f64),
+            Boolean(bool),
+            Port(PublishedPort),
+        }
+
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Text(text) => Provided::Value(text),
+            Raw::Integer(number) => Provided::Value(number.to_string()),
+            Raw::Float(number) => Provided::Value(number.to_string()),
+            Raw::Boolean(value) => Provided::Value(value.to_string()),
+            Raw::Port(port) => Provided::Port(port),
+        })
+    }
+}
+
+/// The prefix for the variables a stack provides, its key in upper case with
+/// anything that can't be in a variable name replaced by `_` unless the stack
+/// gives its own.
+pub fn prefix(stack: &Stack) -> String {
+    match stack.provides_prefix {
+        Some(ref prefix) => prefix.clone(),
+        None => stack
+            .key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    }
+}
+
+/// The name a dependant sees a provided variable as.
+pub fn variable_name(prefix: &str, variable: &str) -> String {
+    if prefix.is_empty() {
+        variable.to_owned()
+    } else {
+        format!("{}_{}", prefix, variable)
+    }
+}
+
+/// The variables the stack receives from its direct dependencies, by name,
+/// along with the dependency that provides each.
+fn received<'a>(
+    stack: &Stack,
+    stacks: &'a BTreeMap<String, Stack>,
+) -> Vec<(String, &'a Stack, &'a Provided)> {
+    stack
+        .dependencies
+        .iter()
+        .filter_map(|key| stacks.get(key))
+        .flat_map(|dependency| {
+            let prefix = prefix(dependency);
+            dependency.provides.iter().map(move |(variable, provided)| {
+                (variable_name(&prefix, variable), dependency, provided)
+            })
+        })
+        .collect()
+}
+
+/// The values the stack receives from its dependencies that are given in the
+/// config.
+pub fn static_variables(
+    stack: &Stack,
+    stacks: &BTreeMap<String, Stack>,
+) -> HashMap<String, String> {
+    received(stack, stacks)
+        .into_iter()
+        .filter_map(|(name, _, provided)| match provided {
+            Provided::Value(value) => Some((name, value.clone())),
+            Provided::Port(_) => None,
+        })
+        .collect()
+}
+
+/// The ports the stack receives from its dependencies, by variable name, that
+/// need to be looked up from the dependency.
+pub fn dynamic_variables<'a>(
+    stack: &Stack,
+    stacks: &'a BTreeMap<String, Stack>,
+) -> Vec<(String, &'a Stack, &'a PublishedPort)> {
+    received(stack, stacks)
+        .into_iter()
+        .filter_map(|(name, dependency, provided)| match provided {
+            Provided::Port(port) => Some((name, dependency, port)),
+            Provided::Value(_) => None,
+        })
+        .collect()
+}
+
+/// Parses the output of `docker compose port` into the host port, `None`
+/// when the port isn't published.
+pub fn parse_port(output: &str) -> Option<String> {
+    let (_, port) = output.lines().next()?.trim().rsplit_once(':')?;
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => None,
+        Ok(port) => Some(port.to_string()),
+    }
+}
+
+/// Checks that the provided variables have usable names and that no stack
+/// receives the same variable from more than one dependency.
+pub fn validate(stacks: &BTreeMap<String, Stack>) -> Result<()> {
+    for stack in stacks.values() {
+        let prefix = prefix(stack);
+        if let Some(variable) = stack
+            .provides
+            .keys()
+            .find(|variable| !is_variable_name(&variable_name(&prefix, variable)))
+        {
+            return Err(Error::Invalid(format!(
+                "Stack `{}` provides `{}` which is not a valid variable name.",
+                stack.key,
+                variable_name(&prefix, variable)
+            )));
+        }
+
+        let mut providers: HashMap<String, &str> = HashMap::new();
+        for (name, dependency, _) in received(stack, stacks) {
+            if let Some(other) = providers.insert(name.clone(), &dependency.key) {
+                return Err(Error::Invalid(format!(
+                    "Stack `{}` receives {} from both `{}` and `{}`, give one of them a \
+                    different `provides_prefix`.",
+                    stack.key, name, other, dependency.key
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dynamic_variables, parse_port, static_variables, validate, PublishedPort};
+    use crate::config::Config;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn parse(yaml: &str) -> Result<Config, String> {
+        Config::from_reader(&PathBuf::from("/stacks"), yaml.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn variables(list: &[(&str, &str)]) -> HashMap<String, String> {
+        list.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn namespacing() {
+        let config = parse(
+            "
+            stacks:
+                db:
+                    provides:
+                        HOST: localhost
+                        PORT: 5432
+                        EXTERNAL_PORT: { service: postgres, port: 5432 }
+                message-queue:
+                    provides:
+                        URL: amqp://localhost
+                cache:
+                    provides_prefix: ''
+                    provides:
+                        REDIS_URL: redis://localhost
+                api:
+                    depends_on: [db, message-queue, cache]
+                web:
+                    depends_on: [api]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            static_variables(&config.stacks["api"], &config.stacks),
+            variables(&[
+                ("DB_HOST", "localhost"),
+                ("DB_PORT", "5432"),
+                ("MESSAGE_QUEUE_URL", "amqp://localhost"),
+                ("REDIS_URL", "redis://localhost"),
+            ])
+        );
+
+        let dynamic: Vec<(String, &str, &PublishedPort)> =
+            dynamic_variables(&config.stacks["api"], &config.stacks)
+                .into_iter()
+                .map(|(name, dependency, port)| (name, dependency.key.as_str(), port))
+                .collect();
+        assert_eq!(
+            dynamic,
+            vec![(
+                "DB_EXTERNAL_PORT".to_string(),
+                "db",
+                &PublishedPort {
+                    service: "postgres".to_string(),
+                    port: 5432,
+                    protocol: None,
+                }
+            )]
+        );
+        assert_eq!(dynamic[0].2.args(), vec!["postgres", "5432"]);
+
+        // Only direct dependencies provide anything.
+        assert!(static_variables(&config.stacks["web"], &config.stacks).is_empty());
+        assert!(static_variables(&config.stacks["db"], &config.stacks).is_empty());
+    }
+
+    #[test]
+    fn collisions() {
+        let error = parse(
+            "
+            stacks:
+                db:
+                    provides_prefix: DATABASE
+                    provides: { HOST: db.local }
+                replica:
+                    provides_prefix: DATABASE
+                    provides: { HOST: replica.local }
+                api:
+                    depends_on: [db, replica]
+            ",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error,
+            "Stack `api` receives DATABASE_HOST from both `db` and `replica`, give one of \
+            them a different `provides_prefix`."
+        );
+
+        // Stacks that are never used together don't collide.
+        let config = parse(
+            "
+            stacks:
+                db:
+                    provides_prefix: DATABASE
+                    provides: { HOST: db.local }
+                replica:
+                    provides_prefix: DATABASE
+                    provides: { HOST: { service: postgres, port: 5432 } }
+                api:
+                    depends_on: [db]
+                reports:
+                    depends_on: [replica]
+            ",
+        )
+        .unwrap();
+        assert!(validate(&config.stacks).is_ok());
+
+        assert!(parse(
+            "
+            stacks:
+                db:
+                    provides_prefix: ''
+                    provides: { 1HOST: localhost }
+            "
+        )
+        .err()
+        .unwrap()
+        .contains("`1HOST` which is not a valid variable name"));
+        let error = parse(
+            "
+            stacks:
+                db:
+                    provides: { HOST: { service: postgres } }
+            ",
+        )
+        .err()
+        .unwrap();
+        assert!(
+            error.contains("expected a string, number, boolean or `{ service, port }`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(parse_port("0.0.0.0:49153\n"), Some("49153".to_string()));
+        assert_eq!(parse_port("[::]:8080\n"), Some("8080".to_string()));
+        assert_eq!(parse_port(":0\n"), None);
+        assert_eq!(parse_port(""), None);
+
+        let port = PublishedPort {
+            service: "dns".to_string(),
+            port: 53,
+            protocol: Some("udp".to_string()),
+        };
+        assert_eq!(port.args(), vec!["--protocol", "udp", "dns", "53"]);
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+
+/// Where an image is pulled to. Stacks that talk to different daemons, or
+/// that want a different platform, can't share pulls.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Daemon {
+    /// `DOCKER_CONTEXT`, if set.
+    pub context: Option<String>,
+    /// `DOCKER_HOST`, if set.
+    pub host: Option<String>,
+    /// `DOCKER_DEFAULT_PLATFORM`, if set.
+    pub platform: Option<String>,
+}
+
+impl Daemon {
+    /// The daemon that a command with the environment talks to.
+    pub fn from_environment(environment: &HashMap<String, OsString>) -> Self {
+        let get = |key: &str| {
+            environment
+                .get(key)
+                .map(|value| value.to_string_lossy().into_owned())
+                .filter(|value| !value.is_empty())
+        };
+
+        Self {
+            context: get("DOCKER_CONTEXT"),
+            host: get("DOCKER_HOST"),
+            platform: get("DOCKER_DEFAULT_PLATFORM"),
+        }
+    }
+}
+
+/// The images that a stack uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Need {
+    pub stack: String,
+    pub daemon: Daemon,
+    pub images: Vec<String>,
+}
+
+/// An image to pull once, along with the stacks that use it in the order
+/// given. The first of these is the one the pull is run for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pull {
+    pub daemon: Daemon,
+    pub image: String,
+    pub stacks: Vec<String>,
+}
+
+/// Works out the unique images to pull for each daemon, in the order they are
+/// first needed.
+pub fn plan(needs: &[Need]) -> Vec<Pull> {
+    let mut pulls: Vec<Pull> = Vec::new();
+    let mut indexes: HashMap<(&Daemon, &str), usize> = HashMap::new();
+
+    for need in needs {
+        for image in need.images.iter() {
+            match indexes.get(&(&need.daemon, image.as_str())) {
+                Some(index) => {
+                    let stacks = &mut pulls[*index].stacks;
+                    if !stacks.contains(&need.stack) {
+                        stacks.push(need.stack.clone());
+                    }
+                }
+                None => {
+                    indexes.insert((&need.daemon, image), pulls.len());
+                    pulls.push(Pull {
+                        daemon: need.daemon.clone(),
+                        image: image.clone(),
+                        stacks: vec![need.stack.clone()],
+                    });
+                }
+            }
+        }
+    }
+
+    pulls
+}
+
+/// Lists each stack's images and whether the stack's own pull fetched them
+/// or they came from a pull shared with another stack.
+pub fn render(needs: &[Need], pulls: &[Pull]) -> String {
+    let header = [
+        "Stack".to_string(),
+        "Image".to_string(),
+        "Pulled".to_string(),
+    ];
+    let mut rows: Vec<[String; 3]> = Vec::new();
+    for need in needs {
+        let mut seen: Vec<&str> = Vec::new();
+        for image in need.images.iter() {
+            if seen.contains(&image.as_str()) {
+                continue;
+            }
+            seen.push(image);
+
+            let pulled_by = pulls
+                .iter()
+                .find(|pull| pull.daemon == need.daemon && &pull.image == image)
+                .map(|pull| pull.stacks[0].as_str());
+            let pulled = match pulled_by {
+                Some(stack) if stack == need.stack => "pulled".to_string(),
+                Some(stack) => format!("shared with {}", stack),
+                None => "-".to_string(),
+            };
+            rows.push([need.stack.clone(), image.clone(), pulled]);
+        }
+    }
+
+    if rows.is_empty() {
+        return "No images to pull.\n".to_string();
+    }
+
+    let mut widths = [0; 3];
+    for row in [&header].into_iter().chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(rows.iter()) {
+        let _ = writeln!(
+            table,
+            "{:<w0$}  {:<w1$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            w0 = widths[0],
+            w1 = widths[1],
+        );
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plan, render, Daemon, Need, Pull};
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+
+    fn need(stack: &str, daemon: &Daemon, images: &[&str]) -> Need {
+        Need {
+            stack: stack.to_string(),
+            daemon: daemon.clone(),
+            images: images.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn daemons() {
+        let environment: HashMap<String, OsString> = [
+            ("DOCKER_CONTEXT", "remote"),
+            ("DOCKER_HOST", ""),
+            ("DOCKER_DEFAULT_PLATFORM", "linux/arm64"),
+            ("HOME", "/root"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.into()))
+        .collect();
+
+        assert_eq!(
+            Daemon::from_environment(&environment),
+            Daemon {
+                context: Some("remote".to_string()),
+                host: None,
+                platform: Some("linux/arm64".to_string()),
+            }
+        );
+        assert_eq!(Daemon::from_environment(&HashMap::new()), Daemon::default());
+    }
+
+    #[test]
+    fn unique_images() {
+        let local = Daemon::default();
+        let remote = Daemon {
+            host: Some("ssh://build".to_string()),
+            ..Default::default()
+        };
+        let arm = Daemon {
+            platform: Some("linux/arm64".to_string()),
+            ..Default::default()
+        };
+
+        let needs = vec![
+            need("db", &local, &["postgres:16"]),
+            need("api", &local, &["app:1", "postgres:16", "postgres:16"]),
+            need("web", &local, &["nginx:1", "app:1"]),
+            need("replica", &remote, &["postgres:16"]),
+            need("edge", &arm, &["nginx:1"]),
+            need("empty", &local, &[]),
+        ];
+        let pulls = plan(&needs);
+
+        assert_eq!(
+            pulls,
+            vec![
+                Pull {
+                    daemon: local.clone(),
+                    image: "postgres:16".to_string(),
+                    stacks: strings(&["db", "api"]),
+                },
+                Pull {
+                    daemon: local.clone(),
+                    image: "app:1".to_string(),
+                    stacks: strings(&["api", "web"]),
+                },
+                Pull {
+                    daemon: local.clone(),
+                    image: "nginx:1".to_string(),
+                    stacks: strings(&["web"]),
+                },
+                Pull {
+                    daemon: remote.clone(),
+                    image: "postgres:16".to_string(),
+                    stacks: strings(&["replica"]),
+                },
+                Pull {
+                    daemon: arm.clone(),
+                    image: "nginx:1".to_string(),
+                    stacks: strings(&["edge"]),
+                },
+            ]
+        );
+
+        assert_eq!(
+            render(&needs, &pulls),
+            "Stack    Image        Pulled\n\
+            db       postgres:16  pulled\n\
+            api      app:1        pulled\n\
+            api      postgres:16  shared with db\n\
+            web      nginx:1      pulled\n\
+            web      app:1        shared with api\n\
+            replica  postgres:16  pulled\n\
+            edge     nginx:1      pulled\n"
+        );
+
+        assert!(plan(&[]).is_empty());
+        assert_eq!(render(&[], &[]), "No images to pull.\n");
+    }
+}
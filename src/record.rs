@@ -0,0 +1,214 @@
+//! Recording the commands stacks would run instead of running them, so the
+//! binary can be tested end to end without docker.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::Cancellation;
+use crate::config::Stack;
+use crate::error::{Error, Result};
+use crate::exec::ExecOptions;
+use crate::executor::{exit_status, Executor};
+use crate::output::OutputHandler;
+
+/// The environment variable naming the file to record commands to.
+pub const RECORD_VAR: &str = "STACK_EXEC_RECORD";
+
+/// The environment variable naming a stack whose commands fail while
+/// recording.
+pub const RECORD_FAIL_VAR: &str = "STACK_EXEC_RECORD_FAIL";
+
+/// A command that would have been run, as a line of the record file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// `run` for commands run against a stack and `query` for commands whose
+    /// output stacks reads.
+    pub kind: String,
+    /// The stack the command was run against, only known for runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stack: Option<String>,
+    /// The compose subcommand, or the host command.
+    pub command: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: String,
+    /// The variables set for the command on top of the inherited
+    /// environment.
+    pub env: BTreeMap<String, String>,
+}
+
+impl Record {
+    fn new(kind: &str, stack: Option<&Stack>, exec_options: &ExecOptions) -> Self {
+        Self {
+            kind: kind.to_owned(),
+            stack: stack.map(|stack| stack.key.clone()),
+            command: exec_options.command.clone(),
+            program: exec_options.program().to_string_lossy().into_owned(),
+            args: exec_options
+                .args()
+                .into_iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            cwd: exec_options.working_dir.display().to_string(),
+            env: exec_options
+                .environment
+                .iter()
+                .map(|(key, value)| (key.clone(), value.to_string_lossy().into_owned()))
+                .collect(),
+        }
+    }
+}
+
+/// Parses the lines of a record file.
+pub fn parse(contents: &str) -> Result<Vec<Record>> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .map_err(|e| Error::Invalid(format!("Invalid record `{}`: {}", line, e)))
+        })
+        .collect()
+}
+
+/// An executor that appends each command to a file as a JSON line rather
+/// than running it. Every command succeeds, printing nothing, except those
+/// run against the failing stack.
+#[derive(Debug)]
+pub struct FileRecorder {
+    path: PathBuf,
+    failing: Option<String>,
+    lock: Mutex<()>,
+}
+
+impl FileRecorder {
+    pub fn new(path: &Path, failing: Option<String>) -> Self {
+        Self {
+            path: path.to_owned(),
+            failing,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// A recorder for the file named by `STACK_EXEC_RECORD`, if it is set.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var_os(RECORD_VAR).filter(|path| !path.is_empty())?;
+        let failing = env::var(RECORD_FAIL_VAR).ok().filter(|key| !key.is_empty());
+        Some(Self::new(Path::new(&path), failing))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_string(record).unwrap();
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| Error::io(format!("Unable to record to {}", self.path.display()), e))
+    }
+}
+
+impl Executor for FileRecorder {
+    fn run(
+        &self,
+        exec_options: &ExecOptions,
+        stack: &Stack,
+        _output: &dyn OutputHandler,
+        _cancel: &Cancellation,
+    ) -> Result<ExitStatus> {
+        self.append(&Record::new("run", Some(stack), exec_options))?;
+
+        let failed = self.failing.as_deref() == Some(stack.key.as_str());
+        Ok(exit_status(if failed { 1 } else { 0 }))
+    }
+
+    fn output(&self, exec_options: &ExecOptions) -> Result<Output> {
+        self.append(&Record::new("query", None, exec_options))?;
+
+        Ok(Output {
+            status: exit_status(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, FileRecorder};
+    use crate::cancel::Cancellation;
+    use crate::config::Config;
+    use crate::exec::ExecOptions;
+    use crate::executor::Executor;
+    use crate::output::Passthrough;
+    use std::fs;
+
+    #[test]
+    fn recording() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config::from_reader(
+            temp.path(),
+            "
+            command: docker compose
+            environment:
+                REGION: eu
+            stacks:
+                db: {}
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let path = temp.path().join("record.jsonl");
+        let recorder = FileRecorder::new(&path, Some("web".to_string()));
+        let cancel = Cancellation::default();
+
+        let run = |key: &str| {
+            let stack = &config.stacks[key];
+            let options = ExecOptions::new(&config, "up", &["--wait"])
+                .with_stack(stack)
+                .unwrap();
+            recorder
+                .run(&options, stack, &Passthrough, &cancel)
+                .unwrap()
+                .success()
+        };
+        assert!(run("db"));
+        assert!(!run("web"));
+
+        let options = ExecOptions::new(&config, "version", &["--short"]);
+        let output = recorder.output(&options).unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+
+        let records = parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].kind, "run");
+        assert_eq!(records[0].stack.as_deref(), Some("db"));
+        assert_eq!(records[0].command, "up");
+        assert_eq!(records[0].program, "docker");
+        assert_eq!(records[0].args.first().map(String::as_str), Some("compose"));
+        assert!(records[0]
+            .args
+            .ends_with(&["up".to_string(), "--wait".to_string()]));
+        assert_eq!(records[0].cwd, temp.path().join("db").display().to_string());
+        assert_eq!(records[0].env["REGION"], "eu");
+        assert_eq!(records[1].stack.as_deref(), Some("web"));
+        assert_eq!(records[2].kind, "query");
+        assert_eq!(records[2].stack, None);
+    }
+}
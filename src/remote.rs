@@ -0,0 +1,308 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::paths;
+use crate::selection::hash;
+
+/// The environment variable with a bearer token to send when fetching a
+/// stacks config.
+pub const TOKEN_VAR: &str = "STACKS_FILE_TOKEN";
+
+/// Whether a `--file` value is an http or https URL.
+pub fn is_url(file: &str) -> bool {
+    ["http://", "https://"].iter().any(|scheme| {
+        file.len() > scheme.len()
+            && file
+                .get(..scheme.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+    })
+}
+
+/// Finds the `ETag` of the final response in headers written by curl, which
+/// includes the headers of any redirects first.
+pub fn parse_etag(headers: &str) -> Option<String> {
+    let mut etag = None;
+    for line in headers.lines() {
+        if line.starts_with("HTTP/") {
+            etag = None;
+        } else if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("etag") && !value.trim().is_empty() {
+                etag = Some(value.trim().to_owned());
+            }
+        }
+    }
+    etag
+}
+
+/// The result of a successful request.
+#[derive(Debug, PartialEq, Eq)]
+enum Fetched {
+    /// A new copy was downloaded, with its `ETag` if it had one.
+    Updated(Option<String>),
+    /// The cached copy is still current.
+    NotModified,
+}
+
+/// Fetches stacks configs given as URLs into a cache, so a config that was
+/// fetched before can be revalidated with its `ETag` and still used when the
+/// server can't be reached. Downloads are done with `curl`.
+pub struct RemoteConfig {
+    dir: PathBuf,
+    timeout: Duration,
+    token: Option<String>,
+}
+
+impl RemoteConfig {
+    pub fn new(dir: &Path, timeout: Duration, token: Option<String>) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            timeout,
+            token,
+        }
+    }
+
+    /// Caches in the user's cache directory and sends the token from
+    /// `STACKS_FILE_TOKEN`, if set.
+    pub fn from_env(timeout: Duration) -> Self {
+        let dir = paths::cache_dir().unwrap_or_else(|| env::temp_dir().join("stack"));
+        let token = env::var(TOKEN_VAR).ok().filter(|token| !token.is_empty());
+        Self::new(&dir, timeout, token)
+    }
+
+    /// Where the config from the URL is cached, along with the file recording
+    /// the URL and `ETag` of the cached copy.
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let key = format!("{:016x}", hash(url));
+        (
+            self.dir.join(format!("{}.yml", key)),
+            self.dir.join(format!("{}.remote", key)),
+        )
+    }
+
+    /// The `ETag` of the cached copy of the URL, or `None` when there is no
+    /// cached copy.
+    fn cached(&self, url: &str) -> Option<Option<String>> {
+        let (path, meta) = self.paths(url);
+        if !path.is_file() {
+            return None;
+        }
+
+        // The first line records the URL in case of hash collisions.
+        let meta = fs::read_to_string(meta).ok()?;
+        let mut lines = meta.lines();
+        if lines.next() != Some(url) {
+            return None;
+        }
+        Some(lines.next().map(|etag| etag.to_owned()))
+    }
+
+    /// Fetches the config at the URL, returning the path of the cached copy.
+    /// If the config can't be fetched a previously cached copy is used.
+    pub fn fetch(&self, url: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| Error::io(format!("Failed to create {}", self.dir.display()), e))?;
+
+        let (path, meta) = self.paths(url);
+        let cached = self.cached(url);
+
+        match (self.download(url, &path, cached.as_ref()), cached) {
+            (Ok(Fetched::Updated(etag)), _) => {
+                let mut content = url.to_owned();
+                if let Some(etag) = etag {
+                    content.push('\n');
+                    content.push_str(&etag);
+                }
+                fs::write(&meta, content)
+                    .map_err(|e| Error::io(format!("Failed to write {}", meta.display()), e))?;
+                Ok(path)
+            }
+            (Ok(Fetched::NotModified), Some(_)) => {
+                log::debug!("The cached copy of {} is current", url);
+                Ok(path)
+            }
+            (Ok(Fetched::NotModified), None) => Err(Error::FetchFailed {
+                url: url.to_owned(),
+                reason: "the server responded with HTTP 304 to a request without an ETag"
+                    .to_owned(),
+            }),
+            (Err(reason), Some(_)) => {
+                log::warn!(
+                    "Failed to fetch the stacks config from {}: {}. Using the cached copy.",
+                    url,
+                    reason
+                );
+                Ok(path)
+            }
+            (Err(reason), None) => Err(Error::FetchFailed {
+                url: url.to_owned(),
+                reason,
+            }),
+        }
+    }
+
+    /// Downloads the URL over the cached copy at `path` if it has changed.
+    fn download(
+        &self,
+        url: &str,
+        path: &Path,
+        cached: Option<&Option<String>>,
+    ) -> std::result::Result<Fetched, String> {
+        let partial = path.with_extension("partial");
+        let headers = path.with_extension("headers");
+
+        // Headers are passed on stdin so the token doesn't show up in the
+        // process list.
+        let mut request_headers = String::new();
+        if let Some(ref token) = self.token {
+            request_headers.push_str(&format!("Authorization: Bearer {}\n", token));
+        }
+        if let Some(Some(etag)) = cached {
+            request_headers.push_str(&format!("If-None-Match: {}\n", etag));
+        }
+
+        let mut child = Command::new("curl")
+            .args(["--silent", "--show-error", "--location", "--max-time"])
+            .arg(self.timeout.as_secs_f64().to_string())
+            .args(["--header", "@-", "--write-out", "%{http_code}", "--output"])
+            .arg(&partial)
+            .arg("--dump-header")
+            .arg(&headers)
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("unable to run curl: {}", e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // curl reports its own problems if the headers can't be read.
+            let _ = stdin.write_all(request_headers.as_bytes());
+        }
+
+        let result = child
+            .wait_with_output()
+            .map_err(|e| format!("unable to run curl: {}", e))
+            .and_then(|output| {
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    return Err(match stderr.trim() {
+                        "" => format!("curl failed with {}", output.status),
+                        message => message.trim_start_matches("curl: ").to_owned(),
+                    });
+                }
+
+                match String::from_utf8_lossy(&output.stdout).trim() {
+                    "200" => {
+                        fs::rename(&partial, path)
+                            .map_err(|e| format!("unable to write {}: {}", path.display(), e))?;
+                        let headers = fs::read_to_string(&headers).unwrap_or_default();
+                        Ok(Fetched::Updated(parse_etag(&headers)))
+                    }
+                    "304" => Ok(Fetched::NotModified),
+                    code => Err(format!("the server responded with HTTP {}", code)),
+                }
+            });
+
+        let _ = fs::remove_file(&partial);
+        let _ = fs::remove_file(&headers);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_url, parse_etag, RemoteConfig};
+    use crate::error::Error;
+    use crate::testing::HttpServer;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn urls() {
+        assert!(is_url("https://internal/stacks.yml"));
+        assert!(is_url("HTTP://internal:8080/stacks.yml?v=1"));
+        assert!(!is_url("http://"));
+        assert!(!is_url("stacks.yml"));
+        assert!(!is_url("ftp://internal/stacks.yml"));
+        assert!(!is_url("-"));
+    }
+
+    #[test]
+    fn etags() {
+        assert_eq!(
+            parse_etag("HTTP/1.1 200 OK\r\nContent-Type: text/yaml\r\nETag: \"v1\"\r\n\r\n"),
+            Some("\"v1\"".to_string())
+        );
+        assert_eq!(
+            parse_etag("HTTP/1.1 200 OK\r\netag:  W/\"v2\" \r\n"),
+            Some("W/\"v2\"".to_string())
+        );
+
+        // Only the final response after redirects counts.
+        assert_eq!(
+            parse_etag("HTTP/1.1 302 Found\r\nETag: \"old\"\r\nLocation: /new\r\n\r\nHTTP/1.1 200 OK\r\n\r\n"),
+            None
+        );
+        assert_eq!(parse_etag(""), None);
+    }
+
+    #[test]
+    fn fetch() {
+        let temp = tempfile::tempdir().unwrap();
+        let remote = RemoteConfig::new(
+            temp.path(),
+            Duration::from_secs(10),
+            Some("secret".to_string()),
+        );
+
+        let server = HttpServer::start(&[
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\n\r\nstacks:\n  web: {}\n",
+            "HTTP/1.1 304 Not Modified\r\n\r\n",
+            "HTTP/1.1 200 OK\r\n\r\nstacks:\n  db: {}\n",
+            "HTTP/1.1 500 Internal Server Error\r\n\r\nbroken",
+        ]);
+        let url = server.url("/stacks.yml");
+
+        let path = remote.fetch(&url).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "stacks:\n  web: {}\n");
+
+        // The cached copy is revalidated.
+        assert_eq!(remote.fetch(&url).unwrap(), path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "stacks:\n  web: {}\n");
+
+        assert_eq!(remote.fetch(&url).unwrap(), path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "stacks:\n  db: {}\n");
+
+        // Failures fall back to the cached copy.
+        assert_eq!(remote.fetch(&url).unwrap(), path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "stacks:\n  db: {}\n");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].starts_with("GET /stacks.yml HTTP/1.1\r\n"));
+        assert!(requests
+            .iter()
+            .all(|request| request.contains("Authorization: Bearer secret\r\n")));
+        assert!(!requests[0].contains("If-None-Match"));
+        assert!(requests[1].contains("If-None-Match: \"v1\"\r\n"));
+        assert!(requests[2].contains("If-None-Match: \"v1\"\r\n"));
+        assert!(!requests[3].contains("If-None-Match"));
+
+        // Without a cached copy failures are errors.
+        let server = HttpServer::start(&["HTTP/1.1 404 Not Found\r\n\r\n"]);
+        match remote.fetch(&server.url("/missing.yml")) {
+            Err(Error::FetchFailed { reason, .. }) => {
+                assert_eq!(reason, "the server responded with HTTP 404")
+            }
+            result => panic!("Unexpected {:?}", result),
+        }
+
+        let url = HttpServer::unreachable_url("/stacks.yml");
+        assert!(matches!(remote.fetch(&url), Err(Error::FetchFailed { .. })));
+    }
+}
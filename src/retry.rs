@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use crate::config::Stack;
+use crate::error::{Error, Result};
+
+/// Subcommands that are safe to run again after a failure.
+pub const IDEMPOTENT_COMMANDS: [&str; 4] = ["pull", "build", "ps", "images"];
+
+/// The delay before the first retry, doubled for each subsequent retry.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Decides how many times a failed command may be retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Overrides the number of retries configured for each stack.
+    pub retries: Option<u32>,
+    /// Retry every command rather than just the idempotent ones.
+    pub all: bool,
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: None,
+            all: false,
+            initial_backoff: INITIAL_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The number of times the command may be retried for the stack.
+    pub fn retries(&self, command: &str, stack: &Stack) -> u32 {
+        if !self.all && !IDEMPOTENT_COMMANDS.contains(&command) {
+            return 0;
+        }
+
+        self.retries.or(stack.retries).unwrap_or(0)
+    }
+
+    /// The delay before the given retry, starting from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+    }
+
+    /// Runs the command, retrying failures as the policy allows. `sleep` waits
+    /// out the backoff and returns false if the run was cancelled meanwhile.
+    pub fn run<S, F>(&self, command: &str, stack: &Stack, mut sleep: S, mut run: F) -> Result<()>
+    where
+        S: FnMut(Duration) -> bool,
+        F: FnMut() -> Result<()>,
+    {
+        let retries = self.retries(command, stack);
+        let mut retry = 0;
+
+        loop {
+            match run() {
+                // Only failures of the command itself are worth retrying.
+                Err(e @ Error::CommandFailed { .. }) if retry < retries => {
+                    retry += 1;
+                    let delay = self.backoff(retry);
+                    log::warn!(
+                        "Attempt {} of {} for stack {} failed, retrying in {:?}: {}",
+                        retry,
+                        retries + 1,
+                        stack.key,
+                        delay,
+                        e
+                    );
+
+                    if !sleep(delay) {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use crate::config::Config;
+    use crate::error::Error;
+    use std::cell::Cell;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::process::ExitStatus;
+    use std::time::Duration;
+
+    fn failure() -> Error {
+        Error::CommandFailed {
+            stack: "web".to_string(),
+            command: "pull".to_string(),
+            status: ExitStatus::from_raw(1 << 8),
+        }
+    }
+
+    #[test]
+    fn policy() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                web:
+                    retries: 2
+                db: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let web = config.stacks.get("web").unwrap();
+        let db = config.stacks.get("db").unwrap();
+
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.retries("pull", web), 2);
+        assert_eq!(policy.retries("pull", db), 0);
+        assert_eq!(policy.retries("up", web), 0);
+
+        let policy = RetryPolicy {
+            retries: Some(5),
+            all: true,
+            ..Default::default()
+        };
+        assert_eq!(policy.retries("pull", web), 5);
+        assert_eq!(policy.retries("up", db), 5);
+
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(4), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn backoff() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let web = config.stacks.get("web").unwrap();
+        let policy = RetryPolicy {
+            retries: Some(3),
+            ..Default::default()
+        };
+
+        // Succeeds on the third attempt.
+        let attempts = Cell::new(0);
+        let mut delays = Vec::new();
+        policy
+            .run(
+                "pull",
+                web,
+                |d| {
+                    delays.push(d);
+                    true
+                },
+                || {
+                    attempts.set(attempts.get() + 1);
+                    if attempts.get() < 3 {
+                        Err(failure())
+                    } else {
+                        Ok(())
+                    }
+                },
+            )
+            .unwrap();
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(delays, vec![Duration::from_secs(1), Duration::from_secs(2)]);
+
+        // Gives up after the retries are exhausted.
+        let attempts = Cell::new(0);
+        let error = policy
+            .run(
+                "pull",
+                web,
+                |_| true,
+                || {
+                    attempts.set(attempts.get() + 1);
+                    Err(failure())
+                },
+            )
+            .unwrap_err();
+        assert!(matches!(error, Error::CommandFailed { .. }));
+        assert_eq!(attempts.get(), 4);
+
+        // Stops retrying when cancelled.
+        let attempts = Cell::new(0);
+        policy
+            .run(
+                "pull",
+                web,
+                |_| false,
+                || {
+                    attempts.set(attempts.get() + 1);
+                    Err(failure())
+                },
+            )
+            .unwrap_err();
+        assert_eq!(attempts.get(), 1);
+
+        // Other errors are not retried.
+        let attempts = Cell::new(0);
+        policy
+            .run(
+                "pull",
+                web,
+                |_| true,
+                || {
+                    attempts.set(attempts.get() + 1);
+                    Err(Error::Invalid("bad".to_string()))
+                },
+            )
+            .unwrap_err();
+        assert_eq!(attempts.get(), 1);
+    }
+}
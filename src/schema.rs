@@ -0,0 +1,429 @@
+use serde_json::{json, Value};
+
+use crate::task::TASK_COMMANDS;
+
+/// A string or a non-empty list of strings, as accepted for `file` and
+/// `env_file`.
+fn files() -> Value {
+    json!({
+        "oneOf": [
+            { "type": "string" },
+            { "type": "array", "items": { "type": "string" }, "minItems": 1 }
+        ]
+    })
+}
+
+/// A command given as a string split on spaces or as a list of arguments.
+fn command() -> Value {
+    json!({
+        "oneOf": [
+            { "type": "string" },
+            { "type": "array", "items": { "type": "string" } }
+        ]
+    })
+}
+
+/// Whole seconds or a string like `30s`, `5m` or `1h`.
+fn duration() -> Value {
+    json!({
+        "oneOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string" }
+        ]
+    })
+}
+
+fn strings() -> Value {
+    json!({ "type": "array", "items": { "type": "string" } })
+}
+
+/// Variable values are plain YAML scalars.
+fn environment() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": { "type": ["string", "number", "boolean"] }
+    })
+}
+
+fn backend() -> Value {
+    json!({ "enum": ["compose", "swarm"] })
+}
+
+fn stack() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "type": {
+                "description": "What manages the stack.",
+                "enum": ["compose", "script"]
+            },
+            "up": { "description": "Brings a script stack up.", "type": "string" },
+            "down": { "description": "Takes a script stack down.", "type": "string" },
+            "status": {
+                "description": "Exits successfully when a script stack is running.",
+                "type": "string"
+            },
+            "logs": { "description": "Shows a script stack's logs.", "type": "string" },
+            "name": { "description": "The compose project name.", "type": "string" },
+            "description": { "type": "string" },
+            "directory": {
+                "description": "The stack's directory relative to the config.",
+                "type": "string"
+            },
+            "file": files(),
+            "url": files(),
+            "depends_on": strings(),
+            "environment": environment(),
+            "env_file": files(),
+            "provides": {
+                "description": "Variables given to the stacks that depend on this one.",
+                "type": "object",
+                "additionalProperties": {
+                    "oneOf": [
+                        { "type": ["string", "number", "boolean"] },
+                        {
+                            "description": "The host port a service's port is published on.",
+                            "type": "object",
+                            "additionalProperties": false,
+                            "required": ["service", "port"],
+                            "properties": {
+                                "service": { "type": "string" },
+                                "port": { "type": "integer", "minimum": 1, "maximum": 65535 },
+                                "protocol": { "type": "string" }
+                            }
+                        }
+                    ]
+                }
+            },
+            "provides_prefix": {
+                "description": "The prefix for the provided variables, the key in upper case by default.",
+                "type": "string"
+            },
+            "pass_env": strings(),
+            "allow_outside_base": { "type": "boolean" },
+            "retries": { "type": "integer", "minimum": 0 },
+            "backend": backend(),
+            "stop_grace": duration(),
+            "concurrency_group": {
+                "description": "Stacks in the same group never run at the same time.",
+                "type": "string"
+            },
+            "verify_up": {
+                "description": "Whether to check that services came up after `up`.",
+                "type": "boolean"
+            },
+            "remove_orphans": {
+                "description": "Whether `down` removes containers for services no longer in the compose file.",
+                "type": "boolean"
+            },
+            "autostart": {
+                "description": "Whether the stack is started when it isn't named, such as by a bare `up`.",
+                "type": "boolean"
+            }
+        }
+    })
+}
+
+/// A step of a task, either a stack command or a host command.
+fn step() -> Value {
+    json!({
+        "oneOf": [
+            {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "command": { "enum": TASK_COMMANDS },
+                    "stacks": strings(),
+                    "args": strings()
+                }
+            },
+            {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "run": {
+                        "description": "A shell command to run in the stack's directory.",
+                        "type": "string"
+                    },
+                    "stack": { "type": "string" }
+                }
+            }
+        ]
+    })
+}
+
+/// A task's steps, optionally with a description.
+fn task() -> Value {
+    let steps = json!({ "type": "array", "items": { "$ref": "#/$defs/step" } });
+    json!({
+        "oneOf": [
+            steps,
+            {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "description": { "type": "string" },
+                    "steps": steps
+                }
+            }
+        ]
+    })
+}
+
+/// The JSON Schema for the stacks config. The `$id` includes the version of
+/// stacks that the schema describes.
+pub fn schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": format!("urn:stacks:config:{}", env!("CARGO_PKG_VERSION")),
+        "title": "stacks.yml",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "command": command(),
+            "base_dir": {
+                "description": "The directory that stack directories are relative to.",
+                "type": "string"
+            },
+            "engine": { "enum": ["docker", "podman"] },
+            "stacks": {
+                "type": "object",
+                "additionalProperties": { "$ref": "#/$defs/stack" }
+            },
+            "backend": backend(),
+            "environment": environment(),
+            "env_file": files(),
+            "inherit_env": { "type": "boolean" },
+            "pass_env": strings(),
+            "env_allowlist": strings(),
+            "warn_missing_env": { "type": "boolean" },
+            "default_stacks": strings(),
+            "kill_grace": { "type": "integer", "minimum": 0 },
+            "stop_grace": duration(),
+            "min_compose_version": { "type": "string" },
+            "history_file": { "type": "string" },
+            "history_max_size": { "type": "integer", "minimum": 0 },
+            "log_dir": { "type": "string" },
+            "notify": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "enabled": { "type": "boolean" },
+                    "threshold": { "type": "string" }
+                }
+            },
+            "verify_up": { "type": "boolean" },
+            "verify_timeout": duration(),
+            "create_dirs": { "type": "boolean" },
+            "frozen": {
+                "description": "Whether `up` uses the images pinned by `stack freeze`.",
+                "type": "boolean"
+            },
+            "orphan_ignore": strings(),
+            "remove_orphans": { "type": "boolean" },
+            "tasks": {
+                "description": "Named sequences of steps run by `stack task`.",
+                "type": "object",
+                "additionalProperties": { "$ref": "#/$defs/task" }
+            }
+        },
+        "$defs": {
+            "stack": stack(),
+            "task": task(),
+            "step": step()
+        }
+    })
+}
+
+/// The schema as pretty printed JSON.
+pub fn render() -> String {
+    serde_json::to_string_pretty(&schema()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::schema;
+    use crate::config::Config;
+    use serde_json::Value;
+    use std::path::PathBuf;
+
+    /// Validates a config against the schema, returning where the first
+    /// problem is and what it is.
+    fn check(yaml: &str) -> Result<(), String> {
+        let instance: Value = serde_yaml::from_str(yaml).unwrap();
+        let validator = jsonschema::validator_for(&schema()).unwrap();
+        validator
+            .validate(&instance)
+            .map_err(|e| format!("{}: {}", e.instance_path, e))
+    }
+
+    /// The configs used in the README and tests.
+    fn fixtures() -> Vec<String> {
+        let readme = include_str!("../README.md");
+        let mut fixtures: Vec<String> = readme
+            .split("```yaml\n")
+            .skip(1)
+            .map(|block| block.split("```").next().unwrap().to_string())
+            .collect();
+        assert!(fixtures.len() >= 3);
+
+        fixtures.extend(
+            [
+                "stacks: {}",
+                "
+                command: /usr/local/bin/docker compose
+                stacks:
+                    foo:
+                        name: baz
+                        depends_on: [bar]
+                    bar:
+                        file: [a.yml, b.yml]
+                        directory: services/bar
+                ",
+                "
+                command: [podman, compose]
+                base_dir: services
+                engine: podman
+                backend: swarm
+                environment:
+                    PORT: 8080
+                    DEBUG: true
+                    NAME: web
+                env_file: global.env
+                inherit_env: false
+                env_allowlist: [HOME, LC_*]
+                warn_missing_env: true
+                default_stacks: [web]
+                kill_grace: 5
+                stop_grace: 2m
+                min_compose_version: 2.20.0
+                history_file: history.log
+                history_max_size: 1000
+                log_dir: logs
+                verify_up: true
+                verify_timeout: 2m
+                create_dirs: true
+                frozen: true
+                orphan_ignore: [scratch-.*]
+                remove_orphans: true
+                notify:
+                    enabled: true
+                    threshold: 1m
+                stacks:
+                    web:
+                        description: The website
+                        url: http://localhost:${WEB_PORT}
+                        env_file: [web.env]
+                        pass_env: [USER]
+                        allow_outside_base: true
+                        retries: 3
+                        backend: compose
+                        stop_grace: 30
+                        concurrency_group: frontends
+                        verify_up: false
+                        remove_orphans: false
+                        autostart: false
+                        provides_prefix: WEBSITE
+                        provides:
+                            URL: http://localhost:8080
+                            PORT: 8080
+                            PUBLISHED_PORT: { service: app, port: 80, protocol: tcp }
+                    agent:
+                        type: script
+                        up: ./agent start
+                        down: ./agent stop
+                        status: ./agent running
+                        logs: ./agent logs
+                tasks:
+                    reset:
+                        description: Start again
+                        steps:
+                            - { command: down, stacks: [web], args: [-v] }
+                            - { run: ./agent reset, stack: agent }
+                    restart:
+                        - { command: restart, stacks: [web] }
+                ",
+            ]
+            .map(str::to_string),
+        );
+
+        fixtures
+    }
+
+    #[test]
+    fn fixtures_validate() {
+        for fixture in fixtures() {
+            // Every fixture is accepted by the config parser as well.
+            let parsed: Result<Config, _> = serde_yaml::from_str(&fixture);
+            assert!(parsed.is_ok(), "{}", fixture);
+            if let Err(e) = check(&fixture) {
+                panic!("{} in\n{}", e, fixture);
+            }
+        }
+    }
+
+    #[test]
+    fn serialized_configs_validate() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            command: docker compose
+            stop_grace: 10
+            stacks:
+                web:
+                    file: compose.yml
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        check(&serde_yaml::to_string(&config).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            check("stacks:\n  web:\n    depend_on: [db]").unwrap_err(),
+            "/stacks/web: Additional properties are not allowed ('depend_on' was unexpected)"
+        );
+        assert_eq!(
+            check("comand: docker").unwrap_err(),
+            ": Additional properties are not allowed ('comand' was unexpected)"
+        );
+        assert!(check("engine: containerd").is_err());
+        assert!(check("stacks:\n  web:\n    file: []").is_err());
+        assert!(check("stacks:\n  web:\n    retries: -1").is_err());
+        assert!(check("stacks:\n  web:\n    type: helm").is_err());
+        assert!(check("tasks:\n  reset: [{ command: explode, stacks: [web] }]").is_err());
+        assert!(check("stacks:\n  web:\n    provides: { PORT: { service: app } }").is_err());
+        assert!(check("tasks:\n  reset: [{ run: make, stacks: [web] }]").is_err());
+
+        // These are rejected by the config parser too.
+        for yaml in [
+            "engine: containerd",
+            "stacks:\n  web:\n    file: []",
+            "stacks:\n  web:\n    type: helm",
+        ] {
+            assert!(serde_yaml::from_str::<Config>(yaml).is_err());
+        }
+    }
+
+    #[test]
+    fn valid_schema() {
+        // Building a validator checks the schema against its meta-schema.
+        if let Err(e) = jsonschema::validator_for(&schema()) {
+            panic!("{}: {}", e.instance_path, e);
+        }
+        assert!(jsonschema::validator_for(&serde_json::json!({ "type": "text" })).is_err());
+    }
+
+    #[test]
+    fn identified() {
+        let schema = schema();
+        assert_eq!(
+            schema["$id"],
+            format!("urn:stacks:config:{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(schema["$defs"]["stack"]["type"], "object");
+    }
+}
@@ -0,0 +1,522 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::error::{Error, Result};
+use crate::paths;
+
+/// The selection token that expands to the stacks used by the previous command.
+pub const LAST: &str = "@last";
+
+/// The selection tokens that expand to the stack containing the current
+/// directory.
+pub const CURRENT: [&str; 2] = [".", "./"];
+
+/// The environment variable that provides a default selection of stacks.
+pub const STACKS_VAR: &str = "STACKS_STACKS";
+
+/// Parses a comma separated list of stacks. An empty list or `*` means all
+/// stacks.
+pub fn parse(list: &str) -> Vec<&str> {
+    if list.is_empty() || list == "*" {
+        Vec::new()
+    } else {
+        list.split(',').collect()
+    }
+}
+
+/// Determines the selection of stacks to use. The first of these that is
+/// present is used:
+///
+/// 1. The selection given on the command line.
+/// 2. The `STACKS_STACKS` environment variable.
+/// 3. The `default_stacks` from the config file.
+/// 4. All stacks.
+///
+/// An empty result means all stacks.
+pub fn resolve(positional: Option<&str>, env: Option<&str>, defaults: &[String]) -> Vec<String> {
+    let list = match (positional, env) {
+        (Some(list), _) => parse(list),
+        (None, Some(list)) if !list.is_empty() => parse(list),
+        _ => return defaults.to_vec(),
+    };
+
+    list.into_iter().map(|s| s.to_owned()).collect()
+}
+
+/// Parses a `--select-regex` pattern, failing with the regex's error.
+pub fn parse_regex(pattern: &str) -> std::result::Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+/// Determines the selection of stacks from the names given, as `resolve`
+/// does, and the `--select-regex` patterns. Without patterns this is just
+/// `resolve`. With them:
+///
+/// 1. Names given on the command line are selected, `*` adds nothing. The
+///    environment and config defaults are not used.
+/// 2. The keys of the stacks that match any pattern are added, in key order,
+///    skipping any already selected.
+/// 3. Later, `@last` is expanded by `expand_last` and then each command adds
+///    dependencies or dependants as it would for stacks selected by name.
+///
+/// Every pattern must match at least one stack.
+pub fn select(
+    positional: Option<&str>,
+    env: Option<&str>,
+    defaults: &[String],
+    patterns: &[Regex],
+    keys: &[&str],
+) -> Result<Vec<String>> {
+    if patterns.is_empty() {
+        return Ok(resolve(positional, env, defaults));
+    }
+
+    let mut selection: Vec<String> = positional
+        .map(parse)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|s| s.to_owned())
+        .collect();
+
+    for pattern in patterns {
+        let mut matched = false;
+        for key in keys.iter().filter(|key| pattern.is_match(key)) {
+            matched = true;
+            if !selection.iter().any(|selected| selected == key) {
+                selection.push((*key).to_owned());
+            }
+        }
+
+        if !matched {
+            return Err(Error::Invalid(format!(
+                "The pattern `{}` given to --select-regex matches no stacks.",
+                pattern
+            )));
+        }
+    }
+
+    Ok(selection)
+}
+
+/// A stable hash of a string used to generate file names and detect changes.
+/// The standard library hashers are not guaranteed to be stable across releases
+/// so this is FNV-1a.
+pub fn hash(s: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in s.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Stores the most recently used stack selection for each stacks config file.
+pub struct SelectionStore {
+    dir: PathBuf,
+}
+
+impl SelectionStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// Finds the user's cache directory, if there is one.
+    pub fn from_env() -> Option<Self> {
+        Some(Self::new(&paths::cache_dir()?))
+    }
+
+    fn path(&self, config_file: &Path) -> PathBuf {
+        self.dir.join(format!(
+            "{:016x}.last",
+            hash(&config_file.to_string_lossy())
+        ))
+    }
+
+    /// Loads the last selection used with the given config file.
+    pub fn load(&self, config_file: &Path) -> Result<Option<Vec<String>>> {
+        let path = self.path(config_file);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| Error::io(format!("Failed to read {}", path.display()), e))?;
+        let mut lines = content.lines();
+
+        // The first line records the config file in case of hash collisions.
+        if lines.next() != Some(&config_file.to_string_lossy()) {
+            return Ok(None);
+        }
+
+        Ok(Some(lines.map(|l| l.to_owned()).collect()))
+    }
+
+    /// Records the selection used with the given config file.
+    pub fn save<S: AsRef<str>>(&self, config_file: &Path, selection: &[S]) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| Error::io(format!("Failed to create {}", self.dir.display()), e))?;
+
+        let mut content = config_file.to_string_lossy().to_string();
+        for key in selection {
+            content.push('\n');
+            content.push_str(key.as_ref());
+        }
+
+        let path = self.path(config_file);
+        fs::write(&path, content)
+            .map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))
+    }
+}
+
+/// Expands any `@last` tokens in the selection to the previously used stacks.
+/// The previous selection is only loaded if needed.
+pub fn expand_last<S, F>(selection: &[S], load_last: F) -> Result<Vec<String>>
+where
+    S: AsRef<str>,
+    F: FnOnce() -> Result<Option<Vec<String>>>,
+{
+    let mut load_last = Some(load_last);
+    let mut expanded: Vec<String> = Vec::new();
+
+    for key in selection {
+        let key = key.as_ref();
+
+        let keys = if key == LAST {
+            let load = match load_last.take() {
+                Some(load) => load,
+                // Already expanded once.
+                None => continue,
+            };
+
+            load()?.ok_or_else(|| {
+                Error::Invalid(format!(
+                    "There is no previous selection of stacks, \"{}\" can only be used after running a command against specific stacks.",
+                    LAST
+                ))
+            })?
+        } else {
+            vec![key.to_owned()]
+        };
+
+        for key in keys {
+            if !expanded.contains(&key) {
+                expanded.push(key);
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Finds the stack whose directory contains `cwd`, the deepest one when
+/// stack directories are nested. Both should already be canonical.
+pub fn containing_stack(cwd: &Path, directories: &[(String, PathBuf)]) -> Result<String> {
+    let containing: Vec<&(String, PathBuf)> = directories
+        .iter()
+        .filter(|(_, dir)| cwd.starts_with(dir))
+        .collect();
+    let depth = containing
+        .iter()
+        .map(|(_, dir)| dir.components().count())
+        .max();
+
+    let deepest: Vec<&str> = containing
+        .iter()
+        .filter(|(_, dir)| Some(dir.components().count()) == depth)
+        .map(|(key, _)| key.as_str())
+        .collect();
+
+    match deepest.as_slice() {
+        [key] => Ok((*key).to_owned()),
+        [] => Err(Error::Invalid(format!(
+            "The current directory {} is not inside any stack's directory, \".\" can only be used from within a stack.",
+            cwd.display()
+        ))),
+        keys => Err(Error::Invalid(format!(
+            "The current directory {} is inside the directories of the stacks {}, select one of them by name.",
+            cwd.display(),
+            keys.join(", ")
+        ))),
+    }
+}
+
+/// Expands any `.` tokens in the selection to the stack containing `cwd`.
+/// The stacks' canonical directories are only found if needed.
+pub fn expand_current<S, F>(selection: &[S], cwd: &Path, directories: F) -> Result<Vec<String>>
+where
+    S: AsRef<str>,
+    F: FnOnce() -> Vec<(String, PathBuf)>,
+{
+    let mut directories = Some(directories);
+    let mut current = String::new();
+    let mut expanded: Vec<String> = Vec::new();
+
+    for key in selection {
+        let mut key = key.as_ref();
+
+        if CURRENT.contains(&key) {
+            // Only found the first time.
+            if let Some(directories) = directories.take() {
+                let cwd = paths::canonicalize(cwd)
+                    .map_err(|e| Error::io(format!("Failed to resolve {}", cwd.display()), e))?;
+                current = containing_stack(&cwd, &directories())?;
+            }
+            key = &current;
+        }
+
+        let key = key.to_owned();
+        if !expanded.contains(&key) {
+            expanded.push(key);
+        }
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        containing_stack, expand_current, expand_last, parse_regex, resolve, select, SelectionStore,
+    };
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn list(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn precedence() {
+        let defaults = list(&["db"]);
+
+        // Nothing set means all stacks.
+        assert_eq!(resolve(None, None, &[]), list(&[]));
+
+        // Config defaults.
+        assert_eq!(resolve(None, None, &defaults), list(&["db"]));
+
+        // The environment overrides the config.
+        assert_eq!(
+            resolve(None, Some("web,api"), &defaults),
+            list(&["web", "api"])
+        );
+        assert_eq!(resolve(None, Some("*"), &defaults), list(&[]));
+        // An empty variable is treated as unset.
+        assert_eq!(resolve(None, Some(""), &defaults), list(&["db"]));
+
+        // The command line overrides everything.
+        assert_eq!(
+            resolve(Some("api"), Some("web,api"), &defaults),
+            list(&["api"])
+        );
+        assert_eq!(resolve(Some("*"), Some("web,api"), &defaults), list(&[]));
+        assert_eq!(resolve(Some(""), None, &defaults), list(&[]));
+    }
+
+    #[test]
+    fn regex() {
+        let keys = [
+            "db",
+            "team-a-api",
+            "team-a-web",
+            "team-a-worker",
+            "team-b-api",
+        ];
+        let patterns = |list: &[&str]| -> Vec<regex::Regex> {
+            list.iter().map(|p| parse_regex(p).unwrap()).collect()
+        };
+        let defaults = list(&["db"]);
+
+        // Without patterns names resolve as usual.
+        assert_eq!(
+            select(None, Some("web"), &defaults, &[], &keys).unwrap(),
+            list(&["web"])
+        );
+
+        // Only the matching stacks are selected, not the defaults.
+        assert_eq!(
+            select(
+                None,
+                Some("db"),
+                &defaults,
+                &patterns(&["^team-a-.*(api|worker)$"]),
+                &keys
+            )
+            .unwrap(),
+            list(&["team-a-api", "team-a-worker"])
+        );
+
+        // Patterns add to names and each other.
+        assert_eq!(
+            select(
+                Some("db,team-a-web"),
+                None,
+                &defaults,
+                &patterns(&["api$", "^team-a-"]),
+                &keys
+            )
+            .unwrap(),
+            list(&[
+                "db",
+                "team-a-web",
+                "team-a-api",
+                "team-b-api",
+                "team-a-worker"
+            ])
+        );
+        assert_eq!(
+            select(Some("*"), None, &defaults, &patterns(&["^db$"]), &keys).unwrap(),
+            list(&["db"])
+        );
+        assert_eq!(
+            select(Some("@last"), None, &defaults, &patterns(&["^db$"]), &keys).unwrap(),
+            list(&["@last", "db"])
+        );
+
+        assert_eq!(
+            select(None, None, &defaults, &patterns(&["db", "^team-c"]), &keys)
+                .unwrap_err()
+                .to_string(),
+            "The pattern `^team-c` given to --select-regex matches no stacks."
+        );
+        assert!(parse_regex("team-(a")
+            .unwrap_err()
+            .contains("unclosed group"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let store = SelectionStore::new(&temp.path().join("cache"));
+        let config = Path::new("/projects/foo/stacks.yml");
+
+        assert_eq!(store.load(config).unwrap(), None);
+
+        store.save(config, &["web", "db"]).unwrap();
+        assert_eq!(
+            store.load(config).unwrap(),
+            Some(vec!["web".to_string(), "db".to_string()])
+        );
+
+        store.save(config, &["api"]).unwrap();
+        assert_eq!(store.load(config).unwrap(), Some(vec!["api".to_string()]));
+    }
+
+    #[test]
+    fn keyed_by_config() {
+        let temp = tempfile::tempdir().unwrap();
+        let store = SelectionStore::new(temp.path());
+        let first = Path::new("/projects/foo/stacks.yml");
+        let second = Path::new("/projects/bar/stacks.yml");
+
+        store.save(first, &["web"]).unwrap();
+        assert_eq!(store.load(second).unwrap(), None);
+
+        store.save(second, &["db"]).unwrap();
+        assert_eq!(store.load(first).unwrap(), Some(vec!["web".to_string()]));
+        assert_eq!(store.load(second).unwrap(), Some(vec!["db".to_string()]));
+    }
+
+    #[test]
+    fn expansion() {
+        let last = || Ok(Some(vec!["web".to_string(), "db".to_string()]));
+
+        assert_eq!(
+            expand_last(&["api"], || panic!("Should not be loaded")).unwrap(),
+            vec!["api".to_string()]
+        );
+        assert_eq!(
+            expand_last(&["@last"], last).unwrap(),
+            vec!["web".to_string(), "db".to_string()]
+        );
+        assert_eq!(
+            expand_last(&["api", "@last", "web"], last).unwrap(),
+            vec!["api".to_string(), "web".to_string(), "db".to_string()]
+        );
+        assert_eq!(
+            expand_last(&["@last", "@last"], last).unwrap(),
+            vec!["web".to_string(), "db".to_string()]
+        );
+
+        let error = expand_last(&["api", "@last"], || Ok(None))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("There is no previous selection of stacks"));
+    }
+
+    #[test]
+    fn current() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        for dir in ["api/src", "db", "tools/lint/rules", "shared"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let directories = || -> Vec<(String, PathBuf)> {
+            [
+                ("api", "api"),
+                ("db", "db"),
+                ("tools", "tools"),
+                ("lint", "tools/lint"),
+                ("shared-a", "shared"),
+                ("shared-b", "shared"),
+            ]
+            .iter()
+            .map(|(key, dir)| (key.to_string(), root.join(dir)))
+            .collect()
+        };
+        let expand = |selection: &[&str], cwd: &str| {
+            expand_current(selection, &root.join(cwd), directories).map_err(|e| e.to_string())
+        };
+
+        // Inside a stack, at any depth.
+        assert_eq!(expand(&["."], "api").unwrap(), list(&["api"]));
+        assert_eq!(
+            expand(&["./", "db"], "api/src").unwrap(),
+            list(&["api", "db"])
+        );
+        assert_eq!(
+            expand(&["api", ".", "./"], "api/src").unwrap(),
+            list(&["api"])
+        );
+        assert_eq!(
+            expand(&["db"], "").unwrap(),
+            list(&["db"]),
+            "Directories are not needed without `.`"
+        );
+
+        // Outside all stacks.
+        assert!(expand(&["."], "")
+            .unwrap_err()
+            .contains("is not inside any stack's directory"));
+        assert!(expand(&["."], "missing")
+            .unwrap_err()
+            .starts_with("Failed to resolve"));
+
+        // Nested stacks pick the deepest.
+        assert_eq!(expand(&["."], "tools").unwrap(), list(&["tools"]));
+        assert_eq!(expand(&["."], "tools/lint/rules").unwrap(), list(&["lint"]));
+
+        // Stacks sharing a directory can't be told apart.
+        assert_eq!(
+            containing_stack(&root.join("shared"), &directories())
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "The current directory {} is inside the directories of the stacks shared-a, \
+                shared-b, select one of them by name.",
+                root.join("shared").display()
+            )
+        );
+
+        // Directories are compared by component, not as strings.
+        assert!(containing_stack(
+            Path::new("/stacks/apiary"),
+            &[("api".to_string(), PathBuf::from("/stacks/api"))]
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,373 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::selection::hash;
+
+/// The directory next to the stacks config that holds the state file.
+pub const STATE_DIR: &str = ".stack";
+
+/// What was last deployed for a stack.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StackState {
+    /// The hash of the rendered compose config and stack environment.
+    pub hash: Option<String>,
+    /// When the stack was last brought up successfully, in seconds since the
+    /// epoch.
+    pub last_up: Option<u64>,
+    /// When the stack was last brought down successfully.
+    pub last_down: Option<u64>,
+    /// The version of stacks that last updated the stack.
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub stacks: BTreeMap<String, StackState>,
+}
+
+/// The current time in seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Converts seconds since the epoch to the UTC year, month, day, hour, minute
+/// and second.
+pub fn utc(timestamp: u64) -> [u64; 6] {
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+
+    // From Howard Hinnant's `civil_from_days`, days are never before the epoch.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    [
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    ]
+}
+
+/// Formats seconds since the epoch as an RFC 3339 UTC date and time.
+pub fn rfc3339(timestamp: u64) -> String {
+    let [year, month, day, hour, minute, second] = utc(timestamp);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Hashes a stack's rendered compose config and environment. The config is
+/// normalised so that the order of keys does not matter.
+pub fn config_hash<V: AsRef<OsStr>>(
+    rendered: &str,
+    environment: &HashMap<String, V>,
+) -> Result<String> {
+    // JSON objects keep their keys sorted.
+    let config: serde_json::Value = serde_yaml::from_str(rendered)?;
+    let environment: BTreeMap<&String, Cow<str>> = environment
+        .iter()
+        .map(|(key, value)| (key, value.as_ref().to_string_lossy()))
+        .collect();
+
+    let content = serde_json::json!({
+        "config": config,
+        "environment": environment,
+    });
+
+    Ok(format!("{:016x}", hash(&content.to_string())))
+}
+
+/// Holds an exclusive lock on a file under the state directory until dropped.
+pub(crate) struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    #[cfg(unix)]
+    pub(crate) fn acquire(path: &Path) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        // SAFETY: the descriptor is valid for the lifetime of the file.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { _file: file })
+    }
+
+    /// Without flock concurrent updates may lose each other's changes but the
+    /// file itself is never left half written.
+    #[cfg(not(unix))]
+    pub(crate) fn acquire(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// The state file recording what was last deployed for each stack.
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// The state file for the given stacks config file.
+    pub fn for_config(stacks_file: &Path) -> Self {
+        let dir = stacks_file.parent().unwrap_or(Path::new("."));
+        Self::new(dir.join(STATE_DIR).join("state.json"))
+    }
+
+    pub fn load(&self) -> Result<State> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| {
+                Error::Invalid(format!("Invalid state file {}: {}", self.path.display(), e))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(Error::io(
+                format!("Failed to read {}", self.path.display()),
+                e,
+            )),
+        }
+    }
+
+    /// Applies a change to the state. The update holds a lock so concurrent
+    /// updates do not lose changes and the new state is written to a temporary
+    /// file and renamed into place so readers never see a partial file.
+    pub fn update<F: FnOnce(&mut State)>(&self, f: F) -> Result<()> {
+        let dir = self.path.parent().unwrap();
+        let io_error = |e| Error::io(format!("Failed to update {}", self.path.display()), e);
+        fs::create_dir_all(dir).map_err(io_error)?;
+
+        let _lock = Lock::acquire(&self.path.with_extension("lock")).map_err(io_error)?;
+        let mut state = self.load()?;
+        f(&mut state);
+
+        let temp = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        let content = serde_json::to_string_pretty(&state)
+            .map_err(|e| Error::Invalid(format!("Failed to serialize state: {}", e)))?;
+        File::create(&temp)
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                file.sync_all()
+            })
+            .map_err(io_error)?;
+        fs::rename(&temp, &self.path).map_err(io_error)
+    }
+
+    /// Records a successful `up` of a stack.
+    pub fn record_up(&self, stack: &str, hash: Option<String>) -> Result<()> {
+        self.update(|state| {
+            let entry = state.stacks.entry(stack.to_owned()).or_default();
+            entry.hash = hash;
+            entry.last_up = Some(now());
+            entry.version = env!("CARGO_PKG_VERSION").to_owned();
+        })
+    }
+
+    /// Where the rendered compose config of the stack's last `up` is kept.
+    fn rendered_path(&self, stack: &str) -> PathBuf {
+        let dir = self.path.parent().unwrap();
+        dir.join("rendered").join(format!("{}.yml", stack))
+    }
+
+    /// Keeps the rendered compose config of a stack that was brought up so
+    /// later changes can be shown.
+    pub fn record_rendered(&self, stack: &str, rendered: &str) -> Result<()> {
+        let path = self.rendered_path(stack);
+        let io_error = |e| Error::io(format!("Failed to write {}", path.display()), e);
+        fs::create_dir_all(path.parent().unwrap()).map_err(io_error)?;
+
+        let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&temp, rendered).map_err(io_error)?;
+        fs::rename(&temp, &path).map_err(io_error)
+    }
+
+    /// The rendered compose config recorded when the stack was last brought
+    /// up, if there is one.
+    pub fn rendered(&self, stack: &str) -> Result<Option<String>> {
+        let path = self.rendered_path(stack);
+        match fs::read_to_string(&path) {
+            Ok(rendered) => Ok(Some(rendered)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::io(format!("Failed to read {}", path.display()), e)),
+        }
+    }
+
+    /// Records a successful `down` of a stack.
+    pub fn record_down(&self, stack: &str) -> Result<()> {
+        self.update(|state| {
+            let entry = state.stacks.entry(stack.to_owned()).or_default();
+            entry.last_down = Some(now());
+            entry.version = env!("CARGO_PKG_VERSION").to_owned();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config_hash, rfc3339, StackState, State, StateFile};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn serialization() {
+        let mut state = State::default();
+        state.stacks.insert(
+            "web".to_string(),
+            StackState {
+                hash: Some("abc".to_string()),
+                last_up: Some(100),
+                last_down: None,
+                version: "1.0.0".to_string(),
+            },
+        );
+
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            json,
+            r#"{"stacks":{"web":{"hash":"abc","last_up":100,"last_down":null,"version":"1.0.0"}}}"#
+        );
+        assert_eq!(serde_json::from_str::<State>(&json).unwrap(), state);
+        assert_eq!(
+            serde_json::from_str::<State>("{}").unwrap(),
+            State::default()
+        );
+    }
+
+    #[test]
+    fn updates() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = StateFile::for_config(&temp.path().join("stacks.yml"));
+        assert_eq!(file.load().unwrap(), State::default());
+
+        file.record_up("web", Some("abc".to_string())).unwrap();
+        let state = file.load().unwrap();
+        let web = state.stacks.get("web").unwrap();
+        assert_eq!(web.hash.as_deref(), Some("abc"));
+        assert!(web.last_up.is_some());
+        assert_eq!(web.version, env!("CARGO_PKG_VERSION"));
+
+        file.record_down("web").unwrap();
+        let web = file.load().unwrap().stacks.get("web").unwrap().clone();
+        assert!(web.last_down.is_some());
+        assert_eq!(web.hash.as_deref(), Some("abc"));
+
+        // Concurrent updates are all kept.
+        thread::scope(|scope| {
+            for index in 0..10 {
+                let file = file.clone();
+                scope.spawn(move || {
+                    file.record_up(&format!("stack{}", index), None).unwrap();
+                });
+            }
+        });
+        let state = file.load().unwrap();
+        assert_eq!(state.stacks.len(), 11);
+
+        assert_eq!(file.rendered("web").unwrap(), None);
+        file.record_rendered("web", "services: {}\n").unwrap();
+        assert_eq!(
+            file.rendered("web").unwrap().as_deref(),
+            Some("services: {}\n")
+        );
+
+        // No temporary files are left behind.
+        let mut entries: Vec<String> = fs::read_dir(temp.path().join(".stack"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec!["rendered", "state.json", "state.lock"]);
+    }
+
+    #[test]
+    fn hashes() {
+        let first = "
+services:
+  web:
+    image: nginx
+    ports:
+      - 80:80
+  db:
+    image: postgres
+";
+        let reordered = "
+services:
+  db:
+    image: postgres
+  web:
+    ports:
+      - 80:80
+    image: nginx
+";
+        let changed = "
+services:
+  web:
+    image: nginx
+    ports:
+      - 8080:80
+  db:
+    image: postgres
+";
+
+        let mut environment = HashMap::new();
+        environment.insert("A".to_string(), "1".to_string());
+        environment.insert("B".to_string(), "2".to_string());
+        let mut other = HashMap::new();
+        other.insert("B".to_string(), "2".to_string());
+        other.insert("A".to_string(), "1".to_string());
+
+        let hash = config_hash(first, &environment).unwrap();
+        assert_eq!(hash.len(), 16);
+        assert_eq!(config_hash(reordered, &other).unwrap(), hash);
+        assert_ne!(config_hash(changed, &environment).unwrap(), hash);
+        other.insert("A".to_string(), "3".to_string());
+        assert_ne!(config_hash(first, &other).unwrap(), hash);
+    }
+}
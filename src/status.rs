@@ -0,0 +1,616 @@
+use std::fmt::Write;
+
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::state::StackState;
+
+/// A container as reported by `docker compose ps --format json`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Container {
+    #[serde(rename = "Service")]
+    pub service: String,
+    #[serde(rename = "State")]
+    pub state: String,
+    #[serde(rename = "Health", default)]
+    pub health: String,
+}
+
+impl Container {
+    /// Whether the container is running and, if it has a health check,
+    /// healthy.
+    pub fn is_up(&self) -> bool {
+        self.state == "running" && (self.health.is_empty() || self.health == "healthy")
+    }
+}
+
+/// Parses the output of `docker compose ps --format json`.
+pub fn parse_ps(output: &str) -> Result<Vec<Container>> {
+    parse_ps_as(output)
+}
+
+/// Parses the output of `docker compose ps --format json` into any type.
+/// Older versions of compose print a single array, newer versions print one
+/// object per line.
+pub fn parse_ps_as<T: DeserializeOwned>(output: &str) -> Result<Vec<T>> {
+    let invalid = |e: serde_json::Error| Error::Invalid(format!("Invalid ps output: {}", e));
+    let output = output.trim();
+
+    if output.starts_with('[') {
+        return serde_json::from_str(output).map_err(invalid);
+    }
+
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(invalid))
+        .collect()
+}
+
+/// The services that don't have a container or have a container that is not
+/// up.
+pub fn not_up<'a>(services: &'a [String], containers: &[Container]) -> Vec<&'a str> {
+    services
+        .iter()
+        .filter(|service| {
+            let mut containers = containers
+                .iter()
+                .filter(|c| &c.service == *service)
+                .peekable();
+            containers.peek().is_none() || !containers.all(Container::is_up)
+        })
+        .map(String::as_str)
+        .collect()
+}
+
+/// Whether every one of the services has a container that is up.
+pub fn all_up(services: &[String], containers: &[Container]) -> bool {
+    !services.is_empty() && not_up(services, containers).is_empty()
+}
+
+/// Whether a project has any containers at all, running or not.
+pub fn exists(containers: &[Container]) -> bool {
+    !containers.is_empty()
+}
+
+/// Whether a project has any containers and whether any of them are running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    /// There are no containers.
+    Missing,
+    /// There are containers but none are running.
+    Stopped,
+    Running,
+}
+
+impl Presence {
+    pub fn of(containers: &[Container]) -> Self {
+        if !exists(containers) {
+            Presence::Missing
+        } else if containers.iter().any(|c| c.state == "running") {
+            Presence::Running
+        } else {
+            Presence::Stopped
+        }
+    }
+}
+
+/// How much of a stack is running, for `list --state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    /// Every container is running and healthy.
+    Up,
+    /// Some containers are running, restarting or unhealthy.
+    Partial,
+    /// There are no containers or none of them are active.
+    Down,
+    /// The state could not be determined.
+    Unknown,
+}
+
+impl RunState {
+    pub fn of(containers: &[Container]) -> Self {
+        let active = |c: &Container| c.state == "running" || c.state == "restarting";
+
+        if !containers.is_empty() && containers.iter().all(Container::is_up) {
+            RunState::Up
+        } else if containers.iter().any(active) {
+            RunState::Partial
+        } else {
+            RunState::Down
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            RunState::Up => "up",
+            RunState::Partial => "partial",
+            RunState::Down => "down",
+            RunState::Unknown => "unknown",
+        }
+    }
+}
+
+/// Parses the output of `docker compose config --services`.
+pub fn parse_services(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// A project as reported by `docker compose ls --all --format json`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Project {
+    #[serde(rename = "Name")]
+    pub name: String,
+    /// Counts of containers in each state, e.g. `running(2), exited(1)`.
+    #[serde(rename = "Status", default)]
+    pub status: String,
+    /// The project's compose files, separated by commas.
+    #[serde(rename = "ConfigFiles", default)]
+    pub config_files: String,
+}
+
+/// Parses the output of `docker compose ls --format json`.
+pub fn parse_ls(output: &str) -> Result<Vec<Project>> {
+    let output = output.trim();
+    if output.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    serde_json::from_str(output).map_err(|e| Error::Invalid(format!("Invalid ls output: {}", e)))
+}
+
+/// The projects that don't belong to any of the known project names and
+/// aren't matched by one of the ignore patterns. Compose lowercases project
+/// names so they are compared without case.
+pub fn orphans<'a>(projects: &'a [Project], known: &[&str], ignore: &[Regex]) -> Vec<&'a Project> {
+    projects
+        .iter()
+        .filter(|project| {
+            !known
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&project.name))
+        })
+        .filter(|project| !ignore.iter().any(|pattern| pattern.is_match(&project.name)))
+        .collect()
+}
+
+/// Lists the projects that stacks doesn't manage.
+pub fn render_orphans(orphans: &[&Project]) -> String {
+    if orphans.is_empty() {
+        return "No unmanaged compose projects.\n".to_string();
+    }
+
+    let width = orphans
+        .iter()
+        .map(|project| project.name.chars().count())
+        .chain(["Project".len()])
+        .max()
+        .unwrap_or(0);
+    let status_width = orphans
+        .iter()
+        .map(|project| project.status.chars().count())
+        .chain(["Status".len()])
+        .max()
+        .unwrap_or(0);
+
+    let mut table = String::new();
+    let _ = writeln!(
+        table,
+        "{:<width$}  {:<status_width$}  Config files",
+        "Project", "Status"
+    );
+    for project in orphans {
+        let _ = writeln!(
+            table,
+            "{:<width$}  {:<status_width$}  {}",
+            project.name, project.status, project.config_files
+        );
+    }
+    table.push_str("Remove a project with `docker compose -p <project> down`.\n");
+
+    table
+}
+
+/// Describes how long ago something happened, e.g. "3 days ago".
+pub fn humanize(seconds: u64) -> String {
+    const UNITS: [(u64, &str); 6] = [
+        (365 * 24 * 60 * 60, "year"),
+        (30 * 24 * 60 * 60, "month"),
+        (7 * 24 * 60 * 60, "week"),
+        (24 * 60 * 60, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+    ];
+
+    match UNITS.iter().find(|(size, _)| seconds >= *size) {
+        Some((size, unit)) => {
+            let count = seconds / size;
+            let plural = if count == 1 { "" } else { "s" };
+            format!("{} {}{} ago", count, unit, plural)
+        }
+        None => "just now".to_string(),
+    }
+}
+
+/// Whether a stack's configuration has changed since it was last brought up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drift {
+    /// The configuration matches what was last deployed.
+    Current,
+    /// The configuration has changed since it was last deployed.
+    Changed,
+    /// Either hash is unavailable.
+    Unknown,
+}
+
+impl Drift {
+    pub fn compare(recorded: Option<&str>, current: Option<&str>) -> Self {
+        match (recorded, current) {
+            (Some(recorded), Some(current)) if recorded == current => Drift::Current,
+            (Some(_), Some(_)) => Drift::Changed,
+            _ => Drift::Unknown,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Drift::Current => "current",
+            Drift::Changed => "changed",
+            Drift::Unknown => "-",
+        }
+    }
+}
+
+/// What is known about a stack for the `status` command.
+#[derive(Debug, Clone)]
+pub struct StackStatus {
+    pub stack: String,
+    /// Whether every service is up, `None` if that could not be determined.
+    pub running: Option<bool>,
+    /// What was recorded in the state file, if anything.
+    pub recorded: Option<StackState>,
+    /// The hash of the stack's current configuration, if it could be computed.
+    pub hash: Option<String>,
+    /// The stack's URLs, with any variables expanded.
+    pub urls: Vec<String>,
+}
+
+impl StackStatus {
+    pub fn drift(&self) -> Drift {
+        Drift::compare(
+            self.recorded.as_ref().and_then(|r| r.hash.as_deref()),
+            self.hash.as_deref(),
+        )
+    }
+
+    fn last_up(&self, now: u64) -> String {
+        match self.recorded.as_ref().and_then(|r| r.last_up) {
+            Some(time) => humanize(now.saturating_sub(time)),
+            None => "never".to_string(),
+        }
+    }
+}
+
+/// Renders the status of the stacks as a table, `now` is in seconds since the
+/// epoch.
+pub fn render(statuses: &[StackStatus], now: u64) -> String {
+    let with_urls = statuses.iter().any(|status| !status.urls.is_empty());
+
+    let mut header = vec![
+        "Stack".to_string(),
+        "Running".to_string(),
+        "Last up".to_string(),
+        "Config".to_string(),
+    ];
+    if with_urls {
+        header.push("URL".to_string());
+    }
+    let rows: Vec<Vec<String>> = statuses
+        .iter()
+        .map(|status| {
+            let mut row = vec![
+                status.stack.clone(),
+                match status.running {
+                    Some(true) => "yes",
+                    Some(false) => "no",
+                    None => "unknown",
+                }
+                .to_string(),
+                status.last_up(now),
+                status.drift().label().to_string(),
+            ];
+            if with_urls {
+                row.push(status.urls.join(", "));
+            }
+            row
+        })
+        .collect();
+
+    let mut widths = vec![0; header.len()];
+    for row in [&header].into_iter().chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(rows.iter()) {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(widths.iter()) {
+            let _ = write!(line, "{:<width$}  ", cell, width = width);
+        }
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        all_up, exists, humanize, not_up, orphans, parse_ls, parse_ps, parse_services, render,
+        render_orphans, Drift, Presence, RunState, StackStatus,
+    };
+    use crate::state::StackState;
+    use regex::Regex;
+
+    #[test]
+    fn ps() {
+        let services = parse_services("web\ndb\n\n");
+        assert_eq!(services, vec!["web".to_string(), "db".to_string()]);
+
+        // Fully up, as an array from older compose versions.
+        let containers = parse_ps(
+            r#"[{"Name":"app-web-1","Service":"web","State":"running","Health":""},
+                {"Name":"app-db-1","Service":"db","State":"running","Health":"healthy"}]"#,
+        )
+        .unwrap();
+        assert_eq!(containers.len(), 2);
+        assert!(all_up(&services, &containers));
+
+        // Fully up, one object per line from newer versions.
+        let containers = parse_ps(
+            r#"{"Name":"app-web-1","Service":"web","State":"running"}
+{"Name":"app-db-1","Service":"db","State":"running","Health":"healthy"}
+"#,
+        )
+        .unwrap();
+        assert!(all_up(&services, &containers));
+
+        // Partially up.
+        let containers = parse_ps(
+            r#"{"Service":"web","State":"running","Health":""}
+{"Service":"db","State":"exited","Health":""}"#,
+        )
+        .unwrap();
+        assert!(!all_up(&services, &containers));
+        assert_eq!(not_up(&services, &containers), vec!["db"]);
+        let containers =
+            parse_ps(r#"{"Service":"db","State":"running","Health":"starting"}"#).unwrap();
+        assert!(!all_up(&services, &containers));
+        assert_eq!(not_up(&services, &containers), vec!["web", "db"]);
+        let containers = parse_ps(r#"{"Service":"web","State":"running"}"#).unwrap();
+        assert!(!all_up(&services, &containers));
+
+        // Absent.
+        let containers = parse_ps("").unwrap();
+        assert!(containers.is_empty());
+        assert!(!all_up(&services, &containers));
+        assert!(!all_up(&services, &parse_ps("[]").unwrap()));
+        assert!(!all_up(&[], &[]));
+
+        assert!(parse_ps("not json").is_err());
+    }
+
+    #[test]
+    fn existence() {
+        // Running.
+        let containers = parse_ps(r#"{"Service":"web","State":"running"}"#).unwrap();
+        assert!(exists(&containers));
+
+        // Stopped but created.
+        let containers = parse_ps(
+            r#"{"Service":"web","State":"exited"}
+{"Service":"db","State":"created"}"#,
+        )
+        .unwrap();
+        assert!(exists(&containers));
+        assert!(!all_up(&["web".to_string()], &containers));
+
+        // Absent.
+        assert!(!exists(&parse_ps("").unwrap()));
+        assert!(!exists(&parse_ps("[]").unwrap()));
+    }
+
+    #[test]
+    fn humanized() {
+        assert_eq!(humanize(0), "just now");
+        assert_eq!(humanize(59), "just now");
+        assert_eq!(humanize(60), "1 minute ago");
+        assert_eq!(humanize(150), "2 minutes ago");
+        assert_eq!(humanize(60 * 60), "1 hour ago");
+        assert_eq!(humanize(3 * 24 * 60 * 60 + 5), "3 days ago");
+        assert_eq!(humanize(10 * 24 * 60 * 60), "1 week ago");
+        assert_eq!(humanize(65 * 24 * 60 * 60), "2 months ago");
+        assert_eq!(humanize(800 * 24 * 60 * 60), "2 years ago");
+    }
+
+    #[test]
+    fn drift() {
+        assert_eq!(Drift::compare(Some("abc"), Some("abc")), Drift::Current);
+        assert_eq!(Drift::compare(Some("abc"), Some("def")), Drift::Changed);
+        assert_eq!(Drift::compare(None, Some("abc")), Drift::Unknown);
+        assert_eq!(Drift::compare(Some("abc"), None), Drift::Unknown);
+        assert_eq!(Drift::compare(None, None), Drift::Unknown);
+    }
+
+    #[test]
+    fn status_table() {
+        let recorded = |hash: &str, last_up| StackState {
+            hash: Some(hash.to_string()),
+            last_up: Some(last_up),
+            last_down: None,
+            version: "1.0.0".to_string(),
+        };
+        let now = 1_000_000;
+        let statuses = vec![
+            StackStatus {
+                stack: "web".to_string(),
+                running: Some(true),
+                recorded: Some(recorded("abc", now - 3 * 24 * 60 * 60)),
+                hash: Some("abc".to_string()),
+                urls: Vec::new(),
+            },
+            StackStatus {
+                stack: "database".to_string(),
+                running: Some(false),
+                recorded: Some(recorded("abc", now - 120)),
+                hash: Some("def".to_string()),
+                urls: Vec::new(),
+            },
+            StackStatus {
+                stack: "new".to_string(),
+                running: None,
+                recorded: None,
+                hash: Some("abc".to_string()),
+                urls: Vec::new(),
+            },
+        ];
+
+        assert_eq!(
+            render(&statuses, now),
+            "\
+Stack     Running  Last up        Config
+web       yes      3 days ago     current
+database  no       2 minutes ago  changed
+new       unknown  never          -
+"
+        );
+
+        let mut statuses = statuses;
+        statuses[0].urls = vec!["http://localhost:8080".to_string()];
+        assert_eq!(
+            render(&statuses, now),
+            "\
+Stack     Running  Last up        Config   URL
+web       yes      3 days ago     current  http://localhost:8080
+database  no       2 minutes ago  changed
+new       unknown  never          -
+"
+        );
+    }
+
+    #[test]
+    fn orphan_projects() {
+        let projects = parse_ls(
+            r#"[{"Name":"web","Status":"running(2)","ConfigFiles":"/srv/web/compose.yaml"},
+                {"Name":"database","Status":"running(1)","ConfigFiles":"/srv/db/compose.yaml"},
+                {"Name":"adhoc","Status":"exited(1)","ConfigFiles":"/tmp/adhoc/compose.yaml"},
+                {"Name":"scratch-1","Status":"running(1)","ConfigFiles":"/tmp/s/compose.yaml"},
+                {"Name":"scratchpad","Status":"running(1)"}]"#,
+        )
+        .unwrap();
+        assert_eq!(projects.len(), 5);
+        assert!(parse_ls("").unwrap().is_empty());
+        assert!(parse_ls("[]").unwrap().is_empty());
+        assert!(parse_ls("nope").is_err());
+
+        let names = |list: Vec<&super::Project>| -> Vec<String> {
+            list.iter().map(|project| project.name.clone()).collect()
+        };
+        assert_eq!(
+            names(orphans(&projects, &["web", "Database"], &[])),
+            vec!["adhoc", "scratch-1", "scratchpad"]
+        );
+
+        // Patterns must match the whole name.
+        let ignore = vec![Regex::new("^(?:scratch-.*)$").unwrap()];
+        let found = orphans(&projects, &["web", "database"], &ignore);
+        assert_eq!(names(found.clone()), vec!["adhoc", "scratchpad"]);
+
+        let table = render_orphans(&found);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "Project     Status      Config files");
+        assert_eq!(lines[1], "adhoc       exited(1)   /tmp/adhoc/compose.yaml");
+        assert_eq!(lines[2].trim_end(), "scratchpad  running(1)");
+        assert!(lines[3].contains("docker compose -p <project> down"));
+
+        assert_eq!(render_orphans(&[]), "No unmanaged compose projects.\n");
+    }
+
+    #[test]
+    fn presence() {
+        assert_eq!(Presence::of(&[]), Presence::Missing);
+        assert_eq!(
+            Presence::of(
+                &parse_ps(
+                    r#"[{"Service":"db","State":"exited"},{"Service":"web","State":"created"}]"#
+                )
+                .unwrap()
+            ),
+            Presence::Stopped
+        );
+        assert_eq!(
+            Presence::of(
+                &parse_ps(
+                    r#"{"Service":"db","State":"exited"}
+                    {"Service":"web","State":"running","Health":"unhealthy"}"#
+                )
+                .unwrap()
+            ),
+            Presence::Running
+        );
+    }
+
+    #[test]
+    fn run_states() {
+        let containers = |output: &str| parse_ps(output).unwrap();
+
+        // Healthy.
+        assert_eq!(
+            RunState::of(&containers(
+                r#"{"Service":"web","State":"running","Health":"healthy"}
+                {"Service":"db","State":"running","Health":""}"#
+            )),
+            RunState::Up
+        );
+
+        // Exited.
+        assert_eq!(
+            RunState::of(&containers(
+                r#"{"Service":"web","State":"running"}
+                {"Service":"db","State":"exited"}"#
+            )),
+            RunState::Partial
+        );
+        assert_eq!(
+            RunState::of(&containers(
+                r#"[{"Service":"web","State":"exited"},{"Service":"db","State":"created"}]"#
+            )),
+            RunState::Down
+        );
+
+        // Restarting and unhealthy containers are not up.
+        assert_eq!(
+            RunState::of(&containers(r#"{"Service":"web","State":"restarting"}"#)),
+            RunState::Partial
+        );
+        assert_eq!(
+            RunState::of(&containers(
+                r#"{"Service":"web","State":"running","Health":"unhealthy"}"#
+            )),
+            RunState::Partial
+        );
+
+        // Empty.
+        assert_eq!(RunState::of(&containers("")), RunState::Down);
+        assert_eq!(RunState::of(&containers("[]")), RunState::Down);
+    }
+}
@@ -0,0 +1,393 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Stack;
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// The command failed, with its exit code when there was one.
+    Failed(Option<i32>),
+    Skipped,
+}
+
+impl Outcome {
+    fn label(&self) -> String {
+        match self {
+            Outcome::Ok => "ok".to_string(),
+            Outcome::Failed(Some(code)) => format!("failed ({})", code),
+            Outcome::Failed(None) => "failed".to_string(),
+            Outcome::Skipped => "skipped".to_string(),
+        }
+    }
+}
+
+/// The result of running a command against a stack.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub stack: String,
+    pub command: String,
+    pub outcome: Outcome,
+    /// When the command started and finished, skipped stacks never ran.
+    pub timing: Option<(Instant, Instant)>,
+}
+
+impl Entry {
+    pub fn duration(&self) -> Option<Duration> {
+        self.timing.map(|(start, end)| end - start)
+    }
+}
+
+/// Formats a duration for display.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs < 60.0 {
+        format!("{:.1}s", secs)
+    } else {
+        format!(
+            "{}m{:02}s",
+            duration.as_secs() / 60,
+            duration.as_secs() % 60
+        )
+    }
+}
+
+/// Records the outcome of every command run against a stack.
+#[derive(Debug, Default)]
+pub struct Summary {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Summary {
+    /// Records the result of a command that was run.
+    pub fn record(&self, stack: &Stack, command: &str, start: Instant, result: &Result<()>) {
+        let outcome = match result {
+            Ok(()) => Outcome::Ok,
+            Err(Error::CommandFailed { status, .. }) => Outcome::Failed(status.code()),
+            Err(_) => Outcome::Failed(None),
+        };
+
+        self.entries.lock().unwrap().push(Entry {
+            stack: stack.key.clone(),
+            command: command.to_owned(),
+            outcome,
+            timing: Some((start, Instant::now())),
+        });
+    }
+
+    /// The number of entries recorded so far.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Whether nothing has been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_empty()
+    }
+
+    /// Records that the stacks were skipped.
+    pub fn skip(&self, stacks: &[&Stack], command: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .extend(stacks.iter().map(|stack| Entry {
+                stack: stack.key.clone(),
+                command: command.to_owned(),
+                outcome: Outcome::Skipped,
+                timing: None,
+            }));
+    }
+
+    /// Marks any of the stacks that were not recorded since `since` as skipped.
+    pub fn skip_missing(&self, since: usize, stacks: &[&Stack], command: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let missing: Vec<Entry> = stacks
+            .iter()
+            .filter(|stack| !entries[since..].iter().any(|e| e.stack == stack.key))
+            .map(|stack| Entry {
+                stack: stack.key.clone(),
+                command: command.to_owned(),
+                outcome: Outcome::Skipped,
+                timing: None,
+            })
+            .collect();
+        entries.extend(missing);
+    }
+
+    /// The entries in the order they were started, skipped stacks last.
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        entries.sort_by_key(|e| (e.timing.is_none(), e.timing.map(|(start, _)| start)));
+        entries
+    }
+
+    /// The distinct stacks that were recorded, in the order first seen.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = Vec::new();
+        for entry in self.entries.lock().unwrap().iter() {
+            if !keys.contains(&entry.stack) {
+                keys.push(entry.stack.clone());
+            }
+        }
+        keys
+    }
+
+    /// The number of distinct stacks that were recorded.
+    pub fn stacks(&self) -> usize {
+        self.keys().len()
+    }
+
+    /// The distinct stacks that the command succeeded for, in the order they
+    /// were started. Skipped stacks are left out.
+    pub fn succeeded(&self, command: &str) -> Vec<String> {
+        let mut keys: Vec<String> = Vec::new();
+        for entry in self.entries() {
+            if entry.command == command
+                && entry.outcome == Outcome::Ok
+                && !keys.contains(&entry.stack)
+            {
+                keys.push(entry.stack);
+            }
+        }
+        keys
+    }
+
+    /// The option that resumes the run from the first stack that failed.
+    pub fn resume_hint(&self) -> Option<String> {
+        self.entries()
+            .iter()
+            .find(|entry| matches!(entry.outcome, Outcome::Failed(_)))
+            .map(|entry| format!("--from {}", entry.stack))
+    }
+
+    /// Renders the summary as a table.
+    pub fn render(&self) -> String {
+        let entries = self.entries();
+        let rows: Vec<[String; 4]> = entries
+            .iter()
+            .map(|e| {
+                [
+                    e.stack.clone(),
+                    e.command.clone(),
+                    e.outcome.label(),
+                    e.duration().map(format_duration).unwrap_or("-".to_string()),
+                ]
+            })
+            .collect();
+
+        let count =
+            |wanted: fn(&Outcome) -> bool| entries.iter().filter(|e| wanted(&e.outcome)).count();
+        let total = match (
+            entries
+                .iter()
+                .filter_map(|e| e.timing)
+                .map(|(s, _)| s)
+                .min(),
+            entries
+                .iter()
+                .filter_map(|e| e.timing)
+                .map(|(_, e)| e)
+                .max(),
+        ) {
+            (Some(start), Some(end)) => format_duration(end - start),
+            _ => "-".to_string(),
+        };
+        let totals = [
+            "Total".to_string(),
+            String::new(),
+            format!(
+                "{} ok, {} failed, {} skipped",
+                count(|o| *o == Outcome::Ok),
+                count(|o| matches!(o, Outcome::Failed(_))),
+                count(|o| *o == Outcome::Skipped)
+            ),
+            total,
+        ];
+
+        let header = [
+            "Stack".to_string(),
+            "Command".to_string(),
+            "Status".to_string(),
+            "Duration".to_string(),
+        ];
+        let mut widths = [0; 4];
+        for row in rows.iter().chain([&header, &totals]) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let mut table = String::new();
+        for row in [&header].into_iter().chain(rows.iter()).chain([&totals]) {
+            let _ = writeln!(
+                table,
+                "{:<w0$}  {:<w1$}  {:<w2$}  {:>w3$}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+            );
+        }
+
+        table
+    }
+}
+
+/// Renders the URLs of the stacks that were brought up, or nothing if none
+/// of them have any.
+pub fn render_endpoints(endpoints: &[(String, Vec<String>)]) -> String {
+    let endpoints: Vec<&(String, Vec<String>)> = endpoints
+        .iter()
+        .filter(|(_, urls)| !urls.is_empty())
+        .collect();
+    if endpoints.is_empty() {
+        return String::new();
+    }
+
+    let width = endpoints
+        .iter()
+        .map(|(stack, _)| stack.chars().count())
+        .max()
+        .unwrap_or_default();
+
+    let mut block = "Available endpoints:\n".to_string();
+    for (stack, urls) in endpoints {
+        for (index, url) in urls.iter().enumerate() {
+            let label = if index == 0 { stack.as_str() } else { "" };
+            let _ = writeln!(block, "  {:<width$}  {}", label, url, width = width);
+        }
+    }
+
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_duration, render_endpoints, Outcome, Summary};
+    use crate::config::Config;
+    use crate::error::Error;
+    use std::path::PathBuf;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_millis(1234)), "1.2s");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59.0s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m05s");
+    }
+
+    #[test]
+    fn render() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                web: {}
+                database: {}
+                cache: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stacks = config.stacks(["web", "database", "cache"]).unwrap();
+        let stack = |key: &str| config.stacks.get(key).unwrap();
+
+        let summary = Summary::default();
+        let start = Instant::now();
+        summary.record(stack("database"), "up", start, &Ok(()));
+        sleep(Duration::from_millis(10));
+        summary.record(
+            stack("web"),
+            "up",
+            Instant::now(),
+            &Err(Error::Invalid("bad".to_string())),
+        );
+        summary.skip_missing(0, &stacks, "up");
+
+        assert_eq!(summary.stacks(), 3);
+        let entries = summary.entries();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.stack.as_str(), e.outcome))
+                .collect::<Vec<_>>(),
+            vec![
+                ("database", Outcome::Ok),
+                ("web", Outcome::Failed(None)),
+                ("cache", Outcome::Skipped)
+            ]
+        );
+
+        let table = summary.render();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("Stack     Command  Status"));
+        assert!(lines[1].starts_with("database  up       ok    "));
+        assert!(lines[2].starts_with("web       up       failed"));
+        assert!(lines[3].starts_with("cache     up       skipped"));
+        assert!(lines[3].ends_with(" -"));
+        assert!(lines[4].starts_with("Total              1 ok, 1 failed, 1 skipped"));
+
+        // The run can be resumed from the stack that failed.
+        assert_eq!(summary.resume_hint().as_deref(), Some("--from web"));
+        assert_eq!(Summary::default().resume_hint(), None);
+    }
+
+    #[test]
+    fn endpoints() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                web: {}
+                admin: {}
+                db: {}
+                api: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stack = |key: &str| config.stacks.get(key).unwrap();
+
+        let summary = Summary::default();
+        summary.record(stack("db"), "up", Instant::now(), &Ok(()));
+        summary.record(
+            stack("api"),
+            "up",
+            Instant::now(),
+            &Err(Error::Invalid("bad".to_string())),
+        );
+        summary.record(stack("web"), "up", Instant::now(), &Ok(()));
+        summary.record(stack("web"), "pull", Instant::now(), &Ok(()));
+        summary.skip(&[stack("admin")], "up");
+
+        // Stacks that failed or were skipped weren't started.
+        assert_eq!(summary.succeeded("up"), vec!["db", "web"]);
+        assert_eq!(summary.succeeded("pull"), vec!["web"]);
+        assert!(summary.succeeded("restart").is_empty());
+
+        let urls = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            render_endpoints(&[
+                ("db".to_string(), urls(&[])),
+                (
+                    "web".to_string(),
+                    urls(&["http://localhost:8080", "http://localhost:8080/admin"])
+                ),
+                ("grafana".to_string(), urls(&["http://localhost:3000"])),
+            ]),
+            "Available endpoints:\n  \
+            web      http://localhost:8080\n  \
+            \x20        http://localhost:8080/admin\n  \
+            grafana  http://localhost:3000\n"
+        );
+        assert_eq!(render_endpoints(&[("db".to_string(), urls(&[]))]), "");
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Stack;
+use crate::error::{Error, Result};
+
+/// The stack commands that a task's steps can run.
+pub const TASK_COMMANDS: [&str; 15] = [
+    "build", "create", "down", "exec", "kill", "pause", "pull", "push", "restart", "rm", "run",
+    "start", "stop", "unpause", "up",
+];
+
+/// A step that runs a stack command against some stacks, like
+/// `{ command: down, stacks: [db], args: [-v] }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandStep {
+    pub command: String,
+    pub stacks: Vec<String>,
+    /// Arguments for the command, as they would be given on the command line.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+}
+
+/// A step that runs a shell command on the host in a stack's directory, like
+/// `{ run: ./migrate.sh, stack: api }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunStep {
+    pub run: String,
+    pub stack: String,
+}
+
+/// A step of a task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Step {
+    Command(CommandStep),
+    Run(RunStep),
+}
+
+impl Step {
+    /// Describes the step for logging.
+    pub fn describe(&self) -> String {
+        match self {
+            Step::Command(step) => {
+                let command: Vec<&str> = [step.command.as_str()]
+                    .into_iter()
+                    .chain(step.args.iter().map(String::as_str))
+                    .collect();
+                format!("{} for {}", command.join(" "), step.stacks.join(", "))
+            }
+            Step::Run(step) => format!("`{}` in {}", step.run, step.stack),
+        }
+    }
+}
+
+/// A task is given either as just its steps or with a description.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TaskDefinition {
+    Steps(Vec<Step>),
+    Described {
+        description: Option<String>,
+        steps: Vec<Step>,
+    },
+}
+
+/// A named sequence of steps, run by `stack task <name>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "TaskDefinition")]
+pub struct Task {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+impl From<TaskDefinition> for Task {
+    fn from(definition: TaskDefinition) -> Self {
+        match definition {
+            TaskDefinition::Steps(steps) => Task {
+                description: None,
+                steps,
+            },
+            TaskDefinition::Described { description, steps } => Task { description, steps },
+        }
+    }
+}
+
+/// Checks that every step of every task runs a known command against known
+/// stacks.
+pub fn validate(tasks: &BTreeMap<String, Task>, stacks: &BTreeMap<String, Stack>) -> Result<()> {
+    for (name, task) in tasks {
+        if task.steps.is_empty() {
+            return Err(Error::Invalid(format!("Task `{}` has no steps.", name)));
+        }
+
+        for (index, step) in task.steps.iter().enumerate() {
+            let problem = |problem: String| {
+                Error::Invalid(format!(
+                    "Step {} of task `{}` {}.",
+                    index + 1,
+                    name,
+                    problem
+                ))
+            };
+
+            let keys: Vec<&String> = match step {
+                Step::Command(step) => {
+                    if !TASK_COMMANDS.contains(&step.command.as_str()) {
+                        return Err(problem(format!(
+                            "runs `{}` which is not one of {}",
+                            step.command,
+                            TASK_COMMANDS.join(", ")
+                        )));
+                    }
+                    if step.stacks.is_empty() {
+                        return Err(problem("has no stacks".to_owned()));
+                    }
+                    step.stacks.iter().collect()
+                }
+                Step::Run(step) => {
+                    if step.run.trim().is_empty() {
+                        return Err(problem("has nothing to run".to_owned()));
+                    }
+                    vec![&step.stack]
+                }
+            };
+
+            if let Some(unknown) = keys.into_iter().find(|key| !stacks.contains_key(*key)) {
+                return Err(problem(format!("uses the unknown stack `{}`", unknown)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the tasks and their descriptions.
+pub fn render_list(tasks: &BTreeMap<String, Task>) -> String {
+    if tasks.is_empty() {
+        return "No tasks are configured.\n".to_owned();
+    }
+
+    let width = tasks
+        .keys()
+        .map(|name| name.chars().count())
+        .chain(["Task".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut list = String::new();
+    let _ = writeln!(list, "{:<width$}  Description", "Task", width = width);
+    for (name, task) in tasks {
+        let line = format!(
+            "{:<width$}  {}",
+            name,
+            task.description.as_deref().unwrap_or_default(),
+            width = width
+        );
+        let _ = writeln!(list, "{}", line.trim_end());
+    }
+
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_list, validate, CommandStep, RunStep, Step, Task};
+    use crate::config::Config;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    fn tasks(yaml: &str) -> BTreeMap<String, Task> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn config() -> Config {
+        Config::from_reader(
+            &PathBuf::from("/stacks"),
+            "
+            stacks:
+                db: {}
+                api: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn definitions() {
+        let tasks = tasks(
+            "
+            reset-db:
+                description: Recreate the database
+                steps:
+                    - { command: down, stacks: [db], args: [-v] }
+                    - { command: up, stacks: [db] }
+                    - { run: ./migrate.sh, stack: api }
+            restart:
+                - command: restart
+                  stacks: [api, db]
+            ",
+        );
+
+        assert_eq!(
+            tasks["reset-db"],
+            Task {
+                description: Some("Recreate the database".to_string()),
+                steps: vec![
+                    Step::Command(CommandStep {
+                        command: "down".to_string(),
+                        stacks: vec!["db".to_string()],
+                        args: vec!["-v".to_string()],
+                    }),
+                    Step::Command(CommandStep {
+                        command: "up".to_string(),
+                        stacks: vec!["db".to_string()],
+                        args: vec![],
+                    }),
+                    Step::Run(RunStep {
+                        run: "./migrate.sh".to_string(),
+                        stack: "api".to_string(),
+                    }),
+                ],
+            }
+        );
+        assert_eq!(tasks["restart"].description, None);
+        assert_eq!(tasks["restart"].steps.len(), 1);
+
+        assert_eq!(
+            tasks["reset-db"]
+                .steps
+                .iter()
+                .map(Step::describe)
+                .collect::<Vec<_>>(),
+            vec!["down -v for db", "up for db", "`./migrate.sh` in api"]
+        );
+
+        // Serializing keeps the steps as they were given.
+        let yaml = serde_yaml::to_string(&tasks).unwrap();
+        let parsed: BTreeMap<String, Task> = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(parsed, tasks);
+        assert!(!yaml.contains("args: []"));
+
+        assert!(
+            serde_yaml::from_str::<BTreeMap<String, Task>>("broken: [{ stacks: [db] }]").is_err()
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let config = config();
+        let check = |yaml: &str| validate(&tasks(yaml), &config.stacks).map_err(|e| e.to_string());
+
+        assert!(
+            check("ok: [{ command: up, stacks: [db, api] }, { run: make, stack: api }]").is_ok()
+        );
+        assert_eq!(
+            check("bad: [{ command: up, stacks: [db, web] }]").unwrap_err(),
+            "Step 1 of task `bad` uses the unknown stack `web`."
+        );
+        assert_eq!(
+            check("bad: [{ command: up, stacks: [db] }, { run: make, stack: web }]").unwrap_err(),
+            "Step 2 of task `bad` uses the unknown stack `web`."
+        );
+        assert!(check("bad: [{ command: explode, stacks: [db] }]")
+            .unwrap_err()
+            .starts_with("Step 1 of task `bad` runs `explode` which is not one of build,"));
+        assert_eq!(
+            check("bad: [{ command: up, stacks: [] }]").unwrap_err(),
+            "Step 1 of task `bad` has no stacks."
+        );
+        assert_eq!(
+            check("bad: [{ run: ' ', stack: db }]").unwrap_err(),
+            "Step 1 of task `bad` has nothing to run."
+        );
+        assert_eq!(check("bad: []").unwrap_err(), "Task `bad` has no steps.");
+    }
+
+    #[test]
+    fn listing() {
+        assert_eq!(
+            render_list(&tasks(
+                "
+                reset-db:
+                    description: Recreate the database
+                    steps: [{ command: up, stacks: [db] }]
+                up: [{ command: up, stacks: [db] }]
+                "
+            )),
+            "Task      Description\n\
+            reset-db  Recreate the database\n\
+            up\n"
+        );
+        assert_eq!(render_list(&BTreeMap::new()), "No tasks are configured.\n");
+    }
+}
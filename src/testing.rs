@@ -0,0 +1,326 @@
+//! Helpers for tests that need to run commands without spawning anything.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::{ExitStatus, Output};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::cancel::Cancellation;
+use crate::config::Stack;
+use crate::error::Result;
+use crate::exec::ExecOptions;
+use crate::executor::{exit_status, Executor};
+use crate::open::Opener;
+use crate::output::OutputHandler;
+
+/// How long to hold commands waiting for others to overlap with them before
+/// giving up, so a scheduler that runs them one at a time fails the test
+/// rather than hanging it.
+const OVERLAP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Running {
+    count: usize,
+    max: usize,
+    overlapped: bool,
+}
+
+/// Tracks how many commands are running at once. Commands can be held until
+/// enough of them are running together, which shows that they can overlap
+/// without relying on how long each takes.
+#[derive(Default)]
+pub struct Concurrency {
+    running: Mutex<Running>,
+    changed: Condvar,
+    /// How many commands must be running before any of them continue.
+    overlap: usize,
+}
+
+impl Concurrency {
+    /// Holds the first commands until `overlap` of them are running together.
+    pub fn overlapping(overlap: usize) -> Self {
+        Self {
+            overlap,
+            ..Default::default()
+        }
+    }
+
+    /// Marks a command as started, returning how many are now running
+    /// including it.
+    pub fn start(&self) -> usize {
+        let mut running = self.running.lock().unwrap();
+        running.count += 1;
+        running.max = running.max.max(running.count);
+        let count = running.count;
+        if count >= self.overlap {
+            running.overlapped = true;
+            self.changed.notify_all();
+        }
+
+        let _ = self
+            .changed
+            .wait_timeout_while(running, OVERLAP_TIMEOUT, |running| !running.overlapped)
+            .unwrap();
+        count
+    }
+
+    /// Marks a command as finished.
+    pub fn finish(&self) {
+        self.running.lock().unwrap().count -= 1;
+    }
+
+    /// The most commands that were running at once.
+    pub fn max(&self) -> usize {
+        self.running.lock().unwrap().max
+    }
+}
+
+/// An executor that records the commands it is asked to run rather than
+/// running them.
+#[derive(Default)]
+pub struct RecordingExecutor {
+    runs: Mutex<Vec<ExecOptions>>,
+    queries: Mutex<Vec<ExecOptions>>,
+    /// Stdout to return for queries, keyed by command.
+    responses: HashMap<String, String>,
+    /// Stdout to return for successive queries, keyed by command. The last
+    /// response is repeated.
+    sequences: Mutex<HashMap<String, VecDeque<String>>>,
+    /// The stack and command pairs that exit with a failure.
+    failures: Vec<(String, String)>,
+    /// How long each command takes to run.
+    delay: Duration,
+    concurrency: Concurrency,
+    /// How many commands were running when each of `runs` started.
+    running: Mutex<Vec<usize>>,
+}
+
+impl RecordingExecutor {
+    /// Returns the given stdout for any query with the command.
+    pub fn respond(mut self, command: &str, stdout: &str) -> Self {
+        self.responses.insert(command.to_owned(), stdout.to_owned());
+        self
+    }
+
+    /// Returns each of the given stdouts in turn for queries with the
+    /// command, repeating the last one once they run out.
+    pub fn respond_sequence(self, command: &str, stdouts: &[&str]) -> Self {
+        self.sequences.lock().unwrap().insert(
+            command.to_owned(),
+            stdouts.iter().map(|s| s.to_string()).collect(),
+        );
+        self
+    }
+
+    /// Makes running the command against the stack fail.
+    pub fn fail(mut self, stack: &str, command: &str) -> Self {
+        self.failures.push((stack.to_owned(), command.to_owned()));
+        self
+    }
+
+    /// Makes every command take some time to run.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Holds the first commands until `count` of them are running together.
+    pub fn overlap(mut self, count: usize) -> Self {
+        self.concurrency = Concurrency::overlapping(count);
+        self
+    }
+
+    /// How many commands were running, including it, when each command
+    /// started, in the same order as `runs`.
+    pub fn concurrency(&self) -> Vec<usize> {
+        self.running.lock().unwrap().clone()
+    }
+
+    /// The options of every command that was run, in order.
+    pub fn runs(&self) -> Vec<ExecOptions> {
+        self.runs.lock().unwrap().clone()
+    }
+
+    /// The options of every query, in order.
+    pub fn queries(&self) -> Vec<ExecOptions> {
+        self.queries.lock().unwrap().clone()
+    }
+
+    /// The stack and command of every command that was run, in order.
+    pub fn commands(&self) -> Vec<(String, String)> {
+        self.runs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|options| {
+                (
+                    options
+                        .environment
+                        .get("STACK_NAME")
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    options.command.clone(),
+                )
+            })
+            .collect()
+    }
+}
+
+impl Executor for RecordingExecutor {
+    fn run(
+        &self,
+        exec_options: &ExecOptions,
+        stack: &Stack,
+        _output: &dyn OutputHandler,
+        _cancel: &Cancellation,
+    ) -> Result<ExitStatus> {
+        let running = self.concurrency.start();
+        {
+            let mut runs = self.runs.lock().unwrap();
+            runs.push(exec_options.clone());
+            self.running.lock().unwrap().push(running);
+        }
+        thread::sleep(self.delay);
+        self.concurrency.finish();
+
+        let failed = self
+            .failures
+            .iter()
+            .any(|(key, command)| *key == stack.key && *command == exec_options.command);
+        Ok(exit_status(if failed { 1 } else { 0 }))
+    }
+
+    fn output(&self, exec_options: &ExecOptions) -> Result<Output> {
+        self.queries.lock().unwrap().push(exec_options.clone());
+
+        // Queries don't say which stack they are for but the project name
+        // defaults to the stack's key.
+        let failed = exec_options
+            .environment
+            .get("COMPOSE_PROJECT_NAME")
+            .is_some_and(|name| {
+                self.failures
+                    .iter()
+                    .any(|(key, command)| name == key.as_str() && *command == exec_options.command)
+            });
+        if failed {
+            return Ok(Output {
+                status: exit_status(1),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        }
+
+        let sequenced = self
+            .sequences
+            .lock()
+            .unwrap()
+            .get_mut(&exec_options.command)
+            .and_then(|sequence| {
+                if sequence.len() > 1 {
+                    sequence.pop_front()
+                } else {
+                    sequence.front().cloned()
+                }
+            });
+
+        Ok(Output {
+            status: exit_status(0),
+            stdout: sequenced
+                .or_else(|| self.responses.get(&exec_options.command).cloned())
+                .unwrap_or_default()
+                .into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+}
+
+/// A tiny HTTP server that answers each connection with the next of some
+/// canned responses and records the requests it receives.
+pub struct HttpServer {
+    port: u16,
+    handle: JoinHandle<Vec<String>>,
+}
+
+impl HttpServer {
+    /// Starts serving the responses, each a status line, headers and body.
+    /// The server stops after the last response.
+    pub fn start(responses: &[&str]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let responses: Vec<String> = responses.iter().map(|s| s.to_string()).collect();
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                requests.push(request);
+
+                let (head, body) = response.split_once("\r\n\r\n").unwrap();
+                write!(
+                    stream,
+                    "{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    head,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            requests
+        });
+
+        Self { port, handle }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    /// A URL that nothing is listening on.
+    pub fn unreachable_url(path: &str) -> String {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        format!("http://127.0.0.1:{}{}", port, path)
+    }
+
+    /// Waits for every response to be sent and returns the requests.
+    pub fn requests(self) -> Vec<String> {
+        self.handle.join().unwrap()
+    }
+}
+
+/// An opener that records the URLs it is asked to open rather than opening
+/// them.
+#[derive(Default)]
+pub struct RecordingOpener {
+    opened: Mutex<Vec<String>>,
+}
+
+impl RecordingOpener {
+    /// The URLs that were opened, in order.
+    pub fn opened(&self) -> Vec<String> {
+        self.opened.lock().unwrap().clone()
+    }
+}
+
+impl Opener for RecordingOpener {
+    fn open(&self, url: &str) -> std::result::Result<(), String> {
+        self.opened.lock().unwrap().push(url.to_owned());
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::Stack;
+use crate::summary::format_duration;
+
+/// The phase that covers everything done for a stack.
+pub const TOTAL: &str = "total";
+
+#[derive(Debug, Clone)]
+struct Record {
+    /// The stack the phase was for, or none for phases of the whole run.
+    stack: Option<String>,
+    phase: &'static str,
+    duration: Duration,
+}
+
+/// Records how long each phase of a run took.
+#[derive(Debug, Default)]
+pub struct Timings {
+    records: Mutex<Vec<Record>>,
+}
+
+/// Times a phase until it is dropped.
+pub struct Scope<'a> {
+    timings: &'a Timings,
+    stack: Option<String>,
+    phase: &'static str,
+    start: Instant,
+}
+
+impl Drop for Scope<'_> {
+    fn drop(&mut self) {
+        self.timings.records.lock().unwrap().push(Record {
+            stack: self.stack.take(),
+            phase: self.phase,
+            duration: self.start.elapsed(),
+        });
+    }
+}
+
+/// The time spent on a stack.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StackTimings {
+    pub stack: String,
+    /// Seconds spent on the stack, including anything not in a phase.
+    pub total: f64,
+    /// Seconds spent in each phase.
+    pub phases: BTreeMap<String, f64>,
+}
+
+/// How long everything took, in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    /// Phases that were not for a specific stack.
+    pub phases: BTreeMap<String, f64>,
+    pub stacks: Vec<StackTimings>,
+}
+
+impl Timings {
+    /// Starts timing a phase, for a stack or for the whole run. Phases that
+    /// happen more than once, like retried commands, are added together.
+    pub fn scope(&self, stack: Option<&Stack>, phase: &'static str) -> Scope<'_> {
+        Scope {
+            timings: self,
+            stack: stack.map(|stack| stack.key.clone()),
+            phase,
+            start: Instant::now(),
+        }
+    }
+
+    /// Totals the phases for the run and each stack, stacks are in the order
+    /// they finished.
+    pub fn report(&self) -> Report {
+        let mut report = Report {
+            phases: BTreeMap::new(),
+            stacks: Vec::new(),
+        };
+
+        for record in self.records.lock().unwrap().iter() {
+            let seconds = record.duration.as_secs_f64();
+            let key = match record.stack {
+                Some(ref key) => key,
+                None => {
+                    *report.phases.entry(record.phase.to_owned()).or_default() += seconds;
+                    continue;
+                }
+            };
+
+            let index = match report.stacks.iter().position(|s| s.stack == *key) {
+                Some(index) => index,
+                None => {
+                    report.stacks.push(StackTimings {
+                        stack: key.clone(),
+                        total: 0.0,
+                        phases: BTreeMap::new(),
+                    });
+                    report.stacks.len() - 1
+                }
+            };
+            let stack = &mut report.stacks[index];
+            if record.phase == TOTAL {
+                stack.total += seconds;
+            } else {
+                *stack.phases.entry(record.phase.to_owned()).or_default() += seconds;
+            }
+        }
+
+        report
+    }
+}
+
+impl Report {
+    /// Renders the report as a table with a column for each phase.
+    pub fn render(&self) -> String {
+        let mut phases: Vec<&String> = self.stacks.iter().flat_map(|s| s.phases.keys()).collect();
+        phases.sort_unstable();
+        phases.dedup();
+
+        let seconds = |value: Option<&f64>| {
+            value
+                .map(|secs| format_duration(Duration::from_secs_f64(*secs)))
+                .unwrap_or("-".to_string())
+        };
+
+        let mut header = vec!["Stack".to_string(), "Total".to_string()];
+        header.extend(phases.iter().map(|phase| phase.to_string()));
+        let mut rows = vec![header];
+        for stack in self.stacks.iter() {
+            let mut row = vec![stack.stack.clone(), seconds(Some(&stack.total))];
+            row.extend(phases.iter().map(|phase| seconds(stack.phases.get(*phase))));
+            rows.push(row);
+        }
+
+        let mut widths = vec![0; rows[0].len()];
+        for row in rows.iter() {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let mut table = String::new();
+        for (phase, secs) in self.phases.iter() {
+            let _ = writeln!(table, "{}: {}", phase, seconds(Some(secs)));
+        }
+        for row in rows.iter() {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(widths.iter())
+                .enumerate()
+                .map(|(index, (cell, width))| {
+                    if index == 0 {
+                        format!("{:<width$}", cell)
+                    } else {
+                        format!("{:>width$}", cell)
+                    }
+                })
+                .collect();
+            let _ = writeln!(table, "{}", cells.join("  ").trim_end());
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Timings, TOTAL};
+    use crate::config::Config;
+    use std::path::PathBuf;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn report() {
+        let config = Config::from_reader(
+            &PathBuf::default(),
+            "
+            stacks:
+                db: {}
+                web: {}
+            "
+            .as_bytes(),
+        )
+        .unwrap();
+        let stack = |key: &str| config.stacks.get(key).unwrap();
+
+        let timings = Timings::default();
+        {
+            let _preflight = timings.scope(None, "preflight");
+            sleep(Duration::from_millis(10));
+        }
+        for key in ["web", "db"] {
+            let _total = timings.scope(Some(stack(key)), TOTAL);
+            for _ in 0..2 {
+                let _command = timings.scope(Some(stack(key)), "command");
+                sleep(Duration::from_millis(10));
+            }
+        }
+
+        let report = timings.report();
+        assert_eq!(report.phases.keys().collect::<Vec<_>>(), vec!["preflight"]);
+        assert!(report.phases["preflight"] >= 0.01);
+
+        let keys: Vec<&str> = report.stacks.iter().map(|s| s.stack.as_str()).collect();
+        assert_eq!(keys, vec!["web", "db"]);
+        for stack in report.stacks.iter() {
+            let command = stack.phases["command"];
+            assert!(command >= 0.02);
+            assert!(stack.total >= command);
+        }
+
+        let table = report.render();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("preflight: "));
+        assert_eq!(lines[1], "Stack  Total  command");
+        assert!(lines[2].starts_with("web  "));
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+/// The label compose gives containers with the name of their service.
+pub const SERVICE_LABEL: &str = "com.docker.compose.service";
+/// The label compose gives containers with the hash of their service's config.
+pub const CONFIG_HASH_LABEL: &str = "com.docker.compose.config-hash";
+/// The label compose gives containers with the directory of the project.
+pub const WORKING_DIR_LABEL: &str = "com.docker.compose.project.working_dir";
+/// The label compose gives containers with the comma separated compose files.
+pub const CONFIG_FILES_LABEL: &str = "com.docker.compose.project.config_files";
+
+/// A container as reported by `docker container inspect`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Inspected {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Config")]
+    config: InspectedConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct InspectedConfig {
+    #[serde(rename = "Labels", default)]
+    labels: Option<HashMap<String, String>>,
+    #[serde(rename = "Env", default)]
+    env: Option<Vec<String>>,
+}
+
+impl Inspected {
+    /// The container's name without docker's leading `/`.
+    pub fn name(&self) -> &str {
+        self.name.strip_prefix('/').unwrap_or(&self.name)
+    }
+
+    pub fn label(&self, label: &str) -> Option<&str> {
+        self.config
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(label))
+            .map(String::as_str)
+    }
+
+    /// The value of a variable in the container's environment.
+    pub fn env(&self, name: &str) -> Option<&str> {
+        self.config
+            .env
+            .iter()
+            .flatten()
+            .filter_map(|entry| entry.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Parses the output of `docker container inspect`.
+pub fn parse_inspect(output: &str) -> Result<Vec<Inspected>> {
+    if output.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    serde_json::from_str(output)
+        .map_err(|e| Error::Invalid(format!("Invalid container inspect output: {}", e)))
+}
+
+/// Parses the output of `docker compose config --hash '*'`, a service and
+/// its hash on each line.
+pub fn parse_hashes(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once(' '))
+        .map(|(service, hash)| (service.to_owned(), hash.trim().to_owned()))
+        .collect()
+}
+
+/// Finds the environment of each service in a rendered compose config.
+/// Variables without a value are left out as the container takes them from
+/// wherever it was started.
+pub fn service_environments(rendered: &str) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    #[derive(Deserialize)]
+    struct Service {
+        #[serde(default)]
+        environment: Option<BTreeMap<String, serde_yaml::Value>>,
+    }
+    #[derive(Deserialize)]
+    struct Project {
+        #[serde(default)]
+        services: BTreeMap<String, Service>,
+    }
+
+    let project: Project = serde_yaml::from_str(rendered)
+        .map_err(|e| Error::Invalid(format!("Invalid compose config: {}", e)))?;
+    Ok(project
+        .services
+        .into_iter()
+        .map(|(service, config)| {
+            let environment = config
+                .environment
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let value = match value {
+                        serde_yaml::Value::String(value) => value,
+                        serde_yaml::Value::Number(value) => value.to_string(),
+                        serde_yaml::Value::Bool(value) => value.to_string(),
+                        _ => return None,
+                    };
+                    Some((key, value))
+                })
+                .collect();
+            (service, environment)
+        })
+        .collect())
+}
+
+/// What a stack's containers should look like if it were brought up now.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expected {
+    pub working_dir: PathBuf,
+    pub config_files: Vec<PathBuf>,
+    /// Each service's config hash, empty if compose can't give them.
+    pub hashes: BTreeMap<String, String>,
+    /// Each configured service's environment.
+    pub environment: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Whether a stack's running containers match its config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    InSync,
+    /// The containers differ from the config in the described ways.
+    Drifted(Vec<String>),
+    NotRunning,
+    /// The stack could not be checked, for the given reason.
+    Unknown(String),
+}
+
+impl Verdict {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Verdict::InSync => "in sync",
+            Verdict::Drifted(_) => "drifted",
+            Verdict::NotRunning => "not running",
+            Verdict::Unknown(_) => "unknown",
+        }
+    }
+
+    /// The details to show with `--verbose`.
+    fn details(&self) -> Vec<String> {
+        match self {
+            Verdict::Drifted(differences) => differences.clone(),
+            Verdict::Unknown(reason) => vec![reason.clone()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn same_paths(label: &str, expected: &[PathBuf]) -> bool {
+    let running: BTreeSet<&Path> = label
+        .split(',')
+        .filter(|file| !file.is_empty())
+        .map(Path::new)
+        .collect();
+    running == expected.iter().map(PathBuf::as_path).collect()
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Compares a stack's running containers with what would be deployed.
+/// Values of variables are never shown as they may be secret.
+pub fn compare(expected: &Expected, containers: &[Inspected]) -> Verdict {
+    if containers.is_empty() {
+        return Verdict::NotRunning;
+    }
+
+    let mut containers: Vec<&Inspected> = containers.iter().collect();
+    containers.sort_by(|a, b| a.name().cmp(b.name()));
+
+    let mut differences = Vec::new();
+    for container in containers {
+        let name = container.name();
+        let service = match container.label(SERVICE_LABEL) {
+            Some(service) => service,
+            None => {
+                differences.push(format!("{} was not started by compose", name));
+                continue;
+            }
+        };
+
+        if let Some(working_dir) = container.label(WORKING_DIR_LABEL) {
+            if Path::new(working_dir) != expected.working_dir {
+                differences.push(format!(
+                    "{} was started in {} rather than {}",
+                    name,
+                    working_dir,
+                    expected.working_dir.display()
+                ));
+            }
+        }
+
+        if let Some(files) = container.label(CONFIG_FILES_LABEL) {
+            if !same_paths(files, &expected.config_files) {
+                differences.push(format!(
+                    "{} was started from {} rather than {}",
+                    name,
+                    files.replace(',', ", "),
+                    display_paths(&expected.config_files)
+                ));
+            }
+        }
+
+        let environment = match expected.environment.get(service) {
+            Some(environment) => environment,
+            None => {
+                differences.push(format!(
+                    "{} runs the service {} which is no longer configured",
+                    name, service
+                ));
+                continue;
+            }
+        };
+
+        if let (Some(running), Some(hash)) = (
+            container.label(CONFIG_HASH_LABEL),
+            expected.hashes.get(service),
+        ) {
+            if running != hash {
+                differences.push(format!(
+                    "{} has a different configuration for {}",
+                    name, service
+                ));
+            }
+        }
+
+        for (key, value) in environment {
+            match container.env(key) {
+                Some(running) if running == value => {}
+                Some(_) => differences.push(format!("{} has a different {}", name, key)),
+                None => differences.push(format!("{} doesn't set {}", name, key)),
+            }
+        }
+    }
+
+    if differences.is_empty() {
+        Verdict::InSync
+    } else {
+        Verdict::Drifted(differences)
+    }
+}
+
+/// Lists whether each stack is in sync and, with details, how the drifted
+/// stacks differ.
+pub fn render(verdicts: &[(String, Verdict)], details: bool) -> String {
+    let width = verdicts
+        .iter()
+        .map(|(stack, _)| stack.chars().count())
+        .chain(["Stack".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut report = String::new();
+    let _ = writeln!(report, "{:<width$}  State", "Stack", width = width);
+    for (stack, verdict) in verdicts {
+        let _ = writeln!(
+            report,
+            "{:<width$}  {}",
+            stack,
+            verdict.label(),
+            width = width
+        );
+    }
+
+    if details {
+        for (stack, verdict) in verdicts {
+            let lines = verdict.details();
+            if lines.is_empty() {
+                continue;
+            }
+            let _ = writeln!(report, "\n{}:", stack);
+            for line in lines {
+                let _ = writeln!(report, "  {}", line);
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compare, parse_hashes, parse_inspect, render, service_environments, Expected, Verdict,
+    };
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    const RENDERED: &str = "
+name: shop
+services:
+  app:
+    image: shop-app
+    environment:
+      DATABASE_URL: postgres://db/shop
+      WORKERS: 4
+      DEBUG: false
+      PASSED_THROUGH: null
+  db:
+    image: postgres:16
+";
+
+    const HASHES: &str = "app 1111aaaa\ndb 2222bbbb\n";
+
+    /// Renders `docker container inspect` output for a container of the shop
+    /// project.
+    fn inspect(name: &str, service: &str, hash: &str, env: &[&str], files: &str) -> String {
+        format!(
+            r#"{{
+                "Id": "{name}0123",
+                "Name": "/{name}",
+                "State": {{ "Status": "running" }},
+                "Config": {{
+                    "Env": {env:?},
+                    "Labels": {{
+                        "com.docker.compose.project": "shop",
+                        "com.docker.compose.service": "{service}",
+                        "com.docker.compose.config-hash": "{hash}",
+                        "com.docker.compose.project.working_dir": "/srv/shop",
+                        "com.docker.compose.project.config_files": "{files}"
+                    }}
+                }}
+            }}"#,
+            name = name,
+            service = service,
+            hash = hash,
+            env = env,
+            files = files,
+        )
+    }
+
+    fn expected() -> Expected {
+        Expected {
+            working_dir: PathBuf::from("/srv/shop"),
+            config_files: vec![
+                PathBuf::from("/srv/shop/compose.yaml"),
+                PathBuf::from("/srv/shop/compose.override.yaml"),
+            ],
+            hashes: parse_hashes(HASHES),
+            environment: service_environments(RENDERED).unwrap(),
+        }
+    }
+
+    const FILES: &str = "/srv/shop/compose.override.yaml,/srv/shop/compose.yaml";
+
+    fn in_sync_app() -> String {
+        inspect(
+            "shop-app-1",
+            "app",
+            "1111aaaa",
+            &[
+                "PATH=/usr/bin",
+                "DATABASE_URL=postgres://db/shop",
+                "WORKERS=4",
+                "DEBUG=false",
+                "PASSED_THROUGH=anything",
+            ],
+            FILES,
+        )
+    }
+
+    fn in_sync_db() -> String {
+        inspect("shop-db-1", "db", "2222bbbb", &["PGDATA=/data"], FILES)
+    }
+
+    fn verdict(containers: &[String]) -> Verdict {
+        let output = format!("[{}]", containers.join(","));
+        compare(&expected(), &parse_inspect(&output).unwrap())
+    }
+
+    fn drifted(containers: &[String]) -> Vec<String> {
+        match verdict(containers) {
+            Verdict::Drifted(differences) => differences,
+            other => panic!("Expected drift but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            parse_hashes(HASHES),
+            [("app", "1111aaaa"), ("db", "2222bbbb")]
+                .iter()
+                .map(|(s, h)| (s.to_string(), h.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        );
+        assert!(parse_hashes("").is_empty());
+
+        let environments = service_environments(RENDERED).unwrap();
+        assert_eq!(environments["app"].len(), 3);
+        assert_eq!(environments["app"]["WORKERS"], "4");
+        assert_eq!(environments["app"]["DEBUG"], "false");
+        assert!(environments["db"].is_empty());
+        assert!(service_environments("services: []").is_err());
+
+        let containers = parse_inspect(&format!("[{}]", in_sync_app())).unwrap();
+        assert_eq!(containers[0].name(), "shop-app-1");
+        assert_eq!(containers[0].env("WORKERS"), Some("4"));
+        assert_eq!(containers[0].env("MISSING"), None);
+        assert!(parse_inspect("").unwrap().is_empty());
+        assert!(parse_inspect("[]").unwrap().is_empty());
+        assert!(parse_inspect("Error: No such container").is_err());
+
+        // Labels and the environment may be null.
+        let bare =
+            parse_inspect(r#"[{"Name":"/bare","Config":{"Labels":null,"Env":null}}]"#).unwrap();
+        assert_eq!(bare[0].label("anything"), None);
+        assert_eq!(bare[0].env("PATH"), None);
+    }
+
+    #[test]
+    fn in_sync() {
+        // The order of the compose files in the label doesn't matter.
+        assert_eq!(verdict(&[in_sync_app(), in_sync_db()]), Verdict::InSync);
+        // Services that aren't running aren't drift.
+        assert_eq!(verdict(&[in_sync_db()]), Verdict::InSync);
+        assert_eq!(verdict(&[]), Verdict::NotRunning);
+
+        // Hashes are skipped when compose can't give them.
+        let mut expected = expected();
+        expected.hashes.clear();
+        let containers = parse_inspect(&format!(
+            "[{}]",
+            inspect("shop-db-1", "db", "other", &[], FILES)
+        ))
+        .unwrap();
+        assert_eq!(compare(&expected, &containers), Verdict::InSync);
+    }
+
+    #[test]
+    fn environment_drift() {
+        let app = inspect(
+            "shop-app-1",
+            "app",
+            "9999ffff",
+            &[
+                "DATABASE_URL=postgres://elsewhere/shop",
+                "WORKERS=4",
+                "DEBUG=false",
+            ],
+            FILES,
+        );
+        assert_eq!(
+            drifted(&[in_sync_db(), app]),
+            vec![
+                "shop-app-1 has a different configuration for app",
+                "shop-app-1 has a different DATABASE_URL",
+            ]
+        );
+
+        let app = inspect(
+            "shop-app-1",
+            "app",
+            "1111aaaa",
+            &["DATABASE_URL=postgres://db/shop", "DEBUG=false"],
+            FILES,
+        );
+        assert_eq!(drifted(&[app]), vec!["shop-app-1 doesn't set WORKERS"]);
+    }
+
+    #[test]
+    fn project_drift() {
+        let app = inspect(
+            "shop-app-1",
+            "app",
+            "1111aaaa",
+            &[
+                "DATABASE_URL=postgres://db/shop",
+                "WORKERS=4",
+                "DEBUG=false",
+            ],
+            "/srv/shop/compose.yaml",
+        )
+        .replace("\"/srv/shop\"", "\"/home/me/shop\"");
+        assert_eq!(
+            drifted(&[app]),
+            vec![
+                "shop-app-1 was started in /home/me/shop rather than /srv/shop",
+                "shop-app-1 was started from /srv/shop/compose.yaml rather than \
+                /srv/shop/compose.yaml, /srv/shop/compose.override.yaml",
+            ]
+        );
+
+        let removed = inspect("shop-worker-1", "worker", "3333cccc", &[], FILES);
+        assert_eq!(
+            drifted(&[in_sync_app(), removed]),
+            vec!["shop-worker-1 runs the service worker which is no longer configured"]
+        );
+
+        let adhoc = r#"{"Name":"/adhoc","Config":{"Labels":{}}}"#.to_string();
+        assert_eq!(drifted(&[adhoc]), vec!["adhoc was not started by compose"]);
+    }
+
+    #[test]
+    fn report() {
+        let verdicts = vec![
+            ("db".to_string(), Verdict::InSync),
+            (
+                "shop".to_string(),
+                Verdict::Drifted(vec!["shop-app-1 has a different WORKERS".to_string()]),
+            ),
+            ("cache".to_string(), Verdict::NotRunning),
+            (
+                "search".to_string(),
+                Verdict::Unknown("docker compose config failed".to_string()),
+            ),
+        ];
+
+        assert_eq!(
+            render(&verdicts, false),
+            "Stack   State\n\
+            db      in sync\n\
+            shop    drifted\n\
+            cache   not running\n\
+            search  unknown\n"
+        );
+        assert_eq!(
+            render(&verdicts, true),
+            "Stack   State\n\
+            db      in sync\n\
+            shop    drifted\n\
+            cache   not running\n\
+            search  unknown\n\
+            \n\
+            shop:\n  \
+              shop-app-1 has a different WORKERS\n\
+            \n\
+            search:\n  \
+              docker compose config failed\n"
+        );
+    }
+}
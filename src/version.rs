@@ -0,0 +1,142 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::Error;
+
+/// A docker compose version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    /// Parses versions like `2.20.1`, ignoring any `v` prefix and pre-release
+    /// or build suffixes such as `-desktop.1` or `+azure-1`. Missing minor or
+    /// patch numbers are treated as 0.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Invalid(format!("Invalid version \"{}\"", s));
+
+        let trimmed = s.trim();
+        let trimmed = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+        let core = trimmed
+            .split(['-', '+'])
+            .next()
+            .filter(|core| !core.is_empty())
+            .ok_or_else(invalid)?;
+
+        let mut parts = core.split('.');
+        let mut next = |required: bool| match parts.next() {
+            Some(part) => part.parse::<u64>().map_err(|_| invalid()),
+            None if required => Err(invalid()),
+            None => Ok(0),
+        };
+
+        let version = Version::new(next(true)?, next(false)?, next(false)?);
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Flags that stacks adds to commands along with the compose version that
+/// introduced them.
+pub const FLAG_VERSIONS: [(&str, Version); 1] = [("--wait", Version::new(2, 1, 1))];
+
+/// Whether a flag is supported by the given version of compose. When the
+/// version is unknown flags are assumed to be supported.
+pub fn supports(flag: &str, version: Option<Version>) -> bool {
+    match (version, FLAG_VERSIONS.iter().find(|(f, _)| *f == flag)) {
+        (Some(version), Some((_, introduced))) => version >= *introduced,
+        _ => true,
+    }
+}
+
+/// Compose commands that stacks runs along with the compose version that
+/// introduced them.
+pub const COMMAND_VERSIONS: [(&str, Version); 1] = [("watch", Version::new(2, 22, 0))];
+
+/// The compose version that introduced the command, if it is newer than
+/// compose v2 itself.
+pub fn command_version(command: &str) -> Option<Version> {
+    COMMAND_VERSIONS
+        .iter()
+        .find(|(c, _)| *c == command)
+        .map(|(_, introduced)| *introduced)
+}
+
+/// Parses the output of `docker compose version --short`.
+pub fn parse_output(stdout: &str) -> Option<Version> {
+    match stdout.parse() {
+        Ok(version) => Some(version),
+        Err(e) => {
+            log::debug!("Unable to determine the compose version: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command_version, supports, Version};
+
+    #[test]
+    fn parse() {
+        let parse = |s: &str| s.parse::<Version>().ok();
+
+        assert_eq!(parse("2.20.1"), Some(Version::new(2, 20, 1)));
+        assert_eq!(parse("v2.20.1\n"), Some(Version::new(2, 20, 1)));
+        assert_eq!(parse("2.20.1-desktop.1"), Some(Version::new(2, 20, 1)));
+        assert_eq!(parse("2.3.3+azure-1"), Some(Version::new(2, 3, 3)));
+        assert_eq!(parse("1.29.2"), Some(Version::new(1, 29, 2)));
+        assert_eq!(parse("2.1"), Some(Version::new(2, 1, 0)));
+        assert_eq!(parse("v2"), Some(Version::new(2, 0, 0)));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("v"), None);
+        assert_eq!(parse("-rc1"), None);
+        assert_eq!(parse("2.x.1"), None);
+        assert_eq!(parse("1.2.3.4"), None);
+        assert_eq!(parse("out web"), None);
+
+        assert!(Version::new(2, 1, 1) > Version::new(2, 0, 9));
+        assert!(Version::new(10, 0, 0) > Version::new(9, 99, 99));
+        assert_eq!(Version::new(2, 20, 1).to_string(), "2.20.1");
+    }
+
+    #[test]
+    fn flags() {
+        assert!(supports("--wait", None));
+        assert!(supports("--wait", Some(Version::new(2, 1, 1))));
+        assert!(supports("--wait", Some(Version::new(2, 20, 0))));
+        assert!(!supports("--wait", Some(Version::new(2, 0, 1))));
+        assert!(!supports("--wait", Some(Version::new(1, 29, 2))));
+        assert!(supports("--build", Some(Version::new(1, 0, 0))));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(command_version("watch"), Some(Version::new(2, 22, 0)));
+        assert_eq!(command_version("up"), None);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+
+use crate::cancel::Cancellation;
+use crate::error::{Error, Result};
+
+/// Moves the cursor to the top left and clears the terminal.
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// Marks the rows of the current sample that don't appear in the previous
+/// one. Rows are compared by their whole content so rows that only moved are
+/// not marked. Nothing is marked in the first sample.
+pub fn changed_rows(previous: Option<&str>, current: &str) -> Vec<bool> {
+    let previous: Option<HashSet<&str>> = previous.map(|sample| sample.lines().collect());
+
+    current
+        .lines()
+        .map(|row| match previous {
+            Some(ref previous) => !previous.contains(row),
+            None => false,
+        })
+        .collect()
+}
+
+/// The current sample with the rows that changed since the previous one shown
+/// in reverse video.
+pub fn highlight(previous: Option<&str>, current: &str) -> String {
+    current
+        .lines()
+        .zip(changed_rows(previous, current))
+        .map(|(row, changed)| {
+            if changed {
+                format!("\x1b[7m{}\x1b[0m\n", row)
+            } else {
+                format!("{}\n", row)
+            }
+        })
+        .collect()
+}
+
+/// Shows each sample as it is taken. On a terminal the screen is redrawn with
+/// the changed rows highlighted, otherwise samples are written one after the
+/// other separated by a blank line.
+pub struct Watcher<W> {
+    out: W,
+    terminal: bool,
+    previous: Option<String>,
+}
+
+impl<W: Write> Watcher<W> {
+    pub fn new(out: W, terminal: bool) -> Self {
+        Self {
+            out,
+            terminal,
+            previous: None,
+        }
+    }
+
+    pub fn show(&mut self, sample: &str) -> io::Result<()> {
+        if self.terminal {
+            write!(
+                self.out,
+                "{}{}",
+                CLEAR,
+                highlight(self.previous.as_deref(), sample)
+            )?;
+        } else {
+            if self.previous.is_some() {
+                writeln!(self.out)?;
+            }
+            write!(self.out, "{}", sample)?;
+        }
+        self.out.flush()?;
+
+        self.previous = Some(sample.to_owned());
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Takes and shows a sample every interval until the run is cancelled.
+pub fn watch<F>(interval: Duration, cancel: &Cancellation, mut sample: F) -> Result<()>
+where
+    F: FnMut() -> Result<String>,
+{
+    let stdout = io::stdout();
+    let terminal = stdout.is_terminal();
+    let mut watcher = Watcher::new(stdout, terminal);
+
+    loop {
+        let current = sample()?;
+        watcher
+            .show(&current)
+            .map_err(|e| Error::io("Failed to write output", e))?;
+
+        if !cancel.sleep(interval) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_rows, highlight, Watcher, CLEAR};
+
+    #[test]
+    fn changes() {
+        let first = "Stack  Running\ndb     yes\nweb    no\n";
+        let second = "Stack  Running\ndb     yes\nweb    yes\n";
+
+        assert_eq!(changed_rows(None, first), vec![false, false, false]);
+        assert_eq!(changed_rows(Some(first), second), vec![false, false, true]);
+        assert_eq!(changed_rows(Some(second), second), vec![false; 3]);
+
+        // Rows that only moved are unchanged, new rows are changed.
+        assert_eq!(
+            changed_rows(
+                Some(first),
+                "Stack  Running\nweb    no\napi    no\ndb     yes\n"
+            ),
+            vec![false, false, true, false]
+        );
+        assert_eq!(changed_rows(Some(first), ""), Vec::<bool>::new());
+
+        assert_eq!(highlight(None, first), first);
+        assert_eq!(
+            highlight(Some(first), second),
+            "Stack  Running\ndb     yes\n\x1b[7mweb    yes\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn terminal() {
+        let mut watcher = Watcher::new(Vec::new(), true);
+        watcher.show("a\nb\n").unwrap();
+        watcher.show("a\nc\n").unwrap();
+
+        assert_eq!(
+            String::from_utf8(watcher.into_inner()).unwrap(),
+            format!("{CLEAR}a\nb\n{CLEAR}a\n\x1b[7mc\x1b[0m\n")
+        );
+    }
+
+    #[test]
+    fn plain() {
+        let mut watcher = Watcher::new(Vec::new(), false);
+        watcher.show("a\nb\n").unwrap();
+        watcher.show("a\nc\n").unwrap();
+        watcher.show("a\nc\n").unwrap();
+
+        assert_eq!(
+            String::from_utf8(watcher.into_inner()).unwrap(),
+            "a\nb\n\na\nc\n\na\nc\n"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+/// Writes a script that logs its name and arguments.
+fn script(dir: &Path, name: &str) {
+    let path = dir.join(name);
+    fs::write(
+        &path,
+        format!(
+            "#!/bin/sh\necho {} \"$@\" >> {}\n",
+            name,
+            dir.join("calls.log").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Runs `ps` and returns the line logged by the compose command that ran.
+fn ps(dir: &Path, env: Option<&str>, args: &[&str]) -> String {
+    let _ = fs::remove_file(dir.join("calls.log"));
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_stacks"));
+    command
+        .current_dir(dir)
+        .env_remove("STACKS_FILE")
+        .env_remove("STACKS_STACKS")
+        .env_remove("STACKS_COMMAND")
+        .env("XDG_CACHE_HOME", dir.join("cache"));
+    if let Some(binary) = env {
+        command.env("STACKS_COMMAND", binary);
+    }
+    let output = command
+        .args(args)
+        .args(["--no-preflight", "ps"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    fs::read_to_string(dir.join("calls.log"))
+        .unwrap()
+        .trim()
+        .to_string()
+}
+
+#[test]
+fn precedence() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().canonicalize().unwrap();
+    for name in ["configured", "variable", "flag"] {
+        script(&dir, name);
+    }
+    fs::create_dir_all(dir.join("web")).unwrap();
+    fs::write(dir.join("web/compose.yaml"), "").unwrap();
+    fs::write(
+        dir.join("stacks.yml"),
+        format!(
+            "command: {}\nstacks:\n  web: {{}}\n",
+            dir.join("configured").display()
+        ),
+    )
+    .unwrap();
+
+    let variable = dir.join("variable").display().to_string();
+    let flag = dir.join("flag").display().to_string();
+
+    assert!(ps(&dir, None, &[]).starts_with("configured "));
+    assert!(ps(&dir, Some(&variable), &[]).starts_with("variable "));
+    assert!(ps(&dir, None, &["--binary", &flag]).starts_with("flag "));
+    assert!(ps(&dir, Some(&variable), &["--binary", &flag]).starts_with("flag "));
+
+    // Both forms of command are accepted.
+    assert!(ps(&dir, None, &["--binary", &format!("{} extra", flag)]).starts_with("flag extra "));
+    assert!(ps(&dir, Some(&format!("[{}, extra]", variable)), &[]).starts_with("variable extra "));
+}
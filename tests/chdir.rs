@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn stack(dir: &Path, file_env: Option<&str>, args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_stacks"));
+    command
+        .current_dir(dir)
+        .env_remove("STACKS_FILE")
+        .env_remove("STACKS_STACKS")
+        .env_remove("STACKS_LOG_FILE")
+        .env("XDG_CACHE_HOME", dir.join("cache"));
+    if let Some(file) = file_env {
+        command.env("STACKS_FILE", file);
+    }
+    command.args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn chdir() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = dir.path().join("project");
+    fs::create_dir_all(project.join("nested")).unwrap();
+    fs::write(project.join("stacks.yml"), "stacks:\n  web: {}\n  db: {}\n").unwrap();
+    fs::write(project.join("other.yml"), "stacks:\n  cache: {}\n").unwrap();
+
+    // The config is found by searching upwards from the new directory.
+    let graph = stdout(&stack(dir.path(), None, &["-C", "project/nested", "graph"]));
+    assert!(graph.contains("\"web\""));
+
+    // `--file` and `STACKS_FILE` are relative to the new directory.
+    let graph = stdout(&stack(
+        dir.path(),
+        None,
+        &["--chdir", "project", "-f", "other.yml", "graph"],
+    ));
+    assert!(graph.contains("\"cache\""));
+    let graph = stdout(&stack(
+        dir.path(),
+        Some("other.yml"),
+        &["-C", "project", "graph"],
+    ));
+    assert!(graph.contains("\"cache\""));
+
+    // A missing directory is an error.
+    let output = stack(dir.path(), None, &["-C", "missing", "graph"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr).trim(),
+        "Cannot change to missing, it is not a directory."
+    );
+}
@@ -0,0 +1,141 @@
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn stack(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_stacks"))
+        .current_dir(dir)
+        .env_remove("STACKS_FILE")
+        .env_remove("STACKS_STACKS")
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn project(config: &str) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("stacks.yml"), config).unwrap();
+    fs::create_dir_all(dir.path().join("web")).unwrap();
+    fs::write(dir.path().join("web/compose.yaml"), "").unwrap();
+    dir
+}
+
+#[test]
+fn success() {
+    let dir = project("command: \"true\"\nstacks:\n  web: {}\n");
+    let output = stack(dir.path(), &["ps"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn invalid_config() {
+    let dir = project("stacks: [\n");
+    let output = stack(dir.path(), &["ps"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn missing_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = stack(dir.path(), &["-f", "missing.yml", "ps"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn unknown_stack() {
+    let dir = project("command: \"true\"\nstacks:\n  web: {}\n");
+    let output = stack(dir.path(), &["api", "ps"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr).trim(),
+        "unknown stack \"api\""
+    );
+}
+
+#[test]
+fn command_failed() {
+    let dir = project("command: \"false\"\nstacks:\n  web: {}\n");
+    let output = stack(dir.path(), &["ps"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+/// Creates a fake compose binary that runs the given script.
+#[cfg(unix)]
+fn fake_binary(dir: &Path, script: &str) -> String {
+    let path = dir.join("fake-compose");
+    fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[cfg(unix)]
+#[test]
+fn exact_code() {
+    let dir = project("");
+    let config = format!(
+        "command: {}\nstacks:\n  web: {{}}\n",
+        fake_binary(dir.path(), "exit 3")
+    );
+    fs::write(dir.path().join("stacks.yml"), config).unwrap();
+    let output = stack(dir.path(), &["web", "exec", "api", "check"]);
+    assert_eq!(output.status.code(), Some(3));
+
+    let config = format!(
+        "command: {}\nstacks:\n  web: {{}}\n",
+        fake_binary(dir.path(), "kill -TERM $$")
+    );
+    fs::write(dir.path().join("stacks.yml"), config).unwrap();
+    let output = stack(dir.path(), &["ps"]);
+    assert_eq!(output.status.code(), Some(143));
+}
+
+#[cfg(unix)]
+#[test]
+fn multiple_stacks_failed() {
+    let dir = project("");
+    fs::create_dir_all(dir.path().join("db")).unwrap();
+    fs::write(dir.path().join("db/compose.yaml"), "").unwrap();
+    let config = format!(
+        "command: {}\nstacks:\n  web: {{}}\n  db: {{}}\n",
+        fake_binary(dir.path(), "exit 3")
+    );
+    fs::write(dir.path().join("stacks.yml"), config).unwrap();
+
+    let output = stack(dir.path(), &["--parallel", "up"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Commands failed for 2 of the stacks:"));
+    assert!(stderr.contains("  web: "));
+    assert!(stderr.contains("  db: "));
+    assert!(stderr.contains("exit status: 3"));
+}
+
+#[test]
+fn missing_program() {
+    let dir = project("command: not-a-real-compose\nstacks:\n  web: {}\n");
+    let output = stack(dir.path(), &["ps"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Could not find `not-a-real-compose`"));
+    assert!(stderr.contains("`command`"));
+}
+
+#[cfg(unix)]
+#[test]
+fn relative_program() {
+    let dir = project("");
+    fs::create_dir_all(dir.path().join("bin")).unwrap();
+    let binary = fake_binary(dir.path(), "exit 3");
+    fs::rename(binary, dir.path().join("bin/compose")).unwrap();
+    fs::write(
+        dir.path().join("stacks.yml"),
+        "command: bin/compose\nstacks:\n  web: {}\n",
+    )
+    .unwrap();
+
+    let output = stack(dir.path(), &["ps"]);
+    assert_eq!(output.status.code(), Some(3));
+}
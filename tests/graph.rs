@@ -0,0 +1,40 @@
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn graph_without_docker() {
+    let dir = tempfile::tempdir().unwrap();
+    // Any attempt to run compose would fail.
+    fs::write(
+        dir.path().join("stacks.yml"),
+        "command: /nonexistent/compose\nstacks:\n  db: {}\n  web:\n    depends_on: [db]\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_stacks"))
+        .current_dir(dir.path())
+        .env_remove("STACKS_FILE")
+        .env_remove("STACKS_STACKS")
+        .env("XDG_CACHE_HOME", dir.path().join("cache"))
+        .args(["graph", "--format", "json"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let graph: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(graph["nodes"][0]["key"], "db");
+    assert_eq!(graph["nodes"][1]["key"], "web");
+    assert_eq!(
+        graph["edges"],
+        serde_json::json!([{ "from": "web", "to": "db" }])
+    );
+    assert!(graph["metadata"]["config"]
+        .as_str()
+        .unwrap()
+        .ends_with("stacks.yml"));
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn stack(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_stacks"))
+        .current_dir(dir)
+        .env_remove("STACKS_FILE")
+        .env_remove("STACKS_STACKS")
+        .env_remove("STACKS_LOG_FILE")
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn log_file() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("stacks.yml"),
+        "command: \"true\"\nstacks:\n  web: {}\n",
+    )
+    .unwrap();
+    fs::create_dir_all(dir.path().join("web")).unwrap();
+    fs::write(dir.path().join("web/compose.yaml"), "").unwrap();
+
+    let output = stack(dir.path(), &["--log-file", "stack.log", "ps"]);
+    assert_eq!(output.status.code(), Some(0));
+    // Debug messages only go to the file.
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Executing"));
+
+    let log = fs::read_to_string(dir.path().join("stack.log")).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert!(lines[0].starts_with("==== "));
+    assert!(lines[0].ends_with(" --log-file stack.log ps ===="));
+    assert!(lines
+        .iter()
+        .any(|line| line.contains("Executing `true -p web --project-directory")));
+
+    // Later runs are appended.
+    let output = stack(dir.path(), &["--log-file", "stack.log", "ps"]);
+    assert_eq!(output.status.code(), Some(0));
+    let log = fs::read_to_string(dir.path().join("stack.log")).unwrap();
+    assert_eq!(log.lines().filter(|l| l.starts_with("==== ")).count(), 2);
+
+    // A log file that can't be written is only a warning.
+    let output = stack(dir.path(), &["--log-file", "missing/dir/stack.log", "ps"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unable to log to"));
+}
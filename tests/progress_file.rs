@@ -0,0 +1,72 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use serde_json::Value;
+
+fn stack(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_stacks"))
+        .current_dir(dir)
+        .env_remove("STACKS_FILE")
+        .env_remove("STACKS_STACKS")
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Reads the events as (event, stack) pairs.
+fn events(path: &Path) -> Vec<(String, Option<String>)> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let event: Value = serde_json::from_str(line).unwrap();
+            (
+                event["event"].as_str().unwrap().to_string(),
+                event["stack"].as_str().map(String::from),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn two_stacks() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("stacks.yml"),
+        "command: echo\nstacks:\n  db: {}\n  web:\n    depends_on: [db]\n",
+    )
+    .unwrap();
+    for stack in ["db", "web"] {
+        fs::create_dir_all(dir.path().join(stack)).unwrap();
+        fs::write(dir.path().join(stack).join("compose.yaml"), "").unwrap();
+    }
+
+    let output = stack(dir.path(), &["--progress-file", "events.json", "up"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let event = |name: &str, stack: Option<&str>| (name.to_string(), stack.map(String::from));
+    assert_eq!(
+        events(&dir.path().join("events.json")),
+        vec![
+            event("header", None),
+            event("stack_started", Some("db")),
+            event("stack_finished", Some("db")),
+            event("stack_started", Some("web")),
+            event("stack_finished", Some("web")),
+            event("run_finished", None),
+        ]
+    );
+
+    // Output can be included.
+    let output = stack(
+        dir.path(),
+        &["--progress-file", "output.json", "--progress-output", "up"],
+    );
+    assert_eq!(output.status.code(), Some(0));
+    let events = events(&dir.path().join("output.json"));
+    assert_eq!(events.len(), 8);
+    assert_eq!(events[2], event("output", Some("db")));
+    assert_eq!(events[5], event("output", Some("web")));
+}
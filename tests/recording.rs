@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use stacks::record::{parse, Record};
+
+/// A diamond of stacks along with one that nothing depends on.
+const CONFIG: &str = "
+command: docker compose
+environment:
+  REGION: eu
+stacks:
+  db: {}
+  cache: {}
+  api:
+    depends_on: [db, cache]
+  web:
+    depends_on: [api]
+  other: {}
+";
+
+fn fixture(dir: &Path) {
+    fs::write(dir.join("stacks.yml"), CONFIG).unwrap();
+    for stack in ["db", "cache", "api", "web", "other"] {
+        fs::create_dir_all(dir.join(stack)).unwrap();
+        fs::write(dir.join(stack).join("compose.yaml"), "services: {}\n").unwrap();
+    }
+}
+
+/// Runs stacks in recording mode, returning how it exited and what it
+/// recorded.
+fn record(dir: &Path, fail: Option<&str>, args: &[&str]) -> (Output, Vec<Record>) {
+    let path = dir.join("record.jsonl");
+    let _ = fs::remove_file(&path);
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_stacks"));
+    command
+        .current_dir(dir)
+        .env_remove("STACKS_FILE")
+        .env_remove("STACKS_STACKS")
+        .env_remove("STACKS_COMMAND")
+        .env_remove("STACKS_LOG_FILE")
+        .env_remove("STACK_EXEC_RECORD_FAIL")
+        .env("XDG_CACHE_HOME", dir.join("cache-home"))
+        .env("STACK_EXEC_RECORD", &path);
+    if let Some(stack) = fail {
+        command.env("STACK_EXEC_RECORD_FAIL", stack);
+    }
+    let output = command.args(args).output().unwrap();
+
+    let records = match fs::read_to_string(&path) {
+        Ok(contents) => parse(&contents).unwrap(),
+        Err(_) => Vec::new(),
+    };
+    (output, records)
+}
+
+/// The stack and command of each command run against a stack.
+fn runs(records: &[Record]) -> Vec<(String, String)> {
+    records
+        .iter()
+        .filter(|record| record.kind == "run")
+        .map(|record| (record.stack.clone().unwrap(), record.command.clone()))
+        .collect()
+}
+
+fn commands(list: &[(&str, &str)]) -> Vec<(String, String)> {
+    list.iter()
+        .map(|(stack, command)| (stack.to_string(), command.to_string()))
+        .collect()
+}
+
+fn succeeded(output: &Output) {
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn up() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().canonicalize().unwrap();
+    fixture(&dir);
+
+    let (output, records) = record(&dir, None, &["web", "up"]);
+    succeeded(&output);
+    assert_eq!(
+        runs(&records),
+        commands(&[("cache", "up"), ("db", "up"), ("api", "up"), ("web", "up")])
+    );
+
+    // Each command runs in the stack's directory with its environment.
+    let web = records
+        .iter()
+        .find(|record| record.stack.as_deref() == Some("web"))
+        .unwrap();
+    assert_eq!(web.program, "docker");
+    assert_eq!(web.args.first().map(String::as_str), Some("compose"));
+    assert!(web.args.contains(&"up".to_string()));
+    assert_eq!(Path::new(&web.cwd), dir.join("web"));
+    assert_eq!(web.env.get("REGION").map(String::as_str), Some("eu"));
+
+    let (output, records) = record(&dir, None, &["up"]);
+    succeeded(&output);
+    assert_eq!(
+        runs(&records),
+        commands(&[
+            ("cache", "up"),
+            ("db", "up"),
+            ("other", "up"),
+            ("api", "up"),
+            ("web", "up"),
+        ])
+    );
+}
+
+#[test]
+fn down() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().canonicalize().unwrap();
+    fixture(&dir);
+
+    let (output, records) = record(&dir, None, &["db", "down"]);
+    succeeded(&output);
+    assert_eq!(
+        runs(&records),
+        commands(&[("web", "down"), ("api", "down"), ("db", "down")])
+    );
+}
+
+#[test]
+fn restart() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().canonicalize().unwrap();
+    fixture(&dir);
+
+    // Nothing has any containers while recording so `--all` is needed.
+    let (output, records) = record(&dir, None, &["api", "restart", "--all"]);
+    succeeded(&output);
+    assert_eq!(
+        runs(&records),
+        commands(&[
+            ("web", "down"),
+            ("api", "down"),
+            ("cache", "up"),
+            ("db", "up"),
+            ("api", "up"),
+            ("web", "up"),
+        ])
+    );
+
+    let (output, records) = record(&dir, None, &["api", "restart"]);
+    succeeded(&output);
+    assert!(runs(&records).is_empty());
+}
+
+#[test]
+fn failure() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().canonicalize().unwrap();
+    fixture(&dir);
+
+    // Stacks that depend on the failed stack aren't started.
+    let (output, records) = record(&dir, Some("db"), &["web", "up"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(runs(&records), commands(&[("cache", "up"), ("db", "up")]));
+}
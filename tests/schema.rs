@@ -0,0 +1,20 @@
+use std::process::Command;
+
+#[test]
+fn schema_without_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_stacks"))
+        .current_dir(dir.path())
+        .env_remove("STACKS_FILE")
+        .arg("schema")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        schema["$id"],
+        format!("urn:stacks:config:{}", env!("CARGO_PKG_VERSION"))
+    );
+    assert!(schema["$defs"]["stack"]["properties"]["depends_on"].is_object());
+}